@@ -3,7 +3,11 @@ mod tests {
     use super::*;
 
     use crate::bus::{Bus, Device};
+    use crate::c64::C64;
     use crate::cpu::Cpu;
+    use crate::host_io::NullMonitor;
+    use crate::joystick::JoystickInput;
+    use crate::keyboard::C64Key;
     use crate::ram::Ram;
     use asm6502::assemble;
     use assert::*;
@@ -31,6 +35,20 @@ mod tests {
         (cpu, bus, ram)
     }
 
+    fn c64_fixture(asm: &'static str) -> C64 {
+        let c64 = C64::new(Rc::new(RefCell::new(NullMonitor {})));
+
+        let mut buf = Vec::<u8>::new();
+        assert_ok!(assemble(asm.as_bytes(), &mut buf));
+        (*c64.ram).borrow_mut().set_memory(&buf, 0).unwrap();
+        // Clear screen with spaces, like KERNAL does
+        (*c64.ram)
+            .borrow_mut()
+            .set_memory(&[0x20; 1000], 0x0400)
+            .unwrap();
+        c64
+    }
+
     #[test]
     fn multiply_test() {
         let (mut cpu, mut bus, _ram) = fixture(
@@ -57,4 +75,181 @@ mod tests {
         cpu.run_until_brk(&mut bus);
         assert_eq!(cpu.reg.x, 5 * 6);
     }
+
+    #[test]
+    fn print_to_screen() {
+        let mut c64 = c64_fixture(
+            r#"
+        LDX #5
+
+    loop:
+        LDA $1fff,X
+        STA $05e3,X
+        DEX
+        BNE loop
+
+    finish:
+        CLC
+        BCC finish
+    "#,
+        );
+        // "HELLO" in screen codes
+        (*c64.ram)
+            .borrow_mut()
+            .set_memory(&[0x08, 0x05, 0x0c, 0x0c, 0x0f], 0x2000)
+            .unwrap();
+
+        c64.run_until_text("HELLO", 10_000).unwrap();
+        let screen = c64.screen_text();
+        assert_eq!(screen.lines().nth(12), Some("    HELLO"));
+    }
+
+    #[test]
+    fn text_never_appears() {
+        let mut c64 = c64_fixture(
+            r#"
+    finish:
+        CLC
+        BCC finish
+    "#,
+        );
+
+        let err = c64.run_until_text("READY.", 5_000).unwrap_err();
+        assert_eq!(err.screen.split('\n').count(), 25);
+        assert!(err.screen.trim().is_empty());
+    }
+
+    #[test]
+    fn cia_timer_interrupt() {
+        let mut c64 = c64_fixture(
+            r#"
+        JMP start
+
+    handler:
+        INC $2000
+        LDA $DC0D
+        RTI
+
+    start:
+        LDA #$03
+        STA $FFFE
+        LDA #$00
+        STA $FFFF
+        LDA #100
+        STA $DC04
+        LDA #0
+        STA $DC05
+        LDA #$81
+        STA $DC0D
+        LDA #$11
+        STA $DC0E
+        CLI
+    wait:
+        JMP wait
+    "#,
+        );
+
+        for _ in 0..1000 {
+            c64.tick();
+        }
+        let interrupts = c64.bus.get_byte(0x2000);
+        assert!((8..=10).contains(&interrupts));
+        // Handler acknowledged the interrupt
+        assert!(!c64.cia1.borrow().interrupt());
+    }
+
+    // Select column with A, S and left shift and read its rows
+    #[test]
+    fn scan_keyboard() {
+        let mut c64 = c64_fixture(
+            r#"
+        LDA #$FF
+        STA $DC02
+        LDA #$00
+        STA $DC03
+        LDA #$FD
+        STA $DC00
+    loop:
+        LDA $DC01
+        STA $2000
+        JMP loop
+    "#,
+        );
+
+        c64.keyboard.press(1, vec![C64Key::A, C64Key::Q]);
+        for _ in 0..100 {
+            c64.tick();
+        }
+        assert_eq!(c64.bus.get_byte(0x2000), 0b1111_1011);
+
+        c64.keyboard.release(1);
+        for _ in 0..100 {
+            c64.tick();
+        }
+        assert_eq!(c64.bus.get_byte(0x2000), 0xff);
+    }
+
+    #[test]
+    fn restore_triggers_nmi() {
+        let mut c64 = c64_fixture(
+            r#"
+        JMP start
+
+    handler:
+        INC $2000
+        RTI
+
+    start:
+        LDA #$03
+        STA $FFFA
+        LDA #$00
+        STA $FFFB
+    wait:
+        JMP wait
+    "#,
+        );
+        for _ in 0..100 {
+            c64.tick();
+        }
+
+        c64.keyboard.press(1, vec![C64Key::Restore]);
+        for _ in 0..200 {
+            c64.tick();
+        }
+        c64.keyboard.release(1);
+        for _ in 0..200 {
+            c64.tick();
+        }
+        // Only once, NMI is edge triggered
+        assert_eq!(c64.bus.get_byte(0x2000), 1);
+    }
+
+    #[test]
+    fn read_joysticks() {
+        let mut c64 = c64_fixture(
+            r#"
+    loop:
+        LDA $DC00
+        STA $2000
+        LDA $DC01
+        STA $2001
+        JMP loop
+    "#,
+        );
+
+        c64.joysticks[1].set(JoystickInput::Up, true);
+        c64.joysticks[1].set(JoystickInput::Fire, true);
+        for _ in 0..100 {
+            c64.tick();
+        }
+        assert_eq!(c64.bus.get_byte(0x2000), 0b1110_1110);
+        assert_eq!(c64.bus.get_byte(0x2001), 0xff);
+
+        c64.swap_joysticks();
+        for _ in 0..100 {
+            c64.tick();
+        }
+        assert_eq!(c64.bus.get_byte(0x2000), 0xff);
+        assert_eq!(c64.bus.get_byte(0x2001), 0b1110_1110);
+    }
 }
@@ -0,0 +1,186 @@
+// One line assembler for the monitor. Takes instruction in the same syntax disassembler
+// prints, numbers are hex with optional $ prefix.
+
+use crate::ops_lookup::{AddressMode, OPCODE_TABLE};
+use crate::options::{hex_digits, parse_address};
+
+fn opcode(mnemonic: &str, mode: AddressMode) -> Option<u8> {
+    OPCODE_TABLE
+        .iter()
+        .position(|op| matches!(op, Some(op) if op.name == mnemonic && op.mode == mode))
+        .map(|opcode| opcode as u8)
+}
+
+// Value and whether it was written with more than two digits, e.g. $0012 is absolute
+fn parse_value(text: &str) -> Result<(u16, bool), String> {
+    let value = parse_address(text)?;
+    Ok((value, value > 0xff || hex_digits(text).len() > 2))
+}
+
+// Zero page form if value fits and instruction has it
+fn zero_page_or_absolute(
+    mnemonic: &str,
+    value: &str,
+    zero_page: AddressMode,
+    absolute: AddressMode,
+) -> Result<(u8, Vec<u8>), String> {
+    let (value, wide) = parse_value(value)?;
+    if !wide {
+        if let Some(opcode) = opcode(mnemonic, zero_page) {
+            return Ok((opcode, vec![value as u8]));
+        }
+    }
+    let opcode = opcode(mnemonic, absolute).ok_or("Bad address mode")?;
+    Ok((opcode, value.to_le_bytes().to_vec()))
+}
+
+pub fn assemble_line(line: &str, address: u16) -> Result<Vec<u8>, String> {
+    let line = line.trim();
+    let (mnemonic, operand) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let mnemonic = mnemonic.to_uppercase();
+    let operand: String = operand
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_uppercase();
+    if !OPCODE_TABLE
+        .iter()
+        .any(|op| matches!(op, Some(op) if op.name == mnemonic))
+    {
+        return Err(format!("Unknown instruction: {}", mnemonic));
+    }
+    let single = |mode: AddressMode| opcode(&mnemonic, mode).ok_or("Bad address mode");
+
+    let (opcode, operand_bytes) = if operand.is_empty() {
+        let opcode = opcode(&mnemonic, AddressMode::Implied)
+            .or_else(|| opcode(&mnemonic, AddressMode::Accumulator))
+            .ok_or("Operand expected")?;
+        (opcode, vec![])
+    } else if operand == "A" {
+        (single(AddressMode::Accumulator)?, vec![])
+    } else if let Some(value) = operand.strip_prefix('#') {
+        let (value, _) = parse_value(value)?;
+        if value > 0xff {
+            return Err("Immediate value doesn't fit in byte".to_string());
+        }
+        (single(AddressMode::Immediate)?, vec![value as u8])
+    } else if let Some(value) = operand
+        .strip_prefix('(')
+        .and_then(|v| v.strip_suffix(",X)"))
+    {
+        (
+            single(AddressMode::IndirectX)?,
+            vec![parse_zero_page(value)?],
+        )
+    } else if let Some(value) = operand
+        .strip_prefix('(')
+        .and_then(|v| v.strip_suffix("),Y"))
+    {
+        (
+            single(AddressMode::IndirectY)?,
+            vec![parse_zero_page(value)?],
+        )
+    } else if let Some(value) = operand.strip_prefix('(').and_then(|v| v.strip_suffix(')')) {
+        let (value, _) = parse_value(value)?;
+        (single(AddressMode::Indirect)?, value.to_le_bytes().to_vec())
+    } else if let Some(value) = operand.strip_suffix(",X") {
+        zero_page_or_absolute(
+            &mnemonic,
+            value,
+            AddressMode::ZeroPageX,
+            AddressMode::AbsoluteX,
+        )?
+    } else if let Some(value) = operand.strip_suffix(",Y") {
+        zero_page_or_absolute(
+            &mnemonic,
+            value,
+            AddressMode::ZeroPageY,
+            AddressMode::AbsoluteY,
+        )?
+    } else if let Some(opcode) = opcode(&mnemonic, AddressMode::Relative) {
+        let (target, _) = parse_value(&operand)?;
+        let offset = target.wrapping_sub(address.wrapping_add(2)) as i16;
+        if !(-128..=127).contains(&offset) {
+            return Err(format!("Branch target ${:04x} out of range", target));
+        }
+        (opcode, vec![offset as u8])
+    } else {
+        zero_page_or_absolute(
+            &mnemonic,
+            &operand,
+            AddressMode::ZeroPage,
+            AddressMode::Absolute,
+        )?
+    };
+
+    let mut bytes = vec![opcode];
+    bytes.extend(operand_bytes);
+    Ok(bytes)
+}
+
+fn parse_zero_page(text: &str) -> Result<u8, String> {
+    match parse_value(text)? {
+        (value, false) => Ok(value as u8),
+        _ => Err(format!("Zero page address expected: {}", text)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn address_modes() {
+        let cases: [(&str, &[u8]); 15] = [
+            ("lda #$01", &[0xa9, 0x01]),
+            ("LDA $02", &[0xa5, 0x02]),
+            ("lda $0002", &[0xad, 0x02, 0x00]),
+            ("lda 0x0002", &[0xad, 0x02, 0x00]),
+            ("lda 03,x", &[0xb5, 0x03]),
+            ("ldx $04,y", &[0xb6, 0x04]),
+            ("sta $d020", &[0x8d, 0x20, 0xd0]),
+            ("lda $0400,x", &[0xbd, 0x00, 0x04]),
+            ("sta $05,y", &[0x99, 0x05, 0x00]),
+            ("jmp ($0314)", &[0x6c, 0x14, 0x03]),
+            ("lda ($fb,x)", &[0xa1, 0xfb]),
+            ("sta ($fb), y", &[0x91, 0xfb]),
+            ("inx", &[0xe8]),
+            ("asl", &[0x0a]),
+            ("rol a", &[0x2a]),
+        ];
+        for (line, bytes) in cases {
+            assert_eq!(assemble_line(line, 0x1000).unwrap(), bytes, "{}", line);
+        }
+    }
+
+    #[test]
+    fn branches() {
+        assert_eq!(
+            assemble_line("bne $c000", 0xc000).unwrap(),
+            vec![0xd0, 0xfe]
+        );
+        assert_eq!(
+            assemble_line("beq $c005", 0xc002).unwrap(),
+            vec![0xf0, 0x01]
+        );
+        assert_eq!(
+            assemble_line("bcc $1081", 0x1000).unwrap(),
+            vec![0x90, 0x7f]
+        );
+        assert!(assemble_line("bcc $1082", 0x1000).is_err());
+        assert_eq!(
+            assemble_line("jsr $ffd2", 0).unwrap(),
+            vec![0x20, 0xd2, 0xff]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert!(assemble_line("foo $10", 0).is_err());
+        assert!(assemble_line("lda #$100", 0).is_err());
+        assert!(assemble_line("inx #$01", 0).is_err());
+        assert!(assemble_line("lda", 0).is_err());
+        assert!(assemble_line("lda ($1000),y", 0).is_err());
+        assert!(assemble_line("jmp $zz", 0).is_err());
+    }
+}
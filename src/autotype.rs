@@ -0,0 +1,194 @@
+// Typing of queued text into C64. Either PETSCII codes are put directly into KERNAL
+// keyboard buffer, or keys are pressed in keyboard matrix, slow enough for KERNAL
+// (or any other program) to notice every press and release.
+
+use crate::bus::Device;
+use crate::keyboard::{C64Key, Keyboard};
+use crate::petscii;
+use crate::ram::Ram;
+use crate::vic::CYCLES_PER_FRAME;
+
+use std::collections::VecDeque;
+
+// KERNAL keyboard buffer and number of characters in it
+const KEYBOARD_BUFFER: u16 = 0x0277;
+const KEYBOARD_BUFFER_COUNT: u16 = 0xc6;
+const KEYBOARD_BUFFER_SIZE: u8 = 10;
+
+// KERNAL scans keyboard once per frame, so hold and release keys a bit longer
+const KEY_HOLD_CYCLES: u32 = 2 * CYCLES_PER_FRAME;
+const KEY_RELEASE_CYCLES: u32 = 2 * CYCLES_PER_FRAME;
+
+// Id for Keyboard::press, host key ids are never negative
+const AUTOTYPE_KEY_ID: i32 = -1;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TypeMode {
+    KeyboardBuffer,
+    Matrix,
+}
+
+impl TypeMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "buffer" => Some(TypeMode::KeyboardBuffer),
+            "matrix" => Some(TypeMode::Matrix),
+            _ => None,
+        }
+    }
+}
+
+pub struct Autotype {
+    pub mode: TypeMode,
+    queue: VecDeque<u8>,
+    // Matrix mode: key is held now, cycles left before the next step
+    key_down: bool,
+    wait: u32,
+}
+
+impl Autotype {
+    pub fn new(mode: TypeMode) -> Self {
+        Self {
+            mode,
+            queue: VecDeque::new(),
+            key_down: false,
+            wait: 0,
+        }
+    }
+
+    // Characters without PETSCII code are skipped
+    pub fn type_text(&mut self, text: &str) {
+        self.queue.extend(petscii::from_str(text));
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.queue.is_empty() && !self.key_down
+    }
+
+    pub fn tick(&mut self, keyboard: &mut Keyboard, ram: &mut Ram) {
+        if self.is_finished() {
+            return;
+        }
+        match self.mode {
+            TypeMode::KeyboardBuffer => self.fill_buffer(ram),
+            TypeMode::Matrix => self.press_keys(keyboard),
+        }
+    }
+
+    // Only empty buffer is filled, so we don't race with KERNAL taking chars from it
+    fn fill_buffer(&mut self, ram: &mut Ram) {
+        if ram.get_byte(KEYBOARD_BUFFER_COUNT) != 0 {
+            return;
+        }
+        let mut count = 0;
+        while count < KEYBOARD_BUFFER_SIZE {
+            match self.queue.pop_front() {
+                Some(code) => ram.set_byte(code, KEYBOARD_BUFFER + count as u16),
+                None => break,
+            }
+            count += 1;
+        }
+        ram.set_byte(count, KEYBOARD_BUFFER_COUNT);
+    }
+
+    fn press_keys(&mut self, keyboard: &mut Keyboard) {
+        if self.wait > 0 {
+            self.wait -= 1;
+            return;
+        }
+        if self.key_down {
+            keyboard.release(AUTOTYPE_KEY_ID);
+            self.key_down = false;
+            self.wait = KEY_RELEASE_CYCLES;
+            return;
+        }
+        // Codes without a key (e.g. colors) are skipped
+        while let Some(code) = self.queue.pop_front() {
+            if let Some((key, shift)) = C64Key::from_petscii(code) {
+                let keys = if shift {
+                    vec![C64Key::LeftShift, key]
+                } else {
+                    vec![key]
+                };
+                keyboard.press(AUTOTYPE_KEY_ID, keys);
+                self.key_down = true;
+                self.wait = KEY_HOLD_CYCLES;
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(autotype: &mut Autotype, keyboard: &mut Keyboard, ram: &mut Ram, cycles: u32) {
+        for _ in 0..cycles {
+            autotype.tick(keyboard, ram);
+        }
+    }
+
+    #[test]
+    fn fills_keyboard_buffer() {
+        let mut autotype = Autotype::new(TypeMode::KeyboardBuffer);
+        let mut keyboard = Keyboard::new();
+        let mut ram = Ram::new(0x10000);
+        autotype.type_text("load\"*\",8,1\n");
+
+        autotype.tick(&mut keyboard, &mut ram);
+        assert_eq!(ram.get_byte(0xc6), 10);
+        assert_eq!(
+            ram.get_bytes_slice(0x277, 0x27b),
+            vec![0x4c, 0x4f, 0x41, 0x44]
+        );
+
+        // Nothing is added until KERNAL takes everything
+        ram.set_byte(3, 0xc6);
+        autotype.tick(&mut keyboard, &mut ram);
+        assert_eq!(ram.get_byte(0xc6), 3);
+
+        ram.set_byte(0, 0xc6);
+        autotype.tick(&mut keyboard, &mut ram);
+        assert_eq!(ram.get_byte(0xc6), 2);
+        assert_eq!(ram.get_bytes_slice(0x277, 0x279), vec![0x31, 0x0d]);
+        assert!(autotype.is_finished());
+    }
+
+    #[test]
+    fn presses_keys_in_matrix() {
+        let mut autotype = Autotype::new(TypeMode::Matrix);
+        let mut keyboard = Keyboard::new();
+        let mut ram = Ram::new(0x10000);
+        autotype.type_text("a\"");
+
+        // A
+        autotype.tick(&mut keyboard, &mut ram);
+        assert_eq!(keyboard.read_rows(!0b10), !0b100);
+        run(&mut autotype, &mut keyboard, &mut ram, KEY_HOLD_CYCLES);
+        assert_eq!(keyboard.read_rows(!0b10), !0b100);
+        autotype.tick(&mut keyboard, &mut ram);
+        assert_eq!(keyboard.read_rows(0x00), 0xff);
+
+        // Shift and 2
+        run(
+            &mut autotype,
+            &mut keyboard,
+            &mut ram,
+            KEY_RELEASE_CYCLES + 1,
+        );
+        assert_eq!(keyboard.read_rows(!0b10), !0b1000_0000);
+        assert_eq!(keyboard.read_rows(!0b1000_0000), !0b1000);
+        assert!(!autotype.is_finished());
+
+        run(&mut autotype, &mut keyboard, &mut ram, KEY_HOLD_CYCLES + 1);
+        assert_eq!(keyboard.read_rows(0x00), 0xff);
+        assert!(autotype.is_finished());
+    }
+
+    #[test]
+    fn mode_by_name() {
+        assert_eq!(TypeMode::from_name("matrix"), Some(TypeMode::Matrix));
+        assert_eq!(TypeMode::from_name("paste"), None);
+    }
+}
@@ -0,0 +1,773 @@
+// Server of VICE binary monitor protocol, so tools made for x64sc can debug programs in
+// this emulator. Requests and responses are little endian:
+//   request:  STX, API version, body length (4), request id (4), command, body
+//   response: STX, API version, body length (4), response type, error code,
+//             request id (4), body
+// Events are responses with request id 0xffffffff. Any command stops emulation, exit
+// command resumes it. Checkpoints are Debugger breakpoints, their ids are the same.
+
+use crate::condition::Condition;
+use crate::debugger::{BreakKind, Breakpoint, Debugger, StopReason};
+use crate::remote;
+
+use std::collections::HashSet;
+
+const STX: u8 = 0x02;
+const API_VERSION: u8 = 0x02;
+const HEADER_SIZE: usize = 11;
+const EVENT_ID: u32 = 0xffff_ffff;
+
+const MEMORY_GET: u8 = 0x01;
+const MEMORY_SET: u8 = 0x02;
+const CHECKPOINT_GET: u8 = 0x11;
+const CHECKPOINT_SET: u8 = 0x12;
+const CHECKPOINT_DELETE: u8 = 0x13;
+const CHECKPOINT_LIST: u8 = 0x14;
+const CHECKPOINT_TOGGLE: u8 = 0x15;
+const CONDITION_SET: u8 = 0x22;
+const REGISTERS_GET: u8 = 0x31;
+const REGISTERS_SET: u8 = 0x32;
+const ADVANCE_INSTRUCTIONS: u8 = 0x71;
+const EXECUTE_UNTIL_RETURN: u8 = 0x73;
+const PING: u8 = 0x81;
+const BANKS_AVAILABLE: u8 = 0x82;
+const REGISTERS_AVAILABLE: u8 = 0x83;
+const VICE_INFO: u8 = 0x85;
+const EXIT: u8 = 0xaa;
+const QUIT: u8 = 0xbb;
+const RESET: u8 = 0xcc;
+
+const EVENT_STOPPED: u8 = 0x62;
+const EVENT_RESUMED: u8 = 0x63;
+
+const ERROR_NONE: u8 = 0x00;
+const ERROR_OBJECT_MISSING: u8 = 0x01;
+const ERROR_INVALID_MEMSPACE: u8 = 0x02;
+const ERROR_INVALID_LENGTH: u8 = 0x80;
+const ERROR_INVALID_PARAMETER: u8 = 0x81;
+const ERROR_INVALID_API_VERSION: u8 = 0x82;
+const ERROR_INVALID_COMMAND: u8 = 0x83;
+
+// Main cpu memory, the only memspace there is
+const MAIN_MEMSPACE: u8 = 0;
+
+// Ids and names as in VICE, LIN and CYC are raster position and can't be set
+const REGISTER_A: u8 = 0x00;
+const REGISTER_X: u8 = 0x01;
+const REGISTER_Y: u8 = 0x02;
+const REGISTER_PC: u8 = 0x03;
+const REGISTER_SP: u8 = 0x04;
+const REGISTER_FLAGS: u8 = 0x05;
+const REGISTER_LINE: u8 = 0x35;
+const REGISTER_CYCLE: u8 = 0x36;
+const REGISTERS: [(u8, &str, u8); 8] = [
+    (REGISTER_A, "A", 8),
+    (REGISTER_X, "X", 8),
+    (REGISTER_Y, "Y", 8),
+    (REGISTER_PC, "PC", 16),
+    (REGISTER_SP, "SP", 8),
+    (REGISTER_FLAGS, "FL", 8),
+    (REGISTER_LINE, "LIN", 16),
+    (REGISTER_CYCLE, "CYC", 16),
+];
+
+// Checkpoint operation bits
+const OPERATION_LOAD: u8 = 0x01;
+const OPERATION_STORE: u8 = 0x02;
+const OPERATION_EXEC: u8 = 0x04;
+
+struct Request {
+    version: u8,
+    id: u32,
+    command: u8,
+    body: Vec<u8>,
+}
+
+// Takes the next complete request from buffer, bytes before STX are dropped
+fn next_request(buffer: &mut Vec<u8>) -> Option<Request> {
+    let start = match buffer.iter().position(|&b| b == STX) {
+        Some(start) => start,
+        None => {
+            buffer.clear();
+            return None;
+        }
+    };
+    buffer.drain(..start);
+    if buffer.len() < HEADER_SIZE {
+        return None;
+    }
+    let length = u32::from_le_bytes(buffer[2..6].try_into().unwrap()) as usize;
+    if buffer.len() < HEADER_SIZE + length {
+        return None;
+    }
+    let request: Vec<u8> = buffer.drain(..HEADER_SIZE + length).collect();
+    Some(Request {
+        version: request[1],
+        id: u32::from_le_bytes(request[6..10].try_into().unwrap()),
+        command: request[10],
+        body: request[HEADER_SIZE..].to_vec(),
+    })
+}
+
+fn message(kind: u8, error: u8, id: u32, body: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![STX, API_VERSION];
+    bytes.extend((body.len() as u32).to_le_bytes());
+    bytes.extend([kind, error]);
+    bytes.extend(id.to_le_bytes());
+    bytes.extend(body);
+    bytes
+}
+
+// Reads little endian fields of request body, invalid length error when body is too
+// short
+struct Body<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Body<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], u8> {
+        if self.bytes.len() < count {
+            return Err(ERROR_INVALID_LENGTH);
+        }
+        let (bytes, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, u8> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, u8> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn memspace(&mut self) -> Result<(), u8> {
+        match self.u8()? {
+            MAIN_MEMSPACE => Ok(()),
+            _ => Err(ERROR_INVALID_MEMSPACE),
+        }
+    }
+}
+
+fn operation(kind: BreakKind) -> u8 {
+    match kind {
+        BreakKind::Exec => OPERATION_EXEC,
+        BreakKind::Load => OPERATION_LOAD,
+        BreakKind::Store => OPERATION_STORE,
+        BreakKind::LoadStore => OPERATION_LOAD | OPERATION_STORE,
+    }
+}
+
+fn break_kind(operation: u8) -> Option<BreakKind> {
+    if operation & OPERATION_EXEC != 0 {
+        return Some(BreakKind::Exec);
+    }
+    match operation & (OPERATION_LOAD | OPERATION_STORE) {
+        OPERATION_LOAD => Some(BreakKind::Load),
+        OPERATION_STORE => Some(BreakKind::Store),
+        0 => None,
+        _ => Some(BreakKind::LoadStore),
+    }
+}
+
+fn register(debugger: &Debugger, id: u8) -> u16 {
+    let cpu = &debugger.c64.cpu;
+    match id {
+        REGISTER_A => cpu.reg.a as u16,
+        REGISTER_X => cpu.reg.x as u16,
+        REGISTER_Y => cpu.reg.y as u16,
+        REGISTER_PC => cpu.pc(),
+        REGISTER_SP => cpu.sp() as u16,
+        REGISTER_FLAGS => cpu.flags.get_register() as u16,
+        REGISTER_LINE => debugger.c64.vic.borrow().raster_line(),
+        _ => debugger.c64.vic.borrow().raster_cycle() as u16,
+    }
+}
+
+fn set_register(debugger: &mut Debugger, id: u8, value: u16) -> Result<(), u8> {
+    let cpu = &mut debugger.c64.cpu;
+    match id {
+        REGISTER_A => cpu.reg.a = value as u8,
+        REGISTER_X => cpu.reg.x = value as u8,
+        REGISTER_Y => cpu.reg.y = value as u8,
+        REGISTER_PC => cpu.set_pc(value),
+        REGISTER_SP => cpu.set_sp(value as u8),
+        REGISTER_FLAGS => cpu.flags.set_register(value as u8),
+        _ => return Err(ERROR_INVALID_PARAMETER),
+    }
+    Ok(())
+}
+
+// Checkpoint bookkeeping for a VICE client
+pub struct Session {
+    running: bool,
+    // Checkpoints deleted after they are hit
+    temporary: HashSet<u32>,
+    // Hit temporary checkpoints, deleted on the next poll as stop doesn't get mutable
+    // debugger
+    expired: Vec<u32>,
+    // Checkpoint emulation stopped on
+    last_hit: Option<u32>,
+    output: Vec<u8>,
+    quit: bool,
+}
+
+impl Session {
+    fn send(&mut self, kind: u8, error: u8, id: u32, body: &[u8]) {
+        self.output.extend(message(kind, error, id, body));
+    }
+
+    fn handle(&mut self, debugger: &mut Debugger, request: Request) {
+        for id in self.expired.drain(..) {
+            debugger.remove_breakpoint(id);
+            self.temporary.remove(&id);
+        }
+        if request.version != API_VERSION && request.version != 0x01 {
+            let error = ERROR_INVALID_API_VERSION;
+            self.send(request.command, error, request.id, &[]);
+            return;
+        }
+        if self.running {
+            self.running = false;
+            self.event(EVENT_STOPPED, debugger);
+        }
+        let kind = match request.command {
+            REGISTERS_SET => REGISTERS_GET,
+            CHECKPOINT_SET => CHECKPOINT_GET,
+            command => command,
+        };
+        let mut body = Body {
+            bytes: &request.body,
+        };
+        match self.execute(debugger, request.command, &mut body, request.id) {
+            Ok(reply) => self.send(kind, ERROR_NONE, request.id, &reply),
+            Err(error) => self.send(kind, error, request.id, &[]),
+        }
+        match request.command {
+            ADVANCE_INSTRUCTIONS | EXECUTE_UNTIL_RETURN => self.event(EVENT_STOPPED, debugger),
+            EXIT => {
+                self.running = true;
+                self.event(EVENT_RESUMED, debugger);
+            }
+            _ => {}
+        }
+    }
+
+    // Body of the response
+    fn execute(
+        &mut self,
+        debugger: &mut Debugger,
+        command: u8,
+        body: &mut Body,
+        id: u32,
+    ) -> Result<Vec<u8>, u8> {
+        let reply = match command {
+            MEMORY_GET => {
+                let side_effects = body.u8()? != 0;
+                let start = body.u16()?;
+                let end = body.u16()?;
+                body.memspace()?;
+                body.u16()?;
+                let bus = &debugger.c64.bus;
+                let bytes: Vec<u8> = (start as u32..=end as u32)
+                    .map(|address| match side_effects {
+                        true => bus.get_byte(address as u16),
+                        false => bus.peek_byte(address as u16),
+                    })
+                    .collect();
+                let mut reply = (bytes.len() as u16).to_le_bytes().to_vec();
+                reply.extend(bytes);
+                reply
+            }
+            MEMORY_SET => {
+                body.u8()?;
+                let start = body.u16()?;
+                let end = body.u16()?;
+                body.memspace()?;
+                body.u16()?;
+                if end < start {
+                    return Err(ERROR_INVALID_PARAMETER);
+                }
+                let bytes = body.bytes((end - start) as usize + 1)?;
+                for (address, &byte) in (start..=end).zip(bytes) {
+                    debugger.c64.bus.set_byte(byte, address);
+                }
+                vec![]
+            }
+            CHECKPOINT_GET => self.checkpoint_info(debugger, body.u32()?)?,
+            CHECKPOINT_SET => {
+                let start = body.u16()?;
+                let end = body.u16()?;
+                let stop = body.u8()? != 0;
+                let enabled = body.u8()? != 0;
+                let kind = break_kind(body.u8()?).ok_or(ERROR_INVALID_PARAMETER)?;
+                let temporary = body.u8()? != 0;
+                if !body.bytes.is_empty() {
+                    body.memspace()?;
+                }
+                if end < start {
+                    return Err(ERROR_INVALID_PARAMETER);
+                }
+                let mut breakpoint = Breakpoint::new(kind, start, end);
+                breakpoint.enabled = enabled;
+                breakpoint.log = !stop;
+                let checkpoint = debugger.add(breakpoint);
+                if temporary {
+                    self.temporary.insert(checkpoint);
+                }
+                self.checkpoint_info(debugger, checkpoint)?
+            }
+            CHECKPOINT_DELETE => {
+                let checkpoint = body.u32()?;
+                if !debugger.remove_breakpoint(checkpoint) {
+                    return Err(ERROR_OBJECT_MISSING);
+                }
+                self.temporary.remove(&checkpoint);
+                vec![]
+            }
+            CHECKPOINT_LIST => {
+                let checkpoints: Vec<u32> = debugger.breakpoints().map(|(id, _)| id).collect();
+                for &checkpoint in &checkpoints {
+                    let info = self.checkpoint_info(debugger, checkpoint)?;
+                    self.send(CHECKPOINT_GET, ERROR_NONE, id, &info);
+                }
+                (checkpoints.len() as u32).to_le_bytes().to_vec()
+            }
+            CHECKPOINT_TOGGLE => {
+                let checkpoint = body.u32()?;
+                let enabled = body.u8()? != 0;
+                if !debugger.set_breakpoint_enabled(checkpoint, enabled) {
+                    return Err(ERROR_OBJECT_MISSING);
+                }
+                vec![]
+            }
+            CONDITION_SET => {
+                let checkpoint = body.u32()?;
+                let length = body.u8()? as usize;
+                let text = String::from_utf8_lossy(body.bytes(length)?).into_owned();
+                let condition = Condition::parse(&text).map_err(|_| ERROR_INVALID_PARAMETER)?;
+                let breakpoint = debugger
+                    .breakpoint_mut(checkpoint)
+                    .ok_or(ERROR_OBJECT_MISSING)?;
+                breakpoint.condition = Some(condition);
+                vec![]
+            }
+            REGISTERS_GET => {
+                body.memspace()?;
+                self.registers(debugger)
+            }
+            REGISTERS_SET => {
+                body.memspace()?;
+                let count = body.u16()?;
+                for _ in 0..count {
+                    let size = body.u8()? as usize;
+                    let item = body.bytes(size)?;
+                    if size < 3 {
+                        return Err(ERROR_INVALID_LENGTH);
+                    }
+                    set_register(debugger, item[0], u16::from_le_bytes([item[1], item[2]]))?;
+                }
+                self.registers(debugger)
+            }
+            ADVANCE_INSTRUCTIONS => {
+                let step_over = body.u8()? != 0;
+                let count = body.u16()?;
+                for _ in 0..count {
+                    let reason = match step_over {
+                        true => debugger.next(debugger.run_limit()),
+                        false => debugger.step(),
+                    };
+                    if reason != StopReason::Step {
+                        self.hit(debugger, reason);
+                        break;
+                    }
+                }
+                vec![]
+            }
+            EXECUTE_UNTIL_RETURN => {
+                let reason = debugger.finish(debugger.run_limit());
+                self.hit(debugger, reason);
+                vec![]
+            }
+            PING | EXIT => vec![],
+            QUIT => {
+                self.quit = true;
+                vec![]
+            }
+            RESET => {
+                body.u8()?;
+                let c64 = &mut debugger.c64;
+                c64.cpu.reset(&c64.bus);
+                vec![]
+            }
+            BANKS_AVAILABLE => {
+                let mut reply = 1u16.to_le_bytes().to_vec();
+                let name = b"cpu";
+                reply.push(3 + name.len() as u8);
+                reply.extend(0u16.to_le_bytes());
+                reply.push(name.len() as u8);
+                reply.extend(name);
+                reply
+            }
+            REGISTERS_AVAILABLE => {
+                body.memspace()?;
+                let mut reply = (REGISTERS.len() as u16).to_le_bytes().to_vec();
+                for (id, name, bits) in REGISTERS {
+                    reply.extend([3 + name.len() as u8, id, bits, name.len() as u8]);
+                    reply.extend(name.as_bytes());
+                }
+                reply
+            }
+            VICE_INFO => {
+                // Version 3.7.0.0, no svn revision
+                vec![4, 3, 7, 0, 0, 4, 0, 0, 0, 0]
+            }
+            _ => return Err(ERROR_INVALID_COMMAND),
+        };
+        Ok(reply)
+    }
+
+    fn registers(&self, debugger: &Debugger) -> Vec<u8> {
+        let mut reply = (REGISTERS.len() as u16).to_le_bytes().to_vec();
+        for (id, _, _) in REGISTERS {
+            reply.extend([3, id]);
+            reply.extend(register(debugger, id).to_le_bytes());
+        }
+        reply
+    }
+
+    fn checkpoint_info(&self, debugger: &Debugger, id: u32) -> Result<Vec<u8>, u8> {
+        let breakpoint = debugger.breakpoint(id).ok_or(ERROR_OBJECT_MISSING)?;
+        let mut info = id.to_le_bytes().to_vec();
+        info.push((self.last_hit == Some(id)) as u8);
+        info.extend(breakpoint.from.to_le_bytes());
+        info.extend(breakpoint.to.to_le_bytes());
+        info.extend([
+            !breakpoint.log as u8,
+            breakpoint.enabled as u8,
+            operation(breakpoint.kind),
+            self.temporary.contains(&id) as u8,
+        ]);
+        info.extend(breakpoint.hits.to_le_bytes());
+        // Ignore count
+        info.extend(0u32.to_le_bytes());
+        info.extend([breakpoint.condition.is_some() as u8, MAIN_MEMSPACE]);
+        Ok(info)
+    }
+
+    // Checkpoint that stopped emulation is announced before stopped event
+    fn hit(&mut self, debugger: &Debugger, reason: StopReason) {
+        let id = match reason {
+            StopReason::Breakpoint { id, .. } | StopReason::Watchpoint { id, .. } => id,
+            _ => return,
+        };
+        self.last_hit = Some(id);
+        if let Ok(info) = self.checkpoint_info(debugger, id) {
+            self.send(CHECKPOINT_GET, ERROR_NONE, EVENT_ID, &info);
+        }
+        if self.temporary.contains(&id) {
+            self.expired.push(id);
+        }
+    }
+
+    fn event(&mut self, kind: u8, debugger: &Debugger) {
+        let pc = debugger.c64.cpu.pc().to_le_bytes();
+        self.send(kind, ERROR_NONE, EVENT_ID, &pc);
+    }
+}
+
+impl remote::Session for Session {
+    const NAME: &'static str = "Binary monitor";
+
+    // Emulator keeps running when client connects
+    fn new() -> Self {
+        Self {
+            running: true,
+            temporary: HashSet::new(),
+            expired: vec![],
+            last_hit: None,
+            output: vec![],
+            quit: false,
+        }
+    }
+
+    fn receive(&mut self, debugger: &mut Debugger, input: &mut Vec<u8>) -> bool {
+        while let Some(request) = next_request(input) {
+            self.handle(debugger, request);
+        }
+        true
+    }
+
+    fn output(&mut self) -> &mut Vec<u8> {
+        &mut self.output
+    }
+
+    fn running(&self) -> bool {
+        self.running
+    }
+
+    fn stopped(&mut self, debugger: &Debugger, reason: StopReason) {
+        self.running = false;
+        self.hit(debugger, reason);
+        self.event(EVENT_STOPPED, debugger);
+    }
+
+    // Checkpoints stay when client disconnects, as they do in VICE
+    fn detach(&mut self, _debugger: &mut Debugger) {}
+
+    fn quit(&self) -> bool {
+        self.quit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugger::test_debugger;
+    use crate::remote::Session as _;
+
+    fn fixture() -> (Session, Debugger) {
+        (Session::new(), test_debugger())
+    }
+
+    fn request(command: u8, id: u32, body: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![STX, API_VERSION];
+        bytes.extend((body.len() as u32).to_le_bytes());
+        bytes.extend(id.to_le_bytes());
+        bytes.push(command);
+        bytes.extend(body);
+        bytes
+    }
+
+    // Response type, error, request id and body of each message in session output
+    fn handle(
+        session: &mut Session,
+        debugger: &mut Debugger,
+        command: u8,
+        body: &[u8],
+    ) -> Vec<(u8, u8, u32, Vec<u8>)> {
+        let mut input = request(command, 7, body);
+        let request = next_request(&mut input).unwrap();
+        session.handle(debugger, request);
+        let output = std::mem::take(&mut session.output);
+        let mut messages = vec![];
+        let mut rest = &output[..];
+        while !rest.is_empty() {
+            let length = u32::from_le_bytes(rest[2..6].try_into().unwrap()) as usize;
+            let id = u32::from_le_bytes(rest[8..12].try_into().unwrap());
+            messages.push((rest[6], rest[7], id, rest[12..12 + length].to_vec()));
+            rest = &rest[12 + length..];
+        }
+        messages
+    }
+
+    // Body of the response, events before it are skipped
+    fn reply(session: &mut Session, debugger: &mut Debugger, command: u8, body: &[u8]) -> Vec<u8> {
+        let messages = handle(session, debugger, command, body);
+        let (_, error, _, body) = messages
+            .into_iter()
+            .find(|message| message.2 != EVENT_ID)
+            .unwrap();
+        assert_eq!(error, ERROR_NONE);
+        body
+    }
+
+    #[test]
+    fn framing() {
+        let mut buffer = vec![0xff];
+        buffer.extend(request(PING, 1, &[]));
+        let second = request(MEMORY_GET, 2, &[0, 0, 0x10, 0, 0x10, 0, 0, 0]);
+        buffer.extend(&second[..5]);
+        let first = next_request(&mut buffer).unwrap();
+        assert_eq!((first.id, first.command), (1, PING));
+        assert!(next_request(&mut buffer).is_none());
+        buffer.extend(&second[5..]);
+        let second = next_request(&mut buffer).unwrap();
+        assert_eq!(
+            (second.id, second.command, second.body.len()),
+            (2, MEMORY_GET, 8)
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn command_stops_and_exit_resumes() {
+        let (mut session, mut debugger) = fixture();
+        let messages = handle(&mut session, &mut debugger, PING, &[]);
+        assert_eq!(
+            messages,
+            vec![
+                (EVENT_STOPPED, ERROR_NONE, EVENT_ID, vec![0x00, 0x10]),
+                (PING, ERROR_NONE, 7, vec![]),
+            ]
+        );
+        assert!(!session.running);
+        let messages = handle(&mut session, &mut debugger, EXIT, &[]);
+        assert_eq!(
+            messages[1],
+            (EVENT_RESUMED, ERROR_NONE, EVENT_ID, vec![0x00, 0x10])
+        );
+        assert!(session.running);
+    }
+
+    #[test]
+    fn memory() {
+        let (mut session, mut debugger) = fixture();
+        assert_eq!(
+            reply(
+                &mut session,
+                &mut debugger,
+                MEMORY_GET,
+                &[0, 0, 0x10, 2, 0x10, 0, 0, 0]
+            ),
+            vec![3, 0, 0xe8, 0xe8, 0x4c]
+        );
+        reply(
+            &mut session,
+            &mut debugger,
+            MEMORY_SET,
+            &[0, 0, 0x20, 1, 0x20, 0, 0, 0, 0xa9, 0x01],
+        );
+        assert_eq!(debugger.c64.bus.peek_byte(0x2001), 0x01);
+
+        let messages = handle(
+            &mut session,
+            &mut debugger,
+            MEMORY_GET,
+            &[0, 0, 0x10, 2, 0x10, 1, 0, 0],
+        );
+        assert_eq!(messages[0].1, ERROR_INVALID_MEMSPACE);
+        let messages = handle(
+            &mut session,
+            &mut debugger,
+            MEMORY_SET,
+            &[0, 0, 0x20, 1, 0x20, 0, 0, 0, 0xa9],
+        );
+        assert_eq!(messages[0].1, ERROR_INVALID_LENGTH);
+    }
+
+    #[test]
+    fn registers() {
+        let (mut session, mut debugger) = fixture();
+        let body = reply(
+            &mut session,
+            &mut debugger,
+            REGISTERS_SET,
+            &[0, 2, 0, 3, REGISTER_A, 0x12, 0, 3, REGISTER_PC, 0x00, 0xc0],
+        );
+        assert_eq!(
+            u16::from_le_bytes([body[0], body[1]]),
+            REGISTERS.len() as u16
+        );
+        assert_eq!(&body[2..6], &[3, REGISTER_A, 0x12, 0]);
+        assert_eq!(&body[14..18], &[3, REGISTER_PC, 0x00, 0xc0]);
+        assert_eq!(debugger.c64.cpu.reg.a, 0x12);
+        assert_eq!(debugger.c64.cpu.pc(), 0xc000);
+
+        let messages = handle(
+            &mut session,
+            &mut debugger,
+            REGISTERS_SET,
+            &[0, 1, 0, 3, REGISTER_LINE, 0, 0],
+        );
+        assert_eq!(messages[0].1, ERROR_INVALID_PARAMETER);
+        let body = reply(&mut session, &mut debugger, REGISTERS_AVAILABLE, &[0]);
+        assert_eq!(&body[2..7], &[4, REGISTER_A, 8, 1, b'A']);
+    }
+
+    #[test]
+    fn checkpoints_and_stepping() {
+        let (mut session, mut debugger) = fixture();
+        // Exec at $1002, stop, enabled, temporary
+        let info = reply(
+            &mut session,
+            &mut debugger,
+            CHECKPOINT_SET,
+            &[0x02, 0x10, 0x02, 0x10, 1, 1, OPERATION_EXEC, 1],
+        );
+        assert_eq!(&info[..4], &1u32.to_le_bytes());
+        assert_eq!(
+            &info[5..13],
+            &[0x02, 0x10, 0x02, 0x10, 1, 1, OPERATION_EXEC, 1]
+        );
+
+        let messages = handle(
+            &mut session,
+            &mut debugger,
+            ADVANCE_INSTRUCTIONS,
+            &[0, 5, 0],
+        );
+        assert_eq!(messages.len(), 3);
+        assert_eq!((messages[0].0, messages[0].2), (CHECKPOINT_GET, EVENT_ID));
+        assert_eq!(messages[0].3[4], 1);
+        assert_eq!(messages[1].0, ADVANCE_INSTRUCTIONS);
+        assert_eq!(
+            messages[2],
+            (EVENT_STOPPED, ERROR_NONE, EVENT_ID, vec![0x02, 0x10])
+        );
+
+        // Temporary checkpoint is gone after the hit
+        let messages = handle(
+            &mut session,
+            &mut debugger,
+            CHECKPOINT_GET,
+            &1u32.to_le_bytes(),
+        );
+        assert_eq!(messages[0].1, ERROR_OBJECT_MISSING);
+
+        // Watch stores to $d020 with condition
+        reply(
+            &mut session,
+            &mut debugger,
+            CHECKPOINT_SET,
+            &[0x20, 0xd0, 0x20, 0xd0, 1, 1, OPERATION_STORE, 0],
+        );
+        let mut condition = vec![2, 0, 0, 0, 8];
+        condition.extend(b"a == $10");
+        reply(&mut session, &mut debugger, CONDITION_SET, &condition);
+        assert!(debugger.breakpoint(2).unwrap().condition.is_some());
+        // Info of each checkpoint, then their count
+        let messages = handle(&mut session, &mut debugger, CHECKPOINT_LIST, &[]);
+        assert_eq!(messages.len(), 2);
+        assert_eq!((messages[0].0, messages[0].2), (CHECKPOINT_GET, 7));
+        assert_eq!(
+            messages[1],
+            (CHECKPOINT_LIST, ERROR_NONE, 7, vec![1, 0, 0, 0])
+        );
+        reply(
+            &mut session,
+            &mut debugger,
+            CHECKPOINT_DELETE,
+            &2u32.to_le_bytes(),
+        );
+        assert_eq!(debugger.breakpoints().count(), 0);
+    }
+
+    #[test]
+    fn stop_while_running() {
+        let (mut session, mut debugger) = fixture();
+        debugger.add_breakpoint(0x1002);
+        let reason = debugger.run(1000);
+        session.stopped(&debugger, reason);
+        assert!(!session.running);
+        // Checkpoint info, then stopped event
+        assert_eq!(session.output[6], CHECKPOINT_GET);
+        assert_eq!(session.output[12 + 23 + 6], EVENT_STOPPED);
+    }
+
+    #[test]
+    fn unknown_command_and_version() {
+        let (mut session, mut debugger) = fixture();
+        let messages = handle(&mut session, &mut debugger, 0x99, &[]);
+        assert_eq!(messages[1].1, ERROR_INVALID_COMMAND);
+        let mut input = request(PING, 1, &[]);
+        input[1] = 0x07;
+        session.handle(&mut debugger, next_request(&mut input).unwrap());
+        assert_eq!(session.output[7], ERROR_INVALID_API_VERSION);
+    }
+}
@@ -4,12 +4,36 @@ use std::rc::Weak;
 pub trait Device {
     fn get_byte(&self, offset: u16) -> u8;
     fn set_byte(&mut self, byte: u8, offset: u16);
+    // Read done by cpu. Unlike get_byte can have side effects (e.g. acknowledge interrupt).
+    fn read_byte(&mut self, offset: u16) -> u8 {
+        self.get_byte(offset)
+    }
     fn tick(&mut self);
 
     // Debug purpose
     fn get_bytes_slice(&self, from: u16, to: u16) -> Vec<u8>;
 }
 
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+// Read or write of watched address, see Bus::watch
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct BusAccess {
+    pub access: Access,
+    pub address: u16,
+    pub value: u8,
+}
+
+struct Watch {
+    access: Access,
+    from: u16,
+    to: u16,
+}
+
 struct DeviceConnection {
     device: Weak<RefCell<dyn Device>>,
     from: u16,
@@ -18,12 +42,46 @@ struct DeviceConnection {
 
 pub struct Bus {
     connections: Vec<DeviceConnection>,
+    watches: Vec<Watch>,
+    // get_byte takes &self, so accesses are collected through RefCell
+    accesses: RefCell<Vec<BusAccess>>,
 }
 
 impl Bus {
     pub fn new() -> Self {
         Bus {
             connections: vec![],
+            watches: vec![],
+            accesses: RefCell::new(vec![]),
+        }
+    }
+
+    // Accesses in from..=to are recorded until clear_watches, peek_byte is not recorded
+    pub fn watch(&mut self, access: Access, from: u16, to: u16) {
+        self.watches.push(Watch { access, from, to });
+    }
+
+    pub fn clear_watches(&mut self) {
+        self.watches.clear();
+        self.accesses.borrow_mut().clear();
+    }
+
+    // Recorded accesses since the previous call, in order
+    pub fn take_accesses(&self) -> Vec<BusAccess> {
+        std::mem::take(&mut *self.accesses.borrow_mut())
+    }
+
+    fn record(&self, access: Access, address: u16, value: u8) {
+        let watched = self
+            .watches
+            .iter()
+            .any(|watch| watch.access == access && address >= watch.from && address <= watch.to);
+        if watched {
+            self.accesses.borrow_mut().push(BusAccess {
+                access,
+                address,
+                value,
+            });
         }
     }
 
@@ -32,6 +90,7 @@ impl Bus {
     }
 
     pub fn set_byte(&mut self, byte: u8, offset: u16) {
+        self.record(Access::Write, offset, byte);
         for conn in &mut self.connections {
             if offset >= conn.from && offset <= conn.to {
                 if let Some(dev) = conn.device.upgrade() {
@@ -50,6 +109,28 @@ impl Bus {
     }
 
     pub fn get_byte(&self, offset: u16) -> u8 {
+        let value = self.fetch_byte(offset);
+        self.record(Access::Read, offset, value);
+        value
+    }
+
+    // Opcode and operand fetch by cpu. Same as get_byte, but not seen by watchpoints.
+    pub fn fetch_byte(&self, offset: u16) -> u8 {
+        for conn in &self.connections {
+            if offset >= conn.from && offset <= conn.to {
+                if let Some(dev) = conn.device.upgrade() {
+                    return (*dev).borrow_mut().read_byte(offset);
+                }
+            }
+        }
+        panic!(
+            "Try to get memory, but device is not assign. Offset: {:#04X}",
+            offset
+        );
+    }
+
+    // Same as get_byte, but without side effects on device
+    pub fn peek_byte(&self, offset: u16) -> u8 {
         for conn in &self.connections {
             if offset >= conn.from && offset <= conn.to {
                 if let Some(dev) = conn.device.upgrade() {
@@ -81,4 +162,52 @@ impl Bus {
     pub fn get_two_bytes(&self, offset: u16) -> u16 {
         ((self.get_byte(offset + 1) as u16) << 8) + self.get_byte(offset) as u16
     }
+
+    pub fn fetch_two_bytes(&self, offset: u16) -> u16 {
+        ((self.fetch_byte(offset + 1) as u16) << 8) + self.fetch_byte(offset) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ram::Ram;
+
+    use std::rc::Rc;
+
+    #[test]
+    fn watched_accesses_are_recorded() {
+        let ram = Rc::new(RefCell::new(Ram::new(0xffff + 1)));
+        let mut bus = Bus::new();
+        bus.connect_device(Rc::downgrade(&ram) as Weak<RefCell<dyn Device>>, 0, 0xffff);
+        bus.watch(Access::Write, 0x1000, 0x10ff);
+        bus.watch(Access::Read, 0x2000, 0x2000);
+
+        bus.set_byte(0x12, 0x1080);
+        bus.set_byte(0x34, 0x2000);
+        bus.get_byte(0x1080);
+        bus.get_byte(0x2000);
+        bus.peek_byte(0x2000);
+        bus.fetch_byte(0x2000);
+        assert_eq!(
+            bus.take_accesses(),
+            vec![
+                BusAccess {
+                    access: Access::Write,
+                    address: 0x1080,
+                    value: 0x12
+                },
+                BusAccess {
+                    access: Access::Read,
+                    address: 0x2000,
+                    value: 0x34
+                },
+            ]
+        );
+        assert!(bus.take_accesses().is_empty());
+
+        bus.clear_watches();
+        bus.set_byte(0x12, 0x1080);
+        assert!(bus.take_accesses().is_empty());
+    }
 }
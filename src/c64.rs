@@ -1,44 +1,353 @@
+use crate::autotype::{Autotype, TypeMode};
 use crate::bus::{Bus, Device};
+use crate::cia::Cia;
 use crate::cpu::Cpu;
 use crate::host_io::Monitor;
-use crate::ram::Ram;
-use crate::vic::SimpleVic;
+use crate::joystick::Joystick;
+use crate::keyboard::Keyboard;
+use crate::palette::Palette;
+use crate::ram::{ColorRam, Ram};
+use crate::screen_codes;
+use crate::screenshot;
+use crate::sid::{Sid, SidModel};
+use crate::vic::{SimpleVic, VideoStandard, COLUMNS, ROWS};
+use crate::wav::WavRecorder;
 
 use std::cell::RefCell;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
 use std::rc::{Rc, Weak};
 
+// How often run_until_text looks at the screen
+const SCREEN_CHECK_INTERVAL: u64 = 1000;
+
+pub struct ScreenTimeout {
+    pub text: String,
+    pub screen: String,
+}
+
+impl fmt::Debug for ScreenTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "text {:?} didn't appear on screen:", self.text)?;
+        write!(f, "{}", self.screen)
+    }
+}
+
 pub struct C64 {
     pub cpu: Cpu,
     pub bus: Bus,
     pub vic: Rc<RefCell<SimpleVic>>,
     pub ram: Rc<RefCell<Ram>>,
+    pub color_ram: Rc<RefCell<ColorRam>>,
+    pub cia1: Rc<RefCell<Cia>>,
+    pub cia2: Rc<RefCell<Cia>>,
+    pub sid: Rc<RefCell<Sid>>,
+    pub keyboard: Keyboard,
+    // Control ports 1 and 2
+    pub joysticks: [Joystick; 2],
+    pub autotype: Autotype,
+    // Recording works without any audio device, so samples are taken here
+    recorder: Option<WavRecorder<BufWriter<File>>>,
 }
 
 impl C64 {
     pub fn new(monitor: Rc<RefCell<dyn Monitor>>) -> Self {
+        let ram = Rc::new(RefCell::new(Ram::new(0xffff + 1)));
+        let color_ram = Rc::new(RefCell::new(ColorRam::new()));
         let mut c64 = Self {
             cpu: Cpu::new(),
             bus: Bus::new(),
-            vic: Rc::new(RefCell::new(SimpleVic::new(monitor, 100))),
-            ram: Rc::new(RefCell::new(Ram::new(0xffff + 1))),
+            vic: Rc::new(RefCell::new(SimpleVic::new(
+                monitor,
+                ram.clone(),
+                color_ram.clone(),
+            ))),
+            ram,
+            color_ram,
+            cia1: Rc::new(RefCell::new(Cia::new())),
+            cia2: Rc::new(RefCell::new(Cia::new())),
+            sid: Rc::new(RefCell::new(Sid::new(SidModel::Mos6581))),
+            keyboard: Keyboard::new(),
+            joysticks: [Joystick::default(); 2],
+            autotype: Autotype::new(TypeMode::KeyboardBuffer),
+            recorder: None,
         };
 
         c64.bus.connect_device(
             Rc::downgrade(&c64.ram) as Weak<RefCell<dyn Device>>,
             0,
-            0xafff,
+            0xcfff,
         );
         c64.bus.connect_device(
             Rc::downgrade(&c64.vic) as Weak<RefCell<dyn Device>>,
-            0xb000,
-            0xb100,
+            0xd000,
+            0xd3ff,
+        );
+        c64.bus.connect_device(
+            Rc::downgrade(&c64.sid) as Weak<RefCell<dyn Device>>,
+            0xd400,
+            0xd7ff,
+        );
+        c64.bus.connect_device(
+            Rc::downgrade(&c64.color_ram) as Weak<RefCell<dyn Device>>,
+            0xd800,
+            0xdbff,
+        );
+        c64.bus.connect_device(
+            Rc::downgrade(&c64.cia1) as Weak<RefCell<dyn Device>>,
+            0xdc00,
+            0xdcff,
+        );
+        c64.bus.connect_device(
+            Rc::downgrade(&c64.cia2) as Weak<RefCell<dyn Device>>,
+            0xdd00,
+            0xddff,
+        );
+        c64.bus.connect_device(
+            Rc::downgrade(&c64.ram) as Weak<RefCell<dyn Device>>,
+            0xe000,
+            0xffff,
         );
 
         c64
     }
 
     pub fn tick(&mut self) {
+        // VIC steals cycles from cpu on badlines and sprite fetches
+        let ba_low = self.vic.borrow().ba_low();
+        self.cpu.set_rdy(!ba_low);
+        self.cpu
+            .set_irq(self.cia1.borrow().interrupt() || self.vic.borrow().interrupt());
+        self.cpu
+            .set_nmi(self.cia2.borrow().interrupt() || self.keyboard.restore());
+        self.autotype
+            .tick(&mut self.keyboard, &mut self.ram.borrow_mut());
+        self.update_control_ports();
+
         self.cpu.tick(&mut self.bus);
         self.bus.tick();
+        self.record_sample();
+
+        // There is no real power line, TOD clocks are driven by frame rate instead
+        let frame_start = {
+            let vic = self.vic.borrow();
+            vic.raster_line() == 0 && vic.raster_cycle() == 1
+        };
+        if frame_start {
+            self.cia1.borrow_mut().tod_pulse();
+            self.cia2.borrow_mut().tod_pulse();
+        }
+
+        // Bits 0-1 of CIA2 port A select VIC bank, inverted
+        let bank = !self.cia2.borrow().port_a_output() & 0b11;
+        self.vic.borrow_mut().set_bank(bank);
+    }
+
+    // Keyboard matrix connects CIA1 port A and port B. Joysticks pull the same lines
+    // low, so they are seen by keyboard scan as well.
+    fn update_control_ports(&mut self) {
+        let mut cia1 = self.cia1.borrow_mut();
+        let joystick1 = self.joysticks[0].port_value();
+        let joystick2 = self.joysticks[1].port_value();
+        let port_a = cia1.port_a_output() & joystick2;
+        let port_b = cia1.port_b_output() & joystick1;
+        cia1.set_port_b_input(self.keyboard.read_rows(port_a) & joystick1);
+        cia1.set_port_a_input(self.keyboard.read_columns(port_b) & joystick2);
+    }
+
+    // Move joysticks (and what is pressed on them) to the other control port
+    pub fn swap_joysticks(&mut self) {
+        self.joysticks.swap(0, 1);
+    }
+
+    // Queue text to be typed, see Autotype for how it gets into the machine
+    pub fn type_text(&mut self, text: &str) {
+        self.autotype.type_text(text);
+    }
+
+    // Changes frame timings of VIC and clock frequency of the whole machine
+    pub fn set_video_standard(&mut self, standard: VideoStandard) {
+        self.vic.borrow_mut().set_standard(standard);
+        self.sid
+            .borrow_mut()
+            .set_clock_frequency(standard.clock_frequency());
+    }
+
+    pub fn video_standard(&self) -> VideoStandard {
+        self.vic.borrow().standard()
+    }
+
+    // SID output is saved to WAV file until stop_recording
+    pub fn start_recording(&mut self, path: &Path) -> io::Result<()> {
+        self.stop_recording()?;
+        let clock_frequency = self.video_standard().clock_frequency();
+        self.recorder = Some(WavRecorder::create(path, clock_frequency)?);
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) -> io::Result<()> {
+        match self.recorder.take() {
+            Some(recorder) => recorder.finish().map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    fn record_sample(&mut self) {
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(err) = recorder.push_sample(self.sid.borrow().output()) {
+                eprintln!("Recording stopped: {}", err);
+                self.recorder = None;
+            }
+        }
+    }
+
+    pub fn run_frame(&mut self) {
+        for _ in 0..self.video_standard().cycles_per_frame() {
+            self.tick();
+        }
+    }
+
+    // Video matrix contents, rows separated by new line with trailing spaces trimmed
+    pub fn screen_text(&self) -> String {
+        self.read_screen(screen_codes::to_unicode)
+    }
+
+    pub fn screen_text_ascii(&self) -> String {
+        self.read_screen(screen_codes::to_ascii)
+    }
+
+    fn read_screen(&self, convert: fn(u8) -> char) -> String {
+        let base = self.vic.borrow().screen_address();
+        let mut rows = vec![];
+        for row in 0..ROWS {
+            let line: String = (0..COLUMNS)
+                .map(|column| convert(self.bus.peek_byte(base + row * COLUMNS + column)))
+                .collect();
+            rows.push(line.trim_end().to_string());
+        }
+        rows.join("\n")
+    }
+
+    // Returns how many cycles it took for text to appear
+    pub fn run_until_text(&mut self, text: &str, max_cycles: u64) -> Result<u64, ScreenTimeout> {
+        let mut cycles = 0;
+        loop {
+            if self.screen_text().contains(text) {
+                return Ok(cycles);
+            }
+            if cycles >= max_cycles {
+                return Err(ScreenTimeout {
+                    text: text.to_string(),
+                    screen: self.screen_text(),
+                });
+            }
+            let steps = SCREEN_CHECK_INTERVAL.min(max_cycles - cycles);
+            for _ in 0..steps {
+                self.tick();
+            }
+            cycles += steps;
+        }
+    }
+
+    // Save last complete frame to PNG. Works without any host window.
+    pub fn save_screenshot(
+        &self,
+        path: &Path,
+        palette: &Palette,
+        with_border: bool,
+    ) -> Result<(), png::EncodingError> {
+        let frame = self.vic.borrow().screenshot(with_border);
+        screenshot::save_png(&frame, palette, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host_io::NullMonitor;
+    use crate::wav;
+
+    fn fixture() -> C64 {
+        let c64 = C64::new(Rc::new(RefCell::new(NullMonitor {})));
+        (*c64.ram)
+            .borrow_mut()
+            .set_memory(&[0xea; 0xd000], 0)
+            .unwrap();
+        c64
+    }
+
+    // Run one raster line and return how many NOPs cpu executed
+    fn nops_on_line(c64: &mut C64, line: u16) -> u16 {
+        while c64.vic.borrow().raster_line() != line {
+            c64.tick();
+        }
+        let start_pc = c64.cpu.pc();
+        while c64.vic.borrow().raster_line() == line {
+            c64.tick();
+        }
+        c64.cpu.pc() - start_pc
+    }
+
+    #[test]
+    fn type_text_into_matrix() {
+        let mut c64 = fixture();
+        c64.autotype.mode = TypeMode::Matrix;
+        c64.type_text("q");
+        c64.tick();
+
+        // Scan column 7 through CIA1
+        c64.bus.set_byte(0xff, 0xdc02);
+        c64.bus.set_byte(0x7f, 0xdc00);
+        c64.tick();
+        assert_eq!(c64.bus.get_byte(0xdc01), 0b1011_1111);
+    }
+
+    #[test]
+    fn record_audio() {
+        let mut c64 = fixture();
+        let path = std::env::temp_dir().join("cpu_emu_record_test.wav");
+        // Voice 1 saw at full volume
+        for (byte, reg) in [
+            (0x20, 0xd401),
+            (0xf0, 0xd406),
+            (0x21, 0xd404),
+            (0x0f, 0xd418),
+        ] {
+            c64.bus.set_byte(byte, reg);
+        }
+
+        c64.start_recording(&path).unwrap();
+        assert!(c64.is_recording());
+        for _ in 0..10 {
+            c64.run_frame();
+        }
+        c64.stop_recording().unwrap();
+        assert!(!c64.is_recording());
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let samples = wav::read_samples(&data).unwrap();
+        // 10 PAL frames are about 0.2 seconds
+        assert_eq!(samples.len(), 8798);
+        assert!(samples.iter().any(|&sample| sample > 2000));
+        assert!(samples.iter().any(|&sample| sample < -2000));
+    }
+
+    #[test]
+    fn badline_stalls_cpu() {
+        let mut c64 = fixture();
+
+        let normal = nops_on_line(&mut c64, 0x32);
+        let badline = nops_on_line(&mut c64, 0x33);
+
+        assert!((31..=32).contains(&normal));
+        // 40 c-accesses + 3 cycles of BA before them
+        assert!((10..=11).contains(&badline));
     }
 }
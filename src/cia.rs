@@ -0,0 +1,709 @@
+use crate::bus::Device;
+
+const REGISTERS_COUNT: u16 = 0x10;
+
+const REG_PRA: u16 = 0x0;
+const REG_PRB: u16 = 0x1;
+const REG_DDRA: u16 = 0x2;
+const REG_DDRB: u16 = 0x3;
+const REG_TA_LO: u16 = 0x4;
+const REG_TA_HI: u16 = 0x5;
+const REG_TB_LO: u16 = 0x6;
+const REG_TB_HI: u16 = 0x7;
+// Seconds and minutes are in between
+const REG_TOD_10THS: u16 = 0x8;
+const REG_TOD_HR: u16 = 0xb;
+const REG_SDR: u16 = 0xc;
+const REG_ICR: u16 = 0xd;
+const REG_CRA: u16 = 0xe;
+const REG_CRB: u16 = 0xf;
+
+// Control register bits, same for both timers unless noted
+const CR_START: u8 = 0b0000_0001;
+const CR_PBON: u8 = 0b0000_0010;
+const CR_OUTMODE_TOGGLE: u8 = 0b0000_0100;
+const CR_ONE_SHOT: u8 = 0b0000_1000;
+const CR_FORCE_LOAD: u8 = 0b0001_0000;
+const CRA_INMODE_CNT: u8 = 0b0010_0000;
+const CRA_SPMODE_OUTPUT: u8 = 0b0100_0000;
+const CRA_TODIN_50HZ: u8 = 0b1000_0000;
+const CRB_INMODE: u8 = 0b0110_0000;
+const CRB_ALARM: u8 = 0b1000_0000;
+
+// Timer B input modes
+const CRB_INMODE_PHI2: u8 = 0b0000_0000;
+const CRB_INMODE_TA: u8 = 0b0100_0000;
+const CRB_INMODE_TA_CNT: u8 = 0b0110_0000;
+
+pub const INT_TIMER_A: u8 = 0b0000_0001;
+pub const INT_TIMER_B: u8 = 0b0000_0010;
+pub const INT_ALARM: u8 = 0b0000_0100;
+pub const INT_SERIAL: u8 = 0b0000_1000;
+const INT_SET_CLEAR: u8 = 0b1000_0000;
+const INT_SOURCES: u8 = 0b0001_1111;
+
+#[derive(Default, Clone, Copy)]
+struct Timer {
+    counter: u16,
+    latch: u16,
+    control: u8,
+    // Timer output on PB6/PB7
+    output: bool,
+}
+
+impl Timer {
+    fn started(&self) -> bool {
+        self.control & CR_START != 0
+    }
+
+    fn set_latch_lo(&mut self, byte: u8) {
+        self.latch = (self.latch & 0xff00) | byte as u16;
+    }
+
+    // Loading high byte of stopped timer also loads the counter
+    fn set_latch_hi(&mut self, byte: u8) {
+        self.latch = (self.latch & 0x00ff) | (byte as u16) << 8;
+        if !self.started() {
+            self.counter = self.latch;
+        }
+    }
+
+    fn set_control(&mut self, byte: u8) {
+        if byte & CR_FORCE_LOAD != 0 {
+            self.counter = self.latch;
+        }
+        if byte & CR_START != 0 && !self.started() {
+            // Toggle output goes high when timer starts
+            self.output = true;
+        }
+        self.control = byte & !CR_FORCE_LOAD;
+    }
+
+    // Count one pulse, returns true on underflow
+    fn count(&mut self) -> bool {
+        if self.counter != 0 {
+            self.counter -= 1;
+            if self.control & CR_OUTMODE_TOGGLE == 0 {
+                self.output = false;
+            }
+            return false;
+        }
+
+        self.counter = self.latch;
+        if self.control & CR_ONE_SHOT != 0 {
+            self.control &= !CR_START;
+        }
+        if self.control & CR_OUTMODE_TOGGLE != 0 {
+            self.output = !self.output;
+        } else {
+            // Pulse lasts one cycle
+            self.output = true;
+        }
+        true
+    }
+}
+
+// Time of day registers in BCD: tenths, seconds, minutes, hours (bit 7 is PM)
+type TodTime = [u8; 4];
+
+const TOD_MASKS: TodTime = [0x0f, 0x7f, 0x7f, 0x9f];
+
+fn bcd_increment(value: u8) -> u8 {
+    if value & 0x0f == 9 {
+        value + 7
+    } else {
+        value + 1
+    }
+}
+
+struct Tod {
+    time: TodTime,
+    alarm: TodTime,
+    // Reading hours freezes what cpu sees until tenths are read, clock keeps going
+    latch: Option<TodTime>,
+    // Writing hours stops the clock, writing tenths starts it again
+    running: bool,
+    // Power line pulses since last tenth
+    pulses: u8,
+}
+
+impl Tod {
+    fn new() -> Self {
+        Self {
+            time: [0x00, 0x00, 0x00, 0x01],
+            alarm: [0x00, 0x00, 0x00, 0x00],
+            latch: None,
+            running: true,
+            pulses: 0,
+        }
+    }
+
+    fn read(&self, index: usize) -> u8 {
+        self.latch.unwrap_or(self.time)[index]
+    }
+
+    fn write(&mut self, index: usize, byte: u8, alarm: bool) {
+        let byte = byte & TOD_MASKS[index];
+        if alarm {
+            self.alarm[index] = byte;
+            return;
+        }
+        self.time[index] = byte;
+        if index == 3 {
+            self.running = false;
+        } else if index == 0 {
+            self.running = true;
+            self.pulses = 0;
+        }
+    }
+
+    // One pulse of power line frequency, returns true when alarm time is reached
+    fn pulse(&mut self, pulses_per_tenth: u8) -> bool {
+        if !self.running {
+            return false;
+        }
+        self.pulses += 1;
+        if self.pulses < pulses_per_tenth {
+            return false;
+        }
+        self.pulses = 0;
+        self.advance();
+        self.time == self.alarm
+    }
+
+    fn advance(&mut self) {
+        let [tenths, seconds, minutes, hours] = &mut self.time;
+        if *tenths != 9 {
+            *tenths += 1;
+            return;
+        }
+        *tenths = 0;
+        if *seconds != 0x59 {
+            *seconds = bcd_increment(*seconds);
+            return;
+        }
+        *seconds = 0;
+        if *minutes != 0x59 {
+            *minutes = bcd_increment(*minutes);
+            return;
+        }
+        *minutes = 0;
+        let pm = *hours & 0x80;
+        *hours = match *hours & 0x1f {
+            0x11 => 0x12 | (pm ^ 0x80),
+            0x12 => 0x01 | pm,
+            hour => bcd_increment(hour) | pm,
+        };
+    }
+}
+
+// 6526 Complex Interface Adapter. C64 has two of them: CIA1 is connected to IRQ,
+// keyboard and joysticks, CIA2 to NMI, serial bus and VIC bank selection.
+pub struct Cia {
+    port_a: u8,
+    port_b: u8,
+    ddr_a: u8,
+    ddr_b: u8,
+    // What devices connected to ports pull low
+    port_a_input: u8,
+    port_b_input: u8,
+    timer_a: Timer,
+    timer_b: Timer,
+    // Interrupt sources which happened (ICR on read)
+    int_data: u8,
+    int_mask: u8,
+    tod: Tod,
+    // Timer A underflows left until serial register is shifted out, two per bit
+    sdr_shifts_left: u8,
+    // Byte written to serial register while previous one is still shifting
+    sdr_pending: bool,
+    // Everything else is kept as written
+    registers: [u8; REGISTERS_COUNT as usize],
+}
+
+impl Cia {
+    pub fn new() -> Self {
+        Self {
+            port_a: 0,
+            port_b: 0,
+            ddr_a: 0,
+            ddr_b: 0,
+            port_a_input: 0xff,
+            port_b_input: 0xff,
+            timer_a: Timer {
+                latch: 0xffff,
+                counter: 0xffff,
+                ..Default::default()
+            },
+            timer_b: Timer {
+                latch: 0xffff,
+                counter: 0xffff,
+                ..Default::default()
+            },
+            int_data: 0,
+            int_mask: 0,
+            tod: Tod::new(),
+            sdr_shifts_left: 0,
+            sdr_pending: false,
+            registers: [0; REGISTERS_COUNT as usize],
+        }
+    }
+
+    // State of the interrupt output (IRQ for CIA1, NMI for CIA2), true when asserted
+    pub fn interrupt(&self) -> bool {
+        self.int_data & self.int_mask != 0
+    }
+
+    // Port pins as seen from outside. Input pins are pulled up.
+    pub fn port_a_output(&self) -> u8 {
+        self.port_a | !self.ddr_a
+    }
+
+    pub fn port_b_output(&self) -> u8 {
+        let mut value = self.port_b | !self.ddr_b;
+        if self.timer_a.control & CR_PBON != 0 {
+            value = (value & !0x40) | (self.timer_a.output as u8) << 6;
+        }
+        if self.timer_b.control & CR_PBON != 0 {
+            value = (value & !0x80) | (self.timer_b.output as u8) << 7;
+        }
+        value
+    }
+
+    // Called with power line frequency (50 or 60 Hz), CRA selects which one is expected
+    pub fn tod_pulse(&mut self) {
+        let pulses_per_tenth = if self.timer_a.control & CRA_TODIN_50HZ != 0 {
+            5
+        } else {
+            6
+        };
+        if self.tod.pulse(pulses_per_tenth) {
+            self.int_data |= INT_ALARM;
+        }
+    }
+
+    fn write_sdr(&mut self) {
+        if self.timer_a.control & CRA_SPMODE_OUTPUT == 0 {
+            return;
+        }
+        if self.sdr_shifts_left == 0 {
+            self.sdr_shifts_left = 16;
+        } else {
+            self.sdr_pending = true;
+        }
+    }
+
+    // Serial register is clocked by timer A underflows in output mode. Input mode
+    // needs CNT, which nothing drives, so it never completes.
+    fn shift_sdr(&mut self) {
+        if self.sdr_shifts_left == 0 || self.timer_a.control & CRA_SPMODE_OUTPUT == 0 {
+            return;
+        }
+        self.sdr_shifts_left -= 1;
+        if self.sdr_shifts_left == 0 {
+            self.int_data |= INT_SERIAL;
+            if self.sdr_pending {
+                self.sdr_pending = false;
+                self.sdr_shifts_left = 16;
+            }
+        }
+    }
+
+    pub fn set_port_a_input(&mut self, value: u8) {
+        self.port_a_input = value;
+    }
+
+    pub fn set_port_b_input(&mut self, value: u8) {
+        self.port_b_input = value;
+    }
+
+    fn register(offset: u16) -> u16 {
+        offset % REGISTERS_COUNT
+    }
+
+    fn icr_value(&self) -> u8 {
+        let ir = if self.interrupt() { INT_SET_CLEAR } else { 0 };
+        self.int_data | ir
+    }
+}
+
+impl Device for Cia {
+    fn set_byte(&mut self, byte: u8, offset: u16) {
+        let reg = Self::register(offset);
+        match reg {
+            REG_PRA => self.port_a = byte,
+            REG_PRB => self.port_b = byte,
+            REG_DDRA => self.ddr_a = byte,
+            REG_DDRB => self.ddr_b = byte,
+            REG_TA_LO => self.timer_a.set_latch_lo(byte),
+            REG_TA_HI => self.timer_a.set_latch_hi(byte),
+            REG_TB_LO => self.timer_b.set_latch_lo(byte),
+            REG_TB_HI => self.timer_b.set_latch_hi(byte),
+            REG_TOD_10THS..=REG_TOD_HR => {
+                let alarm = self.timer_b.control & CRB_ALARM != 0;
+                self.tod.write((reg - REG_TOD_10THS) as usize, byte, alarm);
+            }
+            REG_SDR => self.write_sdr(),
+            REG_ICR => {
+                if byte & INT_SET_CLEAR != 0 {
+                    self.int_mask |= byte & INT_SOURCES;
+                } else {
+                    self.int_mask &= !(byte & INT_SOURCES);
+                }
+            }
+            REG_CRA => self.timer_a.set_control(byte),
+            REG_CRB => self.timer_b.set_control(byte),
+            _ => {}
+        }
+        self.registers[reg as usize] = byte;
+    }
+
+    // Pure read, without clearing ICR
+    fn get_byte(&self, offset: u16) -> u8 {
+        match Self::register(offset) {
+            // Pins are wired-AND of our outputs and external devices
+            REG_PRA => self.port_a_output() & self.port_a_input,
+            REG_PRB => self.port_b_output() & self.port_b_input,
+            REG_DDRA => self.ddr_a,
+            REG_DDRB => self.ddr_b,
+            REG_TA_LO => self.timer_a.counter as u8,
+            REG_TA_HI => (self.timer_a.counter >> 8) as u8,
+            REG_TB_LO => self.timer_b.counter as u8,
+            REG_TB_HI => (self.timer_b.counter >> 8) as u8,
+            reg @ REG_TOD_10THS..=REG_TOD_HR => self.tod.read((reg - REG_TOD_10THS) as usize),
+            REG_ICR => self.icr_value(),
+            REG_CRA => self.timer_a.control,
+            REG_CRB => self.timer_b.control,
+            reg => self.registers[reg as usize],
+        }
+    }
+
+    // Reading ICR acknowledges all interrupts, reading TOD hours latches the clock
+    fn read_byte(&mut self, offset: u16) -> u8 {
+        let value = self.get_byte(offset);
+        match Self::register(offset) {
+            REG_ICR => self.int_data = 0,
+            REG_TOD_HR => self.tod.latch = Some(self.tod.latch.unwrap_or(self.tod.time)),
+            REG_TOD_10THS => self.tod.latch = None,
+            _ => {}
+        }
+        value
+    }
+
+    fn get_bytes_slice(&self, from: u16, to: u16) -> Vec<u8> {
+        (from..to).map(|offset| self.get_byte(offset)).collect()
+    }
+
+    fn tick(&mut self) {
+        // There is nothing connected to CNT, so timers can count only clock
+        // and timer A underflows (CNT is pulled high).
+        let mut ta_underflow = false;
+        if self.timer_a.started() && self.timer_a.control & CRA_INMODE_CNT == 0 {
+            ta_underflow = self.timer_a.count();
+            if ta_underflow {
+                self.int_data |= INT_TIMER_A;
+                self.shift_sdr();
+            }
+        }
+
+        if self.timer_b.started() {
+            let count = match self.timer_b.control & CRB_INMODE {
+                CRB_INMODE_PHI2 => true,
+                CRB_INMODE_TA | CRB_INMODE_TA_CNT => ta_underflow,
+                _ => false,
+            };
+            if count && self.timer_b.count() {
+                self.int_data |= INT_TIMER_B;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tick(cia: &mut Cia, cycles: usize) {
+        for _ in 0..cycles {
+            cia.tick();
+        }
+    }
+
+    fn start_timer_a(cia: &mut Cia, latch: u16, control: u8) {
+        cia.set_byte(latch as u8, 0xdc04);
+        cia.set_byte((latch >> 8) as u8, 0xdc05);
+        cia.set_byte(control | CR_START, 0xdc0e);
+    }
+
+    #[test]
+    fn timer_counts_down() {
+        let mut cia = Cia::new();
+        start_timer_a(&mut cia, 0x1234, 0);
+        tick(&mut cia, 0x34);
+        assert_eq!(cia.get_byte(0xdc04), 0x00);
+        assert_eq!(cia.get_byte(0xdc05), 0x12);
+    }
+
+    #[test]
+    fn continuous_timer_reloads() {
+        let mut cia = Cia::new();
+        start_timer_a(&mut cia, 10, 0);
+
+        tick(&mut cia, 10);
+        assert_eq!(cia.get_byte(0xdc0d) & INT_TIMER_A, 0);
+        tick(&mut cia, 1);
+        assert_eq!(cia.get_byte(0xdc0d) & INT_TIMER_A, INT_TIMER_A);
+        assert_eq!(cia.get_byte(0xdc04), 10);
+        assert_eq!(cia.get_byte(0xdc0e) & CR_START, CR_START);
+
+        cia.read_byte(0xdc0d);
+        tick(&mut cia, 11);
+        assert_eq!(cia.get_byte(0xdc0d) & INT_TIMER_A, INT_TIMER_A);
+    }
+
+    #[test]
+    fn one_shot_timer_stops() {
+        let mut cia = Cia::new();
+        start_timer_a(&mut cia, 5, CR_ONE_SHOT);
+        tick(&mut cia, 6);
+
+        assert_eq!(cia.get_byte(0xdc0e) & CR_START, 0);
+        assert_eq!(cia.get_byte(0xdc04), 5);
+        tick(&mut cia, 3);
+        assert_eq!(cia.get_byte(0xdc04), 5);
+    }
+
+    #[test]
+    fn latch_loads_only_stopped_timer() {
+        let mut cia = Cia::new();
+        start_timer_a(&mut cia, 0x100, 0);
+        cia.set_byte(0x20, 0xdc05);
+        assert_eq!(cia.get_byte(0xdc05), 0x01);
+
+        cia.set_byte(CR_START | CR_FORCE_LOAD, 0xdc0e);
+        assert_eq!(cia.get_byte(0xdc05), 0x20);
+        assert_eq!(cia.get_byte(0xdc0e) & CR_FORCE_LOAD, 0);
+    }
+
+    #[test]
+    fn interrupt_only_when_enabled() {
+        let mut cia = Cia::new();
+        start_timer_a(&mut cia, 1, 0);
+        tick(&mut cia, 2);
+        assert!(!cia.interrupt());
+        assert_eq!(cia.get_byte(0xdc0d), INT_TIMER_A);
+
+        // Enabling already happened interrupt asserts the line
+        cia.set_byte(INT_SET_CLEAR | INT_TIMER_A, 0xdc0d);
+        assert!(cia.interrupt());
+        assert_eq!(cia.get_byte(0xdc0d), INT_SET_CLEAR | INT_TIMER_A);
+    }
+
+    #[test]
+    fn icr_read_clears() {
+        let mut cia = Cia::new();
+        cia.set_byte(INT_SET_CLEAR | INT_TIMER_A, 0xdc0d);
+        start_timer_a(&mut cia, 1, 0);
+        tick(&mut cia, 2);
+        assert!(cia.interrupt());
+
+        assert_eq!(cia.read_byte(0xdc0d), INT_SET_CLEAR | INT_TIMER_A);
+        assert!(!cia.interrupt());
+        assert_eq!(cia.read_byte(0xdc0d), 0);
+    }
+
+    #[test]
+    fn icr_clear_mask() {
+        let mut cia = Cia::new();
+        cia.set_byte(INT_SET_CLEAR | INT_TIMER_A | INT_TIMER_B, 0xdc0d);
+        cia.set_byte(INT_TIMER_A, 0xdc0d);
+        start_timer_a(&mut cia, 1, 0);
+        tick(&mut cia, 2);
+        assert!(!cia.interrupt());
+    }
+
+    #[test]
+    fn timer_b_counts_timer_a_underflows() {
+        let mut cia = Cia::new();
+        cia.set_byte(INT_SET_CLEAR | INT_TIMER_B, 0xdc0d);
+        cia.set_byte(2, 0xdc06);
+        cia.set_byte(0, 0xdc07);
+        cia.set_byte(CR_START | CRB_INMODE_TA, 0xdc0f);
+        start_timer_a(&mut cia, 9, 0);
+
+        // timer B underflows on its third count
+        tick(&mut cia, 20);
+        assert!(!cia.interrupt());
+        tick(&mut cia, 10);
+        assert!(cia.interrupt());
+        assert_eq!(cia.get_byte(0xdc0d) & INT_TIMER_A, INT_TIMER_A);
+    }
+
+    #[test]
+    fn timer_on_cnt_does_not_count() {
+        let mut cia = Cia::new();
+        start_timer_a(&mut cia, 10, CRA_INMODE_CNT);
+        tick(&mut cia, 5);
+        assert_eq!(cia.get_byte(0xdc04), 10);
+    }
+
+    #[test]
+    fn timer_toggle_output_on_pb6() {
+        let mut cia = Cia::new();
+        start_timer_a(&mut cia, 3, CR_PBON | CR_OUTMODE_TOGGLE);
+        assert_eq!(cia.port_b_output() & 0x40, 0x40);
+        tick(&mut cia, 4);
+        assert_eq!(cia.port_b_output() & 0x40, 0);
+        tick(&mut cia, 4);
+        assert_eq!(cia.port_b_output() & 0x40, 0x40);
+    }
+
+    #[test]
+    fn timer_pulse_output_on_pb7() {
+        let mut cia = Cia::new();
+        cia.set_byte(3, 0xdc06);
+        cia.set_byte(0, 0xdc07);
+        cia.set_byte(CR_START | CR_PBON, 0xdc0f);
+        tick(&mut cia, 4);
+        assert_eq!(cia.port_b_output() & 0x80, 0x80);
+        tick(&mut cia, 1);
+        assert_eq!(cia.port_b_output() & 0x80, 0);
+    }
+
+    fn tod_pulses(cia: &mut Cia, count: usize) {
+        for _ in 0..count {
+            cia.tod_pulse();
+        }
+    }
+
+    fn set_tod(cia: &mut Cia, time: TodTime) {
+        for (index, byte) in time.iter().enumerate().rev() {
+            cia.set_byte(*byte, 0xdc08 + index as u16);
+        }
+    }
+
+    fn read_tod(cia: &mut Cia) -> TodTime {
+        let hours = cia.read_byte(0xdc0b);
+        let minutes = cia.read_byte(0xdc0a);
+        let seconds = cia.read_byte(0xdc09);
+        let tenths = cia.read_byte(0xdc08);
+        [tenths, seconds, minutes, hours]
+    }
+
+    #[test]
+    fn tod_counts_tenths() {
+        let mut cia = Cia::new();
+        cia.set_byte(CRA_TODIN_50HZ, 0xdc0e);
+        set_tod(&mut cia, [0, 0x59, 0x59, 0x11]);
+        tod_pulses(&mut cia, 9 * 5);
+        assert_eq!(read_tod(&mut cia), [9, 0x59, 0x59, 0x11]);
+
+        tod_pulses(&mut cia, 5);
+        assert_eq!(read_tod(&mut cia), [0, 0, 0, 0x92]);
+    }
+
+    #[test]
+    fn tod_60hz_input() {
+        let mut cia = Cia::new();
+        set_tod(&mut cia, [0, 0, 0, 0x01]);
+        tod_pulses(&mut cia, 60);
+        assert_eq!(read_tod(&mut cia), [0, 1, 0, 0x01]);
+    }
+
+    #[test]
+    fn tod_pm_to_am() {
+        let mut cia = Cia::new();
+        cia.set_byte(CRA_TODIN_50HZ, 0xdc0e);
+        set_tod(&mut cia, [9, 0x59, 0x59, 0x91]);
+        tod_pulses(&mut cia, 5);
+        assert_eq!(read_tod(&mut cia), [0, 0, 0, 0x12]);
+        set_tod(&mut cia, [9, 0x59, 0x59, 0x12]);
+        tod_pulses(&mut cia, 5);
+        assert_eq!(read_tod(&mut cia), [0, 0, 0, 0x01]);
+    }
+
+    #[test]
+    fn tod_stopped_after_hours_write() {
+        let mut cia = Cia::new();
+        cia.set_byte(CRA_TODIN_50HZ, 0xdc0e);
+        cia.set_byte(0x03, 0xdc0b);
+        tod_pulses(&mut cia, 50);
+        assert_eq!(cia.get_byte(0xdc08), 0);
+
+        cia.set_byte(0, 0xdc08);
+        tod_pulses(&mut cia, 50);
+        assert_eq!(cia.get_byte(0xdc09), 1);
+    }
+
+    #[test]
+    fn tod_latched_on_hours_read() {
+        let mut cia = Cia::new();
+        cia.set_byte(CRA_TODIN_50HZ, 0xdc0e);
+        set_tod(&mut cia, [9, 0x59, 0x59, 0x01]);
+
+        assert_eq!(cia.read_byte(0xdc0b), 0x01);
+        tod_pulses(&mut cia, 5);
+        // Clock keeps going, but reads return latched time until tenths are read
+        assert_eq!(cia.read_byte(0xdc0a), 0x59);
+        assert_eq!(cia.read_byte(0xdc09), 0x59);
+        assert_eq!(cia.read_byte(0xdc08), 9);
+        assert_eq!(cia.read_byte(0xdc0a), 0x00);
+        assert_eq!(cia.read_byte(0xdc0b), 0x02);
+    }
+
+    #[test]
+    fn tod_alarm_interrupt() {
+        let mut cia = Cia::new();
+        cia.set_byte(CRA_TODIN_50HZ, 0xdc0e);
+        cia.set_byte(INT_SET_CLEAR | INT_ALARM, 0xdc0d);
+        set_tod(&mut cia, [0, 0, 0, 0x01]);
+        cia.set_byte(CRB_ALARM, 0xdc0f);
+        set_tod(&mut cia, [5, 1, 0, 0x01]);
+        cia.set_byte(0, 0xdc0f);
+
+        // Writing alarm doesn't change the time
+        assert_eq!(read_tod(&mut cia), [0, 0, 0, 0x01]);
+        tod_pulses(&mut cia, 74);
+        assert!(!cia.interrupt());
+        tod_pulses(&mut cia, 1);
+        assert!(cia.interrupt());
+        assert_eq!(cia.read_byte(0xdc0d), INT_SET_CLEAR | INT_ALARM);
+    }
+
+    #[test]
+    fn serial_output_interrupt() {
+        let mut cia = Cia::new();
+        cia.set_byte(INT_SET_CLEAR | INT_SERIAL, 0xdc0d);
+        start_timer_a(&mut cia, 1, CRA_SPMODE_OUTPUT);
+        cia.set_byte(0xa5, 0xdc0c);
+        cia.set_byte(0x5a, 0xdc0c);
+        assert_eq!(cia.get_byte(0xdc0c), 0x5a);
+
+        // 8 bits, 2 underflows per bit, 2 cycles per underflow
+        tick(&mut cia, 31);
+        assert!(!cia.interrupt());
+        tick(&mut cia, 1);
+        assert!(cia.interrupt());
+
+        // Second byte follows right after the first one
+        cia.read_byte(0xdc0d);
+        tick(&mut cia, 32);
+        assert!(cia.interrupt());
+        cia.read_byte(0xdc0d);
+        tick(&mut cia, 64);
+        assert!(!cia.interrupt());
+    }
+
+    #[test]
+    fn ports_with_data_direction() {
+        let mut cia = Cia::new();
+        cia.set_byte(0b0000_0011, 0xdd02);
+        cia.set_byte(0b0000_0001, 0xdd00);
+        assert_eq!(cia.port_a_output(), 0b1111_1101);
+        assert_eq!(cia.get_byte(0xdd00), 0b1111_1101);
+
+        cia.set_port_a_input(0b0111_1111);
+        assert_eq!(cia.get_byte(0xdd00), 0b0111_1101);
+        assert_eq!(cia.port_a_output(), 0b1111_1101);
+    }
+}
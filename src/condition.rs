@@ -0,0 +1,235 @@
+// Breakpoint conditions like "a == $10 && c == 1 || hits >= 100". && binds tighter
+// than ||. Operands are registers (a, x, y, sp, pc, p), flags (n, v, b, d, i, z, c are
+// 0 or 1), value (byte accessed by watchpoint, opcode for breakpoint), hits (how many
+// times breakpoint was reached) and numbers, hex with optional $ prefix.
+
+use crate::cpu::Cpu;
+use crate::options::parse_address;
+
+use std::fmt;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Operand {
+    A,
+    X,
+    Y,
+    Sp,
+    Pc,
+    P,
+    Flag(u8),
+    Value,
+    Hits,
+    Number(u32),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Compare {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Comparison {
+    left: Operand,
+    compare: Compare,
+    right: Operand,
+}
+
+// What condition is evaluated against
+pub struct Context<'a> {
+    pub cpu: &'a Cpu,
+    pub value: u8,
+    pub hits: u32,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Condition {
+    text: String,
+    // Any of the groups, all comparisons in a group
+    groups: Vec<Vec<Comparison>>,
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+fn parse_operand(token: &str) -> Result<Operand, String> {
+    let operand = match token.to_lowercase().as_str() {
+        "a" => Operand::A,
+        "x" => Operand::X,
+        "y" => Operand::Y,
+        "sp" => Operand::Sp,
+        "pc" => Operand::Pc,
+        "p" => Operand::P,
+        "n" => Operand::Flag(7),
+        "v" => Operand::Flag(6),
+        "b" => Operand::Flag(4),
+        "d" => Operand::Flag(3),
+        "i" => Operand::Flag(2),
+        "z" => Operand::Flag(1),
+        "c" => Operand::Flag(0),
+        "value" => Operand::Value,
+        "hits" => Operand::Hits,
+        _ => {
+            let number = match token.strip_prefix('$') {
+                Some(_) => parse_address(token).map(u32::from),
+                None => u32::from_str_radix(token, 16).map_err(|_| token.to_string()),
+            };
+            Operand::Number(number.map_err(|_| format!("Bad operand: {}", token))?)
+        }
+    };
+    Ok(operand)
+}
+
+fn parse_compare(token: &str) -> Result<Compare, String> {
+    match token {
+        "==" => Ok(Compare::Equal),
+        "!=" => Ok(Compare::NotEqual),
+        "<" => Ok(Compare::Less),
+        "<=" => Ok(Compare::LessOrEqual),
+        ">" => Ok(Compare::Greater),
+        ">=" => Ok(Compare::GreaterOrEqual),
+        _ => Err(format!("Comparison expected instead of {}", token)),
+    }
+}
+
+// Operators are split from operands even without spaces around them
+fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens: Vec<String> = vec![];
+    let mut previous_operator = false;
+    for c in text.chars() {
+        if c.is_whitespace() {
+            previous_operator = false;
+            tokens.push(String::new());
+            continue;
+        }
+        let operator = "=!<>&|".contains(c);
+        match tokens.last_mut() {
+            Some(token) if operator == previous_operator && !token.is_empty() => token.push(c),
+            _ => tokens.push(c.to_string()),
+        }
+        previous_operator = operator;
+    }
+    tokens.retain(|token| !token.is_empty());
+    tokens
+}
+
+impl Condition {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let tokens = tokenize(text);
+        let mut groups = vec![];
+        for group in tokens.split(|token| token == "||") {
+            let mut comparisons = vec![];
+            for comparison in group.split(|token| token == "&&") {
+                match comparison {
+                    [left, compare, right] => comparisons.push(Comparison {
+                        left: parse_operand(left)?,
+                        compare: parse_compare(compare)?,
+                        right: parse_operand(right)?,
+                    }),
+                    _ => {
+                        return Err(format!(
+                            "Expected <operand> <comparison> <operand>: {}",
+                            comparison.join(" ")
+                        ))
+                    }
+                }
+            }
+            groups.push(comparisons);
+        }
+        Ok(Self {
+            text: tokens.join(" "),
+            groups,
+        })
+    }
+
+    pub fn eval(&self, context: &Context) -> bool {
+        self.groups.iter().any(|group| {
+            group.iter().all(|comparison| {
+                let left = Self::operand(comparison.left, context);
+                let right = Self::operand(comparison.right, context);
+                match comparison.compare {
+                    Compare::Equal => left == right,
+                    Compare::NotEqual => left != right,
+                    Compare::Less => left < right,
+                    Compare::LessOrEqual => left <= right,
+                    Compare::Greater => left > right,
+                    Compare::GreaterOrEqual => left >= right,
+                }
+            })
+        })
+    }
+
+    fn operand(operand: Operand, context: &Context) -> u32 {
+        let cpu = context.cpu;
+        match operand {
+            Operand::A => cpu.reg.a as u32,
+            Operand::X => cpu.reg.x as u32,
+            Operand::Y => cpu.reg.y as u32,
+            Operand::Sp => cpu.sp() as u32,
+            Operand::Pc => cpu.pc() as u32,
+            Operand::P => cpu.flags.get_register() as u32,
+            Operand::Flag(bit) => (cpu.flags.get_register() >> bit) as u32 & 1,
+            Operand::Value => context.value as u32,
+            Operand::Hits => context.hits,
+            Operand::Number(number) => number,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(text: &str, cpu: &Cpu, value: u8, hits: u32) -> bool {
+        Condition::parse(text)
+            .unwrap()
+            .eval(&Context { cpu, value, hits })
+    }
+
+    #[test]
+    fn registers_and_flags() {
+        let mut cpu = Cpu::new();
+        cpu.reg.a = 0x10;
+        cpu.reg.x = 3;
+        cpu.flags.set_carry(true);
+        assert!(eval("a == $10", &cpu, 0, 0));
+        assert!(eval("A==10", &cpu, 0, 0));
+        assert!(!eval("a != $10", &cpu, 0, 0));
+        assert!(eval("x > 2 && x <= 3", &cpu, 0, 0));
+        assert!(eval("c == 1 && z == 0", &cpu, 0, 0));
+        assert!(eval("sp == $ff", &cpu, 0, 0));
+        assert!(!eval("x < 3", &cpu, 0, 0));
+    }
+
+    #[test]
+    fn value_hits_and_or() {
+        let cpu = Cpu::new();
+        assert!(eval("value == $ff", &cpu, 0xff, 0));
+        assert!(eval("hits >= $64", &cpu, 0, 100));
+        assert!(!eval("hits >= 100", &cpu, 0, 99));
+        assert!(eval("value == 1 || hits == 5", &cpu, 0, 5));
+        assert!(!eval("value == 1 || hits == 5 && a == 1", &cpu, 0, 5));
+    }
+
+    #[test]
+    fn text_is_normalized() {
+        let condition = Condition::parse("a==$10&&x>=2").unwrap();
+        assert_eq!(condition.to_string(), "a == $10 && x >= 2");
+    }
+
+    #[test]
+    fn errors() {
+        assert!(Condition::parse("a ==").is_err());
+        assert!(Condition::parse("a = 1").is_err());
+        assert!(Condition::parse("q == 1").is_err());
+        assert!(Condition::parse("a == 1 &&").is_err());
+        assert!(Condition::parse("").is_err());
+    }
+}
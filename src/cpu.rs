@@ -1,14 +1,34 @@
 use crate::bus::Bus;
 use crate::flags::Flags;
-use crate::ops_lookup::{AddressMode, Code, OPCODE_TABLE};
+use crate::ops_lookup::{AddressMode, Code, OpDescription, OPCODE_TABLE};
 
+static NMI_PC: u16 = 0xfffa;
 static START_PC: u16 = 0xfffc;
 static INTERRUPT_PC: u16 = 0xfffe;
 
+// Interrupt sequence is the same as BRK: push PCH, PCL, P and then read the vector
+const INTERRUPT_CYCLES: u8 = 7;
+const INTERRUPT_WRITE_CYCLES: u8 = 0b11100;
+
 fn merge_bytes(hi: u8, lo: u8) -> u16 {
     ((hi as u16) << 8) + lo as u16
 }
 
+// Which of the trailing cycles of instruction are bus writes. Bit 0 is the last cycle,
+// bit 1 is the one before it and so on. Everything else is a read (or a dummy read).
+fn write_cycles_mask(op: &OpDescription) -> u8 {
+    match op.code {
+        Code::STA | Code::STX | Code::STY | Code::PHA | Code::PHP => 0b1,
+        Code::ASL | Code::LSR | Code::ROL | Code::ROR if op.mode == AddressMode::Accumulator => 0,
+        // read-modify-write: dummy write of the old value and then the new one
+        Code::ASL | Code::LSR | Code::ROL | Code::ROR | Code::INC | Code::DEC => 0b11,
+        // push PCH, push PCL, fetch high byte of target
+        Code::JSR => 0b110,
+        Code::BRK => INTERRUPT_WRITE_CYCLES,
+        _ => 0,
+    }
+}
+
 #[derive(Default, Clone, Copy)]
 pub struct Registers {
     pub a: u8,
@@ -16,13 +36,46 @@ pub struct Registers {
     pub y: u8,
 }
 
-#[derive(Clone, Copy)]
+// Shadow call stack is not allowed to grow forever if code never returns
+const MAX_CALL_FRAMES: usize = 256;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FrameKind {
+    Jsr,
+    Brk,
+    Irq,
+    Nmi,
+}
+
+// Entry of shadow call stack, see Cpu::call_stack
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CallFrame {
+    pub kind: FrameKind,
+    // JSR instruction, or where execution continues after interrupt
+    pub from: u16,
+    // Subroutine or interrupt handler
+    pub to: u16,
+    // Stack pointer after return address was pushed
+    pub sp: u8,
+}
+
+#[derive(Clone)]
 pub struct Cpu {
     pub reg: Registers,
     pub flags: Flags,
     pc: u16,
     sp: u8,
     cycle_left: u8,
+    // Write cycles of current instruction, see write_cycles_mask
+    write_cycles: u8,
+    // RDY input. When low, cpu halts on the next read cycle, but finishes writes.
+    rdy: bool,
+    // IRQ input, level triggered
+    irq: bool,
+    // NMI input, edge triggered, so we remember that it went active
+    nmi: bool,
+    nmi_pending: bool,
+    call_stack: Vec<CallFrame>,
 }
 
 impl Cpu {
@@ -33,6 +86,12 @@ impl Cpu {
             pc: 0x0000,
             sp: 0xff,
             cycle_left: 0,
+            write_cycles: 0,
+            rdy: true,
+            irq: false,
+            nmi: false,
+            nmi_pending: false,
+            call_stack: vec![],
         }
     }
 
@@ -41,18 +100,77 @@ impl Cpu {
         self.flags = Flags::new(0u8);
         self.sp = 0xff;
         self.cycle_left = 0;
+        self.write_cycles = 0;
+        self.nmi_pending = false;
+        self.call_stack.clear();
 
         self.pc = bus.get_two_bytes(START_PC);
     }
 
-    pub fn nmi(&mut self, bus: &mut Bus) {
+    // Push pc and flags and jump to the address in vector. Used by BRK, IRQ and NMI,
+    // the only difference is break flag in pushed flags.
+    fn interrupt(&mut self, bus: &mut Bus, vector: u16, brk: bool) {
+        let from = self.pc;
+        self.drop_dead_frames();
         self.write_u16_to_stack(bus, self.pc);
-        self.write_u8_to_stack(bus, self.flags.get_register());
+        let mut pushed_flags = self.flags;
+        pushed_flags.set_break_cmd(brk);
+        self.write_u8_to_stack(bus, pushed_flags.get_register());
 
-        self.pc = bus.get_two_bytes(INTERRUPT_PC);
-        // println!("nmi: this is new pc: {:#04X}", self.pc);
+        self.flags.set_interrupt_disabled(true);
+        self.pc = bus.get_two_bytes(vector);
 
-        // TODO: takes 8 (?) cycles
+        let kind = match (brk, vector) {
+            (true, _) => FrameKind::Brk,
+            _ if vector == NMI_PC => FrameKind::Nmi,
+            _ => FrameKind::Irq,
+        };
+        self.push_frame(kind, from, self.pc);
+    }
+
+    // Subroutines and interrupt handlers cpu is in, the innermost is the last. Frames
+    // dropped from the stack since the last JSR or return are left out.
+    pub fn call_stack(&self) -> &[CallFrame] {
+        let live = self
+            .call_stack
+            .iter()
+            .rposition(|frame| frame.sp >= self.sp)
+            .map_or(0, |index| index + 1);
+        &self.call_stack[..live]
+    }
+
+    // Frames below stack pointer were removed by the code itself (PLA, TXS, stack
+    // reset), so they are not on the real stack anymore
+    fn drop_dead_frames(&mut self) {
+        while let Some(frame) = self.call_stack.last() {
+            if frame.sp >= self.sp {
+                break;
+            }
+            self.call_stack.pop();
+        }
+    }
+
+    // Called after return address is pushed. Dead frames must be dropped before the
+    // push, since it can reuse their place on stack.
+    fn push_frame(&mut self, kind: FrameKind, from: u16, to: u16) {
+        if self.call_stack.len() == MAX_CALL_FRAMES {
+            self.call_stack.remove(0);
+        }
+        self.call_stack.push(CallFrame {
+            kind,
+            from,
+            to,
+            sp: self.sp,
+        });
+    }
+
+    // Called before return address is pulled. If something was pushed over return
+    // address (e.g. RTS used as jump through pushed address), it's not a return.
+    fn pop_frame(&mut self) {
+        self.drop_dead_frames();
+        if self.call_stack.last().map(|frame| frame.sp) == Some(self.sp) {
+            self.call_stack.pop();
+        }
     }
 
     pub fn run_until_brk(&mut self, bus: &mut Bus) {
@@ -61,13 +179,76 @@ impl Cpu {
         }
     }
 
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    // For debugger, should be called between instructions
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    pub fn sp(&self) -> u8 {
+        self.sp
+    }
+
+    pub fn set_sp(&mut self, sp: u8) {
+        self.sp = sp;
+    }
+
+    // Previous instruction is finished, the next tick starts a new one (or interrupt)
+    pub fn at_instruction_boundary(&self) -> bool {
+        self.cycle_left == 0
+    }
+
+    pub fn set_rdy(&mut self, rdy: bool) {
+        self.rdy = rdy;
+    }
+
+    // true means line is active (pulled low on real hardware)
+    pub fn set_irq(&mut self, active: bool) {
+        self.irq = active;
+    }
+
+    pub fn set_nmi(&mut self, active: bool) {
+        if active && !self.nmi {
+            self.nmi_pending = true;
+        }
+        self.nmi = active;
+    }
+
+    // Cpu can not be stopped on write cycles, so it will be halted on the first
+    // read after RDY goes low. That's why VIC pull BA 3 cycles before it takes the bus.
+    pub fn is_halted(&self) -> bool {
+        !self.rdy && !self.next_cycle_is_write()
+    }
+
+    fn next_cycle_is_write(&self) -> bool {
+        self.cycle_left > 0 && self.write_cycles & (1 << (self.cycle_left - 1)) != 0
+    }
+
     pub fn tick(&mut self, bus: &mut Bus) {
+        if self.is_halted() {
+            return;
+        }
         if self.cycle_left > 0 {
             self.cycle_left -= 1;
             return;
         }
-        println!("------");
-        let op_code = bus.get_byte(self.pc);
+        // Interrupts are checked between instructions
+        if self.nmi_pending || (self.irq && !self.flags.interrupt_disabled()) {
+            let vector = if self.nmi_pending {
+                NMI_PC
+            } else {
+                INTERRUPT_PC
+            };
+            self.nmi_pending = false;
+            self.interrupt(bus, vector, false);
+            self.cycle_left = INTERRUPT_CYCLES - 1;
+            self.write_cycles = INTERRUPT_WRITE_CYCLES;
+            return;
+        }
+        let op_code = bus.fetch_byte(self.pc);
 
         let op = OPCODE_TABLE[op_code as usize];
         if op.is_none() {
@@ -75,29 +256,33 @@ impl Cpu {
         }
         let op = op.unwrap();
 
-        println!("opcode: {} ({:#04X}) ", op.name, op_code);
-
         let (address, mut cross_page): (u16, bool) = match op.mode {
             AddressMode::Immediate => (self.pc + 1, false),
-            AddressMode::ZeroPage => (bus.get_byte(self.pc + 1) as u16, false),
-            AddressMode::ZeroPageX => (bus.get_byte(self.pc + 1) as u16 + self.reg.x as u16, false),
-            AddressMode::ZeroPageY => (bus.get_byte(self.pc + 1) as u16 + self.reg.y as u16, false),
-            AddressMode::Absolute => (bus.get_two_bytes(self.pc + 1), false),
+            AddressMode::ZeroPage => (bus.fetch_byte(self.pc + 1) as u16, false),
+            AddressMode::ZeroPageX => (
+                bus.fetch_byte(self.pc + 1) as u16 + self.reg.x as u16,
+                false,
+            ),
+            AddressMode::ZeroPageY => (
+                bus.fetch_byte(self.pc + 1) as u16 + self.reg.y as u16,
+                false,
+            ),
+            AddressMode::Absolute => (bus.fetch_two_bytes(self.pc + 1), false),
             AddressMode::AbsoluteX => {
-                let by_arg = bus.get_two_bytes(self.pc + 1);
+                let by_arg = bus.fetch_two_bytes(self.pc + 1);
                 let result = by_arg + self.reg.x as u16;
                 let cross_memory_page = (by_arg & 0xff00) != (result & 0xff);
                 (result, cross_memory_page)
             }
             AddressMode::AbsoluteY => {
-                let by_arg = bus.get_two_bytes(self.pc + 1);
+                let by_arg = bus.fetch_two_bytes(self.pc + 1);
                 let result = by_arg + self.reg.y as u16;
                 let cross_memory_page = (by_arg & 0xff00) != (result & 0xff);
                 (result, cross_memory_page)
             }
             AddressMode::Indirect => {
-                let lo = bus.get_byte(self.pc + 1);
-                let hi = bus.get_byte(self.pc + 2);
+                let lo = bus.fetch_byte(self.pc + 1);
+                let hi = bus.fetch_byte(self.pc + 2);
                 if lo == 0xff {
                     // CPU bug: we crossed page bound, however we read
                     // high byte not from next page, but from current.
@@ -110,11 +295,11 @@ impl Cpu {
                 }
             }
             AddressMode::IndirectX => {
-                let arg = bus.get_byte(self.pc + 1) as u16;
+                let arg = bus.fetch_byte(self.pc + 1) as u16;
                 (bus.get_two_bytes(arg + self.reg.x as u16), false)
             }
             AddressMode::IndirectY => {
-                let by_arg = bus.get_byte(self.pc + 1) as u16;
+                let by_arg = bus.fetch_byte(self.pc + 1) as u16;
                 let result = bus.get_two_bytes(by_arg) + self.reg.y as u16;
                 let cross_memory_page = (by_arg & 0xff00) != (result & 0xff);
                 (result, cross_memory_page)
@@ -125,7 +310,7 @@ impl Cpu {
             }
             AddressMode::Relative => {
                 // this will get propper signed number
-                let relative = bus.get_byte(self.pc + 1) as i8 as i16;
+                let relative = bus.fetch_byte(self.pc + 1) as i8 as i16;
                 let pc_with_offset = (self.pc + op.instruction_bytes as u16) as i16;
 
                 // just assume that input instructions are correct and we won't overflow here...
@@ -135,11 +320,16 @@ impl Cpu {
             }
             AddressMode::Accumulator => (0, false),
         };
-        println!("look at address: {:#04X} ", address);
 
         self.pc += op.instruction_bytes as u16;
         let mut additional_cycles = 0;
 
+        // Immediate operand is part of the instruction, so it's fetched like opcode
+        let load = |bus: &Bus| match op.mode {
+            AddressMode::Immediate => bus.fetch_byte(address),
+            _ => bus.get_byte(address),
+        };
+
         let mut branch_on = |cond: bool| {
             if cond {
                 additional_cycles += 1;
@@ -152,15 +342,15 @@ impl Cpu {
         // TODO: do I need to delay values change until cycles complete?
         match op.code {
             Code::LDA => {
-                self.reg.a = bus.get_byte(address);
+                self.reg.a = load(bus);
                 self.update_n_z_flags(self.reg.a);
             }
             Code::LDX => {
-                self.reg.x = bus.get_byte(address);
+                self.reg.x = load(bus);
                 self.update_n_z_flags(self.reg.x);
             }
             Code::LDY => {
-                self.reg.y = bus.get_byte(address);
+                self.reg.y = load(bus);
                 self.update_n_z_flags(self.reg.y);
             }
             Code::STA => {
@@ -189,7 +379,7 @@ impl Cpu {
                 self.update_n_z_flags(self.reg.a);
             }
             Code::INC => {
-                let new_val = bus.get_byte(address) + 1;
+                let new_val = load(bus) + 1;
                 bus.set_byte(new_val, address);
                 self.update_n_z_flags(new_val);
             }
@@ -204,7 +394,7 @@ impl Cpu {
                 self.update_n_z_flags(new_val);
             }
             Code::DEC => {
-                let new_val = bus.get_byte(address) - 1;
+                let new_val = load(bus) - 1;
                 bus.set_byte(new_val, address);
                 self.update_n_z_flags(new_val);
             }
@@ -219,19 +409,19 @@ impl Cpu {
                 self.update_n_z_flags(new_val);
             }
             Code::AND => {
-                self.reg.a &= bus.get_byte(address);
+                self.reg.a &= load(bus);
                 self.update_n_z_flags(self.reg.a);
             }
             Code::EOR => {
-                self.reg.a ^= bus.get_byte(address);
+                self.reg.a ^= load(bus);
                 self.update_n_z_flags(self.reg.a);
             }
             Code::ORA => {
-                self.reg.a |= bus.get_byte(address);
+                self.reg.a |= load(bus);
                 self.update_n_z_flags(self.reg.a);
             }
             Code::BIT => {
-                let mem = bus.get_byte(address);
+                let mem = load(bus);
                 // TODO: is this really accurate?
                 self.flags.set_zero(self.reg.a & mem == 0);
                 self.flags.set_negative(mem & 0b10000000 != 0);
@@ -285,7 +475,7 @@ impl Cpu {
             Code::ASL => {
                 let mem = match op.mode {
                     AddressMode::Accumulator => self.reg.a,
-                    _ => bus.get_byte(address),
+                    _ => load(bus),
                 };
 
                 self.flags.set_carry(mem & 0x80 == 0x80);
@@ -300,7 +490,7 @@ impl Cpu {
             Code::LSR => {
                 let mem = match op.mode {
                     AddressMode::Accumulator => self.reg.a,
-                    _ => bus.get_byte(address),
+                    _ => load(bus),
                 };
 
                 self.flags.set_carry(mem & 0x01 == 0x01);
@@ -315,7 +505,7 @@ impl Cpu {
             Code::ROL => {
                 let mem = match op.mode {
                     AddressMode::Accumulator => self.reg.a,
-                    _ => bus.get_byte(address),
+                    _ => load(bus),
                 };
 
                 let result = if self.flags.carry() {
@@ -334,7 +524,7 @@ impl Cpu {
             Code::ROR => {
                 let mem = match op.mode {
                     AddressMode::Accumulator => self.reg.a,
-                    _ => bus.get_byte(address),
+                    _ => load(bus),
                 };
 
                 let result = if self.flags.carry() {
@@ -371,10 +561,13 @@ impl Cpu {
                 self.flags.set_register(register);
             }
             Code::JSR => {
+                self.drop_dead_frames();
                 self.write_u16_to_stack(bus, self.pc - 1);
+                self.push_frame(FrameKind::Jsr, self.pc - 3, address);
                 self.pc = address;
             }
             Code::RTS => {
+                self.pop_frame();
                 self.pc = self.read_u16_from_stack(bus);
                 self.pc += 1;
             }
@@ -382,44 +575,45 @@ impl Cpu {
                 self.pc = address;
             }
             Code::CMP => {
-                let mem = bus.get_byte(address);
+                let mem = load(bus);
                 self.flags.set_carry(self.reg.a >= mem);
                 self.flags.set_zero(self.reg.a == mem);
                 // TODO: can I do it without sub?
                 self.flags.set_negative((self.reg.a - mem) & 0x80 != 0);
             }
             Code::CPX => {
-                let mem = bus.get_byte(address);
+                let mem = load(bus);
                 self.flags.set_carry(self.reg.x >= mem);
                 self.flags.set_zero(self.reg.x == mem);
                 // TODO: can I do it without sub?
                 self.flags.set_negative((self.reg.x - mem) & 0x80 != 0);
             }
             Code::CPY => {
-                let mem = bus.get_byte(address);
+                let mem = load(bus);
                 self.flags.set_carry(self.reg.y >= mem);
                 self.flags.set_zero(self.reg.y == mem);
                 // TODO: can I do it without sub?
                 self.flags.set_negative((self.reg.y - mem) & 0x80 != 0);
             }
             Code::ADC => {
-                let mem = bus.get_byte(address);
+                let mem = load(bus);
                 self.adc_impl(mem);
             }
             Code::SBC => {
-                let mem = bus.get_byte(address);
+                let mem = load(bus);
                 self.adc_impl(!mem);
             }
             Code::BRK => {
-                if !self.flags.interrupt_disabled() {
-                    self.nmi(bus);
-                    self.flags.set_break_cmd(true);
-                }
+                // Byte after BRK is padding, RTI returns past it
+                self.pc = self.pc.wrapping_add(1);
+                self.interrupt(bus, INTERRUPT_PC, true);
+                self.flags.set_break_cmd(true);
             }
             Code::RTI => {
+                self.pop_frame();
                 let register = self.read_u8_from_stack(bus);
                 self.flags.set_register(register);
-                self.pc = self.read_u16_from_stack(bus) + 1;
+                self.pc = self.read_u16_from_stack(bus);
             }
             Code::NOP => {}
         }
@@ -427,6 +621,7 @@ impl Cpu {
         if cross_page && op.page_boundary_cycle {
             self.cycle_left += 1;
         }
+        self.write_cycles = write_cycles_mask(&op);
     }
 
     fn update_n_z_flags(&mut self, new_val: u8) {
@@ -530,6 +725,224 @@ mod tests {
         assert_eq!(cpu.cycle_left, 4);
     }
 
+    #[test]
+    fn rdy_low_halts_on_opcode_fetch() {
+        let (mut cpu, mut bus, _ram) = fixture("LDA #42");
+        cpu.set_rdy(false);
+
+        cpu.tick(&mut bus);
+        assert_eq!(cpu.reg.a, 0);
+        assert_eq!(cpu.pc, 0);
+
+        cpu.set_rdy(true);
+        cpu.tick(&mut bus);
+        assert_eq!(cpu.reg.a, 42);
+    }
+
+    #[test]
+    fn rdy_low_does_not_stop_write_cycles() {
+        let (mut cpu, mut bus, _ram) = fixture(
+            r#"
+            STA $4000
+            NOP
+        "#,
+        );
+        cpu.tick(&mut bus);
+        cpu.set_rdy(false);
+
+        // STA abs: two operand reads and then the write
+        assert!(cpu.is_halted());
+        cpu.set_rdy(true);
+        cpu.tick(&mut bus);
+        cpu.tick(&mut bus);
+        cpu.set_rdy(false);
+        assert!(!cpu.is_halted());
+        cpu.tick(&mut bus);
+        assert_eq!(cpu.cycle_left, 0);
+        assert!(cpu.is_halted());
+    }
+
+    #[test]
+    fn rdy_low_does_not_stop_rmw_writes() {
+        let (mut cpu, mut bus, _ram) = fixture("INC $4000");
+        cpu.tick(&mut bus);
+        cpu.tick(&mut bus);
+        cpu.tick(&mut bus);
+        cpu.tick(&mut bus);
+
+        cpu.set_rdy(false);
+        assert_eq!(cpu.cycle_left, 2);
+        cpu.tick(&mut bus);
+        cpu.tick(&mut bus);
+        assert_eq!(cpu.cycle_left, 0);
+    }
+
+    fn run_instructions(cpu: &mut Cpu, bus: &mut Bus, count: usize) {
+        for _ in 0..count {
+            cpu.tick(bus);
+            while cpu.cycle_left > 0 {
+                cpu.tick(bus);
+            }
+        }
+    }
+
+    #[test]
+    fn irq_jumps_to_vector() {
+        let (mut cpu, mut bus, _ram) = fixture("LDA #$80\nNOP");
+        bus.set_byte(0x00, 0xfffe);
+        bus.set_byte(0x30, 0xffff);
+        run_instructions(&mut cpu, &mut bus, 1);
+        cpu.set_irq(true);
+
+        cpu.tick(&mut bus);
+        assert_eq!(cpu.pc, 0x3000);
+        assert_eq!(cpu.cycle_left, 6);
+        assert!(cpu.flags.interrupt_disabled());
+        // return address and flags without break bit
+        assert_eq!(bus.get_byte(0x1ff), 0x00);
+        assert_eq!(bus.get_byte(0x1fe), 0x02);
+        assert_eq!(bus.get_byte(0x1fd), 0x80);
+    }
+
+    #[test]
+    fn irq_masked_by_interrupt_flag() {
+        let (mut cpu, mut bus, _ram) = fixture("SEI\nLDA #42");
+        run_instructions(&mut cpu, &mut bus, 1);
+        cpu.set_irq(true);
+
+        run_instructions(&mut cpu, &mut bus, 1);
+        assert_eq!(cpu.reg.a, 42);
+    }
+
+    #[test]
+    fn irq_returns_with_rti() {
+        let (mut cpu, mut bus, _ram) = fixture(
+            r#"
+            CLI
+            LDA #$01
+            LDX #$02
+            INY
+            RTI
+        "#,
+        );
+        // handler is INY, RTI
+        bus.set_byte(0x05, 0xfffe);
+        bus.set_byte(0x00, 0xffff);
+        run_instructions(&mut cpu, &mut bus, 2);
+
+        cpu.set_irq(true);
+        run_instructions(&mut cpu, &mut bus, 2);
+        cpu.set_irq(false);
+        run_instructions(&mut cpu, &mut bus, 2);
+
+        assert_eq!(cpu.reg.y, 1);
+        assert_eq!(cpu.reg.x, 2);
+        assert!(!cpu.flags.interrupt_disabled());
+    }
+
+    fn write_bytes(bus: &mut Bus, bytes: &[u8], at: u16) {
+        for (i, byte) in bytes.iter().enumerate() {
+            bus.set_byte(*byte, at + i as u16);
+        }
+    }
+
+    fn frames(cpu: &Cpu) -> Vec<(FrameKind, u16, u16)> {
+        cpu.call_stack()
+            .iter()
+            .map(|frame| (frame.kind, frame.from, frame.to))
+            .collect()
+    }
+
+    #[test]
+    fn call_stack_follows_jsr_and_rts() {
+        let (mut cpu, mut bus, _ram) = fixture("NOP");
+        // $00: JSR $10, $10: JSR $20, RTS, $20: RTS
+        write_bytes(&mut bus, &[0x20, 0x10, 0x00], 0x00);
+        write_bytes(&mut bus, &[0x20, 0x20, 0x00, 0x60], 0x10);
+        write_bytes(&mut bus, &[0x60], 0x20);
+
+        run_instructions(&mut cpu, &mut bus, 2);
+        assert_eq!(
+            frames(&cpu),
+            vec![(FrameKind::Jsr, 0x00, 0x10), (FrameKind::Jsr, 0x10, 0x20)]
+        );
+        assert_eq!(cpu.call_stack()[1].sp, 0xfb);
+        run_instructions(&mut cpu, &mut bus, 1);
+        assert_eq!(frames(&cpu), vec![(FrameKind::Jsr, 0x00, 0x10)]);
+        run_instructions(&mut cpu, &mut bus, 1);
+        assert!(cpu.call_stack().is_empty());
+        assert_eq!(cpu.pc, 0x03);
+    }
+
+    #[test]
+    fn call_stack_interrupts() {
+        let (mut cpu, mut bus, _ram) = fixture("CLI\nNOP\nNOP");
+        // IRQ handler at $3000: RTI
+        write_bytes(&mut bus, &[0x00, 0x30], 0xfffe);
+        write_bytes(&mut bus, &[0x40], 0x3000);
+        run_instructions(&mut cpu, &mut bus, 1);
+
+        cpu.set_irq(true);
+        run_instructions(&mut cpu, &mut bus, 1);
+        cpu.set_irq(false);
+        assert_eq!(frames(&cpu), vec![(FrameKind::Irq, 0x01, 0x3000)]);
+        run_instructions(&mut cpu, &mut bus, 1);
+        assert!(cpu.call_stack().is_empty());
+    }
+
+    #[test]
+    fn call_stack_resync_after_stack_manipulation() {
+        let (mut cpu, mut bus, _ram) = fixture("NOP");
+        // $00: JSR $10, $10: PLA, PLA, JSR $20, $20: RTS
+        write_bytes(&mut bus, &[0x20, 0x10, 0x00], 0x00);
+        write_bytes(&mut bus, &[0x68, 0x68, 0x20, 0x20, 0x00], 0x10);
+        write_bytes(&mut bus, &[0x60], 0x20);
+
+        // Return address of the first call is dropped by PLAs
+        run_instructions(&mut cpu, &mut bus, 4);
+        assert_eq!(frames(&cpu), vec![(FrameKind::Jsr, 0x12, 0x20)]);
+        run_instructions(&mut cpu, &mut bus, 1);
+        assert!(cpu.call_stack().is_empty());
+    }
+
+    #[test]
+    fn call_stack_rts_as_jump() {
+        let (mut cpu, mut bus, _ram) = fixture("NOP");
+        // $00: JSR $10, $10: LDA #$00, PHA, LDA #$1f, PHA, RTS (to $20), $20: RTS
+        write_bytes(&mut bus, &[0x20, 0x10, 0x00], 0x00);
+        write_bytes(&mut bus, &[0xa9, 0x00, 0x48, 0xa9, 0x1f, 0x48, 0x60], 0x10);
+        write_bytes(&mut bus, &[0x60], 0x20);
+
+        run_instructions(&mut cpu, &mut bus, 6);
+        assert_eq!(cpu.pc, 0x20);
+        assert_eq!(frames(&cpu), vec![(FrameKind::Jsr, 0x00, 0x10)]);
+        run_instructions(&mut cpu, &mut bus, 1);
+        assert_eq!(cpu.pc, 0x03);
+        assert!(cpu.call_stack().is_empty());
+    }
+
+    #[test]
+    fn nmi_is_edge_triggered() {
+        let (mut cpu, mut bus, _ram) = fixture("SEI\nNOP\nNOP\nNOP");
+        bus.set_byte(0x03, 0xfffa);
+        bus.set_byte(0x00, 0xfffb);
+        run_instructions(&mut cpu, &mut bus, 1);
+
+        // Not masked by interrupt flag
+        cpu.set_nmi(true);
+        run_instructions(&mut cpu, &mut bus, 1);
+        assert_eq!(cpu.pc, 0x0003);
+
+        // Line is still active, but there is no new edge
+        run_instructions(&mut cpu, &mut bus, 1);
+        assert_eq!(cpu.pc, 0x0004);
+
+        cpu.set_nmi(false);
+        cpu.set_nmi(true);
+        run_instructions(&mut cpu, &mut bus, 1);
+        assert_eq!(cpu.pc, 0x0003);
+    }
+
     #[test]
     fn lda_im() {
         let (mut cpu, mut bus, _ram) = fixture("LDA #42");
@@ -1398,4 +1811,35 @@ mod tests {
         assert_eq!(cpu.reg.x, 0x05);
         assert_eq!(cpu.reg.y, 0x05);
     }
+
+    #[test]
+    fn rti_skips_byte_after_brk() {
+        let (mut cpu, mut bus, _ram) = fixture(
+            r#"
+            LDA #$05
+            BRK
+            INY
+            LDX #$05
+            NOP
+            RTI
+        "#,
+        );
+
+        // Given
+        bus.set_byte(0x07, 0xfffe);
+        bus.set_byte(0x00, 0xffff);
+
+        // When
+        for _ in 1..100 {
+            if cpu.reg.x == 0x05 {
+                break;
+            }
+            cpu.tick(&mut bus);
+        }
+
+        // Then
+        assert_eq!(cpu.reg.x, 0x05);
+        assert_eq!(cpu.reg.y, 0x00);
+        assert_eq!(cpu.pc(), 0x0006);
+    }
 }
@@ -0,0 +1,1214 @@
+// Debug Adapter Protocol server on a local TCP port, so VS Code, nvim-dap and other DAP
+// clients can debug programs running in the emulator. Breakpoints by source line need
+// ca65 debug info loaded as symbols (--symbols or symbols of launch arguments), without
+// it breakpoints can be set on functions (labels or addresses) and instructions.
+// There's one thread, its frames are the shadow call stack of the cpu. Evaluate in REPL
+// runs machine language monitor commands.
+//
+// Messages are JSON with a header:  Content-Length: <bytes>\r\n\r\n<message>
+
+use crate::condition::Condition;
+use crate::cpu::{Cpu, FrameKind};
+use crate::debugger::{BreakKind, Breakpoint, Debugger, StopReason};
+use crate::disassembler::disassemble_one;
+use crate::ml_monitor::{Action, MlMonitor};
+use crate::options::parse_address;
+use crate::remote::{self, Session as _};
+use crate::symbols::SymbolTable;
+
+use serde_json::{json, Value};
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+const THREAD_ID: u64 = 1;
+// Memory doesn't hold more instructions, disassemble offset and count are cut to it
+const MAX_INSTRUCTIONS: i64 = 0x10000;
+const HEADER_END: &[u8] = b"\r\n\r\n";
+
+// Variables references of scopes, they are the same for every frame
+const REGISTERS_REFERENCE: u64 = 1;
+const FLAGS_REFERENCE: u64 = 2;
+const STACK_REFERENCE: u64 = 3;
+const LABELS_REFERENCE: u64 = 4;
+
+const REGISTERS: [&str; 6] = ["A", "X", "Y", "SP", "PC", "P"];
+// Name and bit in P
+const FLAGS: [(&str, u8); 7] = [
+    ("N", 7),
+    ("V", 6),
+    ("B", 4),
+    ("D", 3),
+    ("I", 2),
+    ("Z", 1),
+    ("C", 0),
+];
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut text = String::new();
+    for chunk in bytes.chunks(3) {
+        let triple = chunk.iter().enumerate().fold(0u32, |triple, (i, &b)| {
+            triple | ((b as u32) << (16 - 8 * i))
+        });
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => text.push(BASE64[((triple >> (18 - 6 * i)) & 0x3f) as usize] as char),
+                false => text.push('='),
+            }
+        }
+    }
+    text
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim_end_matches('=');
+    let mut bytes = vec![];
+    for chunk in text.as_bytes().chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+        let mut triple = 0u32;
+        for (i, &c) in chunk.iter().enumerate() {
+            let value = BASE64.iter().position(|&b| b == c)? as u32;
+            triple |= value << (18 - 6 * i);
+        }
+        for i in 0..chunk.len() - 1 {
+            bytes.push((triple >> (16 - 8 * i)) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+// Takes the next complete message from buffer
+fn next_message(buffer: &mut Vec<u8>) -> Option<Result<Value, String>> {
+    let end = buffer
+        .windows(HEADER_END.len())
+        .position(|window| window == HEADER_END)?;
+    let start = end + HEADER_END.len();
+    let header = String::from_utf8_lossy(&buffer[..end]).into_owned();
+    let length = header.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        match name.trim().eq_ignore_ascii_case("Content-Length") {
+            true => value.trim().parse::<usize>().ok(),
+            false => None,
+        }
+    });
+    let length = match length {
+        Some(length) => length,
+        None => {
+            buffer.drain(..start);
+            return Some(Err(format!("No Content-Length in header: {}", header)));
+        }
+    };
+    if buffer.len() < start + length {
+        return None;
+    }
+    let message: Vec<u8> = buffer.drain(..start + length).skip(start).collect();
+    Some(serde_json::from_slice(&message).map_err(|err| err.to_string()))
+}
+
+fn frame(message: &Value) -> Vec<u8> {
+    let body = message.to_string();
+    format!("Content-Length: {}\r\n\r\n{}", body.len(), body).into_bytes()
+}
+
+// Memory and instruction references are addresses
+fn reference(address: u16) -> String {
+    format!("0x{:04x}", address)
+}
+
+fn parse_reference(args: &Value, key: &str) -> Result<u16, String> {
+    let reference = args[key]
+        .as_str()
+        .ok_or_else(|| format!("Missing {}", key))?;
+    let offset = args["offset"].as_i64().unwrap_or(0);
+    Ok(parse_address(reference)?.wrapping_add(offset as u16))
+}
+
+// Label, with or without . prefix, or hex address
+fn resolve_address(symbols: &SymbolTable, text: &str) -> Result<u16, String> {
+    let text = text.trim();
+    match symbols.address(text.trim_start_matches('.')) {
+        Some(address) => Ok(address),
+        None => parse_address(text),
+    }
+}
+
+fn source(path: &Path) -> Value {
+    let name = path.file_name().unwrap_or(path.as_os_str());
+    json!({
+        "name": name.to_string_lossy(),
+        "path": path.to_string_lossy(),
+    })
+}
+
+fn register(cpu: &Cpu, name: &str) -> Option<u16> {
+    let value = match name.to_uppercase().as_str() {
+        "A" => cpu.reg.a as u16,
+        "X" => cpu.reg.x as u16,
+        "Y" => cpu.reg.y as u16,
+        "SP" => cpu.sp() as u16,
+        "PC" => cpu.pc(),
+        "P" => cpu.flags.get_register() as u16,
+        _ => return None,
+    };
+    Some(value)
+}
+
+fn set_register(cpu: &mut Cpu, name: &str, value: u16) -> Result<(), String> {
+    let byte = || u8::try_from(value).map_err(|_| format!("Bad value for {}", name));
+    match name.to_uppercase().as_str() {
+        "A" => cpu.reg.a = byte()?,
+        "X" => cpu.reg.x = byte()?,
+        "Y" => cpu.reg.y = byte()?,
+        "SP" => cpu.set_sp(byte()?),
+        "PC" => cpu.set_pc(value),
+        "P" => cpu.flags.set_register(byte()?),
+        _ => return Err(format!("Unknown register: {}", name)),
+    }
+    Ok(())
+}
+
+fn format_register(name: &str, value: u16) -> String {
+    match name {
+        "PC" => format!("${:04x}", value),
+        _ => format!("${:02x}", value),
+    }
+}
+
+fn variable(name: &str, value: String, address: Option<u16>) -> Value {
+    let mut variable = json!({
+        "name": name,
+        "value": value,
+        "variablesReference": 0,
+    });
+    if let Some(address) = address {
+        variable["memoryReference"] = json!(reference(address));
+    }
+    variable
+}
+
+// Hit count like "10" or ">= 10" in condition syntax. Editors give decimal counts,
+// numbers in conditions are hex. Without operator breakpoint stops from that hit on.
+fn hit_condition(text: &str) -> Result<String, String> {
+    let text = text.trim();
+    let count = text.trim_start_matches(|c: char| "=!<>".contains(c));
+    let operator = match &text[..text.len() - count.len()] {
+        "" => ">=",
+        "=" => "==",
+        operator => operator,
+    };
+    let count: u32 = count
+        .trim()
+        .parse()
+        .map_err(|_| format!("Bad hit count: {}", text))?;
+    Ok(format!("hits {} ${:x}", operator, count))
+}
+
+// Execution breakpoint with condition and hit condition of DAP breakpoint
+fn breakpoint(address: u16, args: &Value) -> Result<Breakpoint, String> {
+    let mut breakpoint = Breakpoint::new(BreakKind::Exec, address, address);
+    let mut condition = args["condition"].as_str().unwrap_or("").trim().to_string();
+    if let Some(hits) = args["hitCondition"].as_str() {
+        let hits = hit_condition(hits)?;
+        condition = match condition.is_empty() {
+            true => hits,
+            // && binds tighter than ||, so hit count goes to every group
+            false => condition
+                .split("||")
+                .map(|group| format!("{} && {}", group.trim(), hits))
+                .collect::<Vec<_>>()
+                .join(" || "),
+        };
+    }
+    if !condition.is_empty() {
+        breakpoint.condition = Some(Condition::parse(&condition)?);
+    }
+    Ok(breakpoint)
+}
+
+// Breakpoints of request replace old ones, results are in the order of request.
+// locate gives address and source line of requested breakpoint.
+fn replace_breakpoints(
+    debugger: &mut Debugger,
+    old: Vec<u32>,
+    requested: &Value,
+    locate: impl Fn(&Debugger, &Value) -> Result<(u16, Option<u32>), String>,
+) -> (Vec<u32>, Value) {
+    for id in old {
+        debugger.remove_breakpoint(id);
+    }
+    let mut ids = vec![];
+    let mut results = vec![];
+    for args in requested.as_array().into_iter().flatten() {
+        let located = locate(debugger, args)
+            .and_then(|(address, line)| Ok((breakpoint(address, args)?, address, line)));
+        let result = match located {
+            Ok((breakpoint, address, line)) => {
+                let id = debugger.add(breakpoint);
+                ids.push(id);
+                let mut result = json!({
+                    "id": id,
+                    "verified": true,
+                    "instructionReference": reference(address),
+                });
+                if let Some(line) = line {
+                    result["line"] = json!(line);
+                }
+                result
+            }
+            Err(message) => json!({ "verified": false, "message": message }),
+        };
+        results.push(result);
+    }
+    (ids, json!({ "breakpoints": results }))
+}
+
+// Where disassembly starts to have offset instructions between it and address.
+// Instructions before address are found by decoding from further back until decoding
+// lands on address, as instructions have different lengths.
+fn instruction_start(debugger: &Debugger, address: u16, offset: i64) -> u16 {
+    let bus = &debugger.c64.bus;
+    let length = |address: u16| disassemble_one(bus, address, None).bytes.len() as u16;
+    if offset >= 0 {
+        return (0..offset).fold(address, |address, _| address.wrapping_add(length(address)));
+    }
+    let count = offset.unsigned_abs() as usize;
+    let earliest = (address as usize).saturating_sub(count.saturating_mul(3));
+    // Decoding falls in step with the code after a few instructions, so if none of the
+    // first starts lands on address, the later ones won't either
+    let latest = (earliest + 3).min(address as usize);
+    for start in earliest..latest {
+        let mut starts = vec![];
+        let mut current = start;
+        while current < address as usize {
+            starts.push(current as u16);
+            current += length(current as u16) as usize;
+        }
+        if current == address as usize && starts.len() >= count {
+            return starts[starts.len() - count];
+        }
+    }
+    // Nothing decodes into address, data is before it
+    address.wrapping_sub(count as u16)
+}
+
+// Launch progress and breakpoints of a DAP client
+pub struct Session {
+    // Sequence number of the next message
+    seq: u64,
+    output: Vec<u8>,
+    running: bool,
+    // Emulation starts when launch (or attach) and configuration are both done
+    launched: bool,
+    configured: bool,
+    stop_on_entry: bool,
+    // Debugger breakpoints set by the client. Each request replaces all breakpoints
+    // of its kind, or of its source file.
+    source_breakpoints: HashMap<PathBuf, Vec<u32>>,
+    function_breakpoints: Vec<u32>,
+    instruction_breakpoints: Vec<u32>,
+    // Events of the request being handled, they go after its response
+    events: Vec<Value>,
+    monitor: MlMonitor,
+    quit: bool,
+}
+
+impl Session {
+    fn send(&mut self, mut message: Value) {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        self.output.extend(frame(&message));
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        let event = json!({ "type": "event", "event": event, "body": body });
+        self.events.push(event);
+    }
+
+    fn send_events(&mut self) {
+        for event in std::mem::take(&mut self.events) {
+            self.send(event);
+        }
+    }
+
+    fn handle(&mut self, debugger: &mut Debugger, request: &Value) {
+        if request["type"] != "request" {
+            return;
+        }
+        let command = request["command"].as_str().unwrap_or("");
+        let pc = debugger.c64.cpu.pc();
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": command,
+        });
+        match self.execute(debugger, command, &request["arguments"]) {
+            Ok(body) => {
+                response["success"] = json!(true);
+                response["body"] = body;
+            }
+            Err(message) => {
+                response["success"] = json!(false);
+                response["message"] = json!(message);
+            }
+        }
+        self.send(response);
+
+        // Events caused by the request go after response
+        match command {
+            "initialize" => self.event("initialized", json!({})),
+            "launch" | "attach" => self.launched = true,
+            "configurationDone" => self.configured = true,
+            "terminate" => self.event("terminated", json!({})),
+            _ => {}
+        }
+        if matches!(command, "launch" | "attach" | "configurationDone") {
+            self.start(debugger);
+        }
+        // Monitor command can run or change the cpu
+        if command == "evaluate" && !self.running && debugger.c64.cpu.pc() != pc {
+            self.stopped_at(debugger, "step");
+        }
+        self.send_events();
+    }
+
+    fn start(&mut self, debugger: &Debugger) {
+        if !self.launched || !self.configured {
+            return;
+        }
+        match self.stop_on_entry {
+            true => self.stopped_at(debugger, "entry"),
+            false => self.running = true,
+        }
+    }
+
+    fn execute(
+        &mut self,
+        debugger: &mut Debugger,
+        command: &str,
+        args: &Value,
+    ) -> Result<Value, String> {
+        let body = match command {
+            "initialize" => json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsFunctionBreakpoints": true,
+                "supportsConditionalBreakpoints": true,
+                "supportsHitConditionalBreakpoints": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsSetVariable": true,
+                "supportsReadMemoryRequest": true,
+                "supportsWriteMemoryRequest": true,
+                "supportsDisassembleRequest": true,
+                "supportsTerminateRequest": true,
+            }),
+            "launch" | "attach" => {
+                for path in args["symbols"].as_array().into_iter().flatten() {
+                    let path = path.as_str().ok_or("Symbols should be paths")?;
+                    debugger
+                        .symbols
+                        .load(Path::new(path))
+                        .map_err(|err| format!("Can't load symbols {}: {}", path, err))?;
+                }
+                self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+                json!({})
+            }
+            "configurationDone" | "setExceptionBreakpoints" => json!({}),
+            "setBreakpoints" => {
+                let path = args["source"]["path"]
+                    .as_str()
+                    .ok_or("Breakpoints need source path")?;
+                let path = PathBuf::from(path);
+                let old = self.source_breakpoints.remove(&path).unwrap_or_default();
+                let (ids, body) =
+                    replace_breakpoints(debugger, old, &args["breakpoints"], |debugger, args| {
+                        let line = args["line"].as_u64().ok_or("Missing line")? as u32;
+                        let (line, address) = debugger
+                            .symbols
+                            .line_address(&path, line)
+                            .ok_or("No code at this line")?;
+                        Ok((address, Some(line)))
+                    });
+                self.source_breakpoints.insert(path, ids);
+                body
+            }
+            "setFunctionBreakpoints" => {
+                let old = std::mem::take(&mut self.function_breakpoints);
+                let (ids, body) =
+                    replace_breakpoints(debugger, old, &args["breakpoints"], |debugger, args| {
+                        let name = args["name"].as_str().ok_or("Missing name")?;
+                        Ok((resolve_address(&debugger.symbols, name)?, None))
+                    });
+                self.function_breakpoints = ids;
+                body
+            }
+            "setInstructionBreakpoints" => {
+                let old = std::mem::take(&mut self.instruction_breakpoints);
+                let (ids, body) =
+                    replace_breakpoints(debugger, old, &args["breakpoints"], |_, args| {
+                        Ok((parse_reference(args, "instructionReference")?, None))
+                    });
+                self.instruction_breakpoints = ids;
+                body
+            }
+            "threads" => json!({ "threads": [{ "id": THREAD_ID, "name": "6502" }] }),
+            "stackTrace" => {
+                let frames = self.stack_frames(debugger);
+                let total = frames.len();
+                let start = args["startFrame"].as_u64().unwrap_or(0) as usize;
+                let levels = match args["levels"].as_u64().unwrap_or(0) as usize {
+                    0 => total,
+                    levels => levels,
+                };
+                let frames: Vec<Value> = frames.into_iter().skip(start).take(levels).collect();
+                json!({ "stackFrames": frames, "totalFrames": total })
+            }
+            "scopes" => json!({
+                "scopes": [
+                    { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+                    { "name": "Flags", "variablesReference": FLAGS_REFERENCE, "expensive": false },
+                    { "name": "Stack", "variablesReference": STACK_REFERENCE, "expensive": false },
+                    { "name": "Labels", "variablesReference": LABELS_REFERENCE, "expensive": true },
+                ]
+            }),
+            "variables" => {
+                let reference = args["variablesReference"].as_u64().unwrap_or(0);
+                json!({ "variables": self.variables(debugger, reference)? })
+            }
+            "setVariable" => {
+                let reference = args["variablesReference"].as_u64().unwrap_or(0);
+                let name = args["name"].as_str().ok_or("Missing name")?;
+                let value = args["value"].as_str().ok_or("Missing value")?;
+                let value = self.set_variable(debugger, reference, name, value)?;
+                json!({ "value": value })
+            }
+            "evaluate" => {
+                let expression = args["expression"].as_str().ok_or("Missing expression")?;
+                match args["context"].as_str() {
+                    Some("repl") => self.monitor_command(debugger, expression)?,
+                    _ => self.evaluate(debugger, expression)?,
+                }
+            }
+            "readMemory" => {
+                let address = parse_reference(args, "memoryReference")?;
+                let count = args["count"].as_u64().unwrap_or(0) as usize;
+                let readable = count.min(0x10000 - address as usize);
+                let bytes: Vec<u8> = (0..readable)
+                    .map(|i| debugger.c64.bus.peek_byte(address + i as u16))
+                    .collect();
+                json!({
+                    "address": reference(address),
+                    "data": base64_encode(&bytes),
+                    "unreadableBytes": count - readable,
+                })
+            }
+            "writeMemory" => {
+                let address = parse_reference(args, "memoryReference")?;
+                let data = args["data"].as_str().ok_or("Missing data")?;
+                let bytes = base64_decode(data).ok_or("Bad base64 data")?;
+                for (i, &byte) in bytes.iter().enumerate() {
+                    debugger
+                        .c64
+                        .bus
+                        .set_byte(byte, address.wrapping_add(i as u16));
+                }
+                json!({ "bytesWritten": bytes.len() })
+            }
+            "disassemble" => {
+                let address = parse_reference(args, "memoryReference")?;
+                let offset = args["instructionOffset"].as_i64().unwrap_or(0);
+                let offset = offset.clamp(-MAX_INSTRUCTIONS, MAX_INSTRUCTIONS);
+                let count = args["instructionCount"].as_u64().unwrap_or(0);
+                let count = count.min(MAX_INSTRUCTIONS as u64);
+                let mut address = instruction_start(debugger, address, offset);
+                let mut instructions = vec![];
+                for _ in 0..count {
+                    let symbols = &debugger.symbols;
+                    let line = disassemble_one(&debugger.c64.bus, address, Some(symbols));
+                    let bytes: Vec<String> =
+                        line.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+                    let mut instruction = json!({
+                        "address": reference(address),
+                        "instructionBytes": bytes.join(" "),
+                        "instruction": format!("{} {}", line.mnemonic, line.operand).trim_end(),
+                    });
+                    if let Some(name) = symbols.name(address) {
+                        instruction["symbol"] = json!(name);
+                    }
+                    if let Some((path, source_line)) = symbols.source_line(address) {
+                        instruction["location"] = source(path);
+                        instruction["line"] = json!(source_line);
+                    }
+                    instructions.push(instruction);
+                    address = address.wrapping_add(line.bytes.len() as u16);
+                }
+                json!({ "instructions": instructions })
+            }
+            "continue" => {
+                self.running = true;
+                json!({ "allThreadsContinued": true })
+            }
+            "next" | "stepIn" | "stepOut" => {
+                let reason = match command {
+                    "next" => debugger.next(debugger.run_limit()),
+                    "stepIn" => debugger.step(),
+                    _ => debugger.finish(debugger.run_limit()),
+                };
+                self.stop_reason(debugger, reason);
+                json!({})
+            }
+            "pause" => {
+                if self.running {
+                    self.running = false;
+                    self.stopped_at(debugger, "pause");
+                }
+                json!({})
+            }
+            "disconnect" => {
+                self.detach(debugger);
+                self.quit = args["terminateDebuggee"].as_bool().unwrap_or(false);
+                json!({})
+            }
+            "terminate" => {
+                self.quit = true;
+                json!({})
+            }
+            _ => return Err(format!("Unsupported command: {}", command)),
+        };
+        Ok(body)
+    }
+
+    // Frames in Debugger::backtrace order, ids are depths
+    fn stack_frames(&self, debugger: &Debugger) -> Vec<Value> {
+        let symbols = &debugger.symbols;
+        let name = |address: u16| match symbols.name(address) {
+            Some(symbol) => symbol.to_string(),
+            None => format!("${:04x}", address),
+        };
+        let cpu = &debugger.c64.cpu;
+        let mut locations = vec![];
+        let mut address = cpu.pc();
+        for frame in cpu.call_stack().iter().rev() {
+            let kind = match frame.kind {
+                FrameKind::Jsr => "",
+                FrameKind::Brk => " (BRK)",
+                FrameKind::Irq => " (IRQ)",
+                FrameKind::Nmi => " (NMI)",
+            };
+            locations.push((address, format!("{}{}", name(frame.to), kind)));
+            address = frame.from;
+        }
+        locations.push((address, name(address)));
+
+        let mut frames = vec![];
+        for (id, (address, name)) in locations.into_iter().enumerate() {
+            let mut frame = json!({
+                "id": id,
+                "name": name,
+                "line": 0,
+                "column": 0,
+                "instructionPointerReference": reference(address),
+            });
+            if let Some((path, line)) = symbols.source_line(address) {
+                frame["source"] = source(path);
+                frame["line"] = json!(line);
+                frame["column"] = json!(1);
+            }
+            frames.push(frame);
+        }
+        frames
+    }
+
+    fn variables(&self, debugger: &Debugger, reference: u64) -> Result<Vec<Value>, String> {
+        let c64 = &debugger.c64;
+        let cpu = &c64.cpu;
+        let byte = |address: u16| format!("${:02x}", c64.bus.peek_byte(address));
+        let variables = match reference {
+            REGISTERS_REFERENCE => REGISTERS
+                .iter()
+                .map(|&name| {
+                    let value = register(cpu, name).unwrap();
+                    let address = (name == "PC").then_some(value);
+                    variable(name, format_register(name, value), address)
+                })
+                .collect(),
+            FLAGS_REFERENCE => {
+                let p = cpu.flags.get_register();
+                FLAGS
+                    .iter()
+                    .map(|&(name, bit)| variable(name, ((p >> bit) & 1).to_string(), None))
+                    .collect()
+            }
+            STACK_REFERENCE => (cpu.sp() as u16 + 1..=0xff)
+                .map(|offset| {
+                    let address = 0x100 + offset;
+                    variable(&format!("${:04x}", address), byte(address), Some(address))
+                })
+                .collect(),
+            LABELS_REFERENCE => debugger
+                .symbols
+                .iter()
+                .map(|(name, address)| variable(name, byte(address), Some(address)))
+                .collect(),
+            _ => return Err(format!("Unknown variables reference: {}", reference)),
+        };
+        Ok(variables)
+    }
+
+    // Returns the new value as it's shown
+    fn set_variable(
+        &self,
+        debugger: &mut Debugger,
+        reference: u64,
+        name: &str,
+        value: &str,
+    ) -> Result<String, String> {
+        let set_byte = |debugger: &mut Debugger, address: u16| {
+            let byte = parse_address(value)?;
+            let byte = u8::try_from(byte).map_err(|_| format!("Bad value for {}", name))?;
+            let bus = &mut debugger.c64.bus;
+            bus.set_byte(byte, address);
+            Ok(format!("${:02x}", bus.peek_byte(address)))
+        };
+        match reference {
+            REGISTERS_REFERENCE => {
+                let cpu = &mut debugger.c64.cpu;
+                set_register(cpu, name, parse_address(value)?)?;
+                Ok(format_register(name, register(cpu, name).unwrap()))
+            }
+            FLAGS_REFERENCE => {
+                let &(_, bit) = FLAGS
+                    .iter()
+                    .find(|(flag, _)| *flag == name)
+                    .ok_or_else(|| format!("Unknown flag: {}", name))?;
+                let set = match value.trim() {
+                    "0" | "false" => false,
+                    "1" | "true" => true,
+                    _ => return Err(format!("Bad value for {}: {}", name, value)),
+                };
+                let flags = &mut debugger.c64.cpu.flags;
+                let p = (flags.get_register() & !(1 << bit)) | ((set as u8) << bit);
+                flags.set_register(p);
+                Ok((set as u8).to_string())
+            }
+            STACK_REFERENCE => set_byte(debugger, parse_address(name)?),
+            LABELS_REFERENCE => {
+                let address = debugger
+                    .symbols
+                    .address(name)
+                    .ok_or_else(|| format!("Unknown label: {}", name))?;
+                set_byte(debugger, address)
+            }
+            _ => Err(format!("Unknown variables reference: {}", reference)),
+        }
+    }
+
+    // Register, or byte at label or address for hovers and watches
+    fn evaluate(&self, debugger: &Debugger, expression: &str) -> Result<Value, String> {
+        let expression = expression.trim();
+        if let Some(value) = register(&debugger.c64.cpu, expression) {
+            let name = expression.to_uppercase();
+            return Ok(json!({
+                "result": format_register(&name, value),
+                "variablesReference": 0,
+            }));
+        }
+        let address = resolve_address(&debugger.symbols, expression)
+            .map_err(|_| format!("Unknown expression: {}", expression))?;
+        Ok(json!({
+            "result": format!("${:02x}", debugger.c64.bus.peek_byte(address)),
+            "variablesReference": 0,
+            "memoryReference": reference(address),
+        }))
+    }
+
+    fn monitor_command(&mut self, debugger: &mut Debugger, command: &str) -> Result<Value, String> {
+        let reply = self.monitor.execute(debugger, command)?;
+        match reply.action {
+            Action::Stay => {}
+            Action::Resume => {
+                self.running = true;
+                self.event(
+                    "continued",
+                    json!({ "threadId": THREAD_ID, "allThreadsContinued": true }),
+                );
+            }
+            Action::Quit => self.quit = true,
+        }
+        Ok(json!({ "result": reply.lines.join("\n"), "variablesReference": 0 }))
+    }
+
+    fn stopped_event(&mut self, description: String, reason: &str, breakpoint: Option<u32>) {
+        self.running = false;
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+            "description": description,
+        });
+        if let Some(id) = breakpoint {
+            body["hitBreakpointIds"] = json!([id]);
+        }
+        self.event("stopped", body);
+    }
+
+    fn stop_reason(&mut self, debugger: &Debugger, reason: StopReason) {
+        let description = debugger.describe(reason);
+        match reason {
+            StopReason::Breakpoint { id, .. } => {
+                self.stopped_event(description, "breakpoint", Some(id))
+            }
+            StopReason::Watchpoint { id, .. } => {
+                self.stopped_event(description, "data breakpoint", Some(id))
+            }
+            _ => self.stopped_event(description, "step", None),
+        }
+    }
+
+    // Description of stop which isn't caused by Debugger
+    fn stopped_at(&mut self, debugger: &Debugger, reason: &str) {
+        let description = format!("Stopped at ${:04x}", debugger.c64.cpu.pc());
+        self.stopped_event(description, reason, None);
+    }
+}
+
+impl remote::Session for Session {
+    const NAME: &'static str = "DAP";
+
+    // Emulation is halted until client is configured, so breakpoints are set before the
+    // program goes on
+    fn new() -> Self {
+        Self {
+            seq: 1,
+            output: vec![],
+            running: false,
+            launched: false,
+            configured: false,
+            stop_on_entry: false,
+            source_breakpoints: HashMap::new(),
+            function_breakpoints: vec![],
+            instruction_breakpoints: vec![],
+            events: vec![],
+            monitor: MlMonitor::new(),
+            quit: false,
+        }
+    }
+
+    fn receive(&mut self, debugger: &mut Debugger, input: &mut Vec<u8>) -> bool {
+        while let Some(message) = next_message(input) {
+            match message {
+                Ok(request) => self.handle(debugger, &request),
+                Err(err) => eprintln!("Bad DAP message: {}", err),
+            }
+        }
+        true
+    }
+
+    fn output(&mut self) -> &mut Vec<u8> {
+        &mut self.output
+    }
+
+    fn running(&self) -> bool {
+        self.running
+    }
+
+    fn stopped(&mut self, debugger: &Debugger, reason: StopReason) {
+        self.stop_reason(debugger, reason);
+        self.send_events();
+    }
+
+    // Breakpoints of the client are removed, emulation goes on
+    fn detach(&mut self, debugger: &mut Debugger) {
+        let sources = self.source_breakpoints.drain().flat_map(|(_, ids)| ids);
+        let functions = self.function_breakpoints.drain(..);
+        let instructions = self.instruction_breakpoints.drain(..);
+        for id in sources.chain(functions).chain(instructions) {
+            debugger.remove_breakpoint(id);
+        }
+        self.running = true;
+    }
+
+    fn quit(&self) -> bool {
+        self.quit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugger::test_debugger;
+
+    fn fixture() -> (Session, Debugger) {
+        (Session::new(), test_debugger())
+    }
+
+    fn messages(session: &mut Session) -> Vec<Value> {
+        let mut output = std::mem::take(&mut session.output);
+        let mut messages = vec![];
+        while let Some(message) = next_message(&mut output) {
+            messages.push(message.unwrap());
+        }
+        messages
+    }
+
+    fn request(
+        session: &mut Session,
+        debugger: &mut Debugger,
+        command: &str,
+        arguments: Value,
+    ) -> Vec<Value> {
+        let request = json!({
+            "seq": 7,
+            "type": "request",
+            "command": command,
+            "arguments": arguments,
+        });
+        session.handle(debugger, &request);
+        messages(session)
+    }
+
+    // Body of successful response, events are skipped
+    fn reply(
+        session: &mut Session,
+        debugger: &mut Debugger,
+        command: &str,
+        arguments: Value,
+    ) -> Value {
+        let messages = request(session, debugger, command, arguments);
+        let response = messages
+            .into_iter()
+            .find(|message| message["type"] == "response")
+            .unwrap();
+        assert_eq!(response["success"], true, "{}", response);
+        assert_eq!(response["request_seq"], 7);
+        response["body"].clone()
+    }
+
+    fn variables(session: &mut Session, debugger: &mut Debugger, reference: u64) -> Vec<Value> {
+        let body = reply(
+            session,
+            debugger,
+            "variables",
+            json!({ "variablesReference": reference }),
+        );
+        body["variables"].as_array().unwrap().clone()
+    }
+
+    #[test]
+    fn framing() {
+        let mut buffer = frame(&json!({ "seq": 1 }));
+        let second = frame(&json!({ "seq": 2 }));
+        buffer.extend(&second[..25]);
+        assert_eq!(next_message(&mut buffer).unwrap().unwrap()["seq"], 1);
+        assert!(next_message(&mut buffer).is_none());
+        buffer.extend(&second[25..]);
+        assert_eq!(next_message(&mut buffer).unwrap().unwrap()["seq"], 2);
+        assert!(buffer.is_empty());
+
+        let mut buffer = b"Content-Type: json\r\n\r\n".to_vec();
+        assert!(next_message(&mut buffer).unwrap().is_err());
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn base64() {
+        assert_eq!(base64_encode(b"Man"), "TWFu");
+        assert_eq!(base64_encode(b"Ma"), "TWE=");
+        assert_eq!(base64_encode(b"M"), "TQ==");
+        assert_eq!(base64_decode("TWFuTWE=").unwrap(), b"ManMa");
+        assert_eq!(base64_decode("TQ==").unwrap(), b"M");
+        assert!(base64_decode("T").is_none());
+        assert!(base64_decode("T!==").is_none());
+    }
+
+    #[test]
+    fn emulation_starts_after_configuration() {
+        let (mut session, mut debugger) = fixture();
+        let messages = request(&mut session, &mut debugger, "initialize", json!({}));
+        assert_eq!(
+            messages[0]["body"]["supportsConfigurationDoneRequest"],
+            true
+        );
+        assert_eq!(messages[1]["event"], "initialized");
+        reply(
+            &mut session,
+            &mut debugger,
+            "launch",
+            json!({ "stopOnEntry": true }),
+        );
+        assert!(!session.running);
+        let messages = request(&mut session, &mut debugger, "configurationDone", json!({}));
+        assert_eq!(messages[1]["event"], "stopped");
+        assert_eq!(messages[1]["body"]["reason"], "entry");
+        assert!(!session.running);
+
+        let (mut session, mut debugger) = fixture();
+        reply(&mut session, &mut debugger, "configurationDone", json!({}));
+        assert!(!session.running);
+        reply(&mut session, &mut debugger, "attach", json!({}));
+        assert!(session.running);
+        let messages = request(&mut session, &mut debugger, "pause", json!({}));
+        assert_eq!(messages[1]["body"]["reason"], "pause");
+        assert!(!session.running);
+    }
+
+    #[test]
+    fn source_breakpoints_and_stack() {
+        let (mut session, mut debugger) = fixture();
+        debugger
+            .symbols
+            .parse(
+                "version\tmajor=2,minor=0\n\
+                 file\tid=0,name=\"main.s\",size=100,mtime=0x5F000000,mod=0\n\
+                 line\tid=0,file=0,line=10,span=0\n\
+                 line\tid=1,file=0,line=11,span=1\n\
+                 line\tid=2,file=0,line=13,span=2\n\
+                 seg\tid=0,name=\"CODE\",start=0x001000,size=0x0005,addrsize=absolute,type=ro\n\
+                 span\tid=0,seg=0,start=0,size=1\n\
+                 span\tid=1,seg=0,start=1,size=1\n\
+                 span\tid=2,seg=0,start=2,size=3\n\
+                 sym\tid=0,name=\"main\",addrsize=absolute,scope=0,def=0,val=0x1000,seg=0,type=lab\n",
+            )
+            .unwrap();
+        let body = reply(
+            &mut session,
+            &mut debugger,
+            "setBreakpoints",
+            json!({
+                "source": { "path": "/work/main.s" },
+                "breakpoints": [{ "line": 12 }, { "line": 20 }],
+            }),
+        );
+        let breakpoints = body["breakpoints"].as_array().unwrap();
+        assert_eq!(breakpoints[0]["verified"], true);
+        assert_eq!(breakpoints[0]["line"], 13);
+        assert_eq!(breakpoints[1]["verified"], false);
+        let id = breakpoints[0]["id"].as_u64().unwrap() as u32;
+        assert_eq!(debugger.breakpoint(id).unwrap().from, 0x1002);
+
+        session.running = true;
+        let reason = debugger.run(1000);
+        session.stopped(&debugger, reason);
+        let messages = messages(&mut session);
+        assert_eq!(messages[0]["body"]["reason"], "breakpoint");
+        assert_eq!(messages[0]["body"]["hitBreakpointIds"], json!([id]));
+
+        let body = reply(&mut session, &mut debugger, "stackTrace", json!({}));
+        let frame = &body["stackFrames"][0];
+        assert_eq!(frame["line"], 13);
+        assert_eq!(frame["source"]["path"], "main.s");
+        assert_eq!(frame["instructionPointerReference"], "0x1002");
+
+        // Request for the same file replaces its breakpoints
+        reply(
+            &mut session,
+            &mut debugger,
+            "setBreakpoints",
+            json!({ "source": { "path": "/work/main.s" }, "breakpoints": [] }),
+        );
+        assert_eq!(debugger.breakpoints().count(), 0);
+    }
+
+    #[test]
+    fn function_and_instruction_breakpoints() {
+        let (mut session, mut debugger) = fixture();
+        debugger.symbols.insert("loop", 0x1001);
+        let breakpoints = reply(
+            &mut session,
+            &mut debugger,
+            "setFunctionBreakpoints",
+            json!({ "breakpoints": [
+                { "name": "loop", "condition": "x == 1 || x == 3", "hitCondition": "2" },
+                { "name": "nowhere" },
+            ]}),
+        );
+        assert_eq!(breakpoints["breakpoints"][1]["verified"], false);
+        let id = breakpoints["breakpoints"][0]["id"].as_u64().unwrap() as u32;
+        assert_eq!(
+            debugger
+                .breakpoint(id)
+                .unwrap()
+                .condition
+                .as_ref()
+                .unwrap()
+                .to_string(),
+            "x == 1 && hits >= $2 || x == 3 && hits >= $2"
+        );
+        reply(
+            &mut session,
+            &mut debugger,
+            "setInstructionBreakpoints",
+            json!({ "breakpoints": [{ "instructionReference": "0x1000", "offset": 2 }] }),
+        );
+        let addresses: Vec<u16> = debugger.breakpoints().map(|(_, b)| b.from).collect();
+        assert_eq!(addresses, vec![0x1001, 0x1002]);
+
+        session.detach(&mut debugger);
+        assert_eq!(debugger.breakpoints().count(), 0);
+        assert!(hit_condition("> 16").unwrap() == "hits > $10");
+        assert!(hit_condition("lots").is_err());
+    }
+
+    #[test]
+    fn variables_and_registers() {
+        let (mut session, mut debugger) = fixture();
+        debugger.c64.cpu.reg.a = 0x42;
+        let registers = variables(&mut session, &mut debugger, REGISTERS_REFERENCE);
+        assert_eq!(registers[0]["name"], "A");
+        assert_eq!(registers[0]["value"], "$42");
+        assert_eq!(registers[4]["value"], "$1000");
+        assert_eq!(registers[4]["memoryReference"], "0x1000");
+
+        let body = reply(
+            &mut session,
+            &mut debugger,
+            "setVariable",
+            json!({ "variablesReference": FLAGS_REFERENCE, "name": "C", "value": "1" }),
+        );
+        assert_eq!(body["value"], "1");
+        assert!(debugger.c64.cpu.flags.carry());
+        let flags = variables(&mut session, &mut debugger, FLAGS_REFERENCE);
+        assert_eq!(flags[6], variable("C", "1".to_string(), None));
+
+        reply(
+            &mut session,
+            &mut debugger,
+            "setVariable",
+            json!({ "variablesReference": REGISTERS_REFERENCE, "name": "X", "value": "$12" }),
+        );
+        assert_eq!(debugger.c64.cpu.reg.x, 0x12);
+        let messages = request(
+            &mut session,
+            &mut debugger,
+            "setVariable",
+            json!({ "variablesReference": REGISTERS_REFERENCE, "name": "X", "value": "100" }),
+        );
+        assert_eq!(messages[0]["success"], false);
+
+        debugger.c64.cpu.set_sp(0xfd);
+        let stack = variables(&mut session, &mut debugger, STACK_REFERENCE);
+        assert_eq!(stack.len(), 2);
+        assert_eq!(stack[0]["name"], "$01fe");
+    }
+
+    #[test]
+    fn stepping() {
+        let (mut session, mut debugger) = fixture();
+        let messages = request(
+            &mut session,
+            &mut debugger,
+            "stepIn",
+            json!({ "threadId": 1 }),
+        );
+        assert_eq!(messages[0]["type"], "response");
+        assert_eq!(messages[1]["event"], "stopped");
+        assert_eq!(messages[1]["body"]["reason"], "step");
+        assert_eq!(debugger.c64.cpu.pc(), 0x1001);
+        request(
+            &mut session,
+            &mut debugger,
+            "next",
+            json!({ "threadId": 1 }),
+        );
+        assert_eq!(debugger.c64.cpu.pc(), 0x1002);
+    }
+
+    #[test]
+    fn memory_and_disassembly() {
+        let (mut session, mut debugger) = fixture();
+        reply(
+            &mut session,
+            &mut debugger,
+            "writeMemory",
+            json!({ "memoryReference": "0x2000", "offset": 1, "data": "qQE=" }),
+        );
+        assert_eq!(debugger.c64.bus.peek_byte(0x2002), 0x01);
+        let body = reply(
+            &mut session,
+            &mut debugger,
+            "readMemory",
+            json!({ "memoryReference": "0x2000", "count": 3 }),
+        );
+        assert_eq!(body["data"], base64_encode(&[0x00, 0xa9, 0x01]));
+        let body = reply(
+            &mut session,
+            &mut debugger,
+            "readMemory",
+            json!({ "memoryReference": "0xfffe", "count": 4 }),
+        );
+        assert_eq!(body["unreadableBytes"], 2);
+
+        let body = reply(
+            &mut session,
+            &mut debugger,
+            "disassemble",
+            json!({
+                "memoryReference": "0x1002",
+                "instructionOffset": -2,
+                "instructionCount": 3,
+            }),
+        );
+        let instructions = body["instructions"].as_array().unwrap();
+        assert_eq!(instructions[0]["address"], "0x1000");
+        assert_eq!(instructions[0]["instruction"], "INX");
+        assert_eq!(instructions[2]["instruction"], "JMP $1000");
+        assert_eq!(instructions[2]["instructionBytes"], "4c 00 10");
+
+        // Offset far beyond memory doesn't overflow or take long
+        let body = reply(
+            &mut session,
+            &mut debugger,
+            "disassemble",
+            json!({
+                "memoryReference": "0x1002",
+                "instructionOffset": i64::MIN,
+                "instructionCount": 1,
+            }),
+        );
+        assert_eq!(body["instructions"][0]["address"], "0x1002");
+    }
+
+    #[test]
+    fn evaluate() {
+        let (mut session, mut debugger) = fixture();
+        debugger.symbols.insert("counter", 0x1001);
+        let body = reply(
+            &mut session,
+            &mut debugger,
+            "evaluate",
+            json!({ "expression": "pc" }),
+        );
+        assert_eq!(body["result"], "$1000");
+        let body = reply(
+            &mut session,
+            &mut debugger,
+            "evaluate",
+            json!({ "expression": "counter", "context": "hover" }),
+        );
+        assert_eq!(body["result"], "$e8");
+        assert_eq!(body["memoryReference"], "0x1001");
+
+        // Monitor command which steps tells the editor where cpu is
+        let messages = request(
+            &mut session,
+            &mut debugger,
+            "evaluate",
+            json!({ "expression": "z", "context": "repl" }),
+        );
+        assert!(messages[0]["body"]["result"]
+            .as_str()
+            .unwrap()
+            .contains("1001"));
+        assert_eq!(messages[1]["body"]["reason"], "step");
+        let messages = request(
+            &mut session,
+            &mut debugger,
+            "evaluate",
+            json!({ "expression": "g", "context": "repl" }),
+        );
+        assert_eq!(messages[1]["event"], "continued");
+        assert!(session.running);
+    }
+}
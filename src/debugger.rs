@@ -1,7 +1,836 @@
+use crate::bus::{Access, BusAccess};
 use crate::c64::C64;
+use crate::condition::{Condition, Context};
+use crate::cpu::FrameKind;
+use crate::symbols::SymbolTable;
 
-struct Debugger {
-    c64: &C64
+use std::collections::BTreeMap;
+use std::fmt;
+
+const JSR: u8 = 0x20;
+const RTS: u8 = 0x60;
+const RTI: u8 = 0x40;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StopReason {
+    // Cpu is about to execute instruction at address
+    Breakpoint { id: u32, address: u16 },
+    // Instruction before the current one accessed watched memory
+    Watchpoint { id: u32, access: BusAccess },
+    // Single instruction or instruction with subroutine it calls is done
+    Step,
+    // Current subroutine or interrupt handler returned
+    Finish,
+    // Run to address is done
+    Reached { address: u16 },
+    // Given number of cycles passed without hitting anything
+    CycleLimit,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopReason::Breakpoint { id, address } => {
+                write!(f, "Breakpoint {} hit at ${:04x}", id, address)
+            }
+            StopReason::Watchpoint { id, access } => {
+                let (verb, preposition) = match access.access {
+                    Access::Read => ("load", "from"),
+                    Access::Write => ("store", "to"),
+                };
+                write!(
+                    f,
+                    "Watchpoint {}: {} ${:02x} {} ${:04x}",
+                    id, verb, access.value, preposition, access.address
+                )
+            }
+            StopReason::Step => write!(f, "Step done"),
+            StopReason::Finish => write!(f, "Returned from subroutine"),
+            StopReason::Reached { address } => write!(f, "Reached ${:04x}", address),
+            StopReason::CycleLimit => write!(f, "Cycle limit reached"),
+        }
+    }
+}
+
+fn describe(symbols: &SymbolTable, reason: StopReason) -> String {
+    let address = match reason {
+        StopReason::Breakpoint { address, .. } | StopReason::Reached { address } => address,
+        StopReason::Watchpoint { access, .. } => access.address,
+        _ => return reason.to_string(),
+    };
+    match symbols.name(address) {
+        Some(name) => format!("{} ({})", reason, name),
+        None => reason.to_string(),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BreakKind {
+    Exec,
+    Load,
+    Store,
+    LoadStore,
+}
+
+impl BreakKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "exec" => Some(BreakKind::Exec),
+            "load" => Some(BreakKind::Load),
+            "store" => Some(BreakKind::Store),
+            "loadstore" => Some(BreakKind::LoadStore),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BreakKind::Exec => "exec",
+            BreakKind::Load => "load",
+            BreakKind::Store => "store",
+            BreakKind::LoadStore => "loadstore",
+        }
+    }
+
+    fn accesses(self) -> &'static [Access] {
+        match self {
+            BreakKind::Exec => &[],
+            BreakKind::Load => &[Access::Read],
+            BreakKind::Store => &[Access::Write],
+            BreakKind::LoadStore => &[Access::Read, Access::Write],
+        }
+    }
+}
+
+// Breakpoint on execution or watchpoint on memory access of from..=to
+#[derive(Clone, PartialEq, Debug)]
+pub struct Breakpoint {
+    pub kind: BreakKind,
+    pub from: u16,
+    pub to: u16,
+    pub enabled: bool,
+    // Checked after hit is counted, so hits can be used in it
+    pub condition: Option<Condition>,
+    pub hits: u32,
+    // Hit is printed and execution goes on
+    pub log: bool,
+}
+
+impl Breakpoint {
+    pub fn new(kind: BreakKind, from: u16, to: u16) -> Self {
+        Self {
+            kind,
+            from,
+            to,
+            enabled: true,
+            condition: None,
+            hits: 0,
+            log: false,
+        }
+    }
+
+    fn contains(&self, address: u16) -> bool {
+        address >= self.from && address <= self.to
+    }
+
+    // Count hit and check condition
+    fn hit(&mut self, context: Context) -> bool {
+        self.hits += 1;
+        let context = Context {
+            hits: self.hits,
+            ..context
+        };
+        match &self.condition {
+            Some(condition) => condition.eval(&context),
+            None => true,
+        }
+    }
+}
+
+// Debugger client on the other side of a connection, like GDB or an IDE. It's polled
+// from the emulation loop between frames.
+pub trait RemoteDebugger {
+    // Handles what client sent, false when it asked to quit emulator
+    fn poll(&mut self, debugger: &mut Debugger) -> bool;
+    // Emulation shouldn't run while this is true
+    fn halted(&self) -> bool;
+    // Reports stop to the client if it waits for one. false means there is no such
+    // client and stop is for someone else to handle.
+    fn stopped(&mut self, debugger: &Debugger, reason: StopReason) -> bool;
 }
 
+// Owns the machine and stops it on breakpoints. Checks are done between instructions,
+// so the machine is always stopped with cpu at the start of an instruction.
+pub struct Debugger {
+    pub c64: C64,
+    // By id, ids are never reused so they stay valid for the user
+    breakpoints: BTreeMap<u32, Breakpoint>,
+    next_id: u32,
+    // Names of addresses in backtrace, stop reasons and disassembly
+    pub symbols: SymbolTable,
+    // Where the last stop happened after breakpoints there were checked. They are not
+    // hit again when execution resumes from the same pc.
+    resume_pc: Option<u16>,
+}
+
+impl Debugger {
+    pub fn new(c64: C64) -> Self {
+        // Breakpoint where cpu already is isn't hit before it moves
+        let resume_pc = Some(c64.cpu.pc());
+        Self {
+            c64,
+            breakpoints: BTreeMap::new(),
+            next_id: 1,
+            symbols: SymbolTable::new(),
+            resume_pc,
+        }
+    }
+
+    // Innermost first: where cpu is, then where each subroutine was called from or
+    // interrupt came. Functions are named by their entry addresses.
+    pub fn backtrace(&self) -> Vec<String> {
+        let name = |address: u16| match self.symbols.name(address) {
+            Some(symbol) => symbol.to_string(),
+            None => format!("${:04x}", address),
+        };
+        let cpu = &self.c64.cpu;
+        let mut lines = vec![];
+        let mut address = cpu.pc();
+        for (depth, frame) in cpu.call_stack().iter().rev().enumerate() {
+            let kind = match frame.kind {
+                FrameKind::Jsr => "",
+                FrameKind::Brk => " (BRK)",
+                FrameKind::Irq => " (IRQ)",
+                FrameKind::Nmi => " (NMI)",
+            };
+            lines.push(format!(
+                "#{:<2} ${:04x} in {}{}",
+                depth,
+                address,
+                name(frame.to),
+                kind
+            ));
+            address = frame.from;
+        }
+        lines.push(format!("#{:<2} ${:04x}", cpu.call_stack().len(), address));
+        lines
+    }
+
+    // Stop reason with name of the address it's about
+    pub fn describe(&self, reason: StopReason) -> String {
+        describe(&self.symbols, reason)
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) -> u32 {
+        self.add(Breakpoint::new(BreakKind::Exec, address, address))
+    }
+
+    pub fn add(&mut self, breakpoint: Breakpoint) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.insert(id, breakpoint);
+        id
+    }
+
+    pub fn breakpoint(&self, id: u32) -> Option<&Breakpoint> {
+        self.breakpoints.get(&id)
+    }
+
+    pub fn breakpoint_mut(&mut self, id: u32) -> Option<&mut Breakpoint> {
+        self.breakpoints.get_mut(&id)
+    }
+
+    // false if there is no breakpoint with this id
+    pub fn remove_breakpoint(&mut self, id: u32) -> bool {
+        self.breakpoints.remove(&id).is_some()
+    }
+
+    pub fn set_breakpoint_enabled(&mut self, id: u32, enabled: bool) -> bool {
+        match self.breakpoints.get_mut(&id) {
+            Some(breakpoint) => {
+                breakpoint.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = (u32, &Breakpoint)> {
+        self.breakpoints
+            .iter()
+            .map(|(id, breakpoint)| (*id, breakpoint))
+    }
+
+    // Stop reason of the first breakpoint at pc, logged ones are printed
+    fn check_exec(&mut self) -> Option<StopReason> {
+        let cpu = &self.c64.cpu;
+        let value = self.c64.bus.peek_byte(cpu.pc());
+        for (&id, breakpoint) in &mut self.breakpoints {
+            if !breakpoint.enabled
+                || breakpoint.kind != BreakKind::Exec
+                || !breakpoint.contains(cpu.pc())
+            {
+                continue;
+            }
+            if !breakpoint.hit(Context {
+                cpu,
+                value,
+                hits: 0,
+            }) {
+                continue;
+            }
+            let reason = StopReason::Breakpoint {
+                id,
+                address: cpu.pc(),
+            };
+            if !breakpoint.log {
+                return Some(reason);
+            }
+            println!("Trace: {}", describe(&self.symbols, reason));
+        }
+        None
+    }
+
+    // Watchpoints are checked after every cycle, but stop happens when the instruction
+    // is finished, so only the first hit is kept in pending.
+    fn check_accesses(&mut self, pending: &mut Option<StopReason>) {
+        let cpu = &self.c64.cpu;
+        for access in self.c64.bus.take_accesses() {
+            for (&id, breakpoint) in &mut self.breakpoints {
+                if !breakpoint.enabled
+                    || !breakpoint.kind.accesses().contains(&access.access)
+                    || !breakpoint.contains(access.address)
+                {
+                    continue;
+                }
+                let context = Context {
+                    cpu,
+                    value: access.value,
+                    hits: 0,
+                };
+                if !breakpoint.hit(context) {
+                    continue;
+                }
+                let reason = StopReason::Watchpoint { id, access };
+                if breakpoint.log {
+                    println!("Trace: {}", describe(&self.symbols, reason));
+                } else if pending.is_none() {
+                    *pending = Some(reason);
+                }
+            }
+        }
+    }
+
+    // Bus records only accesses watchpoints are interested in
+    fn update_watches(&mut self) {
+        let bus = &mut self.c64.bus;
+        bus.clear_watches();
+        for breakpoint in self.breakpoints.values().filter(|b| b.enabled) {
+            for &access in breakpoint.kind.accesses() {
+                bus.watch(access, breakpoint.from, breakpoint.to);
+            }
+        }
+    }
+
+    // Run until breakpoint, but not longer than max_cycles. Breakpoint at pc of the
+    // previous stop is not hit again, so it's possible to continue after stop.
+    pub fn run(&mut self, max_cycles: u64) -> StopReason {
+        self.run_until(max_cycles, |_| None)
+    }
+
+    // Execute one instruction. Pending interrupt is taken as one step, then cpu stops
+    // at the start of the handler.
+    pub fn step(&mut self) -> StopReason {
+        let mut first = true;
+        self.run_until(u64::MAX, |_| {
+            (!std::mem::take(&mut first)).then_some(StopReason::Step)
+        })
+    }
+
+    // Step, but JSR is executed until it returns to the next instruction. Stack pointer
+    // is checked too, so returns of recursive calls to the same address don't stop.
+    pub fn next(&mut self, max_cycles: u64) -> StopReason {
+        let cpu = &self.c64.cpu;
+        if self.c64.bus.peek_byte(cpu.pc()) != JSR {
+            return self.step();
+        }
+        let return_address = cpu.pc().wrapping_add(3);
+        let sp = cpu.sp();
+        let mut first = true;
+        self.run_until(max_cycles, |c64| {
+            let returned = c64.cpu.pc() == return_address && c64.cpu.sp() >= sp;
+            (!std::mem::take(&mut first) && returned).then_some(StopReason::Step)
+        })
+    }
+
+    // Run until RTS or RTI pops the current stack frame. Returns from nested calls and
+    // interrupts leave stack pointer where it was, so they don't stop.
+    pub fn finish(&mut self, max_cycles: u64) -> StopReason {
+        let sp = self.c64.cpu.sp();
+        let mut returned = false;
+        self.run_until(max_cycles, |c64| {
+            if std::mem::take(&mut returned) && c64.cpu.sp() > sp {
+                return Some(StopReason::Finish);
+            }
+            returned = matches!(c64.bus.peek_byte(c64.cpu.pc()), RTS | RTI);
+            None
+        })
+    }
+
+    // Limit for next, finish and run_to started by the user, ten seconds of emulated
+    // time, so a routine which never returns doesn't hang the debugger
+    pub fn run_limit(&self) -> u64 {
+        10 * self.c64.video_standard().clock_frequency() as u64
+    }
+
+    // Run until cpu is about to execute instruction at address
+    pub fn run_to(&mut self, address: u16, max_cycles: u64) -> StopReason {
+        let mut first = true;
+        self.run_until(max_cycles, |c64| {
+            let reached = !std::mem::take(&mut first) && c64.cpu.pc() == address;
+            reached.then_some(StopReason::Reached { address })
+        })
+    }
 
+    // stop is called before each instruction, including the first one
+    fn run_until(
+        &mut self,
+        max_cycles: u64,
+        mut stop: impl FnMut(&C64) -> Option<StopReason>,
+    ) -> StopReason {
+        self.update_watches();
+        let mut resume_pc = self.resume_pc.take();
+        let mut pending = None;
+        for _ in 0..max_cycles {
+            let cpu = &self.c64.cpu;
+            // Cpu can be halted by VIC, then no instruction starts on this tick
+            if cpu.at_instruction_boundary() && !cpu.is_halted() {
+                // Breakpoints at pc weren't checked yet, so they are hit on resume
+                if let Some(reason) = pending {
+                    return reason;
+                }
+                let mut reason = None;
+                if resume_pc.take() != Some(cpu.pc()) {
+                    reason = self.check_exec();
+                }
+                if reason.is_none() {
+                    reason = stop(&self.c64);
+                }
+                if let Some(reason) = reason {
+                    self.resume_pc = Some(self.c64.cpu.pc());
+                    return reason;
+                }
+            }
+            self.c64.tick();
+            self.check_accesses(&mut pending);
+        }
+        pending.unwrap_or(StopReason::CycleLimit)
+    }
+}
+
+// 0x1000: INX, INX, JMP $1000, cpu is at its start
+#[cfg(test)]
+pub fn test_debugger() -> Debugger {
+    use crate::host_io::NullMonitor;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let mut c64 = C64::new(Rc::new(RefCell::new(NullMonitor {})));
+    (*c64.ram)
+        .borrow_mut()
+        .set_memory(&[0xe8, 0xe8, 0x4c, 0x00, 0x10], 0x1000)
+        .unwrap();
+    (*c64.ram)
+        .borrow_mut()
+        .set_memory(&[0x00, 0x10], 0xfffc)
+        .unwrap();
+    c64.cpu.reset(&c64.bus);
+    Debugger::new(c64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vic::{VideoStandard, NTSC_CLOCK, PAL_CLOCK};
+
+    #[test]
+    fn stops_at_breakpoint() {
+        let mut debugger = test_debugger();
+        let id = debugger.add_breakpoint(0x1001);
+        assert_eq!(
+            debugger.run(1000),
+            StopReason::Breakpoint {
+                id,
+                address: 0x1001
+            }
+        );
+        assert_eq!(debugger.c64.cpu.reg.x, 1);
+
+        // Continue from breakpoint, the next loop iteration hits it again
+        assert_eq!(
+            debugger.run(1000),
+            StopReason::Breakpoint {
+                id,
+                address: 0x1001
+            }
+        );
+        assert_eq!(debugger.c64.cpu.reg.x, 3);
+    }
+
+    #[test]
+    fn breakpoint_at_start_is_not_hit_immediately() {
+        let mut debugger = test_debugger();
+        debugger.add_breakpoint(0x1000);
+        debugger.run(1000);
+        assert_eq!(debugger.c64.cpu.pc(), 0x1000);
+        assert_eq!(debugger.c64.cpu.reg.x, 2);
+    }
+
+    #[test]
+    fn breakpoint_where_cycle_limit_ends() {
+        let mut debugger = test_debugger();
+        let id = debugger.add_breakpoint(0x1001);
+        assert_eq!(debugger.run(2), StopReason::CycleLimit);
+        assert_eq!(debugger.c64.cpu.pc(), 0x1001);
+        assert_eq!(
+            debugger.run(1000),
+            StopReason::Breakpoint {
+                id,
+                address: 0x1001
+            }
+        );
+        assert_eq!(debugger.c64.cpu.reg.x, 1);
+    }
+
+    #[test]
+    fn disabled_and_removed_breakpoints() {
+        let mut debugger = test_debugger();
+        let first = debugger.add_breakpoint(0x1001);
+        let second = debugger.add_breakpoint(0x1002);
+        assert_ne!(first, second);
+
+        assert!(debugger.set_breakpoint_enabled(first, false));
+        assert_eq!(
+            debugger.run(1000),
+            StopReason::Breakpoint {
+                id: second,
+                address: 0x1002
+            }
+        );
+
+        assert!(debugger.remove_breakpoint(second));
+        assert!(!debugger.remove_breakpoint(second));
+        assert!(!debugger.set_breakpoint_enabled(second, true));
+        assert_eq!(debugger.run(1000), StopReason::CycleLimit);
+        assert_eq!(debugger.breakpoints().count(), 1);
+    }
+
+    // 0x2000: JSR $2010, INY, JMP $2000. 0x2010: INX, RTS
+    fn subroutine_fixture() -> Debugger {
+        let mut debugger = test_debugger();
+        let mut ram = debugger.c64.ram.borrow_mut();
+        ram.set_memory(&[0x20, 0x10, 0x20, 0xc8, 0x4c, 0x00, 0x20], 0x2000)
+            .unwrap();
+        ram.set_memory(&[0xe8, 0x60], 0x2010).unwrap();
+        drop(ram);
+        debugger.c64.cpu.set_pc(0x2000);
+        debugger
+    }
+
+    #[test]
+    fn step() {
+        let mut debugger = test_debugger();
+        assert_eq!(debugger.step(), StopReason::Step);
+        assert_eq!(debugger.c64.cpu.pc(), 0x1001);
+        assert_eq!(debugger.c64.cpu.reg.x, 1);
+        debugger.step();
+        debugger.step();
+        assert_eq!(debugger.c64.cpu.pc(), 0x1000);
+    }
+
+    #[test]
+    fn next_steps_over_subroutine() {
+        let mut debugger = subroutine_fixture();
+        assert_eq!(debugger.next(1000), StopReason::Step);
+        assert_eq!(debugger.c64.cpu.pc(), 0x2003);
+        assert_eq!(debugger.c64.cpu.reg.x, 1);
+
+        // Not a JSR, same as step
+        debugger.next(1000);
+        assert_eq!(debugger.c64.cpu.pc(), 0x2004);
+    }
+
+    #[test]
+    fn next_stops_on_breakpoint_inside_subroutine() {
+        let mut debugger = subroutine_fixture();
+        let id = debugger.add_breakpoint(0x2011);
+        assert_eq!(
+            debugger.next(1000),
+            StopReason::Breakpoint {
+                id,
+                address: 0x2011
+            }
+        );
+    }
+
+    #[test]
+    fn finish_returns_from_subroutine() {
+        let mut debugger = subroutine_fixture();
+        debugger.step();
+        assert_eq!(debugger.c64.cpu.pc(), 0x2010);
+        assert_eq!(debugger.finish(1000), StopReason::Finish);
+        assert_eq!(debugger.c64.cpu.pc(), 0x2003);
+    }
+
+    // 0x4000: JSR $4010, INY, JMP $4000
+    // 0x4010: DEX, BEQ $4016, JSR $4010, RTS, so it recurses until x is 0
+    fn recursion_fixture(x: u8) -> Debugger {
+        let mut debugger = test_debugger();
+        let mut ram = debugger.c64.ram.borrow_mut();
+        ram.set_memory(&[0x20, 0x10, 0x40, 0xc8, 0x4c, 0x00, 0x40], 0x4000)
+            .unwrap();
+        ram.set_memory(&[0xca, 0xf0, 0x03, 0x20, 0x10, 0x40, 0x60], 0x4010)
+            .unwrap();
+        drop(ram);
+        debugger.c64.cpu.set_pc(0x4000);
+        debugger.c64.cpu.reg.x = x;
+        debugger
+    }
+
+    #[test]
+    fn backtrace() {
+        let mut debugger = recursion_fixture(3);
+        debugger.symbols.insert("countdown", 0x4010);
+        assert_eq!(debugger.backtrace(), vec!["#0  $4000"]);
+        for _ in 0..5 {
+            debugger.step();
+        }
+        assert_eq!(
+            debugger.backtrace(),
+            vec![
+                "#0  $4011 in countdown",
+                "#1  $4013 in countdown",
+                "#2  $4000",
+            ]
+        );
+    }
+
+    #[test]
+    fn backtrace_after_return_address_is_pulled() {
+        let mut debugger = test_debugger();
+        // JSR $2010, then PLA, PLA and NOP in the subroutine
+        let mut ram = debugger.c64.ram.borrow_mut();
+        ram.set_memory(&[0x20, 0x10, 0x20], 0x2000).unwrap();
+        ram.set_memory(&[0x68, 0x68, 0xea], 0x2010).unwrap();
+        drop(ram);
+        debugger.c64.cpu.set_pc(0x2000);
+        debugger.step();
+        assert_eq!(
+            debugger.backtrace(),
+            vec!["#0  $2010 in $2010", "#1  $2000"]
+        );
+        debugger.step();
+        debugger.step();
+        assert_eq!(debugger.backtrace(), vec!["#0  $2012"]);
+    }
+
+    #[test]
+    fn stop_reasons_are_described_with_symbols() {
+        let mut debugger = test_debugger();
+        debugger.symbols.insert("loop", 0x1000);
+        debugger.symbols.insert("border", 0xd020);
+        let reason = StopReason::Breakpoint {
+            id: 1,
+            address: 0x1000,
+        };
+        assert_eq!(
+            debugger.describe(reason),
+            "Breakpoint 1 hit at $1000 (loop)"
+        );
+        let access = BusAccess {
+            access: Access::Write,
+            address: 0xd020,
+            value: 1,
+        };
+        assert_eq!(
+            debugger.describe(StopReason::Watchpoint { id: 2, access }),
+            "Watchpoint 2: store $01 to $d020 (border)"
+        );
+        let reason = StopReason::Reached { address: 0x1001 };
+        assert_eq!(debugger.describe(reason), "Reached $1001");
+    }
+
+    #[test]
+    fn next_over_recursive_call() {
+        let mut debugger = recursion_fixture(4);
+        debugger.step();
+        debugger.step();
+        debugger.step();
+        assert_eq!(debugger.c64.cpu.pc(), 0x4013);
+        let sp = debugger.c64.cpu.sp();
+
+        // Deeper calls return to $4016 first, those are not the end of step
+        assert_eq!(debugger.next(10000), StopReason::Step);
+        assert_eq!(debugger.c64.cpu.pc(), 0x4016);
+        assert_eq!(debugger.c64.cpu.sp(), sp);
+        assert_eq!(debugger.c64.cpu.reg.x, 0);
+    }
+
+    #[test]
+    fn finish_recursive_call() {
+        let mut debugger = recursion_fixture(4);
+        let sp = debugger.c64.cpu.sp();
+        debugger.step();
+        assert_eq!(debugger.c64.cpu.pc(), 0x4010);
+        assert_eq!(debugger.finish(10000), StopReason::Finish);
+        assert_eq!(debugger.c64.cpu.pc(), 0x4003);
+        assert_eq!(debugger.c64.cpu.sp(), sp);
+        assert_eq!(debugger.c64.cpu.reg.x, 0);
+    }
+
+    #[test]
+    fn finish_skips_interrupt_handler() {
+        let mut debugger = subroutine_fixture();
+        // NMI handler at $5000: RTI
+        let mut ram = debugger.c64.ram.borrow_mut();
+        ram.set_memory(&[0x40], 0x5000).unwrap();
+        ram.set_memory(&[0x00, 0x50], 0xfffa).unwrap();
+        drop(ram);
+        debugger.step();
+        assert_eq!(debugger.c64.cpu.pc(), 0x2010);
+
+        // Interrupt comes before INX, its RTI doesn't finish the subroutine
+        debugger.c64.cpu.set_nmi(true);
+        assert_eq!(debugger.finish(1000), StopReason::Finish);
+        assert_eq!(debugger.c64.cpu.pc(), 0x2003);
+        assert_eq!(debugger.c64.cpu.reg.x, 1);
+    }
+
+    #[test]
+    fn run_limit_follows_video_standard() {
+        let mut debugger = test_debugger();
+        assert_eq!(debugger.run_limit(), 10 * PAL_CLOCK as u64);
+        debugger.c64.set_video_standard(VideoStandard::Ntsc);
+        assert_eq!(debugger.run_limit(), 10 * NTSC_CLOCK as u64);
+    }
+
+    #[test]
+    fn run_to_address() {
+        let mut debugger = test_debugger();
+        assert_eq!(
+            debugger.run_to(0x1002, 1000),
+            StopReason::Reached { address: 0x1002 }
+        );
+        assert_eq!(debugger.c64.cpu.reg.x, 2);
+
+        // Breakpoint on the way stops it
+        let id = debugger.add_breakpoint(0x1001);
+        assert_eq!(
+            debugger.run_to(0x1002, 1000),
+            StopReason::Breakpoint {
+                id,
+                address: 0x1001
+            }
+        );
+    }
+
+    // 0x3000: LDA #$ff, STA $d020, INC $02, JMP $3000
+    fn store_fixture() -> Debugger {
+        let mut debugger = test_debugger();
+        debugger
+            .c64
+            .ram
+            .borrow_mut()
+            .set_memory(
+                &[0xa9, 0xff, 0x8d, 0x20, 0xd0, 0xe6, 0x02, 0x4c, 0x00, 0x30],
+                0x3000,
+            )
+            .unwrap();
+        debugger.c64.cpu.set_pc(0x3000);
+        debugger
+    }
+
+    #[test]
+    fn watchpoint_stops_after_instruction() {
+        let mut debugger = store_fixture();
+        let id = debugger.add(Breakpoint::new(BreakKind::Store, 0xd000, 0xd3ff));
+        assert_eq!(
+            debugger.run(1000),
+            StopReason::Watchpoint {
+                id,
+                access: BusAccess {
+                    access: Access::Write,
+                    address: 0xd020,
+                    value: 0xff
+                }
+            }
+        );
+        assert_eq!(debugger.c64.cpu.pc(), 0x3005);
+    }
+
+    #[test]
+    fn instruction_fetches_are_not_loads() {
+        let mut debugger = store_fixture();
+        debugger.add(Breakpoint::new(BreakKind::Load, 0x3000, 0x3009));
+        assert_eq!(debugger.run(1000), StopReason::CycleLimit);
+    }
+
+    #[test]
+    fn watchpoint_condition_on_value() {
+        let mut debugger = store_fixture();
+        let mut watchpoint = Breakpoint::new(BreakKind::LoadStore, 0x02, 0x02);
+        watchpoint.condition = Some(Condition::parse("value == 3 && a == $ff").unwrap());
+        let id = debugger.add(watchpoint);
+        assert!(matches!(
+            debugger.run(10000),
+            StopReason::Watchpoint { id: hit, .. } if hit == id
+        ));
+        assert_eq!(debugger.c64.bus.peek_byte(0x02), 3);
+        assert_eq!(debugger.c64.cpu.pc(), 0x3007);
+
+        // Disabled watchpoint doesn't even count hits
+        let hits = debugger.breakpoint_mut(id).unwrap().hits;
+        debugger.set_breakpoint_enabled(id, false);
+        assert_eq!(debugger.run(1000), StopReason::CycleLimit);
+        assert_eq!(debugger.breakpoint_mut(id).unwrap().hits, hits);
+    }
+
+    #[test]
+    fn breakpoint_hit_count_condition() {
+        let mut debugger = test_debugger();
+        let id = debugger.add_breakpoint(0x1001);
+        debugger.breakpoint_mut(id).unwrap().condition =
+            Some(Condition::parse("hits == 3").unwrap());
+        debugger.run(1000);
+        assert_eq!(debugger.c64.cpu.reg.x, 5);
+        assert_eq!(debugger.breakpoint_mut(id).unwrap().hits, 3);
+    }
+
+    #[test]
+    fn logged_breakpoint_does_not_stop() {
+        let mut debugger = test_debugger();
+        let mut breakpoint = Breakpoint::new(BreakKind::Exec, 0x1000, 0x1001);
+        breakpoint.log = true;
+        let id = debugger.add(breakpoint);
+        assert_eq!(debugger.run(100), StopReason::CycleLimit);
+        assert!(debugger.breakpoint_mut(id).unwrap().hits > 10);
+    }
+
+    #[test]
+    fn stop_reason_text() {
+        let reason = StopReason::Breakpoint {
+            id: 2,
+            address: 0xc000,
+        };
+        assert_eq!(reason.to_string(), "Breakpoint 2 hit at $c000");
+        let reason = StopReason::Watchpoint {
+            id: 3,
+            access: BusAccess {
+                access: Access::Read,
+                address: 0xd012,
+                value: 0x30,
+            },
+        };
+        assert_eq!(reason.to_string(), "Watchpoint 3: load $30 from $d012");
+    }
+}
@@ -0,0 +1,217 @@
+// Disassembly of memory as seen by cpu. Memory is read with peek_byte, so disassembling
+// I/O area doesn't disturb devices.
+
+use crate::bus::Bus;
+use crate::ops_lookup::{AddressMode, Code, OPCODE_TABLE};
+use crate::symbols::SymbolTable;
+
+use std::fmt;
+
+pub struct Line {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    // ??? for opcodes not in OPCODE_TABLE
+    pub mnemonic: &'static str,
+    pub operand: String,
+    // Where branch, JMP or JSR goes, None for indirect jumps
+    pub target: Option<u16>,
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let text = format!(
+            "${:04x}  {:<8}  {} {}",
+            self.address,
+            bytes.join(" "),
+            self.mnemonic,
+            self.operand
+        );
+        write!(f, "{}", text.trim_end())
+    }
+}
+
+// Single instruction at address
+pub fn disassemble_one(bus: &Bus, address: u16, symbols: Option<&SymbolTable>) -> Line {
+    let opcode = bus.peek_byte(address);
+    let op = match &OPCODE_TABLE[opcode as usize] {
+        Some(op) => op,
+        None => {
+            return Line {
+                address,
+                bytes: vec![opcode],
+                mnemonic: "???",
+                operand: String::new(),
+                target: None,
+            }
+        }
+    };
+
+    let bytes: Vec<u8> = (0..op.instruction_bytes as u16)
+        .map(|i| bus.peek_byte(address.wrapping_add(i)))
+        .collect();
+    let byte = bytes.get(1).copied().unwrap_or(0);
+    let word = u16::from_le_bytes([byte, bytes.get(2).copied().unwrap_or(0)]);
+    let name = |address: u16, digits: usize| match symbols.and_then(|s| s.name(address)) {
+        Some(symbol) => symbol.to_string(),
+        None => format!("${:0digits$x}", address, digits = digits),
+    };
+
+    let mut target = None;
+    let operand = match op.mode {
+        AddressMode::Immediate => format!("#${:02x}", byte),
+        AddressMode::ZeroPage => name(byte as u16, 2),
+        AddressMode::ZeroPageX => format!("{},X", name(byte as u16, 2)),
+        AddressMode::ZeroPageY => format!("{},Y", name(byte as u16, 2)),
+        AddressMode::Absolute => {
+            if let Code::JMP | Code::JSR = op.code {
+                target = Some(word);
+            }
+            name(word, 4)
+        }
+        AddressMode::AbsoluteX => format!("{},X", name(word, 4)),
+        AddressMode::AbsoluteY => format!("{},Y", name(word, 4)),
+        AddressMode::Indirect => format!("({})", name(word, 4)),
+        AddressMode::IndirectX => format!("({},X)", name(byte as u16, 2)),
+        AddressMode::IndirectY => format!("({}),Y", name(byte as u16, 2)),
+        AddressMode::Implied => String::new(),
+        AddressMode::Relative => {
+            let destination = address.wrapping_add(2).wrapping_add(byte as i8 as u16);
+            target = Some(destination);
+            name(destination, 4)
+        }
+        AddressMode::Accumulator => "A".to_string(),
+    };
+
+    Line {
+        address,
+        bytes,
+        mnemonic: op.name,
+        operand,
+        target,
+    }
+}
+
+// Instructions starting in from..=to, last one can extend past to
+pub fn disassemble(bus: &Bus, from: u16, to: u16, symbols: Option<&SymbolTable>) -> Vec<Line> {
+    let mut lines = vec![];
+    let mut address = from as u32;
+    while address <= to as u32 {
+        let line = disassemble_one(bus, address as u16, symbols);
+        address += line.bytes.len() as u32;
+        lines.push(line);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Device;
+    use crate::ram::Ram;
+
+    use std::cell::RefCell;
+    use std::rc::{Rc, Weak};
+
+    fn fixture(program: &[u8], at: u16) -> (Bus, Rc<RefCell<Ram>>) {
+        let ram = Rc::new(RefCell::new(Ram::new(0xffff + 1)));
+        ram.borrow_mut().set_memory(program, at).unwrap();
+        let mut bus = Bus::new();
+        bus.connect_device(Rc::downgrade(&ram) as Weak<RefCell<dyn Device>>, 0, 0xffff);
+        (bus, ram)
+    }
+
+    fn text(lines: &[Line]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn address_modes() {
+        #[rustfmt::skip]
+        let program = [
+            0xa9, 0x01, // LDA #$01
+            0xa5, 0x02, // LDA $02
+            0xb5, 0x03, // LDA $03,X
+            0xb6, 0x04, // LDX $04,Y
+            0xad, 0x20, 0xd0, // LDA $d020
+            0xbd, 0x00, 0x04, // LDA $0400,X
+            0xb9, 0x00, 0x05, // LDA $0500,Y
+            0x6c, 0x14, 0x03, // JMP ($0314)
+            0xa1, 0xfb, // LDA ($fb,X)
+            0xb1, 0xfc, // LDA ($fc),Y
+            0xe8, // INX
+            0x0a, // ASL A
+        ];
+        let (bus, _ram) = fixture(&program, 0x1000);
+        let lines = disassemble(&bus, 0x1000, 0x1000 + program.len() as u16 - 1, None);
+        assert_eq!(
+            text(&lines),
+            vec![
+                "$1000  a9 01     LDA #$01",
+                "$1002  a5 02     LDA $02",
+                "$1004  b5 03     LDA $03,X",
+                "$1006  b6 04     LDX $04,Y",
+                "$1008  ad 20 d0  LDA $d020",
+                "$100b  bd 00 04  LDA $0400,X",
+                "$100e  b9 00 05  LDA $0500,Y",
+                "$1011  6c 14 03  JMP ($0314)",
+                "$1014  a1 fb     LDA ($fb,X)",
+                "$1016  b1 fc     LDA ($fc),Y",
+                "$1018  e8        INX",
+                "$1019  0a        ASL A",
+            ]
+        );
+    }
+
+    #[test]
+    fn branch_targets() {
+        // BNE back to itself, BEQ forward over NOP, JSR
+        let program = [0xd0, 0xfe, 0xf0, 0x01, 0xea, 0x20, 0xd2, 0xff];
+        let (bus, _ram) = fixture(&program, 0xc000);
+        let lines = disassemble(&bus, 0xc000, 0xc005, None);
+        assert_eq!(lines[0].target, Some(0xc000));
+        assert_eq!(lines[0].to_string(), "$c000  d0 fe     BNE $c000");
+        assert_eq!(lines[1].target, Some(0xc005));
+        assert_eq!(lines[2].target, None);
+        assert_eq!(lines[3].target, Some(0xffd2));
+        assert_eq!(lines.len(), 4);
+    }
+
+    #[test]
+    fn unknown_opcode() {
+        let (bus, _ram) = fixture(&[0x02, 0xea], 0x2000);
+        let lines = disassemble(&bus, 0x2000, 0x2001, None);
+        assert_eq!(
+            text(&lines),
+            vec!["$2000  02        ???", "$2001  ea        NOP"]
+        );
+    }
+
+    #[test]
+    fn symbols() {
+        let program = [0x20, 0xd2, 0xff, 0x85, 0xfb, 0xd0, 0xf9, 0xad, 0x00, 0x10];
+        let (bus, _ram) = fixture(&program, 0x1000);
+        let mut symbols = SymbolTable::new();
+        symbols.insert("CHROUT", 0xffd2);
+        symbols.insert("ptr", 0xfb);
+        symbols.insert("start", 0x1000);
+        let lines = disassemble(&bus, 0x1000, 0x1007, Some(&symbols));
+        assert_eq!(
+            text(&lines),
+            vec![
+                "$1000  20 d2 ff  JSR CHROUT",
+                "$1003  85 fb     STA ptr",
+                "$1005  d0 f9     BNE start",
+                "$1007  ad 00 10  LDA start",
+            ]
+        );
+    }
+
+    #[test]
+    fn wraps_at_end_of_memory() {
+        let (bus, _ram) = fixture(&[0xea, 0xea], 0xfffe);
+        let lines = disassemble(&bus, 0xfffe, 0xffff, None);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1].address, 0xffff);
+    }
+}
@@ -0,0 +1,564 @@
+// GDB remote serial protocol server on a local TCP port. gdb-multiarch (with the target
+// description the server sends) or another RSP client controls the Debugger: registers,
+// memory, single step, continue, breakpoints and watchpoints. Emulation is halted while
+// client is attached, except when it continues.
+//
+// Registers in g packet and numbers in p/P packets: 0 A, 1 X, 2 Y, 3 SP, 4 PC (16 bit,
+// little endian), 5 P.
+
+use crate::debugger::{BreakKind, Breakpoint, Debugger, StopReason};
+use crate::remote;
+
+use std::collections::HashMap;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+// Biggest packet client may send, memory reads are limited to fit in it too
+const PACKET_SIZE: usize = 0x1000;
+const REGISTERS_SIZE: usize = 7;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.gnu.gdb.m6502.core">
+    <reg name="a" bitsize="8" regnum="0" type="uint8"/>
+    <reg name="x" bitsize="8" regnum="1" type="uint8"/>
+    <reg name="y" bitsize="8" regnum="2" type="uint8"/>
+    <reg name="sp" bitsize="8" regnum="3" type="uint8"/>
+    <reg name="pc" bitsize="16" regnum="4" type="code_ptr"/>
+    <reg name="p" bitsize="8" regnum="5" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &b| sum.wrapping_add(b))
+}
+
+fn frame(data: &str) -> String {
+    format!("${}#{:02x}", data, checksum(data.as_bytes()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    text.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [_, _] => u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+fn parse_number(text: &str) -> Option<u16> {
+    u16::from_str_radix(text, 16).ok()
+}
+
+// "addr,length"
+fn parse_range(text: &str) -> Option<(u16, u16)> {
+    let (address, length) = text.split_once(',')?;
+    Some((parse_number(address)?, parse_number(length)?))
+}
+
+fn registers(debugger: &Debugger) -> [u8; REGISTERS_SIZE] {
+    let cpu = &debugger.c64.cpu;
+    let [pc_low, pc_high] = cpu.pc().to_le_bytes();
+    [
+        cpu.reg.a,
+        cpu.reg.x,
+        cpu.reg.y,
+        cpu.sp(),
+        pc_low,
+        pc_high,
+        cpu.flags.get_register(),
+    ]
+}
+
+fn set_registers(debugger: &mut Debugger, bytes: &[u8]) {
+    let cpu = &mut debugger.c64.cpu;
+    cpu.reg.a = bytes[0];
+    cpu.reg.x = bytes[1];
+    cpu.reg.y = bytes[2];
+    cpu.set_sp(bytes[3]);
+    cpu.set_pc(u16::from_le_bytes([bytes[4], bytes[5]]));
+    cpu.flags.set_register(bytes[6]);
+}
+
+// Where register is in g packet and how many bytes it takes
+fn register_bytes(number: u16) -> Option<std::ops::Range<usize>> {
+    match number {
+        0..=3 => Some(number as usize..number as usize + 1),
+        4 => Some(4..6),
+        5 => Some(6..7),
+        _ => None,
+    }
+}
+
+// What client gets for a packet
+#[derive(PartialEq, Debug)]
+enum Response {
+    Reply(String),
+    // Reply is sent when emulation stops
+    Resumed,
+    // Connection is closed after optional reply
+    Close(Option<String>),
+}
+
+// Breakpoints and ack mode of a GDB client
+pub struct Session {
+    // Debugger breakpoint ids by Z packet type, address and kind
+    breakpoints: HashMap<(u8, u16, u16), u32>,
+    running: bool,
+    no_ack: bool,
+    // Reply for ? packet
+    last_stop: String,
+    // Packets and acks waiting to be sent
+    output: Vec<u8>,
+}
+
+impl Session {
+    fn send(&mut self, data: &str) {
+        self.output.extend_from_slice(frame(data).as_bytes());
+    }
+
+    fn handle(&mut self, debugger: &mut Debugger, packet: &str) -> Response {
+        let (command, args) = match packet.get(..1) {
+            Some(command) => (command, &packet[1..]),
+            None => return Response::Reply(String::new()),
+        };
+        let reply = match command {
+            "?" => Some(self.last_stop.clone()),
+            "g" => Some(hex(&registers(debugger))),
+            "G" => parse_hex_bytes(args)
+                .filter(|bytes| bytes.len() == REGISTERS_SIZE)
+                .map(|bytes| {
+                    set_registers(debugger, &bytes);
+                    "OK".to_string()
+                }),
+            "p" => parse_number(args)
+                .and_then(register_bytes)
+                .map(|range| hex(&registers(debugger)[range])),
+            "P" => Self::write_register(debugger, args),
+            "m" => parse_range(args).map(|(address, length)| {
+                let length = length.min(PACKET_SIZE as u16 / 2);
+                let bytes: Vec<u8> = (0..length)
+                    .map(|i| debugger.c64.bus.peek_byte(address.wrapping_add(i)))
+                    .collect();
+                hex(&bytes)
+            }),
+            "M" => Self::write_memory(debugger, args),
+            "c" | "s" => {
+                if !args.is_empty() {
+                    match parse_number(args) {
+                        Some(address) => debugger.c64.cpu.set_pc(address),
+                        None => return Response::Reply("E01".to_string()),
+                    }
+                }
+                if command == "c" {
+                    self.running = true;
+                    return Response::Resumed;
+                }
+                let reason = debugger.step();
+                Some(self.stop_reply(debugger, reason))
+            }
+            "Z" => self.add_breakpoint(debugger, args),
+            "z" => self.remove_breakpoint(debugger, args),
+            "H" | "T" => Some("OK".to_string()),
+            "D" => return Response::Close(Some("OK".to_string())),
+            "k" => return Response::Close(None),
+            "q" | "Q" => Some(self.query(packet)),
+            // Empty reply tells client packet is not supported
+            _ => Some(String::new()),
+        };
+        Response::Reply(reply.unwrap_or_else(|| "E01".to_string()))
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return format!(
+                "PacketSize={:x};qXfer:features:read+;QStartNoAckMode+",
+                PACKET_SIZE
+            );
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let (offset, length) = match range.split_once(',') {
+                Some((offset, length)) => (
+                    usize::from_str_radix(offset, 16).unwrap_or(usize::MAX),
+                    usize::from_str_radix(length, 16).unwrap_or(0),
+                ),
+                None => return "E01".to_string(),
+            };
+            let start = offset.min(TARGET_XML.len());
+            let end = start.saturating_add(length).min(TARGET_XML.len());
+            let more = if end < TARGET_XML.len() { 'm' } else { 'l' };
+            return format!("{}{}", more, &TARGET_XML[start..end]);
+        }
+        match packet {
+            "QStartNoAckMode" => {
+                self.no_ack = true;
+                "OK".to_string()
+            }
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    // "n=value", value is little endian as in g packet
+    fn write_register(debugger: &mut Debugger, args: &str) -> Option<String> {
+        let (number, value) = args.split_once('=')?;
+        let range = register_bytes(parse_number(number)?)?;
+        let value = parse_hex_bytes(value)?;
+        if value.len() != range.len() {
+            return None;
+        }
+        let mut bytes = registers(debugger);
+        bytes[range].copy_from_slice(&value);
+        set_registers(debugger, &bytes);
+        Some("OK".to_string())
+    }
+
+    // "addr,length:bytes", written through the bus like cpu writes
+    fn write_memory(debugger: &mut Debugger, args: &str) -> Option<String> {
+        let (range, data) = args.split_once(':')?;
+        let (address, length) = parse_range(range)?;
+        let bytes = parse_hex_bytes(data)?;
+        if bytes.len() != length as usize {
+            return None;
+        }
+        for (i, byte) in bytes.into_iter().enumerate() {
+            debugger
+                .c64
+                .bus
+                .set_byte(byte, address.wrapping_add(i as u16));
+        }
+        Some("OK".to_string())
+    }
+
+    // "type,addr,kind", kind is length for watchpoints. Software and hardware breakpoints
+    // are the same thing here.
+    fn parse_breakpoint(args: &str) -> Option<(u8, u16, u16)> {
+        let mut fields = args.split(',');
+        let kind = fields.next()?.parse().ok()?;
+        let address = parse_number(fields.next()?)?;
+        let length = parse_number(fields.next()?.split(';').next()?)?;
+        Some((kind, address, length))
+    }
+
+    fn add_breakpoint(&mut self, debugger: &mut Debugger, args: &str) -> Option<String> {
+        let key = Self::parse_breakpoint(args)?;
+        let (kind, address, length) = key;
+        let to = address.saturating_add(length.max(1) - 1);
+        let breakpoint = match kind {
+            0 | 1 => Breakpoint::new(BreakKind::Exec, address, address),
+            2 => Breakpoint::new(BreakKind::Store, address, to),
+            3 => Breakpoint::new(BreakKind::Load, address, to),
+            4 => Breakpoint::new(BreakKind::LoadStore, address, to),
+            _ => return Some(String::new()),
+        };
+        // Client may set the same breakpoint again
+        self.breakpoints
+            .entry(key)
+            .or_insert_with(|| debugger.add(breakpoint));
+        Some("OK".to_string())
+    }
+
+    fn remove_breakpoint(&mut self, debugger: &mut Debugger, args: &str) -> Option<String> {
+        let key = Self::parse_breakpoint(args)?;
+        if let Some(id) = self.breakpoints.remove(&key) {
+            debugger.remove_breakpoint(id);
+        }
+        Some("OK".to_string())
+    }
+
+    fn stop_reply(&mut self, debugger: &Debugger, reason: StopReason) -> String {
+        self.running = false;
+        self.last_stop = match reason {
+            StopReason::Watchpoint { id, access } => {
+                let name = match debugger.breakpoint(id).map(|breakpoint| breakpoint.kind) {
+                    Some(BreakKind::Store) => "watch",
+                    Some(BreakKind::Load) => "rwatch",
+                    _ => "awatch",
+                };
+                format!("T{:02x}{}:{:04x};", SIGTRAP, name, access.address)
+            }
+            _ => format!("S{:02x}", SIGTRAP),
+        };
+        self.last_stop.clone()
+    }
+
+    fn interrupted(&mut self) -> String {
+        self.running = false;
+        self.last_stop = format!("S{:02x}", SIGINT);
+        self.last_stop.clone()
+    }
+}
+
+enum Input {
+    Packet(String),
+    // Checksum doesn't match, client sends it again
+    Corrupted,
+    // Ctrl-C
+    Interrupt,
+}
+
+// Takes the next complete packet or interrupt from buffer, acks are skipped
+fn next_input(buffer: &mut Vec<u8>) -> Option<Input> {
+    loop {
+        match buffer.first()? {
+            0x03 => {
+                buffer.remove(0);
+                return Some(Input::Interrupt);
+            }
+            b'$' => break,
+            _ => {
+                buffer.remove(0);
+            }
+        }
+    }
+    let end = buffer.iter().position(|&b| b == b'#')?;
+    if buffer.len() < end + 3 {
+        return None;
+    }
+    let packet: Vec<u8> = buffer.drain(..end + 3).collect();
+    let data = &packet[1..end];
+    let sent = std::str::from_utf8(&packet[end + 1..])
+        .ok()
+        .and_then(|text| u8::from_str_radix(text, 16).ok());
+    if sent != Some(checksum(data)) {
+        return Some(Input::Corrupted);
+    }
+    Some(Input::Packet(String::from_utf8_lossy(data).into_owned()))
+}
+
+// Kill request only detaches, the emulator keeps running
+impl remote::Session for Session {
+    const NAME: &'static str = "GDB";
+
+    fn new() -> Self {
+        Self {
+            breakpoints: HashMap::new(),
+            running: false,
+            no_ack: false,
+            last_stop: format!("S{:02x}", SIGTRAP),
+            output: vec![],
+        }
+    }
+
+    fn receive(&mut self, debugger: &mut Debugger, input: &mut Vec<u8>) -> bool {
+        while let Some(message) = next_input(input) {
+            match message {
+                Input::Interrupt => {
+                    if self.running {
+                        let reply = self.interrupted();
+                        self.send(&reply);
+                    }
+                }
+                Input::Corrupted => self.output.push(b'-'),
+                Input::Packet(packet) => {
+                    if !self.no_ack {
+                        self.output.push(b'+');
+                    }
+                    match self.handle(debugger, &packet) {
+                        Response::Reply(reply) => self.send(&reply),
+                        Response::Resumed => {}
+                        Response::Close(reply) => {
+                            if let Some(reply) = reply {
+                                self.send(&reply);
+                            }
+                            return false;
+                        }
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    fn output(&mut self) -> &mut Vec<u8> {
+        &mut self.output
+    }
+
+    fn running(&self) -> bool {
+        self.running
+    }
+
+    fn stopped(&mut self, debugger: &Debugger, reason: StopReason) {
+        let reply = self.stop_reply(debugger, reason);
+        self.send(&reply);
+    }
+
+    // Breakpoints of the client go away with it
+    fn detach(&mut self, debugger: &mut Debugger) {
+        for (_, id) in self.breakpoints.drain() {
+            debugger.remove_breakpoint(id);
+        }
+    }
+
+    fn quit(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugger::{test_debugger, RemoteDebugger};
+    use crate::remote::{RemoteServer, Session as _};
+
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    fn fixture() -> (Session, Debugger) {
+        (Session::new(), test_debugger())
+    }
+
+    fn reply(session: &mut Session, debugger: &mut Debugger, packet: &str) -> String {
+        match session.handle(debugger, packet) {
+            Response::Reply(reply) => reply,
+            response => panic!("{:?} for {}", response, packet),
+        }
+    }
+
+    #[test]
+    fn framing() {
+        assert_eq!(frame("OK"), "$OK#9a");
+        let mut buffer = b"+$g#67$m10".to_vec();
+        assert!(matches!(next_input(&mut buffer), Some(Input::Packet(p)) if p == "g"));
+        assert!(next_input(&mut buffer).is_none());
+        buffer.extend_from_slice(b"00,2#00\x03");
+        assert!(matches!(next_input(&mut buffer), Some(Input::Corrupted)));
+        assert!(matches!(next_input(&mut buffer), Some(Input::Interrupt)));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn registers() {
+        let (mut session, mut debugger) = fixture();
+        debugger.c64.cpu.reg.a = 0x12;
+        assert_eq!(reply(&mut session, &mut debugger, "g"), "120000ff001000");
+        assert_eq!(reply(&mut session, &mut debugger, "G010203f000c081"), "OK");
+        let cpu = &debugger.c64.cpu;
+        assert_eq!((cpu.reg.a, cpu.reg.x, cpu.reg.y), (1, 2, 3));
+        assert_eq!((cpu.sp(), cpu.pc()), (0xf0, 0xc000));
+        assert_eq!(reply(&mut session, &mut debugger, "p4"), "00c0");
+        assert_eq!(reply(&mut session, &mut debugger, "P4=0010"), "OK");
+        assert_eq!(reply(&mut session, &mut debugger, "P1=ab"), "OK");
+        assert_eq!(debugger.c64.cpu.pc(), 0x1000);
+        assert_eq!(debugger.c64.cpu.reg.x, 0xab);
+        assert_eq!(reply(&mut session, &mut debugger, "p6"), "E01");
+        assert_eq!(reply(&mut session, &mut debugger, "G0102"), "E01");
+    }
+
+    #[test]
+    fn memory() {
+        let (mut session, mut debugger) = fixture();
+        assert_eq!(reply(&mut session, &mut debugger, "m1000,3"), "e8e84c");
+        assert_eq!(reply(&mut session, &mut debugger, "M2000,2:a901"), "OK");
+        assert_eq!(debugger.c64.bus.peek_byte(0x2001), 0x01);
+        assert_eq!(reply(&mut session, &mut debugger, "M2000,2:a9"), "E01");
+        assert_eq!(reply(&mut session, &mut debugger, "mzz,1"), "E01");
+    }
+
+    #[test]
+    fn step_continue_and_breakpoints() {
+        let (mut session, mut debugger) = fixture();
+        assert_eq!(reply(&mut session, &mut debugger, "s"), "S05");
+        assert_eq!(debugger.c64.cpu.pc(), 0x1001);
+        assert_eq!(reply(&mut session, &mut debugger, "Z0,1002,1"), "OK");
+        assert_eq!(session.handle(&mut debugger, "c"), Response::Resumed);
+        assert!(session.running);
+        let reason = debugger.run(1000);
+        assert_eq!(session.stop_reply(&debugger, reason), "S05");
+        assert_eq!(debugger.c64.cpu.pc(), 0x1002);
+        assert_eq!(reply(&mut session, &mut debugger, "z0,1002,1"), "OK");
+        assert_eq!(debugger.breakpoints().count(), 0);
+        assert_eq!(reply(&mut session, &mut debugger, "s1000"), "S05");
+        assert_eq!(debugger.c64.cpu.pc(), 0x1001);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let (mut session, mut debugger) = fixture();
+        // STA $d020, JMP $2000
+        debugger
+            .c64
+            .ram
+            .borrow_mut()
+            .set_memory(&[0x8d, 0x20, 0xd0, 0x4c, 0x00, 0x20], 0x2000)
+            .unwrap();
+        debugger.c64.cpu.set_pc(0x2000);
+        assert_eq!(reply(&mut session, &mut debugger, "Z2,d020,1"), "OK");
+        session.handle(&mut debugger, "c");
+        let reason = debugger.run(1000);
+        assert_eq!(session.stop_reply(&debugger, reason), "T05watch:d020;");
+        assert_eq!(reply(&mut session, &mut debugger, "?"), "T05watch:d020;");
+        session.detach(&mut debugger);
+        assert_eq!(debugger.breakpoints().count(), 0);
+    }
+
+    #[test]
+    fn queries() {
+        let (mut session, mut debugger) = fixture();
+        assert!(reply(&mut session, &mut debugger, "qSupported:swbreak+").contains("qXfer"));
+        let xml = reply(
+            &mut session,
+            &mut debugger,
+            "qXfer:features:read:target.xml:0,20",
+        );
+        assert_eq!(xml, format!("m{}", &TARGET_XML[..0x20]));
+        let xml = reply(
+            &mut session,
+            &mut debugger,
+            "qXfer:features:read:target.xml:20,1000",
+        );
+        assert_eq!(xml, format!("l{}", &TARGET_XML[0x20..]));
+        assert_eq!(reply(&mut session, &mut debugger, "vCont?"), "");
+        assert_eq!(reply(&mut session, &mut debugger, "QStartNoAckMode"), "OK");
+        assert!(session.no_ack);
+        assert_eq!(
+            session.handle(&mut debugger, "D"),
+            Response::Close(Some("OK".to_string()))
+        );
+    }
+
+    #[test]
+    fn client_over_tcp() {
+        let (_, mut debugger) = fixture();
+        let mut server = RemoteServer::<Session>::bind(0).unwrap();
+        let mut client = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        client.write_all(b"$m1000,2#8c").unwrap();
+        let expected = b"+$e8e8#3a";
+        // Connection and packet may need a few polls to arrive
+        let mut received = vec![];
+        client.set_nonblocking(true).unwrap();
+        for _ in 0..100 {
+            server.poll(&mut debugger);
+            let mut buffer = [0; 64];
+            if let Ok(count) = client.read(&mut buffer) {
+                received.extend_from_slice(&buffer[..count]);
+            }
+            if received.len() >= expected.len() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        assert_eq!(received, expected);
+        assert!(server.halted());
+
+        drop(client);
+        for _ in 0..100 {
+            server.poll(&mut debugger);
+            if !server.halted() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        assert!(!server.halted());
+    }
+}
@@ -1,6 +1,20 @@
+use crate::joystick::JoystickInput;
+use crate::keyboard::C64Key;
+use crate::keymap::{self, JoystickKeys, Keymap};
+use crate::palette::Palette;
+use crate::vic::PAL_CLOCK;
+
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::controller::{Axis, GameController};
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{Keycode, Mod};
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::Texture;
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum Color {
     Black,
     White,
@@ -20,86 +34,621 @@ pub enum Color {
     LightGray,
 }
 
+const COLORS: [Color; 16] = [
+    Color::Black,
+    Color::White,
+    Color::Red,
+    Color::Cyan,
+    Color::Pink,
+    Color::Green,
+    Color::Blue,
+    Color::Yellow,
+    Color::Orange,
+    Color::Brown,
+    Color::LightRed,
+    Color::DarkGrey,
+    Color::MediumGrey,
+    Color::LightGreen,
+    Color::LightBlue,
+    Color::LightGray,
+];
+
+impl Color {
+    // VIC color registers use only low nibble
+    pub fn from_index(index: u8) -> Self {
+        COLORS[(index & 0x0f) as usize]
+    }
+}
+
+// Picture produced by VIC, one byte per pixel with color index (see Color).
+pub struct FrameBuffer {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl FrameBuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![0; width * height],
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Color {
+        Color::from_index(self.pixels[y * self.width + x])
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, color: u8) {
+        self.pixels[y * self.width + x] = color;
+    }
+
+    pub fn crop(&self, left: usize, top: usize, width: usize, height: usize) -> FrameBuffer {
+        let mut result = FrameBuffer::new(width, height);
+        for y in 0..height {
+            let from = (top + y) * self.width + left;
+            result.pixels[y * width..(y + 1) * width]
+                .copy_from_slice(&self.pixels[from..from + width]);
+        }
+        result
+    }
+}
+
 pub trait Monitor {
-    fn clean(&mut self);
-    fn set_symbol(&mut self, x: u16, y: u16, s: char, color: Color);
+    // Called by VIC when the whole frame (including border) is drawn
+    fn draw_frame(&mut self, frame: &FrameBuffer);
+}
+
+// For headless runs and tests
+pub struct NullMonitor {}
+
+impl Monitor for NullMonitor {
+    fn draw_frame(&mut self, _frame: &FrameBuffer) {}
+}
+
+pub trait AudioSink {
+    // Called by SID every cycle
+    fn push_sample(&mut self, sample: i16);
+}
+
+// Converts samples from SID clock rate to host rate. Every output sample is an average
+// of input samples since the previous one, which also filters out most of what is
+// above the host Nyquist frequency.
+pub struct Resampler {
+    input_rate: u32,
+    output_rate: u32,
+    // Output rate times input samples since the last output sample
+    phase: u32,
+    sum: i32,
+    count: i32,
+}
+
+impl Resampler {
+    pub fn new(input_rate: u32, output_rate: u32) -> Self {
+        Self {
+            input_rate,
+            output_rate,
+            phase: 0,
+            sum: 0,
+            count: 0,
+        }
+    }
+
+    pub fn push(&mut self, sample: i16) -> Option<i16> {
+        self.sum += sample as i32;
+        self.count += 1;
+        self.phase += self.output_rate;
+        if self.phase < self.input_rate {
+            return None;
+        }
+        self.phase -= self.input_rate;
+        let output = self.sum / self.count;
+        self.sum = 0;
+        self.count = 0;
+        Some(output as i16)
+    }
+
+    pub fn set_input_rate(&mut self, input_rate: u32) {
+        *self = Self::new(input_rate, self.output_rate);
+    }
+}
+
+const AUDIO_RATE: i32 = 48000;
+// Samples are queued in chunks of this size
+const AUDIO_CHUNK: usize = 512;
+// How much audio we try to keep queued, more means more latency, less means crackles
+const AUDIO_TARGET_LATENCY_MS: u32 = 60;
+
+struct SdlAudio {
+    queue: AudioQueue<i16>,
+    // In warp mode there would be too much audio, so it is dropped
+    muted: bool,
+    resampler: Resampler,
+    chunk: Vec<i16>,
+    target_level: u32,
+}
+
+impl SdlAudio {
+    fn new(audio_subsystem: &sdl2::AudioSubsystem) -> Result<Self, String> {
+        let desired = AudioSpecDesired {
+            freq: Some(AUDIO_RATE),
+            channels: Some(1),
+            samples: Some(AUDIO_CHUNK as u16),
+        };
+        let queue = audio_subsystem.open_queue::<i16, _>(None, &desired)?;
+        // Device can give us another rate (usually 44100)
+        let rate = queue.spec().freq as u32;
+        queue.resume();
+        Ok(Self {
+            queue,
+            muted: false,
+            // Until machine clock is set with SdlHandler::set_clock_frequency
+            resampler: Resampler::new(PAL_CLOCK, rate),
+            chunk: Vec::with_capacity(AUDIO_CHUNK),
+            target_level: rate * AUDIO_TARGET_LATENCY_MS / 1000,
+        })
+    }
+
+    fn push_sample(&mut self, sample: i16) {
+        if self.muted {
+            return;
+        }
+        if let Some(sample) = self.resampler.push(sample) {
+            self.chunk.push(sample);
+        }
+        if self.chunk.len() == AUDIO_CHUNK {
+            if let Err(err) = self.queue.queue_audio(&self.chunk) {
+                eprintln!("Can't queue audio: {}", err);
+            }
+            self.chunk.clear();
+        }
+    }
+
+    // Queued samples relative to target level
+    fn level(&self) -> f64 {
+        let queued = self.queue.size() / std::mem::size_of::<i16>() as u32;
+        queued as f64 / self.target_level as f64
+    }
+}
+
+// Requests from the user to the emulator, produced by SdlHandler::process_events
+#[derive(Debug, PartialEq)]
+pub enum HostEvent {
+    Quit,
+    Screenshot {
+        with_border: bool,
+    },
+    // id is the host key, all keys are released by KeyUp with the same id
+    KeyDown {
+        id: i32,
+        keys: Vec<C64Key>,
+    },
+    KeyUp {
+        id: i32,
+    },
+    // port is 0 for control port 1 and 1 for port 2
+    Joystick {
+        port: usize,
+        input: JoystickInput,
+        pressed: bool,
+    },
+    SwapJoysticks,
+    ToggleRecording,
+    ToggleWarp,
+    TogglePause,
+    EnterMonitor,
+    // Text from clipboard to type
+    Paste(String),
+}
+
+const WINDOW_SCALE: u32 = 2;
+pub const WINDOW_TITLE: &str = "C64 emulator";
+
+// How often emulation speed is updated
+const SPEED_INTERVAL: Duration = Duration::from_secs(1);
+
+// Emulation speed relative to the real machine
+pub struct SpeedMeter {
+    start: Instant,
+    // Real machine time of frames run since start
+    emulated: Duration,
 }
 
-const MAX_LINES: u32 = 25;
-const MAX_ROWS: u32 = 40;
+impl SpeedMeter {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            start: now,
+            emulated: Duration::ZERO,
+        }
+    }
+
+    // Speed in percent, once in SPEED_INTERVAL
+    pub fn frame_done(&mut self, frame_duration: Duration, now: Instant) -> Option<u32> {
+        self.emulated += frame_duration;
+        let elapsed = now - self.start;
+        if elapsed < SPEED_INTERVAL {
+            return None;
+        }
+        let percent = self.emulated.as_secs_f64() / elapsed.as_secs_f64() * 100.0;
+        self.start = now;
+        self.emulated = Duration::ZERO;
+        Some(percent.round() as u32)
+    }
+}
 
 pub struct SdlHandler {
     pub canvas: sdl2::render::WindowCanvas,
     event_pump: sdl2::EventPump,
 
-    char_size_px: u8,
+    screen: Texture,
+    // RGB24 pixels, size of one texture line
+    pitch: usize,
+    palette: Palette,
+
+    keymap: Keymap,
+    // Host key which is waiting for its text input event in symbolic keymap
+    text_key: Option<i32>,
+
+    joystick_keys: JoystickKeys,
+    // Port of keyboard joystick and the first game controller, others use another one
+    joystick_port: usize,
+    controller_subsystem: sdl2::GameControllerSubsystem,
+    controllers: Vec<GameController>,
+    // Last position of controller axes, to tell which directions changed
+    axes: HashMap<(u32, Axis), i16>,
+    clipboard: sdl2::clipboard::ClipboardUtil,
+
+    // None if there is no audio device, emulator works silently then
+    audio: Option<SdlAudio>,
 }
 
 impl SdlHandler {
-    pub fn new() -> Self {
+    pub fn new(
+        screen_width: usize,
+        screen_height: usize,
+        palette: Palette,
+        keymap: Keymap,
+        joystick_keys: JoystickKeys,
+        joystick_port: usize,
+    ) -> Self {
         let sdl_context = sdl2::init().unwrap();
         let event_pump = sdl_context.event_pump().unwrap();
+        // Already connected controllers are reported with ControllerDeviceAdded events
+        let controller_subsystem = sdl_context.game_controller().unwrap();
+        let audio = sdl_context
+            .audio()
+            .and_then(|audio_subsystem| SdlAudio::new(&audio_subsystem))
+            .map_err(|err| eprintln!("Can't open audio device: {}", err))
+            .ok();
 
         let video = sdl_context.video().unwrap();
+        let clipboard = video.clipboard();
         {
             let gl_attr = video.gl_attr();
             gl_attr.set_context_profile(sdl2::video::GLProfile::Core);
             gl_attr.set_context_version(3, 0);
         }
 
-        let char_size_px = 16;
-        let screen_x = MAX_ROWS * char_size_px as u32;
-        let screen_y = MAX_LINES * char_size_px as u32;
         let window = video
-            .window("C64 emulator", screen_x, screen_y)
+            .window(
+                WINDOW_TITLE,
+                screen_width as u32 * WINDOW_SCALE,
+                screen_height as u32 * WINDOW_SCALE,
+            )
             .position_centered()
             .opengl()
             .build()
             .unwrap();
 
         let canvas = window.into_canvas().build().unwrap();
-
+        let screen = canvas
+            .texture_creator()
+            .create_texture_streaming(
+                PixelFormatEnum::RGB24,
+                screen_width as u32,
+                screen_height as u32,
+            )
+            .unwrap();
 
         Self {
             canvas,
             event_pump,
-            char_size_px,
+            screen,
+            pitch: screen_width * 3,
+            palette,
+            keymap,
+            text_key: None,
+            joystick_keys,
+            joystick_port,
+            controller_subsystem,
+            controllers: vec![],
+            axes: HashMap::new(),
+            clipboard,
+            audio,
+        }
+    }
+
+    // SID clock, which audio is resampled from
+    pub fn set_clock_frequency(&mut self, clock_frequency: u32) {
+        if let Some(audio) = &mut self.audio {
+            audio.resampler.set_input_rate(clock_frequency);
+        }
+    }
+
+    pub fn set_warp(&mut self, warp: bool) {
+        if let Some(audio) = &mut self.audio {
+            audio.muted = warp;
+            audio.queue.clear();
+        }
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        if let Err(err) = self.canvas.window_mut().set_title(title) {
+            eprintln!("Can't set window title: {}", err);
+        }
+    }
+
+    // Multiplier for frame duration. Host and emulated clocks drift apart, so we slow
+    // down when audio piles up in the queue and speed up when it is about to run out.
+    pub fn speed_adjustment(&self) -> f64 {
+        match &self.audio {
+            Some(audio) => audio.level().clamp(0.5, 2.0),
+            None => 1.0,
         }
     }
 
     pub fn render_screen(&mut self) {
+        self.canvas.clear();
+        self.canvas.copy(&self.screen, None, None).unwrap();
         self.canvas.present();
+    }
 
-        self.canvas.window().gl_swap_window();
+    fn controller_port(&self, which: u32) -> Option<usize> {
+        let index = self
+            .controllers
+            .iter()
+            .position(|controller| controller.instance_id() == which)?;
+        if index == 0 {
+            Some(self.joystick_port)
+        } else {
+            Some(1 - self.joystick_port)
+        }
     }
 
-    pub fn process_events(&mut self) -> bool {
-        for event in self.event_pump.poll_iter() {
+    // F12 saves screenshot with border, Shift+F12 without, F9 swaps joystick ports,
+    // F10 pastes clipboard, F11 starts and stops audio recording, Alt+W toggles warp,
+    // Alt+M enters monitor, Pause pauses emulation. Other keys go to C64 keyboard or
+    // joystick.
+    pub fn process_events(&mut self) -> Vec<HostEvent> {
+        let mut events = vec![];
+        let sdl_events: Vec<Event> = self.event_pump.poll_iter().collect();
+        for event in sdl_events {
             match event {
                 Event::Quit { .. }
                 | Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
                 } => {
-                    return false;
+                    events.push(HostEvent::Quit);
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F12),
+                    keymod,
+                    repeat: false,
+                    ..
+                } => {
+                    let shift = keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+                    events.push(HostEvent::Screenshot {
+                        with_border: !shift,
+                    });
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F9),
+                    repeat: false,
+                    ..
+                } => {
+                    self.joystick_port = 1 - self.joystick_port;
+                    events.push(HostEvent::SwapJoysticks);
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::W),
+                    keymod,
+                    repeat: false,
+                    ..
+                } if keymod.intersects(Mod::LALTMOD | Mod::RALTMOD) => {
+                    events.push(HostEvent::ToggleWarp)
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::M),
+                    keymod,
+                    repeat: false,
+                    ..
+                } if keymod.intersects(Mod::LALTMOD | Mod::RALTMOD) => {
+                    events.push(HostEvent::EnterMonitor)
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::Pause),
+                    repeat: false,
+                    ..
+                } => events.push(HostEvent::TogglePause),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F11),
+                    repeat: false,
+                    ..
+                } => events.push(HostEvent::ToggleRecording),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F10),
+                    repeat: false,
+                    ..
+                } => match self.clipboard.clipboard_text() {
+                    Ok(text) => events.push(HostEvent::Paste(text)),
+                    Err(err) => eprintln!("Can't read clipboard: {}", err),
+                },
+                Event::KeyDown {
+                    scancode: Some(scancode),
+                    repeat: false,
+                    ..
+                } if self.joystick_keys.input(scancode).is_some() => {
+                    events.push(HostEvent::Joystick {
+                        port: self.joystick_port,
+                        input: self.joystick_keys.input(scancode).unwrap(),
+                        pressed: true,
+                    });
+                }
+                Event::KeyUp {
+                    scancode: Some(scancode),
+                    ..
+                } if self.joystick_keys.input(scancode).is_some() => {
+                    events.push(HostEvent::Joystick {
+                        port: self.joystick_port,
+                        input: self.joystick_keys.input(scancode).unwrap(),
+                        pressed: false,
+                    });
+                }
+                Event::KeyDown {
+                    scancode: Some(scancode),
+                    keymod,
+                    repeat: false,
+                    ..
+                } => {
+                    let id = scancode as i32;
+                    let shift = keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+                    match self.keymap.key_down(scancode, shift) {
+                        Some(keys) => events.push(HostEvent::KeyDown { id, keys }),
+                        None => self.text_key = Some(id),
+                    }
+                }
+                Event::TextInput { text, .. } => {
+                    // Text can come without key press (e.g. from input method), ignore it
+                    let keys = text.chars().next().and_then(keymap::text_keys);
+                    if let (Some(id), Some(keys)) = (self.text_key.take(), keys) {
+                        events.push(HostEvent::KeyDown { id, keys });
+                    }
+                }
+                Event::KeyUp {
+                    scancode: Some(scancode),
+                    ..
+                } => {
+                    events.push(HostEvent::KeyUp {
+                        id: scancode as i32,
+                    });
+                }
+                Event::ControllerDeviceAdded { which, .. } => {
+                    match self.controller_subsystem.open(which) {
+                        Ok(controller) => {
+                            println!("Game controller connected: {}", controller.name());
+                            self.controllers.push(controller);
+                        }
+                        Err(err) => eprintln!("Can't open game controller: {}", err),
+                    }
+                }
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    self.controllers
+                        .retain(|controller| controller.instance_id() != which);
+                    self.axes.retain(|&(id, _), _| id != which);
+                }
+                Event::ControllerButtonDown { which, button, .. }
+                | Event::ControllerButtonUp { which, button, .. } => {
+                    let pressed = matches!(event, Event::ControllerButtonDown { .. });
+                    if let (Some(port), Some(input)) = (
+                        self.controller_port(which),
+                        keymap::controller_button(button),
+                    ) {
+                        events.push(HostEvent::Joystick {
+                            port,
+                            input,
+                            pressed,
+                        });
+                    }
+                }
+                Event::ControllerAxisMotion {
+                    which, axis, value, ..
+                } => {
+                    let previous = self.axes.insert((which, axis), value).unwrap_or(0);
+                    if let (Some(port), Some(inputs)) = (
+                        self.controller_port(which),
+                        keymap::controller_axis(axis, previous, value),
+                    ) {
+                        for (input, pressed) in inputs {
+                            events.push(HostEvent::Joystick {
+                                port,
+                                input,
+                                pressed,
+                            });
+                        }
+                    }
                 }
                 _ => {}
             }
         }
 
-        true
+        events
+    }
+}
+
+impl AudioSink for SdlHandler {
+    fn push_sample(&mut self, sample: i16) {
+        if let Some(audio) = &mut self.audio {
+            audio.push_sample(sample);
+        }
     }
 }
 
 impl Monitor for SdlHandler {
-    fn clean(&mut self) {
-        todo!();
+    fn draw_frame(&mut self, frame: &FrameBuffer) {
+        let pitch = self.pitch;
+        let palette = &self.palette;
+        self.screen
+            .with_lock(None, |buffer: &mut [u8], texture_pitch: usize| {
+                for y in 0..frame.height {
+                    let line = &mut buffer[y * texture_pitch..y * texture_pitch + pitch];
+                    for x in 0..frame.width {
+                        let (r, g, b) = palette.rgb(frame.get(x, y));
+                        line[x * 3] = r;
+                        line[x * 3 + 1] = g;
+                        line[x * 3 + 2] = b;
+                    }
+                }
+            })
+            .unwrap();
     }
+}
 
-    fn set_symbol(&mut self, x: u16, y: u16, s: char, color: Color) {
-        let color = sdl2::pixels::Color::RGB(x as u8, x as u8, x as u8);
-        println!("color: {:?}", color);
-        self.canvas.set_draw_color(color);
-        self.canvas.clear();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speed_meter() {
+        let start = Instant::now();
+        let frame = Duration::from_millis(20);
+        let mut meter = SpeedMeter::new(start);
+        for i in 1..100 {
+            assert_eq!(meter.frame_done(frame, start + frame * i / 2), None);
+        }
+        // 100 frames in a second is twice as fast as real machine
+        assert_eq!(meter.frame_done(frame, start + frame * 50), Some(200));
+        assert_eq!(meter.frame_done(frame, start + frame * 51), None);
+    }
+
+    #[test]
+    fn resampler_rate() {
+        let mut resampler = Resampler::new(PAL_CLOCK, 44100);
+        let outputs = (0..PAL_CLOCK)
+            .filter_map(|_| resampler.push(100))
+            .collect::<Vec<_>>();
+        assert_eq!(outputs.len(), 44100);
+        assert!(outputs.iter().all(|&sample| sample == 100));
+    }
+
+    #[test]
+    fn resampler_averages() {
+        let mut resampler = Resampler::new(PAL_CLOCK, PAL_CLOCK / 4);
+        let outputs = [0, 100, 200, 300, -400, -400, -400, -400]
+            .iter()
+            .filter_map(|&sample| resampler.push(sample))
+            .collect::<Vec<_>>();
+        assert_eq!(outputs, vec![150, -400]);
     }
 }
@@ -0,0 +1,58 @@
+// Digital joystick in C64 control port. Switches pull CIA1 port lines low: port 1 is
+// connected to port B and port 2 to port A, the same lines keyboard uses.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum JoystickInput {
+    Up,
+    Down,
+    Left,
+    Right,
+    Fire,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct Joystick {
+    // Bit is set for pressed switch, in port order
+    pressed: u8,
+}
+
+impl Joystick {
+    pub fn set(&mut self, input: JoystickInput, pressed: bool) {
+        let bit = 1 << input as u8;
+        if pressed {
+            self.pressed |= bit;
+        } else {
+            self.pressed &= !bit;
+        }
+    }
+
+    // Value on port lines, active low
+    pub fn port_value(&self) -> u8 {
+        !self.pressed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn port_value() {
+        let mut joystick = Joystick::default();
+        assert_eq!(joystick.port_value(), 0xff);
+
+        joystick.set(JoystickInput::Up, true);
+        joystick.set(JoystickInput::Fire, true);
+        assert_eq!(joystick.port_value(), 0b1110_1110);
+
+        joystick.set(JoystickInput::Up, false);
+        assert_eq!(joystick.port_value(), 0b1110_1111);
+    }
+
+    #[test]
+    fn directions_order() {
+        let mut joystick = Joystick::default();
+        joystick.set(JoystickInput::Right, true);
+        assert_eq!(joystick.port_value(), 0b1111_0111);
+    }
+}
@@ -0,0 +1,298 @@
+// C64 keyboard is a matrix of 8x8 keys. CIA1 port A selects columns (low bit means
+// column is scanned) and port B reads rows of pressed keys (low bit when pressed).
+// It works the other way around as well, some programs scan rows through port B.
+// RESTORE is not part of the matrix, it is wired to NMI.
+
+use crate::petscii;
+
+use std::collections::HashMap;
+
+// In matrix order: value is column * 8 + row
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum C64Key {
+    Delete,
+    Return,
+    CursorRight,
+    F7,
+    F1,
+    F3,
+    F5,
+    CursorDown,
+
+    Num3,
+    W,
+    A,
+    Num4,
+    Z,
+    S,
+    E,
+    LeftShift,
+
+    Num5,
+    R,
+    D,
+    Num6,
+    C,
+    F,
+    T,
+    X,
+
+    Num7,
+    Y,
+    G,
+    Num8,
+    B,
+    H,
+    U,
+    V,
+
+    Num9,
+    I,
+    J,
+    Num0,
+    M,
+    K,
+    O,
+    N,
+
+    Plus,
+    P,
+    L,
+    Minus,
+    Period,
+    Colon,
+    At,
+    Comma,
+
+    Pound,
+    Asterisk,
+    Semicolon,
+    Home,
+    RightShift,
+    Equals,
+    UpArrow,
+    Slash,
+
+    Num1,
+    LeftArrow,
+    Control,
+    Num2,
+    Space,
+    Commodore,
+    Q,
+    RunStop,
+
+    Restore,
+}
+
+use C64Key::*;
+
+#[rustfmt::skip]
+const LETTERS: [C64Key; 26] = [
+    A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+];
+
+const DIGITS: [C64Key; 10] = [Num0, Num1, Num2, Num3, Num4, Num5, Num6, Num7, Num8, Num9];
+
+const FUNCTION_KEYS: [C64Key; 4] = [F1, F3, F5, F7];
+
+impl C64Key {
+    // (column, row), None for RESTORE
+    pub fn matrix_position(self) -> Option<(usize, usize)> {
+        if self == Restore {
+            return None;
+        }
+        let index = self as usize;
+        Some((index / 8, index % 8))
+    }
+
+    // Key which types given PETSCII code and whether shift is needed for it
+    pub fn from_petscii(code: u8) -> Option<(C64Key, bool)> {
+        let key = match code {
+            0x0d => (Return, false),
+            0x11 => (CursorDown, false),
+            0x91 => (CursorDown, true),
+            0x1d => (CursorRight, false),
+            0x9d => (CursorRight, true),
+            0x13 => (Home, false),
+            0x93 => (Home, true),
+            0x14 => (Delete, false),
+            0x94 => (Delete, true),
+            0x85..=0x88 => (FUNCTION_KEYS[(code - 0x85) as usize], false),
+            0x89..=0x8c => (FUNCTION_KEYS[(code - 0x89) as usize], true),
+            0x20 => (Space, false),
+            0x21..=0x29 => (DIGITS[(code - 0x20) as usize], true),
+            0x2a => (Asterisk, false),
+            0x2b => (Plus, false),
+            0x2c => (Comma, false),
+            0x2d => (Minus, false),
+            0x2e => (Period, false),
+            0x2f => (Slash, false),
+            0x30..=0x39 => (DIGITS[(code - 0x30) as usize], false),
+            0x3a => (Colon, false),
+            0x3b => (Semicolon, false),
+            0x3c => (Comma, true),
+            0x3d => (Equals, false),
+            0x3e => (Period, true),
+            0x3f => (Slash, true),
+            0x40 => (At, false),
+            0x41..=0x5a => (LETTERS[(code - 0x41) as usize], false),
+            0x5b => (Colon, true),
+            0x5c => (Pound, false),
+            0x5d => (Semicolon, true),
+            0x5e => (UpArrow, false),
+            0x5f => (LeftArrow, false),
+            0xc1..=0xda => (LETTERS[(code - 0xc1) as usize], true),
+            0xde => (UpArrow, true),
+            _ => return None,
+        };
+        Some(key)
+    }
+
+    // Key which types given character and whether shift is needed for it
+    pub fn from_char(ch: char) -> Option<(C64Key, bool)> {
+        petscii::from_char(ch).and_then(C64Key::from_petscii)
+    }
+}
+
+pub struct Keyboard {
+    // Host keys can press several C64 keys at once (e.g. shift and 2 for '"'), so
+    // presses are remembered by id of host key and released together
+    pressed: HashMap<i32, Vec<C64Key>>,
+    // Bit is set for pressed key, indexed by column
+    matrix: [u8; 8],
+    restore: bool,
+}
+
+impl Keyboard {
+    pub fn new() -> Self {
+        Self {
+            pressed: HashMap::new(),
+            matrix: [0; 8],
+            restore: false,
+        }
+    }
+
+    pub fn press(&mut self, id: i32, keys: Vec<C64Key>) {
+        self.pressed.insert(id, keys);
+        self.update();
+    }
+
+    pub fn release(&mut self, id: i32) {
+        self.pressed.remove(&id);
+        self.update();
+    }
+
+    // RESTORE key state, connected to NMI
+    pub fn restore(&self) -> bool {
+        self.restore
+    }
+
+    fn update(&mut self) {
+        self.matrix = [0; 8];
+        self.restore = false;
+        for key in self.pressed.values().flatten() {
+            match key.matrix_position() {
+                Some((column, row)) => self.matrix[column] |= 1 << row,
+                None => self.restore = true,
+            }
+        }
+    }
+
+    // Port B input for given port A output, both active low
+    pub fn read_rows(&self, columns: u8) -> u8 {
+        let mut rows = 0xff;
+        for (column, pressed) in self.matrix.iter().enumerate() {
+            if columns & (1 << column) == 0 {
+                rows &= !pressed;
+            }
+        }
+        rows
+    }
+
+    // Port A input for given port B output, both active low
+    pub fn read_columns(&self, rows: u8) -> u8 {
+        let mut columns = 0xff;
+        for (column, pressed) in self.matrix.iter().enumerate() {
+            if pressed & !rows != 0 {
+                columns &= !(1 << column);
+            }
+        }
+        columns
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matrix_position() {
+        assert_eq!(Delete.matrix_position(), Some((0, 0)));
+        assert_eq!(LeftShift.matrix_position(), Some((1, 7)));
+        assert_eq!(Slash.matrix_position(), Some((6, 7)));
+        assert_eq!(RunStop.matrix_position(), Some((7, 7)));
+        assert_eq!(Restore.matrix_position(), None);
+    }
+
+    #[test]
+    fn scan_columns() {
+        let mut keyboard = Keyboard::new();
+        keyboard.press(1, vec![A]);
+        assert_eq!(keyboard.read_rows(0xff), 0xff);
+        assert_eq!(keyboard.read_rows(!0b10), !0b100);
+        assert_eq!(keyboard.read_rows(0x00), !0b100);
+        assert_eq!(keyboard.read_rows(!0b100), 0xff);
+
+        keyboard.release(1);
+        assert_eq!(keyboard.read_rows(0x00), 0xff);
+    }
+
+    #[test]
+    fn scan_rows() {
+        let mut keyboard = Keyboard::new();
+        keyboard.press(1, vec![Space]);
+        assert_eq!(keyboard.read_columns(!0b1_0000), !0b1000_0000);
+        assert_eq!(keyboard.read_columns(!0b10), 0xff);
+    }
+
+    #[test]
+    fn host_key_presses_several_keys() {
+        let mut keyboard = Keyboard::new();
+        keyboard.press(1, vec![LeftShift, Num2]);
+        keyboard.press(2, vec![Q]);
+        assert_eq!(keyboard.read_rows(!0b10), !0b1000_0000);
+        assert_eq!(keyboard.read_rows(!0b1000_0000), !0b0100_1000);
+
+        keyboard.release(1);
+        assert_eq!(keyboard.read_rows(!0b10), 0xff);
+        assert_eq!(keyboard.read_rows(!0b1000_0000), !0b0100_0000);
+    }
+
+    #[test]
+    fn restore_is_not_in_matrix() {
+        let mut keyboard = Keyboard::new();
+        keyboard.press(1, vec![Restore]);
+        assert!(keyboard.restore());
+        assert_eq!(keyboard.read_rows(0x00), 0xff);
+    }
+
+    #[test]
+    fn char_to_key() {
+        assert_eq!(C64Key::from_char('a'), Some((A, false)));
+        assert_eq!(C64Key::from_char('Z'), Some((Z, true)));
+        assert_eq!(C64Key::from_char('"'), Some((Num2, true)));
+        assert_eq!(C64Key::from_char(')'), Some((Num9, true)));
+        assert_eq!(C64Key::from_char(':'), Some((Colon, false)));
+        assert_eq!(C64Key::from_char('?'), Some((Slash, true)));
+        assert_eq!(C64Key::from_char('~'), None);
+    }
+
+    #[test]
+    fn petscii_to_key() {
+        assert_eq!(C64Key::from_petscii(0x0d), Some((Return, false)));
+        assert_eq!(C64Key::from_petscii(0x93), Some((Home, true)));
+        assert_eq!(C64Key::from_petscii(0x8c), Some((F7, true)));
+        assert_eq!(C64Key::from_petscii(0x00), None);
+    }
+}
@@ -0,0 +1,308 @@
+// Translation of host keys and game controllers to C64 keys and joysticks. Positional
+// keymap keeps keys where they are on the C64 keyboard, e.g. host '=' is C64 '-'.
+// Symbolic keymap types the character printed on host key, pressing or releasing C64
+// shift as needed.
+
+use crate::joystick::JoystickInput;
+use crate::keyboard::C64Key::{self, *};
+
+use sdl2::controller::{Axis, Button};
+use sdl2::keyboard::Scancode;
+
+// How far analog stick should be moved to count as pressed direction
+const STICK_DEAD_ZONE: i16 = 16384;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Keymap {
+    Positional,
+    Symbolic,
+}
+
+impl Keymap {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "positional" => Some(Keymap::Positional),
+            "symbolic" => Some(Keymap::Symbolic),
+            _ => None,
+        }
+    }
+
+    // C64 keys for pressed host key. In symbolic keymap character keys return None,
+    // they are pressed by text_keys when text input event comes.
+    pub fn key_down(&self, scancode: Scancode, shift: bool) -> Option<Vec<C64Key>> {
+        match self {
+            Keymap::Positional => {
+                special_keys(scancode).or_else(|| positional_key(scancode).map(|key| vec![key]))
+            }
+            Keymap::Symbolic => {
+                let mut keys = special_keys(scancode)?;
+                if shift && !keys.contains(&LeftShift) {
+                    keys.insert(0, LeftShift);
+                }
+                Some(keys)
+            }
+        }
+    }
+}
+
+// Host keys which emulate joystick. They are taken away from C64 keyboard.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum JoystickKeys {
+    // 8, 2, 4, 6 and 0 or 5 for fire
+    Numpad,
+    // Arrows and right control for fire
+    Cursor,
+    None,
+}
+
+impl JoystickKeys {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "numpad" => Some(JoystickKeys::Numpad),
+            "cursor" => Some(JoystickKeys::Cursor),
+            "none" => Some(JoystickKeys::None),
+            _ => None,
+        }
+    }
+
+    pub fn input(&self, scancode: Scancode) -> Option<JoystickInput> {
+        let input = match (self, scancode) {
+            (JoystickKeys::Numpad, Scancode::Kp8) => JoystickInput::Up,
+            (JoystickKeys::Numpad, Scancode::Kp2) => JoystickInput::Down,
+            (JoystickKeys::Numpad, Scancode::Kp4) => JoystickInput::Left,
+            (JoystickKeys::Numpad, Scancode::Kp6) => JoystickInput::Right,
+            (JoystickKeys::Numpad, Scancode::Kp0 | Scancode::Kp5) => JoystickInput::Fire,
+            (JoystickKeys::Cursor, Scancode::Up) => JoystickInput::Up,
+            (JoystickKeys::Cursor, Scancode::Down) => JoystickInput::Down,
+            (JoystickKeys::Cursor, Scancode::Left) => JoystickInput::Left,
+            (JoystickKeys::Cursor, Scancode::Right) => JoystickInput::Right,
+            (JoystickKeys::Cursor, Scancode::RCtrl) => JoystickInput::Fire,
+            _ => return None,
+        };
+        Some(input)
+    }
+}
+
+// Game controller d-pad and face buttons
+pub fn controller_button(button: Button) -> Option<JoystickInput> {
+    match button {
+        Button::DPadUp => Some(JoystickInput::Up),
+        Button::DPadDown => Some(JoystickInput::Down),
+        Button::DPadLeft => Some(JoystickInput::Left),
+        Button::DPadRight => Some(JoystickInput::Right),
+        Button::A | Button::B | Button::X | Button::Y => Some(JoystickInput::Fire),
+        _ => None,
+    }
+}
+
+// Left stick as directions: which ones are pressed or released when axis moves from
+// previous position. Directions which don't change are left alone, so they don't
+// override d-pad or keyboard joystick.
+pub fn controller_axis(
+    axis: Axis,
+    previous: i16,
+    value: i16,
+) -> Option<Vec<(JoystickInput, bool)>> {
+    let (negative, positive) = match axis {
+        Axis::LeftX => (JoystickInput::Left, JoystickInput::Right),
+        Axis::LeftY => (JoystickInput::Up, JoystickInput::Down),
+        _ => return None,
+    };
+    let directions = |value: i16| [value <= -STICK_DEAD_ZONE, value >= STICK_DEAD_ZONE];
+    let changes = [negative, positive]
+        .into_iter()
+        .zip(directions(previous).into_iter().zip(directions(value)))
+        .filter(|(_, (was, is))| was != is)
+        .map(|(input, (_, is))| (input, is))
+        .collect();
+    Some(changes)
+}
+
+// Used by symbolic keymap
+pub fn text_keys(ch: char) -> Option<Vec<C64Key>> {
+    let (key, shift) = C64Key::from_char(ch)?;
+    if shift {
+        Some(vec![LeftShift, key])
+    } else {
+        Some(vec![key])
+    }
+}
+
+// Keys which don't type characters, same for both keymaps. Keys missing on C64
+// (cursor left/up, even function keys) are shifted versions of existing ones.
+fn special_keys(scancode: Scancode) -> Option<Vec<C64Key>> {
+    let keys = match scancode {
+        Scancode::Return | Scancode::KpEnter => vec![Return],
+        Scancode::Backspace => vec![Delete],
+        Scancode::Insert => vec![LeftShift, Delete],
+        Scancode::Home => vec![Home],
+        Scancode::Right => vec![CursorRight],
+        Scancode::Left => vec![LeftShift, CursorRight],
+        Scancode::Down => vec![CursorDown],
+        Scancode::Up => vec![LeftShift, CursorDown],
+        Scancode::F1 => vec![F1],
+        Scancode::F2 => vec![LeftShift, F1],
+        Scancode::F3 => vec![F3],
+        Scancode::F4 => vec![LeftShift, F3],
+        Scancode::F5 => vec![F5],
+        Scancode::F6 => vec![LeftShift, F5],
+        Scancode::F7 => vec![F7],
+        Scancode::F8 => vec![LeftShift, F7],
+        Scancode::Tab => vec![Control],
+        Scancode::LCtrl => vec![Commodore],
+        Scancode::CapsLock => vec![RunStop],
+        Scancode::PageUp => vec![Restore],
+        _ => return None,
+    };
+    Some(keys)
+}
+
+fn positional_key(scancode: Scancode) -> Option<C64Key> {
+    let key = match scancode {
+        Scancode::A => A,
+        Scancode::B => B,
+        Scancode::C => C,
+        Scancode::D => D,
+        Scancode::E => E,
+        Scancode::F => F,
+        Scancode::G => G,
+        Scancode::H => H,
+        Scancode::I => I,
+        Scancode::J => J,
+        Scancode::K => K,
+        Scancode::L => L,
+        Scancode::M => M,
+        Scancode::N => N,
+        Scancode::O => O,
+        Scancode::P => P,
+        Scancode::Q => Q,
+        Scancode::R => R,
+        Scancode::S => S,
+        Scancode::T => T,
+        Scancode::U => U,
+        Scancode::V => V,
+        Scancode::W => W,
+        Scancode::X => X,
+        Scancode::Y => Y,
+        Scancode::Z => Z,
+        Scancode::Num0 => Num0,
+        Scancode::Num1 => Num1,
+        Scancode::Num2 => Num2,
+        Scancode::Num3 => Num3,
+        Scancode::Num4 => Num4,
+        Scancode::Num5 => Num5,
+        Scancode::Num6 => Num6,
+        Scancode::Num7 => Num7,
+        Scancode::Num8 => Num8,
+        Scancode::Num9 => Num9,
+        Scancode::Grave => LeftArrow,
+        Scancode::Minus => Plus,
+        Scancode::Equals => Minus,
+        Scancode::LeftBracket => At,
+        Scancode::RightBracket => Asterisk,
+        Scancode::Backslash => Equals,
+        Scancode::Semicolon => Colon,
+        Scancode::Apostrophe => Semicolon,
+        Scancode::Comma => Comma,
+        Scancode::Period => Period,
+        Scancode::Slash => Slash,
+        Scancode::End => Pound,
+        Scancode::Delete => UpArrow,
+        Scancode::Space => Space,
+        Scancode::LShift => LeftShift,
+        Scancode::RShift => RightShift,
+        _ => return None,
+    };
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positional() {
+        let keymap = Keymap::Positional;
+        assert_eq!(keymap.key_down(Scancode::Equals, false), Some(vec![Minus]));
+        assert_eq!(
+            keymap.key_down(Scancode::LShift, false),
+            Some(vec![LeftShift])
+        );
+        // Host shift is a key on its own
+        assert_eq!(keymap.key_down(Scancode::Num2, true), Some(vec![Num2]));
+        assert_eq!(
+            keymap.key_down(Scancode::Left, false),
+            Some(vec![LeftShift, CursorRight])
+        );
+        assert_eq!(keymap.key_down(Scancode::PrintScreen, false), None);
+    }
+
+    #[test]
+    fn symbolic() {
+        let keymap = Keymap::Symbolic;
+        assert_eq!(keymap.key_down(Scancode::Num2, false), None);
+        assert_eq!(keymap.key_down(Scancode::LShift, false), None);
+        assert_eq!(
+            keymap.key_down(Scancode::Home, true),
+            Some(vec![LeftShift, Home])
+        );
+        assert_eq!(
+            keymap.key_down(Scancode::Up, true),
+            Some(vec![LeftShift, CursorDown])
+        );
+    }
+
+    #[test]
+    fn symbolic_text() {
+        assert_eq!(text_keys('"'), Some(vec![LeftShift, Num2]));
+        // Shift is needed on host, but not on C64
+        assert_eq!(text_keys(':'), Some(vec![Colon]));
+        assert_eq!(text_keys('~'), None);
+    }
+
+    #[test]
+    fn joystick_keys() {
+        assert_eq!(
+            JoystickKeys::Numpad.input(Scancode::Kp5),
+            Some(JoystickInput::Fire)
+        );
+        assert_eq!(JoystickKeys::Numpad.input(Scancode::Up), None);
+        assert_eq!(
+            JoystickKeys::Cursor.input(Scancode::Left),
+            Some(JoystickInput::Left)
+        );
+        assert_eq!(JoystickKeys::None.input(Scancode::Kp8), None);
+        assert_eq!(
+            JoystickKeys::from_name("cursor"),
+            Some(JoystickKeys::Cursor)
+        );
+    }
+
+    #[test]
+    fn controller_stick() {
+        assert_eq!(
+            controller_axis(Axis::LeftY, 0, -30000),
+            Some(vec![(JoystickInput::Up, true)])
+        );
+        assert_eq!(
+            controller_axis(Axis::LeftX, -30000, 1000),
+            Some(vec![(JoystickInput::Left, false)])
+        );
+        assert_eq!(
+            controller_axis(Axis::LeftX, -30000, 30000),
+            Some(vec![
+                (JoystickInput::Left, false),
+                (JoystickInput::Right, true)
+            ])
+        );
+        // Moving within centre doesn't release directions held by d-pad
+        assert_eq!(controller_axis(Axis::LeftX, 0, 1000), Some(vec![]));
+        assert_eq!(controller_axis(Axis::TriggerLeft, 0, 30000), None);
+    }
+
+    #[test]
+    fn by_name() {
+        assert_eq!(Keymap::from_name("positional"), Some(Keymap::Positional));
+        assert_eq!(Keymap::from_name("qwerty"), None);
+    }
+}
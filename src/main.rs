@@ -1,16 +1,46 @@
+mod assembler;
+mod autotype;
+mod binary_monitor;
 mod bus;
 mod c64;
+mod cia;
+mod condition;
 mod cpu;
+mod dap_server;
+mod debugger;
+mod disassembler;
 mod flags;
+mod gdb_stub;
 mod host_io;
+mod joystick;
+mod keyboard;
+mod keymap;
+mod ml_monitor;
 mod ops_lookup;
+mod options;
+mod palette;
+mod petscii;
+mod psid;
 mod ram;
+mod remote;
+mod screen_codes;
+mod screenshot;
+mod sid;
+mod symbols;
 mod vic;
+mod wav;
 mod asm_tests;
 
 use asm6502::assemble;
 use c64::C64;
-use host_io::SdlHandler;
+use debugger::{Debugger, RemoteDebugger, StopReason};
+use host_io::{HostEvent, NullMonitor, SdlHandler, SpeedMeter, WINDOW_TITLE};
+use ml_monitor::{Action, MlMonitor};
+use options::Options;
+use psid::{SidTune, SidTuneError};
+use remote::RemoteServer;
+use sid::SidModel;
+use vic::{VideoStandard, FRAME_HEIGHT, FRAME_WIDTH};
 
 #[macro_use]
 extern crate lazy_static;
@@ -18,16 +48,28 @@ extern crate lazy_static;
 extern crate sdl2;
 
 use std::cell::RefCell;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// How often remote debugger client is checked while it holds emulation halted
+const REMOTE_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
 fn main() {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+    };
+
     let asm = r#"
     LDX #0
     loop:
     INX
     INX
-    STX $b000
+    STX $d020
     NOP
     CLC
     BCC loop
@@ -37,19 +79,317 @@ fn main() {
     let mut buf = Vec::<u8>::new();
     assemble(asm, &mut buf).unwrap();
 
-    let sdl_handler = Rc::new(RefCell::new(SdlHandler::new()));
+    if options.headless {
+        run_headless(&options, &buf);
+    } else {
+        run_sdl(&options, &buf);
+    }
+}
+
+fn run_headless(options: &Options, program: &[u8]) {
+    let mut c64 = C64::new(Rc::new(RefCell::new(NullMonitor {})));
+    setup(&mut c64, options, program);
+    let mut debugger = debugger(c64, options);
+    let mut monitor = MlMonitor::new();
+    let mut remotes = remote_debuggers(options);
+
+    // Without monitor run is finished on breakpoint
+    let frame_cycles = debugger.c64.video_standard().cycles_per_frame() as u64;
+    let mut running = !options.monitor || enter_monitor(&mut monitor, &mut debugger);
+    let mut frame = 0;
+    while running && frame < options.frames {
+        if !poll_remotes(&mut remotes, &mut debugger) {
+            break;
+        }
+        if remotes.iter().any(|remote| remote.halted()) {
+            std::thread::sleep(REMOTE_POLL_INTERVAL);
+            continue;
+        }
+        let reason = debugger.run(frame_cycles);
+        if reason == StopReason::CycleLimit {
+            frame += 1;
+        } else if report_stop(&mut remotes, &debugger, reason) {
+            // Client decides how to go on
+        } else if options.monitor {
+            println!("{}", debugger.describe(reason));
+            running = enter_monitor(&mut monitor, &mut debugger);
+        } else {
+            print_stop(&debugger, reason);
+            running = false;
+        }
+    }
+    let c64 = &mut debugger.c64;
+    stop_recording(c64);
+
+    if let Some(path) = &options.screenshot {
+        if let Err(err) = c64.save_screenshot(path, &options.palette, options.screenshot_border) {
+            eprintln!("Can't save screenshot {}: {}", path.display(), err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run_sdl(options: &Options, program: &[u8]) {
+    let sdl_handler = Rc::new(RefCell::new(SdlHandler::new(
+        FRAME_WIDTH,
+        FRAME_HEIGHT,
+        options.palette,
+        options.keymap,
+        options.joystick_keys,
+        options.joystick_port,
+    )));
 
     let mut c64 = C64::new(sdl_handler.clone());
-    (*c64.ram).borrow_mut().set_memory(&buf, 0).unwrap();
+    c64.sid.borrow_mut().set_sink(sdl_handler.clone());
+    setup(&mut c64, options, program);
+    let mut debugger = debugger(c64, options);
+    let standard = debugger.c64.video_standard();
+    sdl_handler
+        .borrow_mut()
+        .set_clock_frequency(standard.clock_frequency());
+    let mut monitor = MlMonitor::new();
+    let mut remotes = remote_debuggers(options);
+    if options.monitor && !enter_monitor(&mut monitor, &mut debugger) {
+        return;
+    }
 
+    let frame_duration = Duration::from_secs_f64(
+        standard.cycles_per_frame() as f64 / standard.clock_frequency() as f64,
+    );
+    let mut warp = options.warp;
+    sdl_handler.borrow_mut().set_warp(warp);
+    let mut speed = SpeedMeter::new(Instant::now());
+    let mut next_frame = Instant::now();
+    let mut paused = false;
     'running: loop {
-        if !sdl_handler.borrow_mut().process_events() {
-            break 'running;
+        let events = sdl_handler.borrow_mut().process_events();
+        for event in events {
+            match event {
+                HostEvent::Quit => break 'running,
+                HostEvent::TogglePause => paused = !paused,
+                HostEvent::EnterMonitor => {
+                    if !enter_monitor(&mut monitor, &mut debugger) {
+                        break 'running;
+                    }
+                    next_frame = Instant::now();
+                }
+                HostEvent::ToggleWarp => {
+                    warp = !warp;
+                    sdl_handler.borrow_mut().set_warp(warp);
+                }
+                HostEvent::ToggleRecording => {
+                    if debugger.c64.is_recording() {
+                        stop_recording(&mut debugger.c64);
+                    } else {
+                        start_recording(&mut debugger.c64, &timestamped_path("recording", "wav"));
+                    }
+                }
+                HostEvent::Screenshot { with_border } => {
+                    let path = timestamped_path("screenshot", "png");
+                    match debugger.c64.save_screenshot(&path, &options.palette, with_border) {
+                        Ok(()) => println!("Screenshot saved to {}", path.display()),
+                        Err(err) => eprintln!("Can't save screenshot: {}", err),
+                    }
+                }
+                HostEvent::KeyDown { id, keys } => debugger.c64.keyboard.press(id, keys),
+                HostEvent::KeyUp { id } => debugger.c64.keyboard.release(id),
+                HostEvent::Joystick {
+                    port,
+                    input,
+                    pressed,
+                } => debugger.c64.joysticks[port].set(input, pressed),
+                HostEvent::Paste(text) => debugger.c64.type_text(&text),
+                HostEvent::SwapJoysticks => {
+                    debugger.c64.swap_joysticks();
+                    println!("Joystick ports swapped");
+                }
+            }
         }
 
-        c64.tick();
+        if !poll_remotes(&mut remotes, &mut debugger) {
+            break 'running;
+        }
+        let halted = remotes.iter().any(|remote| remote.halted());
+        if !paused && !halted {
+            let reason = debugger.run(standard.cycles_per_frame() as u64);
+            if reason != StopReason::CycleLimit && !report_stop(&mut remotes, &debugger, reason) {
+                println!("{}", debugger.describe(reason));
+                if !enter_monitor(&mut monitor, &mut debugger) {
+                    break 'running;
+                }
+                next_frame = Instant::now();
+            }
+        }
         sdl_handler.borrow_mut().render_screen();
 
-        ::std::thread::sleep(Duration::new(0, 1_000_000_000u32 / 60));
+        if let Some(percent) = speed.frame_done(frame_duration, Instant::now()) {
+            let mode = if warp { " (warp)" } else { "" };
+            let title = format!("{} - {}%{}", WINDOW_TITLE, percent, mode);
+            sdl_handler.borrow_mut().set_title(&title);
+        }
+
+        let now = Instant::now();
+        if warp {
+            next_frame = now;
+            continue;
+        }
+        next_frame += frame_duration.mul_f64(sdl_handler.borrow().speed_adjustment());
+        match next_frame.checked_duration_since(now) {
+            Some(delay) => ::std::thread::sleep(delay),
+            // Host is too slow, don't try to catch up
+            None => next_frame = now,
+        }
+    }
+    stop_recording(&mut debugger.c64);
+}
+
+// Load the program or the tune and apply options, exits if tune can't be played
+fn setup(c64: &mut C64, options: &Options, program: &[u8]) {
+    c64.set_video_standard(options.video.unwrap_or(VideoStandard::Pal));
+    let model = match &options.tune {
+        Some(path) => match load_tune(c64, path, options) {
+            Ok(tune) => options.sid_model.or(tune.model()),
+            Err(err) => {
+                eprintln!("Can't play {}: {}", path.display(), err);
+                std::process::exit(1);
+            }
+        },
+        None => {
+            (*c64.ram).borrow_mut().set_memory(program, 0).unwrap();
+            options.sid_model
+        }
+    };
+    c64.sid
+        .borrow_mut()
+        .set_model(model.unwrap_or(SidModel::Mos6581));
+    c64.autotype.mode = options.type_mode;
+    if let Some(text) = &options.type_text {
+        c64.type_text(text);
+    }
+    if let Some(path) = &options.record {
+        start_recording(c64, path);
+    }
+}
+
+fn debugger(c64: C64, options: &Options) -> Debugger {
+    let mut debugger = Debugger::new(c64);
+    for path in &options.symbols {
+        if let Err(err) = debugger.symbols.load(path) {
+            eprintln!("Can't load symbols {}: {}", path.display(), err);
+            std::process::exit(1);
+        }
     }
+    for &address in &options.breakpoints {
+        debugger.add_breakpoint(address);
+    }
+    debugger
+}
+
+// Servers requested by options, exits if one can't listen on its port
+fn remote_debuggers(options: &Options) -> Vec<Box<dyn RemoteDebugger>> {
+    let mut remotes: Vec<Box<dyn RemoteDebugger>> = vec![];
+    if let Some(port) = options.gdb_port {
+        remotes.push(Box::new(listen::<gdb_stub::Session>(port)));
+    }
+    if let Some(port) = options.binary_monitor_port {
+        remotes.push(Box::new(listen::<binary_monitor::Session>(port)));
+    }
+    if let Some(port) = options.dap_port {
+        remotes.push(Box::new(listen::<dap_server::Session>(port)));
+    }
+    remotes
+}
+
+fn listen<S: remote::Session>(port: u16) -> RemoteServer<S> {
+    match RemoteServer::bind(port).and_then(|server| Ok((server.local_addr()?, server))) {
+        Ok((address, server)) => {
+            println!("{} server listening on {}", S::NAME, address);
+            server
+        }
+        Err(err) => {
+            eprintln!("Can't start {} server on port {}: {}", S::NAME, port, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+// false when one of the clients asked to quit
+fn poll_remotes(remotes: &mut [Box<dyn RemoteDebugger>], debugger: &mut Debugger) -> bool {
+    remotes.iter_mut().all(|remote| remote.poll(debugger))
+}
+
+// Stop goes to the first client waiting for one, false if it's not taken
+fn report_stop(
+    remotes: &mut [Box<dyn RemoteDebugger>],
+    debugger: &Debugger,
+    reason: StopReason,
+) -> bool {
+    remotes
+        .iter_mut()
+        .any(|remote| remote.stopped(debugger, reason))
+}
+
+// Monitor works on stdin and stdout, false means emulator should quit
+fn enter_monitor(monitor: &mut MlMonitor, debugger: &mut Debugger) -> bool {
+    let stdin = std::io::stdin();
+    match monitor.run(debugger, &mut stdin.lock(), &mut std::io::stdout()) {
+        Ok(action) => action != Action::Quit,
+        Err(err) => {
+            eprintln!("Monitor failed: {}", err);
+            true
+        }
+    }
+}
+
+// Reason and the instruction cpu stopped at
+fn print_stop(debugger: &Debugger, reason: StopReason) {
+    println!("{}", debugger.describe(reason));
+    let c64 = &debugger.c64;
+    let line = disassembler::disassemble_one(&c64.bus, c64.cpu.pc(), Some(&debugger.symbols));
+    println!("{}", line);
+}
+
+fn load_tune(c64: &mut C64, path: &Path, options: &Options) -> Result<SidTune, SidTuneError> {
+    let tune = SidTune::load(path)?;
+    // Command line wins over what tune asks for
+    if let (None, Some(standard)) = (options.video, tune.video_standard()) {
+        c64.set_video_standard(standard);
+    }
+    let song = options.song.unwrap_or(tune.start_song);
+    tune.install(c64, song)?;
+    println!(
+        "{} by {} ({}), song {} of {}, {:?} clock",
+        tune.name,
+        tune.author,
+        tune.released,
+        song,
+        tune.songs,
+        tune.clock()
+    );
+    Ok(tune)
+}
+
+fn start_recording(c64: &mut C64, path: &Path) {
+    match c64.start_recording(path) {
+        Ok(()) => println!("Recording audio to {}", path.display()),
+        Err(err) => eprintln!("Can't record audio to {}: {}", path.display(), err),
+    }
+}
+
+fn stop_recording(c64: &mut C64) {
+    if !c64.is_recording() {
+        return;
+    }
+    match c64.stop_recording() {
+        Ok(()) => println!("Recording stopped"),
+        Err(err) => eprintln!("Can't finish recording: {}", err),
+    }
+}
+
+fn timestamped_path(name: &str, extension: &str) -> PathBuf {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis())
+        .unwrap_or(0);
+    PathBuf::from(format!("{}-{}.{}", name, millis, extension))
 }
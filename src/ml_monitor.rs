@@ -0,0 +1,919 @@
+// Machine language monitor in the style of VICE. Numbers are hex, $ prefix is optional.
+// Memory is read without side effects on devices, writes go through the bus.
+
+use crate::assembler::assemble_line;
+use crate::condition::Condition;
+use crate::debugger::{BreakKind, Breakpoint, Debugger, StopReason};
+use crate::disassembler::{disassemble, disassemble_one};
+use crate::options::parse_address;
+use crate::screen_codes;
+use crate::symbols::SymbolTable;
+
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+const MEMORY_LINE_BYTES: u16 = 16;
+const DEFAULT_MEMORY_BYTES: u16 = 8 * MEMORY_LINE_BYTES;
+const DEFAULT_DISASSEMBLE_BYTES: u16 = 0x20;
+
+const HELP: &str = "\
+r [reg=value ...]          show or set registers (a, x, y, sp, pc, p)
+m [start [end]]            memory dump
+d [start [end]]            disassemble
+a <address> [instruction]  assemble, empty line ends assembling
+> <address> <bytes>        write bytes
+f <start> <end> <bytes>    fill range with byte pattern
+h <start> <end> <bytes>    hunt for byte sequence
+c <start> <end> <dest>     compare range with memory at dest
+t <start> <end> <dest>     transfer (copy) range to dest
+g [address]                go, continue emulation
+z [count]                  step instructions
+n [count]                  step over subroutines
+ret                        run until subroutine or interrupt handler returns
+un <address>               run until address is reached
+bt                         show subroutines and interrupts cpu is in
+l \"file\" [address]         load PRG file, to address from file if not given
+s \"file\" <start> <end>     save range as PRG file
+break [kind] <start> [end] [if <condition>]
+                           add breakpoint, kind is exec (default), load, store or
+                           loadstore, without arguments lists all breakpoints
+watch [kind] <start> [end] [if <condition>]
+                           add watchpoint, kind is loadstore by default
+trace [kind] <start> [end] [if <condition>]
+                           add tracepoint, it prints hits without stopping
+cond <id> [if <condition>] set or clear condition, e.g. a == $10 && c == 1,
+                           also x, y, sp, pc, p, flags, value and hits can be used
+del <id>                   delete breakpoint
+enable <id>, disable <id>  enable or disable breakpoint
+ll \"file\"                  load labels (VICE, ca65 .dbg, KickAssembler, ACME)
+al <address> <label>       add label
+shl                        show labels
+cl                         clear labels
+x                          exit monitor, continue emulation
+q                          quit emulator
+Addresses can be given by labels, with or without . prefix. Registers and flags
+win over hex numbers: operand a is the accumulator and c in conditions is carry,
+write $0a or $0c for the numbers";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Action {
+    // Wait for next command
+    Stay,
+    // Continue emulation
+    Resume,
+    Quit,
+}
+
+pub struct Reply {
+    pub lines: Vec<String>,
+    pub action: Action,
+}
+
+impl Reply {
+    fn stay(lines: Vec<String>) -> Self {
+        Self {
+            lines,
+            action: Action::Stay,
+        }
+    }
+}
+
+pub struct MlMonitor {
+    // Where m and d without arguments continue
+    next_memory: u16,
+    next_disassemble: u16,
+    // Some when in assemble mode, lines are instructions then
+    assemble_address: Option<u16>,
+}
+
+fn parse_bytes(args: &[String]) -> Result<Vec<u8>, String> {
+    if args.is_empty() {
+        return Err("Bytes expected".to_string());
+    }
+    args.iter()
+        .map(|arg| match parse_address(arg)? {
+            value @ 0..=0xff => Ok(value as u8),
+            _ => Err(format!("Bad byte: {}", arg)),
+        })
+        .collect()
+}
+
+// Words separated by spaces or commas, double quoted words can have spaces
+fn split_args(text: &str) -> Vec<String> {
+    let mut args = vec![];
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() || c == ',' {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            args.push(chars.by_ref().take_while(|&c| c != '"').collect());
+        } else {
+            let mut arg = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == ',' {
+                    break;
+                }
+                arg.push(c);
+                chars.next();
+            }
+            args.push(arg);
+        }
+    }
+    args
+}
+
+// Label, VICE style with . prefix or without it, or hex address
+fn parse_location(symbols: &SymbolTable, text: &str) -> Result<u16, String> {
+    match symbols.address(text.strip_prefix('.').unwrap_or(text)) {
+        Some(address) => Ok(address),
+        None => parse_address(text),
+    }
+}
+
+fn arg_address(
+    symbols: &SymbolTable,
+    args: &[String],
+    index: usize,
+    name: &str,
+) -> Result<u16, String> {
+    match args.get(index) {
+        Some(arg) => parse_location(symbols, arg),
+        None => Err(format!("{} expected", name)),
+    }
+}
+
+fn arg_id(args: &[String]) -> Result<u32, String> {
+    let arg = args.first().ok_or("Breakpoint id expected")?;
+    arg.parse()
+        .map_err(|_| format!("Bad breakpoint id: {}", arg))
+}
+
+// Breakpoint, watchpoint or tracepoint depending on kind and log
+fn describe(symbols: &SymbolTable, id: u32, breakpoint: &Breakpoint) -> String {
+    let name = match (breakpoint.log, breakpoint.kind) {
+        (true, _) => "Tracepoint",
+        (false, BreakKind::Exec) => "Breakpoint",
+        (false, _) => "Watchpoint",
+    };
+    let mut text = format!("{} {}", name, id);
+    if breakpoint.kind != BreakKind::Exec {
+        text += &format!(" {}", breakpoint.kind.name());
+    }
+    text += &format!(" at ${:04x}", breakpoint.from);
+    if breakpoint.to != breakpoint.from {
+        text += &format!("-${:04x}", breakpoint.to);
+    }
+    if let Some(name) = symbols.name(breakpoint.from) {
+        text += &format!(" ({})", name);
+    }
+    if let Some(condition) = &breakpoint.condition {
+        text += &format!(" if {}", condition);
+    }
+    if !breakpoint.enabled {
+        text += " (disabled)";
+    }
+    if breakpoint.hits > 0 {
+        text += &format!(", hits {}", breakpoint.hits);
+    }
+    text
+}
+
+// [kind] <start> [end] [if <condition>]
+fn add_breakpoint(
+    debugger: &mut Debugger,
+    text: &str,
+    default_kind: BreakKind,
+    log: bool,
+) -> Result<Vec<String>, String> {
+    let (text, condition) = match text.split_once(" if ") {
+        Some((text, condition)) => (text, Some(Condition::parse(condition)?)),
+        None => (text, None),
+    };
+    let mut args = split_args(text);
+    let kind = match args.first().and_then(|arg| BreakKind::from_name(arg)) {
+        Some(kind) => {
+            args.remove(0);
+            kind
+        }
+        None => default_kind,
+    };
+    let from = arg_address(&debugger.symbols, &args, 0, "Address")?;
+    let to = match args.get(1) {
+        Some(arg) => parse_location(&debugger.symbols, arg)?,
+        None => from,
+    };
+    if to < from {
+        return Err("End address is before start".to_string());
+    }
+    let mut breakpoint = Breakpoint::new(kind, from, to);
+    breakpoint.condition = condition;
+    breakpoint.log = log;
+    let id = debugger.add(breakpoint);
+    Ok(vec![describe(
+        &debugger.symbols,
+        id,
+        debugger.breakpoint(id).unwrap(),
+    )])
+}
+
+// start..=end, end must not be before start
+fn arg_range(symbols: &SymbolTable, args: &[String]) -> Result<(u16, u16), String> {
+    let start = arg_address(symbols, args, 0, "Start address")?;
+    let end = arg_address(symbols, args, 1, "End address")?;
+    if end < start {
+        return Err("End address is before start".to_string());
+    }
+    Ok((start, end))
+}
+
+impl MlMonitor {
+    pub fn new() -> Self {
+        Self {
+            next_memory: 0,
+            next_disassemble: 0,
+            assemble_address: None,
+        }
+    }
+
+    pub fn prompt(&self, debugger: &Debugger) -> String {
+        match self.assemble_address {
+            Some(address) => format!(".{:04x} ", address),
+            None => format!("(C:${:04x}) ", debugger.c64.cpu.pc()),
+        }
+    }
+
+    // Reads commands from input until one of them resumes emulation or quits. End of
+    // input resumes emulation.
+    pub fn run(
+        &mut self,
+        debugger: &mut Debugger,
+        input: &mut dyn BufRead,
+        output: &mut dyn Write,
+    ) -> io::Result<Action> {
+        self.next_disassemble = debugger.c64.cpu.pc();
+        writeln!(output, "{}", self.current_line(debugger))?;
+        loop {
+            write!(output, "{}", self.prompt(debugger))?;
+            output.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(Action::Resume);
+            }
+            match self.execute(debugger, &line) {
+                Ok(reply) => {
+                    for line in reply.lines {
+                        writeln!(output, "{}", line)?;
+                    }
+                    if reply.action != Action::Stay {
+                        return Ok(reply.action);
+                    }
+                }
+                Err(message) => writeln!(output, "Error: {}", message)?,
+            }
+        }
+    }
+
+    pub fn execute(&mut self, debugger: &mut Debugger, line: &str) -> Result<Reply, String> {
+        let line = line.trim();
+        if let Some(address) = self.assemble_address {
+            if line.is_empty() {
+                self.assemble_address = None;
+                return Ok(Reply::stay(vec![]));
+            }
+            return self.assemble(debugger, address, line);
+        }
+        let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let args = split_args(rest);
+        let command = command.to_lowercase();
+        let lines = match command.as_str() {
+            "" => vec![],
+            "r" | "registers" => self.registers(debugger, &args)?,
+            "m" | "mem" => self.memory(debugger, &args)?,
+            "d" | "disass" => self.disassemble(debugger, &args)?,
+            "a" => {
+                let address = arg_address(&debugger.symbols, &args, 0, "Address")?;
+                self.assemble_address = Some(address);
+                match rest.trim().split_once(char::is_whitespace) {
+                    Some((_, instruction)) => return self.assemble(debugger, address, instruction),
+                    None => vec![],
+                }
+            }
+            ">" => {
+                let address = arg_address(&debugger.symbols, &args, 0, "Address")?;
+                let bytes = parse_bytes(&args[1..])?;
+                for (i, byte) in bytes.into_iter().enumerate() {
+                    debugger
+                        .c64
+                        .bus
+                        .set_byte(byte, address.wrapping_add(i as u16));
+                }
+                vec![]
+            }
+            "f" | "fill" => {
+                let (start, end) = arg_range(&debugger.symbols, &args)?;
+                let pattern = parse_bytes(&args[2..])?;
+                for (address, byte) in (start..=end).zip(pattern.iter().cycle()) {
+                    debugger.c64.bus.set_byte(*byte, address);
+                }
+                vec![]
+            }
+            "h" | "hunt" => self.hunt(debugger, &args)?,
+            "c" | "compare" => self.compare(debugger, &args)?,
+            "t" | "transfer" => {
+                let (start, end) = arg_range(&debugger.symbols, &args)?;
+                let dest = arg_address(&debugger.symbols, &args, 2, "Destination")?;
+                // Through buffer, so overlapping ranges are copied right
+                let bytes: Vec<u8> = (start..=end)
+                    .map(|address| debugger.c64.bus.peek_byte(address))
+                    .collect();
+                for (i, byte) in bytes.into_iter().enumerate() {
+                    debugger.c64.bus.set_byte(byte, dest.wrapping_add(i as u16));
+                }
+                vec![]
+            }
+            "g" | "goto" => {
+                if !args.is_empty() {
+                    debugger
+                        .c64
+                        .cpu
+                        .set_pc(arg_address(&debugger.symbols, &args, 0, "Address")?);
+                }
+                return Ok(Reply {
+                    lines: vec![],
+                    action: Action::Resume,
+                });
+            }
+            "z" | "step" => self.step(debugger, &args, |debugger| debugger.step())?,
+            "n" | "next" => self.step(debugger, &args, |debugger| {
+                debugger.next(debugger.run_limit())
+            })?,
+            "bt" | "backtrace" => debugger.backtrace(),
+            "un" | "until" => {
+                let address = arg_address(&debugger.symbols, &args, 0, "Address")?;
+                let reason = debugger.run_to(address, debugger.run_limit());
+                self.stopped(debugger, reason)
+            }
+            "ret" | "return" => {
+                let reason = debugger.finish(debugger.run_limit());
+                self.stopped(debugger, reason)
+            }
+            "l" | "load" => self.load(debugger, &args)?,
+            "s" | "save" => self.save(debugger, &args)?,
+            "break" | "bk" if args.is_empty() => debugger
+                .breakpoints()
+                .map(|(id, breakpoint)| describe(&debugger.symbols, id, breakpoint))
+                .collect(),
+            "break" | "bk" => add_breakpoint(debugger, rest, BreakKind::Exec, false)?,
+            "watch" | "w" => add_breakpoint(debugger, rest, BreakKind::LoadStore, false)?,
+            "trace" | "tr" => add_breakpoint(debugger, rest, BreakKind::Exec, true)?,
+            "cond" | "condition" => {
+                let id = arg_id(&args)?;
+                let text = match rest.trim().split_once(char::is_whitespace) {
+                    Some((_, text)) => text.trim(),
+                    None => "",
+                };
+                let text = text.strip_prefix("if ").unwrap_or(text);
+                let condition = match text {
+                    "" => None,
+                    text => Some(Condition::parse(text)?),
+                };
+                debugger
+                    .breakpoint_mut(id)
+                    .ok_or_else(|| format!("No breakpoint {}", id))?
+                    .condition = condition;
+                vec![describe(
+                    &debugger.symbols,
+                    id,
+                    debugger.breakpoint(id).unwrap(),
+                )]
+            }
+            "del" | "delete" => {
+                let id = arg_id(&args)?;
+                if !debugger.remove_breakpoint(id) {
+                    return Err(format!("No breakpoint {}", id));
+                }
+                vec![]
+            }
+            "enable" | "en" | "disable" | "dis" => {
+                let id = arg_id(&args)?;
+                if !debugger.set_breakpoint_enabled(id, command.starts_with('e')) {
+                    return Err(format!("No breakpoint {}", id));
+                }
+                vec![]
+            }
+            "ll" | "load_labels" => {
+                let path = args.first().ok_or("File name expected")?;
+                let count = debugger
+                    .symbols
+                    .load(Path::new(path))
+                    .map_err(|err| format!("Can't load labels {}: {}", path, err))?;
+                vec![format!("Loaded {} labels", count)]
+            }
+            "al" | "add_label" => {
+                let address = arg_address(&debugger.symbols, &args, 0, "Address")?;
+                let name = args.get(1).ok_or("Label expected")?;
+                debugger
+                    .symbols
+                    .insert(name.strip_prefix('.').unwrap_or(name), address);
+                vec![]
+            }
+            "shl" | "show_labels" => debugger
+                .symbols
+                .iter()
+                .map(|(name, address)| format!("${:04x} .{}", address, name))
+                .collect(),
+            "cl" | "clear_labels" => {
+                debugger.symbols.clear();
+                vec![]
+            }
+            "x" | "exit" => {
+                return Ok(Reply {
+                    lines: vec![],
+                    action: Action::Resume,
+                })
+            }
+            "q" | "quit" => {
+                return Ok(Reply {
+                    lines: vec![],
+                    action: Action::Quit,
+                })
+            }
+            "help" | "?" => HELP.lines().map(str::to_string).collect(),
+            _ => return Err(format!("Unknown command: {}", command)),
+        };
+        Ok(Reply::stay(lines))
+    }
+
+    fn current_line(&self, debugger: &Debugger) -> String {
+        let c64 = &debugger.c64;
+        disassemble_one(&c64.bus, c64.cpu.pc(), Some(&debugger.symbols)).to_string()
+    }
+
+    fn registers(&self, debugger: &mut Debugger, args: &[String]) -> Result<Vec<String>, String> {
+        for arg in args {
+            let (name, value) = arg
+                .split_once('=')
+                .ok_or_else(|| format!("Expected register=value: {}", arg))?;
+            let value = parse_address(value)?;
+            let byte = || u8::try_from(value).map_err(|_| format!("Bad value for {}", name));
+            let cpu = &mut debugger.c64.cpu;
+            match name.to_lowercase().as_str() {
+                "a" => cpu.reg.a = byte()?,
+                "x" => cpu.reg.x = byte()?,
+                "y" => cpu.reg.y = byte()?,
+                "sp" => cpu.set_sp(byte()?),
+                "p" => cpu.flags.set_register(byte()?),
+                "pc" => cpu.set_pc(value),
+                _ => return Err(format!("Unknown register: {}", name)),
+            }
+        }
+        let c64 = &debugger.c64;
+        let vic = c64.vic.borrow();
+        Ok(vec![
+            "  ADDR A  X  Y  SP NV-BDIZC LIN CYC".to_string(),
+            format!(
+                ".;{:04x} {:02x} {:02x} {:02x} {:02x} {:08b} {:03} {:03}",
+                c64.cpu.pc(),
+                c64.cpu.reg.a,
+                c64.cpu.reg.x,
+                c64.cpu.reg.y,
+                c64.cpu.sp(),
+                c64.cpu.flags.get_register(),
+                vic.raster_line(),
+                vic.raster_cycle()
+            ),
+        ])
+    }
+
+    fn memory(&mut self, debugger: &Debugger, args: &[String]) -> Result<Vec<String>, String> {
+        let start = match args.first() {
+            Some(arg) => parse_location(&debugger.symbols, arg)?,
+            None => self.next_memory,
+        };
+        let end = match args.get(1) {
+            Some(arg) => parse_location(&debugger.symbols, arg)?,
+            None => start.saturating_add(DEFAULT_MEMORY_BYTES - 1),
+        };
+        let bus = &debugger.c64.bus;
+        let mut lines = vec![];
+        let mut address = start as u32;
+        while address <= end as u32 {
+            let last = (address + MEMORY_LINE_BYTES as u32 - 1).min(end as u32);
+            let bytes: Vec<u8> = (address..=last)
+                .map(|address| bus.peek_byte(address as u16))
+                .collect();
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            let text: String = bytes.iter().map(|&b| screen_codes::to_ascii(b)).collect();
+            lines.push(format!(
+                ">C:{:04x}  {:<47}  {}",
+                address,
+                hex.join(" "),
+                text
+            ));
+            address = last + 1;
+        }
+        self.next_memory = address as u16;
+        Ok(lines)
+    }
+
+    fn disassemble(&mut self, debugger: &Debugger, args: &[String]) -> Result<Vec<String>, String> {
+        let start = match args.first() {
+            Some(arg) => parse_location(&debugger.symbols, arg)?,
+            None => self.next_disassemble,
+        };
+        let end = match args.get(1) {
+            Some(arg) => parse_location(&debugger.symbols, arg)?,
+            None => start.saturating_add(DEFAULT_DISASSEMBLE_BYTES - 1),
+        };
+        let lines = disassemble(&debugger.c64.bus, start, end, Some(&debugger.symbols));
+        if let Some(line) = lines.last() {
+            self.next_disassemble = line.address.wrapping_add(line.bytes.len() as u16);
+        }
+        Ok(lines.iter().map(|line| line.to_string()).collect())
+    }
+
+    fn assemble(
+        &mut self,
+        debugger: &mut Debugger,
+        address: u16,
+        instruction: &str,
+    ) -> Result<Reply, String> {
+        let bytes = assemble_line(instruction, address)?;
+        for (i, byte) in bytes.iter().enumerate() {
+            debugger
+                .c64
+                .bus
+                .set_byte(*byte, address.wrapping_add(i as u16));
+        }
+        self.assemble_address = Some(address.wrapping_add(bytes.len() as u16));
+        Ok(Reply::stay(vec![]))
+    }
+
+    fn hunt(&self, debugger: &Debugger, args: &[String]) -> Result<Vec<String>, String> {
+        let (start, end) = arg_range(&debugger.symbols, args)?;
+        let pattern = parse_bytes(&args[2..])?;
+        let bus = &debugger.c64.bus;
+        let found: Vec<String> = (start as u32..=end as u32)
+            .filter(|&address| {
+                pattern.iter().enumerate().all(|(i, &byte)| {
+                    bus.peek_byte((address as u16).wrapping_add(i as u16)) == byte
+                })
+            })
+            .map(|address| format!("{:04x}", address))
+            .collect();
+        Ok(found
+            .chunks(8)
+            .map(|addresses| addresses.join(" "))
+            .collect())
+    }
+
+    fn compare(&self, debugger: &Debugger, args: &[String]) -> Result<Vec<String>, String> {
+        let (start, end) = arg_range(&debugger.symbols, args)?;
+        let dest = arg_address(&debugger.symbols, args, 2, "Destination")?;
+        let bus = &debugger.c64.bus;
+        Ok((start..=end)
+            .filter_map(|address| {
+                let other = dest.wrapping_add(address - start);
+                let (a, b) = (bus.peek_byte(address), bus.peek_byte(other));
+                (a != b).then(|| format!("${:04x} {:02x}  ${:04x} {:02x}", address, a, other, b))
+            })
+            .collect())
+    }
+
+    fn step(
+        &mut self,
+        debugger: &mut Debugger,
+        args: &[String],
+        step: impl Fn(&mut Debugger) -> StopReason,
+    ) -> Result<Vec<String>, String> {
+        let count = match args.first() {
+            Some(arg) => parse_address(arg)?,
+            None => 1,
+        };
+        let mut lines = vec![];
+        for _ in 0..count {
+            let reason = step(debugger);
+            lines = self.stopped(debugger, reason);
+            if reason != StopReason::Step {
+                break;
+            }
+        }
+        Ok(lines)
+    }
+
+    // Where cpu is after step, and why if it's not the step itself
+    fn stopped(&mut self, debugger: &Debugger, reason: StopReason) -> Vec<String> {
+        self.next_disassemble = debugger.c64.cpu.pc();
+        let mut lines = vec![];
+        if reason != StopReason::Step {
+            lines.push(debugger.describe(reason));
+        }
+        lines.push(self.current_line(debugger));
+        lines
+    }
+
+    fn load(&self, debugger: &mut Debugger, args: &[String]) -> Result<Vec<String>, String> {
+        let path = args.first().ok_or("File name expected")?;
+        let data = std::fs::read(path).map_err(|err| format!("Can't read {}: {}", path, err))?;
+        if data.len() < 2 {
+            return Err(format!("{} is too short for PRG file", path));
+        }
+        let address = match args.get(1) {
+            Some(arg) => parse_location(&debugger.symbols, arg)?,
+            None => u16::from_le_bytes([data[0], data[1]]),
+        };
+        let bytes = &data[2..];
+        for (i, byte) in bytes.iter().enumerate() {
+            debugger
+                .c64
+                .bus
+                .set_byte(*byte, address.wrapping_add(i as u16));
+        }
+        Ok(vec![format!(
+            "Loaded {} bytes to ${:04x}-${:04x}",
+            bytes.len(),
+            address,
+            address.wrapping_add(bytes.len().max(1) as u16 - 1)
+        )])
+    }
+
+    fn save(&self, debugger: &Debugger, args: &[String]) -> Result<Vec<String>, String> {
+        let path = args.first().ok_or("File name expected")?;
+        let (start, end) = arg_range(&debugger.symbols, &args[1..])?;
+        let mut data = start.to_le_bytes().to_vec();
+        data.extend((start..=end).map(|address| debugger.c64.bus.peek_byte(address)));
+        std::fs::write(path, &data).map_err(|err| format!("Can't write {}: {}", path, err))?;
+        Ok(vec![format!(
+            "Saved ${:04x}-${:04x} to {}",
+            start, end, path
+        )])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugger::test_debugger;
+
+    fn fixture() -> (MlMonitor, Debugger) {
+        (MlMonitor::new(), test_debugger())
+    }
+
+    fn execute(monitor: &mut MlMonitor, debugger: &mut Debugger, line: &str) -> Vec<String> {
+        monitor.execute(debugger, line).unwrap().lines
+    }
+
+    #[test]
+    fn registers() {
+        let (mut monitor, mut debugger) = fixture();
+        let lines = execute(&mut monitor, &mut debugger, "r a=$12, x=34 pc=c000 p=81");
+        assert_eq!(lines[0], "  ADDR A  X  Y  SP NV-BDIZC LIN CYC");
+        assert!(lines[1].starts_with(".;c000 12 34 00 ff 10000001"));
+        assert!(monitor.execute(&mut debugger, "r a=100").is_err());
+        assert!(monitor.execute(&mut debugger, "r q=1").is_err());
+    }
+
+    #[test]
+    fn memory_dump() {
+        let (mut monitor, mut debugger) = fixture();
+        // Screen codes of "hi"
+        execute(&mut monitor, &mut debugger, "> 2000 08 09");
+        let lines = execute(&mut monitor, &mut debugger, "m 2000 2011");
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with(">C:2000  08 09 00 00"));
+        assert!(lines[0].ends_with("HI@@@@@@@@@@@@@@"));
+        assert!(lines[1].starts_with(">C:2010  00 00  "));
+
+        // Continues where previous dump ended
+        let lines = execute(&mut monitor, &mut debugger, "m");
+        assert!(lines[0].starts_with(">C:2012"));
+        assert_eq!(lines.len(), 8);
+    }
+
+    #[test]
+    fn assemble_and_disassemble() {
+        let (mut monitor, mut debugger) = fixture();
+        execute(&mut monitor, &mut debugger, "a c000 lda #$01");
+        assert_eq!(monitor.prompt(&debugger), ".c002 ");
+        execute(&mut monitor, &mut debugger, "sta $d020");
+        execute(&mut monitor, &mut debugger, "bne $c000");
+        execute(&mut monitor, &mut debugger, "");
+        assert_eq!(monitor.prompt(&debugger), "(C:$1000) ");
+
+        let lines = execute(&mut monitor, &mut debugger, "d c000 c005");
+        assert_eq!(
+            lines,
+            vec![
+                "$c000  a9 01     LDA #$01",
+                "$c002  8d 20 d0  STA $d020",
+                "$c005  d0 f9     BNE $c000",
+            ]
+        );
+    }
+
+    #[test]
+    fn fill_hunt_compare_transfer() {
+        let (mut monitor, mut debugger) = fixture();
+        execute(&mut monitor, &mut debugger, "f 2000 2007 aa 55");
+        assert_eq!(
+            execute(&mut monitor, &mut debugger, "h 2000 20ff 55 aa"),
+            vec!["2001 2003 2005"]
+        );
+
+        execute(&mut monitor, &mut debugger, "t 2000 2007 3000");
+        assert!(execute(&mut monitor, &mut debugger, "c 2000 2007 3000").is_empty());
+        execute(&mut monitor, &mut debugger, "> 3002 00");
+        assert_eq!(
+            execute(&mut monitor, &mut debugger, "c 2000 2007 3000"),
+            vec!["$2002 aa  $3002 00"]
+        );
+        assert!(monitor.execute(&mut debugger, "f 2007 2000 00").is_err());
+    }
+
+    #[test]
+    fn until_and_return() {
+        let (mut monitor, mut debugger) = fixture();
+        assert_eq!(
+            execute(&mut monitor, &mut debugger, "un 1002"),
+            vec!["Reached $1002", "$1002  4c 00 10  JMP $1000"]
+        );
+        // JSR $2010, NOP at $2000, INX, RTS at $2010
+        execute(&mut monitor, &mut debugger, "> 2000 20 10 20 ea");
+        execute(&mut monitor, &mut debugger, "> 2010 e8 60");
+        execute(&mut monitor, &mut debugger, "g 2000");
+        execute(&mut monitor, &mut debugger, "z");
+        assert_eq!(
+            execute(&mut monitor, &mut debugger, "ret"),
+            vec!["Returned from subroutine", "$2003  ea        NOP"]
+        );
+    }
+
+    #[test]
+    fn backtrace() {
+        let (mut monitor, mut debugger) = fixture();
+        execute(&mut monitor, &mut debugger, "> 2000 20 00 10");
+        execute(&mut monitor, &mut debugger, "g 2000");
+        execute(&mut monitor, &mut debugger, "z 2");
+        assert_eq!(
+            execute(&mut monitor, &mut debugger, "bt"),
+            vec!["#0  $1001 in $1000", "#1  $2000"]
+        );
+    }
+
+    #[test]
+    fn step_and_go() {
+        let (mut monitor, mut debugger) = fixture();
+        let lines = execute(&mut monitor, &mut debugger, "z");
+        assert_eq!(lines, vec!["$1001  e8        INX"]);
+        execute(&mut monitor, &mut debugger, "z 2");
+        assert_eq!(debugger.c64.cpu.pc(), 0x1000);
+        assert_eq!(debugger.c64.cpu.reg.x, 2);
+
+        let reply = monitor.execute(&mut debugger, "g 1002").unwrap();
+        assert_eq!(reply.action, Action::Resume);
+        assert_eq!(debugger.c64.cpu.pc(), 0x1002);
+        assert_eq!(
+            monitor.execute(&mut debugger, "x").unwrap().action,
+            Action::Resume
+        );
+        assert_eq!(
+            monitor.execute(&mut debugger, "q").unwrap().action,
+            Action::Quit
+        );
+    }
+
+    #[test]
+    fn breakpoints() {
+        let (mut monitor, mut debugger) = fixture();
+        assert_eq!(
+            execute(&mut monitor, &mut debugger, "break 1002"),
+            vec!["Breakpoint 1 at $1002"]
+        );
+        execute(&mut monitor, &mut debugger, "break $1001");
+        execute(&mut monitor, &mut debugger, "disable 2");
+        assert_eq!(
+            execute(&mut monitor, &mut debugger, "break"),
+            vec!["Breakpoint 1 at $1002", "Breakpoint 2 at $1001 (disabled)"]
+        );
+        execute(&mut monitor, &mut debugger, "del 1");
+        assert!(monitor.execute(&mut debugger, "del 1").is_err());
+        assert_eq!(debugger.breakpoints().count(), 1);
+    }
+
+    #[test]
+    fn watchpoints_and_conditions() {
+        let (mut monitor, mut debugger) = fixture();
+        assert_eq!(
+            execute(
+                &mut monitor,
+                &mut debugger,
+                "watch store d000 d3ff if value == $ff"
+            ),
+            vec!["Watchpoint 1 store at $d000-$d3ff if value == $ff"]
+        );
+        assert_eq!(
+            execute(&mut monitor, &mut debugger, "w 02"),
+            vec!["Watchpoint 2 loadstore at $0002"]
+        );
+        assert_eq!(
+            execute(&mut monitor, &mut debugger, "trace 1000 1001"),
+            vec!["Tracepoint 3 at $1000-$1001"]
+        );
+        assert_eq!(
+            execute(&mut monitor, &mut debugger, "break 1001 if x==3"),
+            vec!["Breakpoint 4 at $1001 if x == 3"]
+        );
+        assert_eq!(
+            execute(&mut monitor, &mut debugger, "cond 4 if hits >= 2"),
+            vec!["Breakpoint 4 at $1001 if hits >= 2"]
+        );
+        assert_eq!(
+            execute(&mut monitor, &mut debugger, "cond 4"),
+            vec!["Breakpoint 4 at $1001"]
+        );
+        assert!(monitor.execute(&mut debugger, "cond 4 if a =").is_err());
+        assert!(monitor.execute(&mut debugger, "cond 9 if a == 1").is_err());
+        assert!(monitor
+            .execute(&mut debugger, "watch store d3ff d000")
+            .is_err());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let (mut monitor, mut debugger) = fixture();
+        let path = std::env::temp_dir().join("cpu_emu_monitor_test.prg");
+        let path = path.to_str().unwrap();
+        execute(
+            &mut monitor,
+            &mut debugger,
+            &format!("s \"{}\" 1000 1004", path),
+        );
+        assert_eq!(
+            std::fs::read(path).unwrap(),
+            vec![0x00, 0x10, 0xe8, 0xe8, 0x4c, 0x00, 0x10]
+        );
+
+        let lines = execute(&mut monitor, &mut debugger, &format!("l \"{}\" 2000", path));
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(lines, vec!["Loaded 5 bytes to $2000-$2004"]);
+        assert_eq!(debugger.c64.bus.peek_byte(0x2002), 0x4c);
+    }
+
+    #[test]
+    fn labels() {
+        let (mut monitor, mut debugger) = fixture();
+        let path = std::env::temp_dir().join("cpu_emu_monitor_test.lbl");
+        std::fs::write(&path, "al C:1000 .main_loop\nal C:1002 .jump\n").unwrap();
+        let lines = execute(
+            &mut monitor,
+            &mut debugger,
+            &format!("ll \"{}\"", path.to_str().unwrap()),
+        );
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(lines, vec!["Loaded 2 labels"]);
+        execute(&mut monitor, &mut debugger, "al d020 .border");
+
+        assert_eq!(
+            execute(&mut monitor, &mut debugger, "d .jump jump"),
+            vec!["$1002  4c 00 10  JMP main_loop"]
+        );
+        assert_eq!(
+            execute(&mut monitor, &mut debugger, "break jump"),
+            vec!["Breakpoint 1 at $1002 (jump)"]
+        );
+        assert_eq!(
+            execute(&mut monitor, &mut debugger, "g"),
+            Vec::<String>::new()
+        );
+        let reason = debugger.run(1000);
+        assert_eq!(
+            debugger.describe(reason),
+            "Breakpoint 1 hit at $1002 (jump)"
+        );
+        assert_eq!(
+            execute(&mut monitor, &mut debugger, "shl"),
+            vec!["$1000 .main_loop", "$1002 .jump", "$d020 .border"]
+        );
+        execute(&mut monitor, &mut debugger, "cl");
+        assert!(monitor.execute(&mut debugger, "break jump").is_err());
+        assert!(monitor
+            .execute(&mut debugger, "ll \"missing.lbl\"")
+            .is_err());
+    }
+
+    #[test]
+    fn repl() {
+        let (mut monitor, mut debugger) = fixture();
+        let mut input = "z\nfoo\nx\n".as_bytes();
+        let mut output = vec![];
+        let action = monitor.run(&mut debugger, &mut input, &mut output).unwrap();
+        assert_eq!(action, Action::Resume);
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "$1000  e8        INX\n(C:$1000) $1001  e8        INX\n\
+             (C:$1001) Error: Unknown command: foo\n(C:$1001) "
+        );
+    }
+}
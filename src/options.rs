@@ -0,0 +1,372 @@
+use crate::autotype::TypeMode;
+use crate::keymap::{JoystickKeys, Keymap};
+use crate::palette::Palette;
+use crate::sid::SidModel;
+use crate::vic::VideoStandard;
+
+use std::path::{Path, PathBuf};
+
+pub struct Options {
+    pub palette: Palette,
+    pub keymap: Keymap,
+    pub joystick_keys: JoystickKeys,
+    // Control port of keyboard joystick and the first game controller, 0 for port 1
+    pub joystick_port: usize,
+    // Typed after start, "\n" is RETURN
+    pub type_text: Option<String>,
+    pub type_mode: TypeMode,
+    pub video: Option<VideoStandard>,
+    // Run as fast as possible, Alt+W toggles it
+    pub warp: bool,
+    // None means model requested by the tune or 6581
+    pub sid_model: Option<SidModel>,
+    // PSID/RSID file to play instead of running a program, song None is the start one
+    pub tune: Option<PathBuf>,
+    pub song: Option<u16>,
+    // SID output is recorded from the start, F11 toggles recording in window
+    pub record: Option<PathBuf>,
+    // Execution breakpoints, they enter monitor (or finish headless run without it)
+    pub breakpoints: Vec<u16>,
+    // Label files of the program, names are shown by monitor and usable as addresses
+    pub symbols: Vec<PathBuf>,
+    // Port of GDB remote protocol server on localhost
+    pub gdb_port: Option<u16>,
+    // Port of VICE binary monitor protocol server on localhost
+    pub binary_monitor_port: Option<u16>,
+    // Port of Debug Adapter Protocol server on localhost, for editors
+    pub dap_port: Option<u16>,
+    // Enter machine language monitor before running, Alt+M enters it in window
+    pub monitor: bool,
+    // Run without window, as fast as possible
+    pub headless: bool,
+    // How many frames to run in headless mode
+    pub frames: u32,
+    // Saved when headless run is finished
+    pub screenshot: Option<PathBuf>,
+    pub screenshot_border: bool,
+}
+
+const USAGE: &str = "Usage: cpu_emu [options]
+    --palette pepto|colodore|vice|<file.vpl>
+    --keymap symbolic|positional
+    --joystick-keys numpad|cursor|none
+    --joystick-port 1|2     port for keyboard joystick (default 2), F9 swaps
+    --type <text>           type text after start, \\n is RETURN
+    --type-mode buffer|matrix
+                            through KERNAL keyboard buffer or keyboard matrix
+    --video pal|ntsc        machine timings (default is what tune asks for or pal)
+    --warp                  don't sync to real time, Alt+W toggles it
+    --sid-model 6581|8580   (default is what tune asks for or 6581)
+    --tune <file.sid>       play PSID/RSID tune
+    --song <n>              song of the tune (default is its start song)
+    --record <file.wav>     record audio, F11 starts and stops recording as well
+    --break <address>       stop on execution of address (hex), can be repeated
+    --symbols <file>        load labels (VICE, ca65 .dbg, KickAssembler, ACME),
+                            can be repeated
+    --gdb <port>            serve GDB remote protocol on localhost port
+    --binary-monitor <port> serve VICE binary monitor protocol on localhost port
+    --dap <port>            serve Debug Adapter Protocol on localhost port
+    --monitor               start in machine language monitor, Alt+M enters it
+    --headless              run without window
+    --frames <n>            frames to run in headless mode (default 1)
+    --screenshot <file.png> save screen after headless run
+    --no-border             crop border from screenshots";
+
+impl Options {
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        let mut options = Options {
+            palette: Palette::default(),
+            keymap: Keymap::Symbolic,
+            joystick_keys: JoystickKeys::Numpad,
+            joystick_port: 1,
+            type_text: None,
+            type_mode: TypeMode::KeyboardBuffer,
+            video: None,
+            warp: false,
+            sid_model: None,
+            tune: None,
+            song: None,
+            record: None,
+            breakpoints: vec![],
+            symbols: vec![],
+            gdb_port: None,
+            binary_monitor_port: None,
+            dap_port: None,
+            monitor: false,
+            headless: false,
+            frames: 1,
+            screenshot: None,
+            screenshot_border: true,
+        };
+
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .ok_or(format!("Missing value for {}\n{}", name, USAGE))
+            };
+            match arg.as_str() {
+                "--palette" => options.palette = parse_palette(&value("--palette")?)?,
+                "--keymap" => {
+                    let name = value("--keymap")?;
+                    options.keymap =
+                        Keymap::from_name(&name).ok_or(format!("Unknown keymap: {}", name))?;
+                }
+                "--joystick-keys" => {
+                    let name = value("--joystick-keys")?;
+                    options.joystick_keys = JoystickKeys::from_name(&name)
+                        .ok_or(format!("Unknown joystick keys: {}", name))?;
+                }
+                "--joystick-port" => {
+                    options.joystick_port = match value("--joystick-port")?.as_str() {
+                        "1" => 0,
+                        "2" => 1,
+                        port => return Err(format!("Bad joystick port: {}", port)),
+                    }
+                }
+                "--type" => options.type_text = Some(value("--type")?.replace("\\n", "\n")),
+                "--type-mode" => {
+                    let name = value("--type-mode")?;
+                    options.type_mode =
+                        TypeMode::from_name(&name).ok_or(format!("Unknown type mode: {}", name))?;
+                }
+                "--video" => {
+                    let name = value("--video")?;
+                    options.video = Some(
+                        VideoStandard::from_name(&name)
+                            .ok_or(format!("Unknown video standard: {}", name))?,
+                    );
+                }
+                "--warp" => options.warp = true,
+                "--sid-model" => {
+                    let name = value("--sid-model")?;
+                    options.sid_model = Some(
+                        SidModel::from_name(&name).ok_or(format!("Unknown SID model: {}", name))?,
+                    );
+                }
+                "--tune" => options.tune = Some(PathBuf::from(value("--tune")?)),
+                "--song" => {
+                    let song = value("--song")?;
+                    options.song = Some(song.parse().map_err(|_| format!("Bad song: {}", song))?);
+                }
+                "--record" => options.record = Some(PathBuf::from(value("--record")?)),
+                "--break" => options.breakpoints.push(parse_address(&value("--break")?)?),
+                "--symbols" => options.symbols.push(PathBuf::from(value("--symbols")?)),
+                "--gdb" => {
+                    let port = value("--gdb")?;
+                    options.gdb_port =
+                        Some(port.parse().map_err(|_| format!("Bad port: {}", port))?);
+                }
+                "--binary-monitor" => {
+                    let port = value("--binary-monitor")?;
+                    options.binary_monitor_port =
+                        Some(port.parse().map_err(|_| format!("Bad port: {}", port))?);
+                }
+                "--dap" => {
+                    let port = value("--dap")?;
+                    options.dap_port =
+                        Some(port.parse().map_err(|_| format!("Bad port: {}", port))?);
+                }
+                "--monitor" => options.monitor = true,
+                "--headless" => options.headless = true,
+                "--frames" => {
+                    let frames = value("--frames")?;
+                    options.frames = frames
+                        .parse()
+                        .map_err(|_| format!("Bad number of frames: {}", frames))?;
+                }
+                "--screenshot" => options.screenshot = Some(PathBuf::from(value("--screenshot")?)),
+                "--no-border" => options.screenshot_border = false,
+                "--help" | "-h" => return Err(USAGE.to_string()),
+                _ => return Err(format!("Unknown argument: {}\n{}", arg, USAGE)),
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+// Hex, with optional $ or 0x prefix
+pub fn parse_address(value: &str) -> Result<u16, String> {
+    u16::from_str_radix(hex_digits(value), 16).map_err(|_| format!("Bad address: {}", value))
+}
+
+// Hex number without its prefix, 0x in either case
+pub fn hex_digits(value: &str) -> &str {
+    value
+        .strip_prefix('$')
+        .or_else(|| value.strip_prefix("0x"))
+        .or_else(|| value.strip_prefix("0X"))
+        .unwrap_or(value)
+}
+
+fn parse_palette(value: &str) -> Result<Palette, String> {
+    if let Some(palette) = Palette::from_name(value) {
+        return Ok(palette);
+    }
+    Palette::load_vpl(Path::new(value))
+        .map_err(|err| format!("Can't load palette {}: {}", value, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn defaults() {
+        let options = parse(&[]).unwrap();
+        assert_eq!(options.palette, Palette::pepto());
+        assert!(!options.headless);
+        assert!(options.screenshot_border);
+    }
+
+    #[test]
+    fn builtin_palette() {
+        let options = parse(&["--palette", "colodore"]).unwrap();
+        assert_eq!(options.palette, Palette::colodore());
+    }
+
+    #[test]
+    fn missing_palette_file() {
+        assert!(parse(&["--palette", "/nonexistent.vpl"]).is_err());
+        assert!(parse(&["--palette"]).is_err());
+    }
+
+    #[test]
+    fn headless_screenshot() {
+        let options = parse(&[
+            "--headless",
+            "--frames",
+            "10",
+            "--screenshot",
+            "out.png",
+            "--no-border",
+        ])
+        .unwrap();
+        assert!(options.headless);
+        assert_eq!(options.frames, 10);
+        assert_eq!(options.screenshot, Some(PathBuf::from("out.png")));
+        assert!(!options.screenshot_border);
+    }
+
+    #[test]
+    fn keymap() {
+        assert_eq!(parse(&[]).unwrap().keymap, Keymap::Symbolic);
+        let options = parse(&["--keymap", "positional"]).unwrap();
+        assert_eq!(options.keymap, Keymap::Positional);
+        assert!(parse(&["--keymap", "dvorak"]).is_err());
+    }
+
+    #[test]
+    fn joystick() {
+        let options = parse(&[]).unwrap();
+        assert_eq!(options.joystick_keys, JoystickKeys::Numpad);
+        assert_eq!(options.joystick_port, 1);
+
+        let options = parse(&["--joystick-keys", "cursor", "--joystick-port", "1"]).unwrap();
+        assert_eq!(options.joystick_keys, JoystickKeys::Cursor);
+        assert_eq!(options.joystick_port, 0);
+        assert!(parse(&["--joystick-port", "3"]).is_err());
+    }
+
+    #[test]
+    fn type_text() {
+        let options = parse(&["--type", "run\\n", "--type-mode", "matrix"]).unwrap();
+        assert_eq!(options.type_text, Some("run\n".to_string()));
+        assert_eq!(options.type_mode, TypeMode::Matrix);
+        assert!(parse(&["--type-mode", "fast"]).is_err());
+    }
+
+    #[test]
+    fn video() {
+        let options = parse(&[]).unwrap();
+        assert_eq!(options.video, None);
+        assert!(!options.warp);
+
+        let options = parse(&["--video", "ntsc", "--warp"]).unwrap();
+        assert_eq!(options.video, Some(VideoStandard::Ntsc));
+        assert!(options.warp);
+        assert!(parse(&["--video", "secam"]).is_err());
+    }
+
+    #[test]
+    fn sid_model() {
+        assert_eq!(parse(&[]).unwrap().sid_model, None);
+        let options = parse(&["--sid-model", "8580"]).unwrap();
+        assert_eq!(options.sid_model, Some(SidModel::Mos8580));
+        assert!(parse(&["--sid-model", "6582"]).is_err());
+    }
+
+    #[test]
+    fn tune() {
+        let options = parse(&["--tune", "Commando.sid", "--song", "2"]).unwrap();
+        assert_eq!(options.tune, Some(PathBuf::from("Commando.sid")));
+        assert_eq!(options.song, Some(2));
+        assert!(parse(&["--song", "first"]).is_err());
+    }
+
+    #[test]
+    fn record() {
+        assert_eq!(parse(&[]).unwrap().record, None);
+        let options = parse(&["--headless", "--record", "out.wav"]).unwrap();
+        assert_eq!(options.record, Some(PathBuf::from("out.wav")));
+        assert!(parse(&["--record"]).is_err());
+    }
+
+    #[test]
+    fn breakpoints() {
+        let options = parse(&["--break", "$c000", "--break", "0x1000", "--break", "e5cd"]).unwrap();
+        assert_eq!(options.breakpoints, vec![0xc000, 0x1000, 0xe5cd]);
+        assert_eq!(parse_address("0X0801"), Ok(0x0801));
+        assert!(parse(&["--break", "10000"]).is_err());
+        assert!(parse(&["--break", "$"]).is_err());
+        assert!(!options.monitor);
+        assert!(parse(&["--monitor"]).unwrap().monitor);
+    }
+
+    #[test]
+    fn symbols() {
+        let options = parse(&["--symbols", "game.lbl", "--symbols", "game.dbg"]).unwrap();
+        assert_eq!(
+            options.symbols,
+            vec![PathBuf::from("game.lbl"), PathBuf::from("game.dbg")]
+        );
+        assert!(parse(&["--symbols"]).is_err());
+    }
+
+    #[test]
+    fn gdb_port() {
+        assert_eq!(parse(&[]).unwrap().gdb_port, None);
+        assert_eq!(parse(&["--gdb", "1234"]).unwrap().gdb_port, Some(1234));
+        assert!(parse(&["--gdb", "70000"]).is_err());
+        assert!(parse(&["--gdb"]).is_err());
+    }
+
+    #[test]
+    fn binary_monitor_port() {
+        assert_eq!(parse(&[]).unwrap().binary_monitor_port, None);
+        let options = parse(&["--binary-monitor", "6502"]).unwrap();
+        assert_eq!(options.binary_monitor_port, Some(6502));
+        assert!(parse(&["--binary-monitor", "port"]).is_err());
+    }
+
+    #[test]
+    fn dap_port() {
+        assert_eq!(parse(&[]).unwrap().dap_port, None);
+        assert_eq!(parse(&["--dap", "4711"]).unwrap().dap_port, Some(4711));
+        assert!(parse(&["--dap"]).is_err());
+    }
+
+    #[test]
+    fn bad_frames() {
+        assert!(parse(&["--frames", "many"]).is_err());
+    }
+
+    #[test]
+    fn unknown_argument() {
+        assert!(parse(&["--foo"]).is_err());
+    }
+}
@@ -0,0 +1,225 @@
+use crate::host_io::Color;
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+const COLORS_COUNT: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Palette {
+    colors: [(u8, u8, u8); COLORS_COUNT],
+}
+
+#[derive(Debug)]
+pub enum PaletteError {
+    Io(std::io::Error),
+    Parse { line: usize, message: String },
+    WrongColorsCount(usize),
+}
+
+impl fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PaletteError::Io(err) => write!(f, "{}", err),
+            PaletteError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+            PaletteError::WrongColorsCount(count) => {
+                write!(f, "expected {} colors, got {}", COLORS_COUNT, count)
+            }
+        }
+    }
+}
+
+impl Palette {
+    // https://www.pepto.de/projects/colorvic/
+    pub fn pepto() -> Self {
+        Self {
+            colors: [
+                (0x00, 0x00, 0x00),
+                (0xff, 0xff, 0xff),
+                (0x68, 0x37, 0x2b),
+                (0x70, 0xa4, 0xb2),
+                (0x6f, 0x3d, 0x86),
+                (0x58, 0x8d, 0x43),
+                (0x35, 0x28, 0x79),
+                (0xb8, 0xc7, 0x6f),
+                (0x6f, 0x4f, 0x25),
+                (0x43, 0x39, 0x00),
+                (0x9a, 0x67, 0x59),
+                (0x44, 0x44, 0x44),
+                (0x6c, 0x6c, 0x6c),
+                (0x9a, 0xd2, 0x84),
+                (0x6c, 0x5e, 0xb5),
+                (0x95, 0x95, 0x95),
+            ],
+        }
+    }
+
+    // https://www.colodore.com/ with default settings
+    pub fn colodore() -> Self {
+        Self {
+            colors: [
+                (0x00, 0x00, 0x00),
+                (0xff, 0xff, 0xff),
+                (0x81, 0x33, 0x38),
+                (0x75, 0xce, 0xc8),
+                (0x8e, 0x3c, 0x97),
+                (0x56, 0xac, 0x4d),
+                (0x2e, 0x2c, 0x9b),
+                (0xed, 0xf1, 0x71),
+                (0x8e, 0x50, 0x29),
+                (0x55, 0x38, 0x00),
+                (0xc4, 0x6c, 0x71),
+                (0x4a, 0x4a, 0x4a),
+                (0x7b, 0x7b, 0x7b),
+                (0xa9, 0xff, 0x9f),
+                (0x70, 0x6d, 0xeb),
+                (0xb2, 0xb2, 0xb2),
+            ],
+        }
+    }
+
+    // vice.vpl from VICE distribution
+    pub fn vice() -> Self {
+        Self {
+            colors: [
+                (0x00, 0x00, 0x00),
+                (0xfd, 0xfe, 0xfc),
+                (0xbe, 0x1a, 0x24),
+                (0x30, 0xe6, 0xc6),
+                (0xb4, 0x1a, 0xe2),
+                (0x1f, 0xd2, 0x1e),
+                (0x21, 0x1b, 0xae),
+                (0xdf, 0xf6, 0x0a),
+                (0xb8, 0x41, 0x04),
+                (0x6a, 0x33, 0x04),
+                (0xfe, 0x4a, 0x57),
+                (0x42, 0x45, 0x40),
+                (0x70, 0x74, 0x6f),
+                (0x59, 0xfe, 0x59),
+                (0x5f, 0x53, 0xfe),
+                (0xa4, 0xa7, 0xa2),
+            ],
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "pepto" => Some(Self::pepto()),
+            "colodore" => Some(Self::colodore()),
+            "vice" => Some(Self::vice()),
+            _ => None,
+        }
+    }
+
+    pub fn load_vpl(path: &Path) -> Result<Self, PaletteError> {
+        let text = fs::read_to_string(path).map_err(PaletteError::Io)?;
+        Self::parse_vpl(&text)
+    }
+
+    // VICE palette file: '#' comments and one "RR GG BB [dither]" hex line per color
+    pub fn parse_vpl(text: &str) -> Result<Self, PaletteError> {
+        let mut colors = vec![];
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let parse_error = |message: &str| PaletteError::Parse {
+                line: number + 1,
+                message: message.to_string(),
+            };
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 3 || fields.len() > 4 {
+                return Err(parse_error("expected red, green, blue and optional dither"));
+            }
+            let mut rgb = [0u8; 3];
+            for (component, field) in rgb.iter_mut().zip(&fields) {
+                *component =
+                    u8::from_str_radix(field, 16).map_err(|_| parse_error("bad hex number"))?;
+            }
+            colors.push((rgb[0], rgb[1], rgb[2]));
+        }
+
+        if colors.len() != COLORS_COUNT {
+            return Err(PaletteError::WrongColorsCount(colors.len()));
+        }
+        let mut palette = Self::pepto();
+        palette.colors.copy_from_slice(&colors);
+        Ok(palette)
+    }
+
+    pub fn rgb(&self, color: Color) -> (u8, u8, u8) {
+        self.colors[color as usize]
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::pepto()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_by_name() {
+        assert_eq!(Palette::from_name("colodore"), Some(Palette::colodore()));
+        assert_eq!(Palette::from_name("unknown"), None);
+    }
+
+    #[test]
+    fn rgb_of_color() {
+        let palette = Palette::vice();
+        assert_eq!(palette.rgb(Color::Black), (0, 0, 0));
+        assert_eq!(palette.rgb(Color::LightGray), (0xa4, 0xa7, 0xa2));
+    }
+
+    #[test]
+    fn parse_vpl() {
+        let mut text = String::from("#\n# VICE Palette file\n#\n\n");
+        for i in 0..16 {
+            text += &format!(
+                "# Color {}\n{:02X} {:02x} 0{:X} {}\n\n",
+                i,
+                i * 16,
+                i,
+                i,
+                i % 4
+            );
+        }
+
+        let palette = Palette::parse_vpl(&text).unwrap();
+        assert_eq!(palette.rgb(Color::Black), (0x00, 0x00, 0x00));
+        assert_eq!(palette.rgb(Color::Red), (0x20, 0x02, 0x02));
+        assert_eq!(palette.rgb(Color::LightGray), (0xf0, 0x0f, 0x0f));
+    }
+
+    #[test]
+    fn parse_vpl_without_dither() {
+        let text = "ff 00 00\n".repeat(16);
+        let palette = Palette::parse_vpl(&text).unwrap();
+        assert_eq!(palette.rgb(Color::Cyan), (0xff, 0, 0));
+    }
+
+    #[test]
+    fn parse_vpl_wrong_count() {
+        let text = "00 00 00 0\n".repeat(15);
+        assert!(matches!(
+            Palette::parse_vpl(&text),
+            Err(PaletteError::WrongColorsCount(15))
+        ));
+    }
+
+    #[test]
+    fn parse_vpl_bad_number() {
+        let text = "00 00 00 0\n00 0g 00 0\n";
+        assert!(matches!(
+            Palette::parse_vpl(text),
+            Err(PaletteError::Parse { line: 2, .. })
+        ));
+    }
+}
@@ -0,0 +1,51 @@
+// Conversion of text to PETSCII, what KERNAL puts into keyboard buffer. Lowercase
+// letters are unshifted ones, which C64 shows as uppercase in default character set.
+
+pub const RETURN: u8 = 0x0d;
+
+pub fn from_char(ch: char) -> Option<u8> {
+    let code = match ch {
+        'a'..='z' => ch as u8 - b'a' + 0x41,
+        'A'..='Z' => ch as u8 - b'A' + 0xc1,
+        ' '..='@' => ch as u8,
+        '[' => 0x5b,
+        '£' | '\\' => 0x5c,
+        ']' => 0x5d,
+        '↑' | '^' => 0x5e,
+        '←' | '_' => 0x5f,
+        'π' => 0xde,
+        '\n' => RETURN,
+        _ => return None,
+    };
+    Some(code)
+}
+
+// Unknown characters are skipped
+pub fn from_str(text: &str) -> Vec<u8> {
+    text.chars().filter_map(from_char).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_command() {
+        assert_eq!(
+            from_str("load\"*\",8,1\n"),
+            vec![0x4c, 0x4f, 0x41, 0x44, 0x22, 0x2a, 0x22, 0x2c, 0x38, 0x2c, 0x31, 0x0d]
+        );
+    }
+
+    #[test]
+    fn shifted_letters() {
+        assert_eq!(from_char('A'), Some(0xc1));
+        assert_eq!(from_char('Z'), Some(0xda));
+    }
+
+    #[test]
+    fn unknown_skipped() {
+        assert_eq!(from_char('~'), None);
+        assert_eq!(from_str("a\r\nb"), vec![0x41, RETURN, 0x42]);
+    }
+}
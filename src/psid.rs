@@ -0,0 +1,487 @@
+// PSID/RSID music files, see SID_file_format.txt from HVSC. We have no ROMs, so the
+// player puts into RAM a small driver which calls init with selected song and then
+// play from interrupt, plus the few KERNAL interrupt routines tunes jump to.
+
+use crate::bus::Bus;
+use crate::c64::C64;
+use crate::sid::SidModel;
+use crate::vic::VideoStandard;
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+const HEADER_V1_SIZE: usize = 0x76;
+const HEADER_V2_SIZE: usize = 0x7c;
+
+const FLAG_MUS: u16 = 0b0000_0001;
+// RSID only: tune is BASIC program
+const FLAG_BASIC: u16 = 0b0000_0010;
+const FLAG_CLOCK_SHIFT: u16 = 2;
+const FLAG_MODEL_SHIFT: u16 = 4;
+
+// KERNAL interrupt entry and exit routines, tunes jump to the exits from their handlers
+const KERNAL_IRQ: u16 = 0xff48;
+const KERNAL_NMI: u16 = 0xfe43;
+const KERNAL_IRQ_EXIT: u16 = 0xea31;
+const KERNAL_REGISTERS_EXIT: u16 = 0xea81;
+const KERNAL_NMI_EXIT: u16 = 0xfebc;
+const IRQ_VECTOR: u16 = 0x0314;
+const NMI_VECTOR: u16 = 0x0318;
+
+// Push registers and jump through RAM vector, like KERNAL does
+const ENTRY_CODE: [u8; 8] = [0x48, 0x8a, 0x48, 0x98, 0x48, 0x6c, 0x00, 0x00];
+// Pull registers and return from interrupt
+const EXIT_CODE: [u8; 6] = [0x68, 0xa8, 0x68, 0xaa, 0x68, 0x40];
+
+// Where the driver goes if tune doesn't tell us
+const DRIVER_CANDIDATES: [u16; 3] = [0x0334, 0xcf00, 0x0200];
+const DRIVER_SIZE: u16 = 21;
+// Offset of play interrupt handler in the driver
+const DRIVER_IRQ: u16 = 10;
+
+// KERNAL sets CIA1 timer A to about 60 Hz
+const CIA_TIMER_60HZ_PAL: u16 = 0x4025;
+const CIA_TIMER_60HZ_NTSC: u16 = 0x4295;
+// Raster line of play interrupt for VBI tunes
+const PLAY_RASTER_LINE: u8 = 0;
+
+#[derive(Debug)]
+pub enum SidTuneError {
+    Io(std::io::Error),
+    Format(String),
+    Unsupported(String),
+    BadSong(u16),
+}
+
+impl fmt::Display for SidTuneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SidTuneError::Io(err) => write!(f, "{}", err),
+            SidTuneError::Format(message) => write!(f, "bad SID file: {}", message),
+            SidTuneError::Unsupported(message) => write!(f, "unsupported tune: {}", message),
+            SidTuneError::BadSong(song) => write!(f, "no song {} in the tune", song),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TuneFormat {
+    Psid,
+    Rsid,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Clock {
+    Unknown,
+    Pal,
+    Ntsc,
+    Any,
+}
+
+pub struct SidTune {
+    pub format: TuneFormat,
+    pub load_address: u16,
+    pub init_address: u16,
+    // 0 when tune installs its own interrupt handler
+    pub play_address: u16,
+    pub songs: u16,
+    // 1-based, as everything with songs here
+    pub start_song: u16,
+    // Bit for each song, 0 for VBI and 1 for CIA timer
+    speed: u32,
+    pub name: String,
+    pub author: String,
+    pub released: String,
+    flags: u16,
+    start_page: u8,
+    page_length: u8,
+    // C64 data, without load address
+    pub data: Vec<u8>,
+}
+
+fn word(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+// Latin-1, padded with zeroes
+fn text(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .take_while(|&&byte| byte != 0)
+        .map(|&byte| byte as char)
+        .collect()
+}
+
+fn write_bytes(bus: &mut Bus, address: u16, bytes: &[u8]) {
+    for (i, byte) in bytes.iter().enumerate() {
+        bus.set_byte(*byte, address + i as u16);
+    }
+}
+
+fn write_word(bus: &mut Bus, address: u16, value: u16) {
+    write_bytes(bus, address, &value.to_le_bytes());
+}
+
+impl SidTune {
+    pub fn load(path: &Path) -> Result<Self, SidTuneError> {
+        let bytes = fs::read(path).map_err(SidTuneError::Io)?;
+        Self::parse(&bytes)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, SidTuneError> {
+        let format_error = |message: &str| SidTuneError::Format(message.to_string());
+        if bytes.len() < HEADER_V1_SIZE {
+            return Err(format_error("header is too short"));
+        }
+        let format = match &bytes[0..4] {
+            b"PSID" => TuneFormat::Psid,
+            b"RSID" => TuneFormat::Rsid,
+            _ => return Err(format_error("no PSID or RSID magic")),
+        };
+        let version = word(bytes, 0x04);
+        let data_offset = word(bytes, 0x06) as usize;
+        let min_offset = if version == 1 {
+            HEADER_V1_SIZE
+        } else {
+            HEADER_V2_SIZE
+        };
+        if !(1..=4).contains(&version) || data_offset < min_offset || data_offset > bytes.len() {
+            return Err(format_error("bad version or data offset"));
+        }
+        let (flags, start_page, page_length) = if version >= 2 {
+            (word(bytes, 0x76), bytes[0x78], bytes[0x79])
+        } else {
+            (0, 0, 0)
+        };
+
+        let mut load_address = word(bytes, 0x08);
+        let mut data = &bytes[data_offset..];
+        if load_address == 0 {
+            if data.len() < 2 {
+                return Err(format_error("no load address"));
+            }
+            load_address = u16::from_le_bytes([data[0], data[1]]);
+            data = &data[2..];
+        }
+        if load_address as usize + data.len() > 0x10000 {
+            return Err(format_error("data doesn't fit into memory"));
+        }
+        let init_address = match word(bytes, 0x0a) {
+            0 => load_address,
+            address => address,
+        };
+
+        let songs = word(bytes, 0x0e);
+        let start_song = match word(bytes, 0x10) {
+            0 => 1,
+            song => song,
+        };
+        if songs == 0 || start_song > songs {
+            return Err(format_error("bad number of songs"));
+        }
+
+        Ok(Self {
+            format,
+            load_address,
+            init_address,
+            play_address: word(bytes, 0x0c),
+            songs,
+            start_song,
+            speed: u32::from_be_bytes([bytes[0x12], bytes[0x13], bytes[0x14], bytes[0x15]]),
+            name: text(&bytes[0x16..0x36]),
+            author: text(&bytes[0x36..0x56]),
+            released: text(&bytes[0x56..0x76]),
+            flags,
+            start_page,
+            page_length,
+            data: data.to_vec(),
+        })
+    }
+
+    pub fn clock(&self) -> Clock {
+        match (self.flags >> FLAG_CLOCK_SHIFT) & 0b11 {
+            0b01 => Clock::Pal,
+            0b10 => Clock::Ntsc,
+            0b11 => Clock::Any,
+            _ => Clock::Unknown,
+        }
+    }
+
+    // None if tune works on either
+    pub fn video_standard(&self) -> Option<VideoStandard> {
+        match self.clock() {
+            Clock::Pal => Some(VideoStandard::Pal),
+            Clock::Ntsc => Some(VideoStandard::Ntsc),
+            Clock::Unknown | Clock::Any => None,
+        }
+    }
+
+    // None if tune works on any
+    pub fn model(&self) -> Option<SidModel> {
+        match (self.flags >> FLAG_MODEL_SHIFT) & 0b11 {
+            0b01 => Some(SidModel::Mos6581),
+            0b10 => Some(SidModel::Mos8580),
+            _ => None,
+        }
+    }
+
+    // Whether play is called from CIA timer interrupt, otherwise from raster one.
+    // Songs after 32 share the last bit.
+    pub fn uses_cia_timer(&self, song: u16) -> bool {
+        let bit = (song.clamp(1, 32) - 1) as u32;
+        self.format == TuneFormat::Rsid || self.speed & (1 << bit) != 0
+    }
+
+    fn overlaps(&self, address: u16, size: u16) -> bool {
+        let start = self.load_address as u32;
+        let end = start + self.data.len() as u32;
+        (address as u32) < end && start < address as u32 + size as u32
+    }
+
+    // Free memory for the driver: tune can tell which page is free (0xff means none)
+    fn driver_address(&self) -> Option<u16> {
+        match self.start_page {
+            0 => DRIVER_CANDIDATES
+                .iter()
+                .copied()
+                .find(|&address| !self.overlaps(address, DRIVER_SIZE)),
+            0xff => None,
+            page if self.page_length > 0 => Some((page as u16) << 8),
+            _ => None,
+        }
+    }
+
+    #[rustfmt::skip]
+    fn driver_code(&self, address: u16, song: u16) -> Vec<u8> {
+        let [init_lo, init_hi] = self.init_address.to_le_bytes();
+        let [play_lo, play_hi] = self.play_address.to_le_bytes();
+        let [loop_lo, loop_hi] = (address + 7).to_le_bytes();
+        let [exit_lo, exit_hi] = KERNAL_IRQ_EXIT.to_le_bytes();
+        vec![
+            0x78, // SEI
+            0xa9, (song - 1) as u8, // LDA #song
+            0x20, init_lo, init_hi, // JSR init
+            0x58, // CLI
+            0x4c, loop_lo, loop_hi, // JMP *
+            // DRIVER_IRQ: play, acknowledge raster interrupt, KERNAL acknowledges CIA one
+            0x20, play_lo, play_hi, // JSR play
+            0xa9, 0xff, // LDA #$ff
+            0x8d, 0x19, 0xd0, // STA $d019
+            0x4c, exit_lo, exit_hi, // JMP $ea31
+        ]
+    }
+
+    // Load tune into memory and start cpu at the driver, which calls init for song
+    pub fn install(&self, c64: &mut C64, song: u16) -> Result<(), SidTuneError> {
+        if song == 0 || song > self.songs {
+            return Err(SidTuneError::BadSong(song));
+        }
+        if self.flags & FLAG_MUS != 0 {
+            return Err(SidTuneError::Unsupported("MUS data".to_string()));
+        }
+        if self.format == TuneFormat::Rsid && self.flags & FLAG_BASIC != 0 {
+            return Err(SidTuneError::Unsupported("needs BASIC ROM".to_string()));
+        }
+        let driver = self.driver_address().ok_or(SidTuneError::Unsupported(
+            "no free memory for driver".to_string(),
+        ))?;
+
+        let cia_timer = match c64.video_standard() {
+            VideoStandard::Pal => CIA_TIMER_60HZ_PAL,
+            VideoStandard::Ntsc => CIA_TIMER_60HZ_NTSC,
+        };
+        let bus = &mut c64.bus;
+        let mut irq_entry = ENTRY_CODE;
+        irq_entry[6..].copy_from_slice(&IRQ_VECTOR.to_le_bytes());
+        let mut nmi_entry = ENTRY_CODE;
+        nmi_entry[6..].copy_from_slice(&NMI_VECTOR.to_le_bytes());
+        write_bytes(bus, KERNAL_IRQ, &irq_entry);
+        write_bytes(bus, KERNAL_NMI, &nmi_entry);
+        // LDA $dc0d, JMP $ea81
+        write_bytes(bus, KERNAL_IRQ_EXIT, &[0xad, 0x0d, 0xdc, 0x4c, 0x81, 0xea]);
+        write_bytes(bus, KERNAL_REGISTERS_EXIT, &EXIT_CODE);
+        write_bytes(bus, KERNAL_NMI_EXIT, &EXIT_CODE);
+        write_word(bus, 0xfffa, KERNAL_NMI);
+        write_word(bus, 0xfffc, driver);
+        write_word(bus, 0xfffe, KERNAL_IRQ);
+        write_word(bus, NMI_VECTOR, KERNAL_NMI_EXIT);
+
+        // Tunes without play address set up interrupts themselves, expecting
+        // the state KERNAL leaves
+        let play_from_driver = self.play_address != 0 && self.format == TuneFormat::Psid;
+        let irq_handler = if play_from_driver {
+            driver + DRIVER_IRQ
+        } else {
+            KERNAL_IRQ_EXIT
+        };
+        write_word(bus, IRQ_VECTOR, irq_handler);
+        if !play_from_driver || self.uses_cia_timer(song) {
+            write_word(bus, 0xdc04, cia_timer);
+            // Timer A interrupt on, force load and start
+            bus.set_byte(0x81, 0xdc0d);
+            bus.set_byte(0x11, 0xdc0e);
+        } else {
+            bus.set_byte(0x1b, 0xd011);
+            bus.set_byte(PLAY_RASTER_LINE, 0xd012);
+            bus.set_byte(0x01, 0xd01a);
+        }
+
+        write_bytes(bus, driver, &self.driver_code(driver, song));
+        write_bytes(bus, self.load_address, &self.data);
+        c64.cpu.reset(&c64.bus);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host_io::NullMonitor;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // init: STA $0400, RTS. play: INC $0401, RTS
+    const CODE: [u8; 8] = [0x8d, 0x00, 0x04, 0x60, 0xee, 0x01, 0x04, 0x60];
+
+    fn header(magic: &[u8; 4], load: u16, play: u16, songs: u16, speed: u32) -> Vec<u8> {
+        let mut bytes = vec![0; HEADER_V2_SIZE];
+        bytes[0..4].copy_from_slice(magic);
+        bytes[0x04..0x06].copy_from_slice(&2u16.to_be_bytes());
+        bytes[0x06..0x08].copy_from_slice(&(HEADER_V2_SIZE as u16).to_be_bytes());
+        bytes[0x08..0x0a].copy_from_slice(&load.to_be_bytes());
+        bytes[0x0c..0x0e].copy_from_slice(&play.to_be_bytes());
+        bytes[0x0e..0x10].copy_from_slice(&songs.to_be_bytes());
+        bytes[0x10..0x12].copy_from_slice(&1u16.to_be_bytes());
+        bytes[0x12..0x16].copy_from_slice(&speed.to_be_bytes());
+        bytes[0x16..0x1b].copy_from_slice(b"Tune\0");
+        bytes[0x36..0x3c].copy_from_slice(b"Me\0\0\0\0");
+        // PAL, 8580
+        bytes[0x76..0x78].copy_from_slice(&0b10_0100u16.to_be_bytes());
+        bytes
+    }
+
+    fn tune(speed: u32) -> Vec<u8> {
+        let mut bytes = header(b"PSID", 0, 0x1004, 3, speed);
+        bytes.extend_from_slice(&[0x00, 0x10]);
+        bytes.extend_from_slice(&CODE);
+        bytes
+    }
+
+    fn plays(speed: u32, song: u16, frames: u32) -> (u8, u8) {
+        let mut c64 = C64::new(Rc::new(RefCell::new(NullMonitor {})));
+        let tune = SidTune::parse(&tune(speed)).unwrap();
+        tune.install(&mut c64, song).unwrap();
+        for _ in 0..frames {
+            c64.run_frame();
+        }
+        (c64.bus.peek_byte(0x0400), c64.bus.peek_byte(0x0401))
+    }
+
+    #[test]
+    fn parse_header() {
+        let tune = SidTune::parse(&tune(0b010)).unwrap();
+        assert_eq!(tune.format, TuneFormat::Psid);
+        assert_eq!(tune.load_address, 0x1000);
+        assert_eq!(tune.init_address, 0x1000);
+        assert_eq!(tune.play_address, 0x1004);
+        assert_eq!(tune.songs, 3);
+        assert_eq!(tune.name, "Tune");
+        assert_eq!(tune.author, "Me");
+        assert_eq!(tune.data, CODE.to_vec());
+        assert_eq!(tune.clock(), Clock::Pal);
+        assert_eq!(tune.model(), Some(SidModel::Mos8580));
+        assert!(!tune.uses_cia_timer(1));
+        assert!(tune.uses_cia_timer(2));
+        assert!(!tune.uses_cia_timer(40));
+    }
+
+    #[test]
+    fn bad_files() {
+        assert!(matches!(
+            SidTune::parse(b"PSID"),
+            Err(SidTuneError::Format(_))
+        ));
+        let mut bytes = tune(0);
+        bytes[0] = b'X';
+        assert!(SidTune::parse(&bytes).is_err());
+        let mut bytes = header(b"PSID", 0xfffe, 0, 1, 0);
+        bytes.extend_from_slice(&CODE);
+        assert!(SidTune::parse(&bytes).is_err());
+    }
+
+    #[test]
+    fn bad_song() {
+        let mut c64 = C64::new(Rc::new(RefCell::new(NullMonitor {})));
+        let tune = SidTune::parse(&tune(0)).unwrap();
+        assert!(matches!(
+            tune.install(&mut c64, 4),
+            Err(SidTuneError::BadSong(4))
+        ));
+    }
+
+    #[test]
+    fn play_from_raster_interrupt() {
+        let (song, plays) = plays(0, 3, 50);
+        assert_eq!(song, 2);
+        assert!((49..=50).contains(&plays));
+    }
+
+    #[test]
+    fn play_from_cia_timer() {
+        let (song, plays) = plays(0b1, 1, 50);
+        assert_eq!(song, 0);
+        // 60 Hz timer in 50 PAL frames
+        assert!((59..=60).contains(&plays));
+    }
+
+    #[test]
+    fn ntsc_cia_timer() {
+        let mut c64 = C64::new(Rc::new(RefCell::new(NullMonitor {})));
+        c64.set_video_standard(VideoStandard::Ntsc);
+        let tune = SidTune::parse(&tune(0b1)).unwrap();
+        tune.install(&mut c64, 1).unwrap();
+        assert_eq!(c64.bus.peek_byte(0xdc04), 0x95);
+        // One frame is a bit longer than the timer
+        for _ in 0..60 {
+            c64.run_frame();
+        }
+        assert!((60..=61).contains(&c64.bus.peek_byte(0x0401)));
+    }
+
+    #[test]
+    fn ntsc_tune_gets_ntsc_timer() {
+        let mut bytes = tune(0b1);
+        // NTSC, 8580
+        bytes[0x76..0x78].copy_from_slice(&0b10_1000u16.to_be_bytes());
+        let tune = SidTune::parse(&bytes).unwrap();
+        assert_eq!(tune.video_standard(), Some(VideoStandard::Ntsc));
+
+        let mut c64 = C64::new(Rc::new(RefCell::new(NullMonitor {})));
+        c64.set_video_standard(tune.video_standard().unwrap());
+        tune.install(&mut c64, 1).unwrap();
+        assert_eq!(c64.bus.peek_byte(0xdc04), 0x95);
+        assert_eq!(c64.bus.peek_byte(0xdc05), 0x42);
+    }
+
+    #[test]
+    fn rsid_uses_kernal_vector() {
+        // init sets $0314 to play, which goes back through KERNAL exit:
+        // LDA #$0f, STA $0314, LDA #$10, STA $0315, RTS, INC $0401, JMP $ea31
+        let code = [
+            0xa9, 0x0f, 0x8d, 0x14, 0x03, 0xa9, 0x10, 0x8d, 0x15, 0x03, 0x60, 0x00, 0x00, 0x00,
+            0x00, 0xee, 0x01, 0x04, 0x4c, 0x31, 0xea,
+        ];
+        let mut bytes = header(b"RSID", 0x1000, 0, 1, 0);
+        bytes.extend_from_slice(&code);
+        let tune = SidTune::parse(&bytes).unwrap();
+
+        let mut c64 = C64::new(Rc::new(RefCell::new(NullMonitor {})));
+        tune.install(&mut c64, 1).unwrap();
+        for _ in 0..50 {
+            c64.run_frame();
+        }
+        assert!((59..=60).contains(&c64.bus.peek_byte(0x0401)));
+    }
+}
@@ -41,6 +41,42 @@ impl Device for Ram {
     fn tick(&mut self) {}
 }
 
+// 1K x 4 bit static ram at $d800. Only low nibble is connected to data bus,
+// high one reads as whatever was on the bus, for us it's always 0xf.
+pub struct ColorRam {
+    memory: [u8; COLOR_RAM_SIZE],
+}
+
+pub const COLOR_RAM_SIZE: usize = 0x400;
+
+impl ColorRam {
+    pub fn new() -> Self {
+        ColorRam {
+            memory: [0; COLOR_RAM_SIZE],
+        }
+    }
+
+    // Index is offset from the start of color ram
+    pub fn get_color(&self, index: u16) -> u8 {
+        self.memory[index as usize % COLOR_RAM_SIZE]
+    }
+}
+
+impl Device for ColorRam {
+    fn set_byte(&mut self, byte: u8, offset: u16) {
+        self.memory[offset as usize % COLOR_RAM_SIZE] = byte & 0x0f;
+    }
+
+    fn get_byte(&self, offset: u16) -> u8 {
+        0xf0 | self.memory[offset as usize % COLOR_RAM_SIZE]
+    }
+
+    fn get_bytes_slice(&self, from: u16, to: u16) -> Vec<u8> {
+        (from..to).map(|offset| self.get_byte(offset)).collect()
+    }
+    fn tick(&mut self) {}
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -78,4 +114,12 @@ mod tests {
         let card = vec![1, 2, 3, 4];
         assert_err!(ram.set_memory(&card, 5));
     }
+
+    #[test]
+    fn color_ram_keeps_low_nibble() {
+        let mut ram = ColorRam::new();
+        ram.set_byte(0x7e, 0xd801);
+        assert_eq!(ram.get_color(1), 0x0e);
+        assert_eq!(ram.get_byte(0xd801), 0xfe);
+    }
 }
@@ -0,0 +1,182 @@
+// TCP server shared by the remote debugging protocols. Listens on localhost, one client
+// at a time. Never blocks, it's polled from the emulation loop; what the socket doesn't
+// take is kept and sent on the next poll.
+
+use crate::debugger::{Debugger, RemoteDebugger, StopReason};
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+
+// Protocol state of one client connection
+pub trait Session {
+    // Protocol name in messages, e.g. GDB client connected
+    const NAME: &'static str;
+
+    fn new() -> Self;
+    // Handles complete messages of input and removes them from it. false when client
+    // closed the connection.
+    fn receive(&mut self, debugger: &mut Debugger, input: &mut Vec<u8>) -> bool;
+    // Messages waiting to be sent
+    fn output(&mut self) -> &mut Vec<u8>;
+    // Emulation goes on until it stops, false when client is halting it
+    fn running(&self) -> bool;
+    // Emulation stopped while client waited for it
+    fn stopped(&mut self, debugger: &Debugger, reason: StopReason);
+    // Client has gone
+    fn detach(&mut self, debugger: &mut Debugger);
+    // Client asked emulator to quit
+    fn quit(&self) -> bool;
+}
+
+struct Client<S> {
+    stream: TcpStream,
+    // Received bytes which don't make a message yet
+    input: Vec<u8>,
+    // Bytes socket didn't take yet
+    output: Vec<u8>,
+    session: S,
+}
+
+impl<S: Session> Client<S> {
+    // Writes as much as socket takes without blocking
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.append(self.session.output());
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(count) => drop(self.output.drain(..count)),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    // false when client has gone
+    fn poll(&mut self, debugger: &mut Debugger) -> io::Result<bool> {
+        let mut buffer = [0; 4096];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Ok(false),
+                Ok(count) => self.input.extend_from_slice(&buffer[..count]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+        let open = self.session.receive(debugger, &mut self.input);
+        self.flush()?;
+        Ok(open)
+    }
+}
+
+pub struct RemoteServer<S> {
+    listener: TcpListener,
+    client: Option<Client<S>>,
+}
+
+impl<S: Session> RemoteServer<S> {
+    pub fn bind(port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            client: None,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    fn accept(&mut self) {
+        let (stream, address) = match self.listener.accept() {
+            Ok(connection) => connection,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return,
+            Err(err) => {
+                eprintln!("Can't accept {} client: {}", S::NAME, err);
+                return;
+            }
+        };
+        if let Err(err) = stream.set_nonblocking(true) {
+            eprintln!("Can't accept {} client: {}", S::NAME, err);
+            return;
+        }
+        println!("{} client connected from {}", S::NAME, address);
+        self.client = Some(Client {
+            stream,
+            input: vec![],
+            output: vec![],
+            session: S::new(),
+        });
+    }
+}
+
+impl<S: Session> RemoteDebugger for RemoteServer<S> {
+    fn poll(&mut self, debugger: &mut Debugger) -> bool {
+        if self.client.is_none() {
+            self.accept();
+        }
+        let client = match &mut self.client {
+            Some(client) => client,
+            None => return true,
+        };
+        match client.poll(debugger) {
+            Ok(true) => return !client.session.quit(),
+            Ok(false) => println!("{} client disconnected", S::NAME),
+            Err(err) => eprintln!("{} connection failed: {}", S::NAME, err),
+        }
+        client.session.detach(debugger);
+        self.client = None;
+        true
+    }
+
+    fn halted(&self) -> bool {
+        matches!(&self.client, Some(client) if !client.session.running())
+    }
+
+    fn stopped(&mut self, debugger: &Debugger, reason: StopReason) -> bool {
+        let client = match &mut self.client {
+            Some(client) if client.session.running() => client,
+            _ => return false,
+        };
+        client.session.stopped(debugger, reason);
+        if let Err(err) = client.flush() {
+            eprintln!("{} connection failed: {}", S::NAME, err);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gdb_stub;
+
+    #[test]
+    fn output_waits_for_slow_client() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let mut reader = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let mut client = Client {
+            stream,
+            input: vec![],
+            output: vec![],
+            session: gdb_stub::Session::new(),
+        };
+        // More than socket buffers hold, the rest is kept for later
+        let expected = vec![b'x'; 1 << 25];
+        client.session.output().extend_from_slice(&expected);
+        client.flush().unwrap();
+        assert!(!client.output.is_empty());
+
+        let mut received = vec![0; expected.len()];
+        let mut count = 0;
+        while count < expected.len() {
+            client.flush().unwrap();
+            count += reader.read(&mut received[count..]).unwrap();
+        }
+        assert_eq!(received, expected);
+        assert!(client.output.is_empty());
+    }
+}
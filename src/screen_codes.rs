@@ -0,0 +1,72 @@
+// Conversion of screen codes (what is stored in video matrix, not PETSCII!) to text.
+// Only uppercase/graphics character set is supported, since that's what C64 starts with.
+
+// Screen codes $40-$7f, graphics characters
+#[rustfmt::skip]
+const GRAPHICS: [char; 64] = [
+    '─', '♠', '🭲', '🭸', '🭷', '🭶', '🭺', '🭱', '🭴', '╮', '╰', '╯', '🭼', '╲', '╱', '🭽',
+    '🭾', '●', '🭻', '♥', '🭰', '╭', '╳', '○', '♣', '🭵', '♦', '┼', '🮌', '│', 'π', '◥',
+    '\u{a0}', '▌', '▄', '▔', '▁', '▏', '▒', '▕', '🮏', '◤', '🮇', '├', '▗', '└', '┐', '▂',
+    '┌', '┴', '┬', '┤', '▎', '▍', '🮈', '🮂', '🮃', '▃', '🭿', '▖', '▝', '┘', '▘', '▚',
+];
+
+// Reversed characters ($80-$ff) are shown as normal ones
+pub fn to_unicode(code: u8) -> char {
+    let code = code & 0x7f;
+    match code {
+        0x00 => '@',
+        0x01..=0x1a => (b'A' + code - 1) as char,
+        0x1b => '[',
+        0x1c => '£',
+        0x1d => ']',
+        0x1e => '↑',
+        0x1f => '←',
+        0x20..=0x3f => code as char,
+        _ => GRAPHICS[(code - 0x40) as usize],
+    }
+}
+
+// Same as to_unicode, but graphics characters which don't have ASCII counterpart
+// are replaced with '#'
+pub fn to_ascii(code: u8) -> char {
+    match to_unicode(code) {
+        '£' => '#',
+        '↑' => '^',
+        '←' => '_',
+        '─' => '-',
+        '│' => '|',
+        '┼' => '+',
+        '\u{a0}' => ' ',
+        c if c.is_ascii() => c,
+        _ => '#',
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn letters_and_digits() {
+        let text: String = [0x08, 0x05, 0x0c, 0x0c, 0x0f, 0x20, 0x31, 0x32, 0x21]
+            .iter()
+            .map(|&code| to_unicode(code))
+            .collect();
+        assert_eq!(text, "HELLO 12!");
+    }
+
+    #[test]
+    fn reversed_chars() {
+        assert_eq!(to_unicode(0x81), 'A');
+        assert_eq!(to_unicode(0xa0), ' ');
+    }
+
+    #[test]
+    fn graphics() {
+        assert_eq!(to_unicode(0x41), '♠');
+        assert_eq!(to_unicode(0x7f), '▚');
+        assert_eq!(to_ascii(0x41), '#');
+        assert_eq!(to_ascii(0x5d), '|');
+        assert_eq!(to_ascii(0x60), ' ');
+    }
+}
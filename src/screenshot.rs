@@ -0,0 +1,58 @@
+use crate::host_io::{Color, FrameBuffer};
+use crate::palette::Palette;
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+// Frame is saved as 8 bit indexed PNG with the palette stored in PLTE chunk,
+// so pixels keep the exact C64 color numbers.
+pub fn save_png(
+    frame: &FrameBuffer,
+    palette: &Palette,
+    path: &Path,
+) -> Result<(), png::EncodingError> {
+    let writer = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(writer, frame.width as u32, frame.height as u32);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut plte = Vec::with_capacity(16 * 3);
+    for index in 0..16 {
+        let (r, g, b) = palette.rgb(Color::from_index(index));
+        plte.extend_from_slice(&[r, g, b]);
+    }
+    encoder.set_palette(plte);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&frame.pixels)?;
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_png_has_palette_colors() {
+        let mut frame = FrameBuffer::new(4, 2);
+        frame.set(1, 0, 0x01);
+        frame.set(3, 1, 0x02);
+        let path = std::env::temp_dir().join("cpu_emu_screenshot_test.png");
+
+        save_png(&frame, &Palette::pepto(), &path).unwrap();
+
+        let mut decoder = png::Decoder::new(File::open(&path).unwrap());
+        decoder.set_transformations(png::Transformations::EXPAND);
+        let mut reader = decoder.read_info().unwrap();
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!((info.width, info.height), (4, 2));
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+        assert_eq!(&buf[0..3], &[0x00, 0x00, 0x00]);
+        assert_eq!(&buf[3..6], &[0xff, 0xff, 0xff]);
+        assert_eq!(&buf[21..24], &[0x68, 0x37, 0x2b]);
+    }
+}
@@ -0,0 +1,756 @@
+// 6581/8580 Sound Interface Device. Every cycle three voices produce 12 bit waveforms,
+// which are multiplied by 8 bit envelopes, mixed through the filter and master volume.
+// Oscillators and envelopes follow the chip closely (including the ADSR delay bug),
+// combined waveforms and the filter are approximations.
+
+use crate::bus::Device;
+use crate::host_io::AudioSink;
+use crate::vic::PAL_CLOCK;
+
+use std::cell::RefCell;
+use std::f32::consts::PI;
+use std::rc::Rc;
+
+const REGISTERS_COUNT: u16 = 0x20;
+const VOICE_REGISTERS: u16 = 7;
+
+// Voice registers, relative to voice base
+const REG_FREQ_LO: u16 = 0x0;
+const REG_FREQ_HI: u16 = 0x1;
+const REG_PW_LO: u16 = 0x2;
+const REG_PW_HI: u16 = 0x3;
+const REG_CONTROL: u16 = 0x4;
+const REG_ATTACK_DECAY: u16 = 0x5;
+const REG_SUSTAIN_RELEASE: u16 = 0x6;
+
+const REG_FC_LO: u16 = 0x15;
+const REG_FC_HI: u16 = 0x16;
+const REG_RES_FILT: u16 = 0x17;
+const REG_MODE_VOL: u16 = 0x18;
+const REG_POTX: u16 = 0x19;
+const REG_POTY: u16 = 0x1a;
+const REG_OSC3: u16 = 0x1b;
+const REG_ENV3: u16 = 0x1c;
+
+const CONTROL_GATE: u8 = 0b0000_0001;
+const CONTROL_SYNC: u8 = 0b0000_0010;
+const CONTROL_RING: u8 = 0b0000_0100;
+const CONTROL_TEST: u8 = 0b0000_1000;
+const CONTROL_TRIANGLE: u8 = 0b0001_0000;
+const CONTROL_SAW: u8 = 0b0010_0000;
+const CONTROL_PULSE: u8 = 0b0100_0000;
+const CONTROL_NOISE: u8 = 0b1000_0000;
+
+const MODE_LOW_PASS: u8 = 0b0001_0000;
+const MODE_BAND_PASS: u8 = 0b0010_0000;
+const MODE_HIGH_PASS: u8 = 0b0100_0000;
+const MODE_3OFF: u8 = 0b1000_0000;
+
+const ACCUMULATOR_MSB: u32 = 0x80_0000;
+const NOISE_CLOCK_BIT: u32 = 0x08_0000;
+const NOISE_INITIAL: u32 = 0x7f_fff8;
+
+// Cycles per envelope step for each rate value
+const RATE_PERIODS: [u16; 16] = [
+    9, 32, 63, 95, 149, 220, 267, 313, 392, 977, 1954, 3126, 3907, 11720, 19532, 31251,
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SidModel {
+    Mos6581,
+    Mos8580,
+}
+
+impl SidModel {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "6581" => Some(SidModel::Mos6581),
+            "8580" => Some(SidModel::Mos8580),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum EnvelopeState {
+    Attack,
+    DecaySustain,
+    Release,
+}
+
+#[derive(Clone, Copy)]
+struct Voice {
+    frequency: u16,
+    pulse_width: u16,
+    control: u8,
+    attack_decay: u8,
+    sustain_release: u8,
+
+    accumulator: u32,
+    // Accumulator MSB went from 0 to 1 on this cycle, used for sync of the next voice
+    msb_rising: bool,
+    noise: u32,
+
+    state: EnvelopeState,
+    level: u8,
+    rate_counter: u16,
+    exponential_counter: u8,
+    // Level stops at zero until the next attack
+    hold_zero: bool,
+}
+
+impl Voice {
+    fn new() -> Self {
+        Self {
+            frequency: 0,
+            pulse_width: 0,
+            control: 0,
+            attack_decay: 0,
+            sustain_release: 0,
+            accumulator: 0,
+            msb_rising: false,
+            noise: NOISE_INITIAL,
+            state: EnvelopeState::Release,
+            level: 0,
+            rate_counter: 0,
+            exponential_counter: 0,
+            hold_zero: true,
+        }
+    }
+
+    fn set_control(&mut self, control: u8) {
+        let gate_on = control & CONTROL_GATE != 0 && self.control & CONTROL_GATE == 0;
+        let gate_off = control & CONTROL_GATE == 0 && self.control & CONTROL_GATE != 0;
+        if gate_on {
+            self.state = EnvelopeState::Attack;
+            self.hold_zero = false;
+        } else if gate_off {
+            self.state = EnvelopeState::Release;
+        }
+
+        if control & CONTROL_TEST != 0 {
+            self.accumulator = 0;
+            self.noise = NOISE_INITIAL;
+        }
+        self.control = control;
+    }
+
+    fn clock_oscillator(&mut self) {
+        if self.control & CONTROL_TEST != 0 {
+            self.msb_rising = false;
+            return;
+        }
+        let previous = self.accumulator;
+        self.accumulator = (self.accumulator + self.frequency as u32) & 0xff_ffff;
+        self.msb_rising =
+            previous & ACCUMULATOR_MSB == 0 && self.accumulator & ACCUMULATOR_MSB != 0;
+
+        if previous & NOISE_CLOCK_BIT == 0 && self.accumulator & NOISE_CLOCK_BIT != 0 {
+            let feedback = ((self.noise >> 22) ^ (self.noise >> 17)) & 1;
+            self.noise = ((self.noise << 1) | feedback) & 0x7f_ffff;
+        }
+    }
+
+    // Hard sync: accumulator is reset when accumulator of the source voice overflows
+    fn sync(&mut self, source: &Voice) {
+        if self.control & CONTROL_SYNC != 0 && source.msb_rising {
+            self.accumulator = 0;
+        }
+    }
+
+    fn noise_output(&self) -> u16 {
+        let n = self.noise;
+        (((n >> 20) & 1) << 11
+            | ((n >> 18) & 1) << 10
+            | ((n >> 14) & 1) << 9
+            | ((n >> 11) & 1) << 8
+            | ((n >> 9) & 1) << 7
+            | ((n >> 5) & 1) << 6
+            | ((n >> 2) & 1) << 5
+            | (n & 1) << 4) as u16
+    }
+
+    // 12 bit waveform output. Ring modulation replaces triangle MSB with XOR of both
+    // oscillator MSBs. Combined waveforms are approximated with AND of them.
+    fn waveform_output(&self, ring_source: &Voice) -> u16 {
+        let mut output = 0xfff;
+        let mut any = false;
+
+        if self.control & CONTROL_TRIANGLE != 0 {
+            let mut msb = self.accumulator & ACCUMULATOR_MSB;
+            if self.control & CONTROL_RING != 0 {
+                msb ^= ring_source.accumulator & ACCUMULATOR_MSB;
+            }
+            let folded = if msb != 0 {
+                !self.accumulator
+            } else {
+                self.accumulator
+            };
+            output &= ((folded >> 11) & 0xfff) as u16;
+            any = true;
+        }
+        if self.control & CONTROL_SAW != 0 {
+            output &= (self.accumulator >> 12) as u16;
+            any = true;
+        }
+        if self.control & CONTROL_PULSE != 0 {
+            let high = self.control & CONTROL_TEST != 0
+                || (self.accumulator >> 12) as u16 >= self.pulse_width;
+            output &= if high { 0xfff } else { 0 };
+            any = true;
+        }
+        if self.control & CONTROL_NOISE != 0 {
+            output &= self.noise_output();
+            any = true;
+        }
+
+        if any {
+            output
+        } else {
+            0
+        }
+    }
+
+    // Noise combined with other waveforms clears bits of shift register (and usually
+    // silences noise until test bit is set)
+    fn write_back_noise(&mut self, output: u16) {
+        const TAPS: [u32; 8] = [20, 18, 14, 11, 9, 5, 2, 0];
+        if self.control & CONTROL_NOISE == 0 || self.control & 0x70 == 0 {
+            return;
+        }
+        for (index, tap) in TAPS.iter().enumerate() {
+            if output & (1 << (11 - index)) == 0 {
+                self.noise &= !(1 << tap);
+            }
+        }
+    }
+
+    fn sustain_level(&self) -> u8 {
+        (self.sustain_release >> 4) * 0x11
+    }
+
+    fn rate_period(&self) -> u16 {
+        let rate = match self.state {
+            EnvelopeState::Attack => self.attack_decay >> 4,
+            EnvelopeState::DecaySustain => self.attack_decay & 0x0f,
+            EnvelopeState::Release => self.sustain_release & 0x0f,
+        };
+        RATE_PERIODS[rate as usize]
+    }
+
+    // Decay and release are exponential: steps get slower as level goes down
+    fn exponential_period(&self) -> u8 {
+        match self.level {
+            0x5d..=0xff => 1,
+            0x36..=0x5c => 2,
+            0x1a..=0x35 => 4,
+            0x0e..=0x19 => 8,
+            0x06..=0x0d => 16,
+            _ => 30,
+        }
+    }
+
+    fn clock_envelope(&mut self) {
+        // 15 bit counter is compared for equality. If rate is changed to a smaller
+        // period than counter already is, it has to wrap around first (ADSR delay bug).
+        self.rate_counter = (self.rate_counter + 1) & 0x7fff;
+        if self.rate_counter != self.rate_period() {
+            return;
+        }
+        self.rate_counter = 0;
+
+        if self.state == EnvelopeState::Attack {
+            self.exponential_counter = 0;
+            self.level = self.level.wrapping_add(1);
+            if self.level == 0xff {
+                self.state = EnvelopeState::DecaySustain;
+            }
+            return;
+        }
+
+        self.exponential_counter += 1;
+        if self.exponential_counter < self.exponential_period() {
+            return;
+        }
+        self.exponential_counter = 0;
+
+        if self.hold_zero {
+            return;
+        }
+        let step = match self.state {
+            EnvelopeState::DecaySustain => self.level != self.sustain_level(),
+            _ => true,
+        };
+        if step {
+            // Release from zero before the first attack step wraps to $ff like the chip
+            self.level = self.level.wrapping_sub(1);
+            if self.level == 0 {
+                self.hold_zero = true;
+            }
+        }
+    }
+}
+
+// Multimode state variable filter, run every cycle
+struct Filter {
+    low_pass: f32,
+    band_pass: f32,
+}
+
+impl Filter {
+    fn new() -> Self {
+        Self {
+            low_pass: 0.0,
+            band_pass: 0.0,
+        }
+    }
+
+    // Cutoff frequency in Hz for 11 bit register value. 8580 is close to linear, 6581
+    // is not and varies a lot between chips, this is a typical curve.
+    fn cutoff(model: SidModel, fc: u16) -> f32 {
+        let fc = fc as f32 / 2047.0;
+        match model {
+            SidModel::Mos8580 => 30.0 + fc * 12000.0,
+            SidModel::Mos6581 => 220.0 + 17800.0 * fc.powf(2.2),
+        }
+    }
+
+    // Cutoff is relative to clock frequency
+    fn clock(&mut self, input: f32, cutoff: f32, resonance: u8, mode: u8) -> f32 {
+        let w0 = (2.0 * PI * cutoff).min(0.9);
+        let q = 0.707 + resonance as f32 / 15.0;
+
+        let high_pass = input - self.low_pass - self.band_pass / q;
+        self.band_pass += w0 * high_pass;
+        self.low_pass += w0 * self.band_pass;
+
+        let mut output = 0.0;
+        if mode & MODE_LOW_PASS != 0 {
+            output += self.low_pass;
+        }
+        if mode & MODE_BAND_PASS != 0 {
+            output += self.band_pass;
+        }
+        if mode & MODE_HIGH_PASS != 0 {
+            output += high_pass;
+        }
+        output
+    }
+}
+
+pub struct Sid {
+    model: SidModel,
+    clock_frequency: u32,
+    voices: [Voice; 3],
+    filter: Filter,
+    fc: u16,
+    res_filt: u8,
+    mode_vol: u8,
+    // Reading write only register returns what was last written to any register
+    bus_value: u8,
+    output: i16,
+    sink: Option<Rc<RefCell<dyn AudioSink>>>,
+}
+
+impl Sid {
+    pub fn new(model: SidModel) -> Self {
+        Self {
+            model,
+            clock_frequency: PAL_CLOCK,
+            voices: [Voice::new(); 3],
+            filter: Filter::new(),
+            fc: 0,
+            res_filt: 0,
+            mode_vol: 0,
+            bus_value: 0,
+            output: 0,
+            sink: None,
+        }
+    }
+
+    pub fn set_model(&mut self, model: SidModel) {
+        self.model = model;
+    }
+
+    // Filter depends on it, the rest works in cycles
+    pub fn set_clock_frequency(&mut self, clock_frequency: u32) {
+        self.clock_frequency = clock_frequency;
+    }
+
+    // Current sample, one per cycle
+    pub fn output(&self) -> i16 {
+        self.output
+    }
+
+    // Receives every sample
+    pub fn set_sink(&mut self, sink: Rc<RefCell<dyn AudioSink>>) {
+        self.sink = Some(sink);
+    }
+
+    fn register(offset: u16) -> u16 {
+        offset % REGISTERS_COUNT
+    }
+
+    fn osc3(&self) -> u8 {
+        let voice = &self.voices[2];
+        (voice.waveform_output(&self.voices[1]) >> 4) as u8
+    }
+
+    fn mix(&mut self) -> i16 {
+        let mut filtered = 0.0;
+        let mut unfiltered = 0.0;
+        for index in 0..3 {
+            let ring_source = &self.voices[(index + 2) % 3];
+            let waveform = self.voices[index].waveform_output(ring_source);
+            self.voices[index].write_back_noise(waveform);
+
+            // 20 bit signed, waveform is centered around zero
+            let sample = (waveform as i32 - 0x800) * self.voices[index].level as i32;
+            let sample = sample as f32 / (1 << 19) as f32;
+            if self.res_filt & (1 << index) != 0 {
+                filtered += sample;
+            } else if index != 2 || self.mode_vol & MODE_3OFF == 0 {
+                unfiltered += sample;
+            }
+        }
+
+        let cutoff = Filter::cutoff(self.model, self.fc) / self.clock_frequency as f32;
+        let filtered = self
+            .filter
+            .clock(filtered, cutoff, self.res_filt >> 4, self.mode_vol);
+
+        // 6581 has DC offset in mixer, so volume changes are heard (used for samples)
+        let dc = match self.model {
+            SidModel::Mos6581 => 0.5,
+            SidModel::Mos8580 => 0.0,
+        };
+        let volume = (self.mode_vol & 0x0f) as f32 / 15.0;
+        let mixed = (filtered + unfiltered + dc) * volume / 3.5;
+        (mixed.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+    }
+}
+
+impl Device for Sid {
+    fn set_byte(&mut self, byte: u8, offset: u16) {
+        let reg = Self::register(offset);
+        self.bus_value = byte;
+        if reg < 3 * VOICE_REGISTERS {
+            let voice = &mut self.voices[(reg / VOICE_REGISTERS) as usize];
+            match reg % VOICE_REGISTERS {
+                REG_FREQ_LO => voice.frequency = (voice.frequency & 0xff00) | byte as u16,
+                REG_FREQ_HI => voice.frequency = (voice.frequency & 0x00ff) | (byte as u16) << 8,
+                REG_PW_LO => voice.pulse_width = (voice.pulse_width & 0x0f00) | byte as u16,
+                REG_PW_HI => {
+                    voice.pulse_width = (voice.pulse_width & 0x00ff) | ((byte & 0x0f) as u16) << 8
+                }
+                REG_CONTROL => voice.set_control(byte),
+                REG_ATTACK_DECAY => voice.attack_decay = byte,
+                REG_SUSTAIN_RELEASE => voice.sustain_release = byte,
+                _ => unreachable!(),
+            }
+            return;
+        }
+        match reg {
+            REG_FC_LO => self.fc = (self.fc & 0x7f8) | (byte & 0x07) as u16,
+            REG_FC_HI => self.fc = (self.fc & 0x007) | (byte as u16) << 3,
+            REG_RES_FILT => self.res_filt = byte,
+            REG_MODE_VOL => self.mode_vol = byte,
+            _ => {}
+        }
+    }
+
+    fn get_byte(&self, offset: u16) -> u8 {
+        match Self::register(offset) {
+            // Nothing is connected to paddle inputs
+            REG_POTX | REG_POTY => 0xff,
+            REG_OSC3 => self.osc3(),
+            REG_ENV3 => self.voices[2].level,
+            _ => self.bus_value,
+        }
+    }
+
+    fn get_bytes_slice(&self, from: u16, to: u16) -> Vec<u8> {
+        (from..to).map(|offset| self.get_byte(offset)).collect()
+    }
+
+    fn tick(&mut self) {
+        for voice in &mut self.voices {
+            voice.clock_oscillator();
+        }
+        // Voice 1 is synced by voice 3, voice 2 by voice 1, voice 3 by voice 2
+        for index in 0..3 {
+            let source = self.voices[(index + 2) % 3];
+            self.voices[index].sync(&source);
+        }
+        for voice in &mut self.voices {
+            voice.clock_envelope();
+        }
+        self.output = self.mix();
+        if let Some(sink) = &self.sink {
+            sink.borrow_mut().push_sample(self.output);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tick(sid: &mut Sid, cycles: u32) {
+        for _ in 0..cycles {
+            sid.tick();
+        }
+    }
+
+    // Voice 3 is the one we can read back
+    fn set_voice3(sid: &mut Sid, frequency: u16, control: u8) {
+        sid.set_byte(frequency as u8, 0xd40e);
+        sid.set_byte((frequency >> 8) as u8, 0xd40f);
+        sid.set_byte(control, 0xd412);
+    }
+
+    #[test]
+    fn saw_follows_accumulator() {
+        let mut sid = Sid::new(SidModel::Mos8580);
+        set_voice3(&mut sid, 0x1000, CONTROL_SAW);
+        tick(&mut sid, 0x100);
+        assert_eq!(sid.get_byte(0xd41b), 0x10);
+        tick(&mut sid, 0x80);
+        assert_eq!(sid.get_byte(0xd41b), 0x18);
+    }
+
+    #[test]
+    fn test_bit_resets_oscillator() {
+        let mut sid = Sid::new(SidModel::Mos8580);
+        set_voice3(&mut sid, 0x1000, CONTROL_SAW);
+        tick(&mut sid, 0x100);
+        sid.set_byte(CONTROL_SAW | CONTROL_TEST, 0xd412);
+        tick(&mut sid, 0x100);
+        assert_eq!(sid.get_byte(0xd41b), 0x00);
+    }
+
+    #[test]
+    fn triangle() {
+        let mut sid = Sid::new(SidModel::Mos8580);
+        set_voice3(&mut sid, 0x8000, CONTROL_TRIANGLE);
+        tick(&mut sid, 0x80);
+        assert_eq!(sid.get_byte(0xd41b), 0x80);
+        // Accumulator is at the top of the first half
+        tick(&mut sid, 0x7f);
+        assert_eq!(sid.get_byte(0xd41b), 0xff);
+        tick(&mut sid, 0x41);
+        assert_eq!(sid.get_byte(0xd41b), 0xbf);
+    }
+
+    #[test]
+    fn pulse_width() {
+        let mut sid = Sid::new(SidModel::Mos8580);
+        sid.set_byte(0x00, 0xd410);
+        sid.set_byte(0x08, 0xd411);
+        set_voice3(&mut sid, 0x1000, CONTROL_PULSE);
+        tick(&mut sid, 0x7f0);
+        assert_eq!(sid.get_byte(0xd41b), 0x00);
+        tick(&mut sid, 0x20);
+        assert_eq!(sid.get_byte(0xd41b), 0xff);
+    }
+
+    #[test]
+    fn noise_changes() {
+        let mut sid = Sid::new(SidModel::Mos8580);
+        set_voice3(&mut sid, 0xffff, CONTROL_NOISE);
+        let mut values = std::collections::HashSet::new();
+        for _ in 0..100 {
+            tick(&mut sid, 16);
+            values.insert(sid.get_byte(0xd41b));
+        }
+        assert!(values.len() > 20);
+    }
+
+    #[test]
+    fn ring_modulation() {
+        let mut sid = Sid::new(SidModel::Mos8580);
+        // Voice 2 is ring source for voice 3, its MSB is set
+        sid.set_byte(0xff, 0xd408);
+        sid.set_byte(0x40, 0xd40b);
+        tick(&mut sid, 0x81);
+        set_voice3(&mut sid, 0, CONTROL_TRIANGLE | CONTROL_RING);
+        assert_eq!(sid.get_byte(0xd41b), 0xff);
+    }
+
+    #[test]
+    fn hard_sync() {
+        let mut sid = Sid::new(SidModel::Mos8580);
+        // Voice 2 overflows every 0x100 cycles and resets voice 3
+        sid.set_byte(0x00, 0xd407);
+        sid.set_byte(0x80, 0xd408);
+        set_voice3(&mut sid, 0x4000, CONTROL_SAW | CONTROL_SYNC);
+        tick(&mut sid, 0x100);
+        assert_eq!(sid.get_byte(0xd41b), 0x00);
+        tick(&mut sid, 0x80);
+        assert_eq!(sid.get_byte(0xd41b), 0x20);
+    }
+
+    #[test]
+    fn combined_waveform() {
+        let mut sid = Sid::new(SidModel::Mos8580);
+        set_voice3(&mut sid, 0x1000, CONTROL_SAW | CONTROL_PULSE);
+        sid.set_byte(0xff, 0xd411);
+        tick(&mut sid, 0x100);
+        assert_eq!(sid.get_byte(0xd41b), 0x00);
+    }
+
+    #[test]
+    fn attack_decay_sustain_release() {
+        let mut sid = Sid::new(SidModel::Mos8580);
+        sid.set_byte(0x00, 0xd413);
+        sid.set_byte(0x80, 0xd414);
+        set_voice3(&mut sid, 0, CONTROL_GATE);
+
+        // Fastest attack: 9 cycles per step
+        tick(&mut sid, 9 * 100);
+        assert_eq!(sid.get_byte(0xd41c), 100);
+        tick(&mut sid, 9 * 155);
+        assert_eq!(sid.get_byte(0xd41c), 0xff);
+
+        // Decay to sustain level
+        tick(&mut sid, 20000);
+        assert_eq!(sid.get_byte(0xd41c), 0x88);
+
+        sid.set_byte(0, 0xd412);
+        tick(&mut sid, 100000);
+        assert_eq!(sid.get_byte(0xd41c), 0x00);
+    }
+
+    #[test]
+    fn release_before_attack_wraps() {
+        let mut sid = Sid::new(SidModel::Mos8580);
+        sid.set_byte(0x00, 0xd413);
+        sid.set_byte(0x00, 0xd414);
+        set_voice3(&mut sid, 0, CONTROL_GATE);
+        sid.set_byte(0, 0xd412);
+        // Exponential period is 30 at level zero
+        tick(&mut sid, 9 * 30);
+        assert_eq!(sid.get_byte(0xd41c), 0xff);
+        tick(&mut sid, 9);
+        assert_eq!(sid.get_byte(0xd41c), 0xfe);
+    }
+
+    #[test]
+    fn adsr_delay_bug() {
+        let mut sid = Sid::new(SidModel::Mos8580);
+        // Slowest attack, counter runs far beyond fast attack period
+        sid.set_byte(0xf0, 0xd413);
+        set_voice3(&mut sid, 0, CONTROL_GATE);
+        tick(&mut sid, 1000);
+        assert_eq!(sid.get_byte(0xd41c), 0);
+
+        sid.set_byte(0x00, 0xd413);
+        tick(&mut sid, 1000);
+        assert_eq!(sid.get_byte(0xd41c), 0);
+        // Counter has to wrap around 15 bits before it matches again
+        tick(&mut sid, 0x8000 - 2000 + 9);
+        assert_eq!(sid.get_byte(0xd41c), 1);
+    }
+
+    #[test]
+    fn envelope_holds_zero() {
+        let mut sid = Sid::new(SidModel::Mos8580);
+        set_voice3(&mut sid, 0, CONTROL_GATE);
+        tick(&mut sid, 9 * 3);
+        sid.set_byte(0, 0xd412);
+        tick(&mut sid, 1000);
+        assert_eq!(sid.get_byte(0xd41c), 0);
+    }
+
+    #[test]
+    fn write_only_registers_read_bus_value() {
+        let mut sid = Sid::new(SidModel::Mos8580);
+        sid.set_byte(0x42, 0xd400);
+        assert_eq!(sid.get_byte(0xd405), 0x42);
+        assert_eq!(sid.get_byte(0xd419), 0xff);
+        // Registers are mirrored every 0x20 bytes
+        sid.set_byte(0x11, 0xd438);
+        assert_eq!(sid.mode_vol, 0x11);
+    }
+
+    fn loudness(sid: &mut Sid, cycles: u32) -> f64 {
+        let mut sum = 0.0;
+        for _ in 0..cycles {
+            sid.tick();
+            sum += (sid.output() as f64).powi(2);
+        }
+        (sum / cycles as f64).sqrt()
+    }
+
+    fn loud_saw(sid: &mut Sid, frequency: u16) {
+        sid.set_byte(0x00, 0xd405);
+        sid.set_byte(0xf0, 0xd406);
+        sid.set_byte(frequency as u8, 0xd400);
+        sid.set_byte((frequency >> 8) as u8, 0xd401);
+        sid.set_byte(CONTROL_SAW | CONTROL_GATE, 0xd404);
+        tick(sid, 5000);
+    }
+
+    #[test]
+    fn low_pass_filter() {
+        // ~8 kHz saw
+        let mut unfiltered = Sid::new(SidModel::Mos8580);
+        unfiltered.set_byte(0x0f, 0xd418);
+        loud_saw(&mut unfiltered, 0x8000);
+
+        let mut filtered = Sid::new(SidModel::Mos8580);
+        filtered.set_byte(0x00, 0xd416);
+        filtered.set_byte(0x01, 0xd417);
+        filtered.set_byte(MODE_LOW_PASS | 0x0f, 0xd418);
+        loud_saw(&mut filtered, 0x8000);
+
+        assert!(loudness(&mut filtered, 10000) < loudness(&mut unfiltered, 10000) / 4.0);
+    }
+
+    #[test]
+    fn voice3_off() {
+        let mut sid = Sid::new(SidModel::Mos8580);
+        sid.set_byte(MODE_3OFF | 0x0f, 0xd418);
+        sid.set_byte(0x00, 0xd413);
+        sid.set_byte(0xf0, 0xd414);
+        set_voice3(&mut sid, 0x1000, CONTROL_SAW | CONTROL_GATE);
+        tick(&mut sid, 5000);
+        assert_eq!(loudness(&mut sid, 1000), 0.0);
+        // Still can be read back
+        assert_ne!(sid.get_byte(0xd41c), 0);
+    }
+
+    struct CountingSink {
+        samples: u32,
+    }
+
+    impl AudioSink for CountingSink {
+        fn push_sample(&mut self, _sample: i16) {
+            self.samples += 1;
+        }
+    }
+
+    #[test]
+    fn sample_every_cycle() {
+        let sink = Rc::new(RefCell::new(CountingSink { samples: 0 }));
+        let mut sid = Sid::new(SidModel::Mos8580);
+        sid.set_sink(sink.clone());
+        tick(&mut sid, 100);
+        assert_eq!(sink.borrow().samples, 100);
+    }
+
+    #[test]
+    fn volume_dc_offset_on_6581() {
+        let mut sid = Sid::new(SidModel::Mos6581);
+        sid.set_byte(0x00, 0xd418);
+        sid.tick();
+        let silent = sid.output();
+        sid.set_byte(0x0f, 0xd418);
+        sid.tick();
+        assert!(sid.output() > silent);
+
+        sid.set_model(SidModel::Mos8580);
+        sid.tick();
+        assert_eq!(sid.output(), 0);
+    }
+}
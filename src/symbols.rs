@@ -0,0 +1,492 @@
+// Symbol table loaded from label files of assemblers. Format is detected by content:
+//   VICE:          al C:0810 .start
+//   ca65 debug:    sym id=0,name="start",addrsize=absolute,...,val=0x810,...,type=lab
+//                  (source lines of the code are taken from it too)
+//   KickAssembler: .label start=$0810, labels in .namespace blocks are prefixed
+//   ACME:          start = $0810 ; ?
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum SymbolError {
+    Io(std::io::Error),
+    Parse { line: usize, message: String },
+    NoSymbols,
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SymbolError::Io(err) => write!(f, "{}", err),
+            SymbolError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+            SymbolError::NoSymbols => write!(f, "no symbols found"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Format {
+    Vice,
+    Ca65,
+    KickAssembler,
+    Acme,
+}
+
+fn detect_format(text: &str) -> Format {
+    let first = text
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with(';') && !line.starts_with("//"))
+        .unwrap_or("");
+    if first.starts_with("al ") {
+        Format::Vice
+    } else if first.starts_with("version") && first.contains("major=") {
+        Format::Ca65
+    } else if first.starts_with('.') {
+        Format::KickAssembler
+    } else {
+        Format::Acme
+    }
+}
+
+// Value of key=value attribute of ca65 debug info line
+fn ca65_attribute<'a>(attributes: &'a str, key: &str) -> Option<&'a str> {
+    attributes
+        .trim()
+        .split(',')
+        .find_map(|pair| pair.strip_prefix(key)?.strip_prefix('='))
+}
+
+// 0xhex or decimal, sizes and offsets in ca65 debug info can be over 16 bits
+fn ca65_number(text: &str) -> Option<u32> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+// One path ends with the other, so relative names in debug info match absolute paths
+// given by an editor
+fn same_file(a: &Path, b: &Path) -> bool {
+    a.ends_with(b) || b.ends_with(a)
+}
+
+// Code generated for a line of source file
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct SourceLine {
+    // Index in SymbolTable::files
+    file: usize,
+    line: u32,
+    address: u16,
+    size: u16,
+}
+
+// $hex, 0xhex or decimal
+fn parse_value(text: &str) -> Option<u16> {
+    let text = text.trim();
+    if let Some(hex) = text.strip_prefix('$') {
+        u16::from_str_radix(hex, 16).ok()
+    } else if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        u16::from_str_radix(hex, 16).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+#[derive(Default)]
+pub struct SymbolTable {
+    names: HashMap<u16, String>,
+    addresses: HashMap<String, u16>,
+    files: Vec<PathBuf>,
+    lines: Vec<SourceLine>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // The first name given to address is the one shown for it
+    pub fn insert(&mut self, name: &str, address: u16) {
+        self.names
+            .entry(address)
+            .or_insert_with(|| name.to_string());
+        self.addresses.insert(name.to_string(), address);
+    }
+
+    pub fn name(&self, address: u16) -> Option<&str> {
+        self.names.get(&address).map(String::as_str)
+    }
+
+    pub fn address(&self, name: &str) -> Option<u16> {
+        self.addresses.get(name).copied()
+    }
+
+    // Name and address, sorted by address
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u16)> {
+        let mut symbols: Vec<(&str, u16)> = self
+            .addresses
+            .iter()
+            .map(|(name, address)| (name.as_str(), *address))
+            .collect();
+        symbols.sort_by_key(|&(name, address)| (address, name));
+        symbols.into_iter()
+    }
+
+    // Address of the first line with code at or after line of file, and that line
+    pub fn line_address(&self, file: &Path, line: u32) -> Option<(u32, u16)> {
+        self.lines
+            .iter()
+            .filter(|source| source.line >= line && same_file(&self.files[source.file], file))
+            .min_by_key(|source| (source.line, source.address))
+            .map(|source| (source.line, source.address))
+    }
+
+    // File and line the code at address was generated for
+    pub fn source_line(&self, address: u16) -> Option<(&Path, u32)> {
+        let address = address as u32;
+        self.lines
+            .iter()
+            .filter(|source| {
+                let start = source.address as u32;
+                (start..start + source.size as u32).contains(&address)
+            })
+            .min_by_key(|source| source.size)
+            .map(|source| (self.files[source.file].as_path(), source.line))
+    }
+
+    pub fn clear(&mut self) {
+        self.names.clear();
+        self.addresses.clear();
+        self.files.clear();
+        self.lines.clear();
+    }
+
+    // Returns how many symbols were added. Source files are relative to the label file.
+    pub fn load(&mut self, path: &Path) -> Result<usize, SymbolError> {
+        let text = fs::read_to_string(path).map_err(SymbolError::Io)?;
+        let first_file = self.files.len();
+        let count = self.parse(&text)?;
+        let directory = path.parent().unwrap_or(Path::new(""));
+        for file in &mut self.files[first_file..] {
+            if file.is_relative() {
+                let joined = directory.join(&file);
+                *file = fs::canonicalize(&joined).unwrap_or(joined);
+            }
+        }
+        Ok(count)
+    }
+
+    pub fn parse(&mut self, text: &str) -> Result<usize, SymbolError> {
+        let format = detect_format(text);
+        let mut symbols = vec![];
+        // KickAssembler namespaces the current line is in
+        let mut namespaces: Vec<String> = vec![];
+        for (number, line) in text.lines().enumerate() {
+            let parse_error = |message: &str| SymbolError::Parse {
+                line: number + 1,
+                message: message.to_string(),
+            };
+            let line = line.trim();
+            let symbol = match format {
+                Format::Vice => Self::parse_vice(line),
+                Format::Ca65 => Self::parse_ca65(line),
+                Format::KickAssembler => Self::parse_kick(line, &mut namespaces),
+                Format::Acme => Self::parse_acme(line),
+            };
+            match symbol {
+                Ok(Some(symbol)) => symbols.push(symbol),
+                Ok(None) => {}
+                Err(message) => return Err(parse_error(message)),
+            }
+        }
+        let lines = match format {
+            Format::Ca65 => Self::parse_ca65_lines(text)?,
+            _ => vec![],
+        };
+        if symbols.is_empty() && lines.is_empty() {
+            return Err(SymbolError::NoSymbols);
+        }
+        let count = symbols.len();
+        for (name, address) in symbols {
+            self.insert(&name, address);
+        }
+        for (file, line, address, size) in lines {
+            let file = PathBuf::from(file);
+            let file = match self.files.iter().position(|known| *known == file) {
+                Some(index) => index,
+                None => {
+                    self.files.push(file);
+                    self.files.len() - 1
+                }
+            };
+            self.lines.push(SourceLine {
+                file,
+                line,
+                address,
+                size,
+            });
+        }
+        Ok(count)
+    }
+
+    fn parse_vice(line: &str) -> Result<Option<(String, u16)>, &'static str> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [] => Ok(None),
+            ["al", address, name] => {
+                let address = address.trim_start_matches("C:");
+                let address = u16::from_str_radix(address, 16).map_err(|_| "bad address")?;
+                Ok(Some((name.trim_start_matches('.').to_string(), address)))
+            }
+            _ => Err("expected al <address> <label>"),
+        }
+    }
+
+    // Only labels, there are constants and a lot of other debug info in the file
+    fn parse_ca65(line: &str) -> Result<Option<(String, u16)>, &'static str> {
+        let attributes = match line.strip_prefix("sym") {
+            Some(attributes) if attributes.starts_with(char::is_whitespace) => attributes,
+            _ => return Ok(None),
+        };
+        let attribute = |key: &str| ca65_attribute(attributes, key);
+        if attribute("type") != Some("lab") {
+            return Ok(None);
+        }
+        let name = attribute("name").ok_or("symbol without name")?;
+        let value = attribute("val").ok_or("symbol without value")?;
+        let address = parse_value(value).ok_or("bad value")?;
+        Ok(Some((name.trim_matches('"').to_string(), address)))
+    }
+
+    // File, line, address and size of code. Line refers to spans, which are pieces of
+    // segments, and they come after lines in the file. Lines of macro bodies are left
+    // out, the code is given to the line invoking macro.
+    fn parse_ca65_lines(text: &str) -> Result<Vec<(String, u32, u16, u16)>, SymbolError> {
+        let mut files = HashMap::new();
+        let mut segments = HashMap::new();
+        let mut spans = HashMap::new();
+        // Line number in debug info, file id, source line and span ids
+        let mut lines = vec![];
+        for (number, line) in text.lines().enumerate() {
+            let parse_error = |message: &str| SymbolError::Parse {
+                line: number + 1,
+                message: message.to_string(),
+            };
+            let (kind, attributes) = match line.trim().split_once(char::is_whitespace) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let attribute = |key: &str| ca65_attribute(attributes, key);
+            let value = |key: &str| {
+                attribute(key)
+                    .and_then(ca65_number)
+                    .ok_or_else(|| parse_error(&format!("bad {}", key)))
+            };
+            match kind {
+                "file" => {
+                    let name = attribute("name").ok_or_else(|| parse_error("file without name"))?;
+                    files.insert(value("id")?, name.trim_matches('"').to_string());
+                }
+                "seg" => {
+                    segments.insert(value("id")?, value("start")?);
+                }
+                "span" => {
+                    spans.insert(
+                        value("id")?,
+                        (value("seg")?, value("start")?, value("size")?),
+                    );
+                }
+                "line" if attribute("type") != Some("2") => {
+                    let ids = match attribute("span") {
+                        Some(ids) => ids,
+                        None => continue,
+                    };
+                    let ids: Option<Vec<u32>> = ids.split('+').map(ca65_number).collect();
+                    let ids = ids.ok_or_else(|| parse_error("bad span"))?;
+                    lines.push((number, value("file")?, value("line")?, ids));
+                }
+                _ => {}
+            }
+        }
+
+        let mut result = vec![];
+        for (number, file, line, ids) in lines {
+            let parse_error = |message: &str| SymbolError::Parse {
+                line: number + 1,
+                message: message.to_string(),
+            };
+            let file = files
+                .get(&file)
+                .ok_or_else(|| parse_error("unknown file"))?;
+            let mut code = vec![];
+            for id in ids {
+                let (segment, start, size) =
+                    spans.get(&id).ok_or_else(|| parse_error("unknown span"))?;
+                let base = segments
+                    .get(segment)
+                    .ok_or_else(|| parse_error("unknown segment"))?;
+                code.push(((base + start) as u16, *size as u16));
+            }
+            if let Some(&(address, size)) = code.iter().min() {
+                result.push((file.clone(), line, address, size));
+            }
+        }
+        Ok(result)
+    }
+
+    fn parse_kick(
+        line: &str,
+        namespaces: &mut Vec<String>,
+    ) -> Result<Option<(String, u16)>, &'static str> {
+        if let Some(namespace) = line.strip_prefix(".namespace") {
+            let namespace = namespace.trim().trim_end_matches('{').trim();
+            namespaces.push(namespace.to_string());
+            return Ok(None);
+        }
+        if line == "}" {
+            namespaces.pop().ok_or("unexpected }")?;
+            return Ok(None);
+        }
+        let definition = match line.strip_prefix(".label") {
+            Some(definition) => definition,
+            None => return Ok(None),
+        };
+        let (name, value) = definition.split_once('=').ok_or("expected name=value")?;
+        let address = parse_value(value).ok_or("bad value")?;
+        let mut full_name = namespaces.join(".");
+        if !full_name.is_empty() {
+            full_name.push('.');
+        }
+        full_name.push_str(name.trim());
+        Ok(Some((full_name, address)))
+    }
+
+    fn parse_acme(line: &str) -> Result<Option<(String, u16)>, &'static str> {
+        let line = line.split(';').next().unwrap().trim();
+        if line.is_empty() {
+            return Ok(None);
+        }
+        let (name, value) = line.split_once('=').ok_or("expected name = value")?;
+        let address = parse_value(value).ok_or("bad value")?;
+        Ok(Some((name.trim().to_string(), address)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> SymbolTable {
+        let mut symbols = SymbolTable::new();
+        symbols.parse(text).unwrap();
+        symbols
+    }
+
+    #[test]
+    fn vice() {
+        let symbols = parse("al C:0810 .start\nal C:0820 .loop\n\nal 0830 .end\n");
+        assert_eq!(symbols.address("start"), Some(0x810));
+        assert_eq!(symbols.name(0x820), Some("loop"));
+        assert_eq!(symbols.address("end"), Some(0x830));
+        assert_eq!(symbols.iter().count(), 3);
+    }
+
+    #[test]
+    fn ca65() {
+        let symbols = parse(
+            "version\tmajor=2,minor=0\n\
+             file\tid=0,name=\"main.s\",size=100,mtime=0x5F000000,mod=0\n\
+             sym\tid=0,name=\"main\",addrsize=absolute,scope=0,def=1,ref=5,val=0x80D,seg=0,type=lab\n\
+             sym\tid=1,name=\"SCREEN\",addrsize=absolute,scope=0,def=2,val=0x400,type=equ\n\
+             sym\tid=2,name=\"@loop\",addrsize=absolute,scope=0,def=3,val=0x812,seg=0,type=lab\n",
+        );
+        assert_eq!(symbols.address("main"), Some(0x80d));
+        assert_eq!(symbols.address("@loop"), Some(0x812));
+        assert_eq!(symbols.address("SCREEN"), None);
+    }
+
+    #[test]
+    fn ca65_source_lines() {
+        let symbols = parse(
+            "version\tmajor=2,minor=0\n\
+             file\tid=0,name=\"src/main.s\",size=100,mtime=0x5F000000,mod=0\n\
+             line\tid=0,file=0,line=3,span=0\n\
+             line\tid=1,file=0,line=4\n\
+             line\tid=2,file=0,line=6,span=2+1\n\
+             line\tid=3,file=0,line=20,type=2,span=3\n\
+             seg\tid=0,name=\"CODE\",start=0x000810,size=0x0010,addrsize=absolute,type=ro\n\
+             span\tid=0,seg=0,start=0,size=2\n\
+             span\tid=1,seg=0,start=2,size=3\n\
+             span\tid=2,seg=0,start=5,size=6\n\
+             span\tid=3,seg=0,start=2,size=1\n",
+        );
+        let file = Path::new("/home/user/game/src/main.s");
+        assert_eq!(symbols.line_address(file, 3), Some((3, 0x810)));
+        // Line without code goes to the next one
+        assert_eq!(symbols.line_address(file, 4), Some((6, 0x812)));
+        assert_eq!(symbols.line_address(file, 7), None);
+        assert_eq!(symbols.line_address(Path::new("other.s"), 3), None);
+        assert_eq!(
+            symbols.source_line(0x811),
+            Some((Path::new("src/main.s"), 3))
+        );
+        assert_eq!(
+            symbols.source_line(0x814),
+            Some((Path::new("src/main.s"), 6))
+        );
+        assert_eq!(symbols.source_line(0x820), None);
+
+        let mut symbols = SymbolTable::new();
+        assert!(matches!(
+            symbols.parse("version\tmajor=2,minor=0\nline\tid=0,file=0,line=3,span=0\n"),
+            Err(SymbolError::Parse { line: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn kick_assembler() {
+        let symbols = parse(
+            ".label start=$0810\n.namespace irq {\n.label handler=$0900\n}\n.label end=$0a00\n",
+        );
+        assert_eq!(symbols.address("start"), Some(0x810));
+        assert_eq!(symbols.address("irq.handler"), Some(0x900));
+        assert_eq!(symbols.address("end"), Some(0xa00));
+    }
+
+    #[test]
+    fn acme() {
+        let symbols = parse("; labels\n\tstart\t= $0810\n\tcount\t= 16\t; ?\n");
+        assert_eq!(symbols.address("start"), Some(0x810));
+        assert_eq!(symbols.address("count"), Some(16));
+    }
+
+    #[test]
+    fn first_name_of_address_is_shown() {
+        let symbols = parse("al C:1000 .main\nal C:1000 .init\n");
+        assert_eq!(symbols.name(0x1000), Some("main"));
+        assert_eq!(symbols.address("init"), Some(0x1000));
+        assert_eq!(
+            symbols.iter().collect::<Vec<_>>(),
+            vec![("init", 0x1000), ("main", 0x1000)]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let mut symbols = SymbolTable::new();
+        assert!(matches!(
+            symbols.parse("al C:zz .start"),
+            Err(SymbolError::Parse { line: 1, .. })
+        ));
+        assert!(matches!(
+            symbols.parse("start = $0810\nfoo\n"),
+            Err(SymbolError::Parse { line: 2, .. })
+        ));
+        assert!(matches!(symbols.parse("\n"), Err(SymbolError::NoSymbols)));
+        assert_eq!(symbols.iter().count(), 0);
+    }
+}
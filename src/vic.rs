@@ -1,56 +1,932 @@
 use crate::bus::Device;
-use crate::host_io::{Color, Monitor};
+use crate::host_io::{FrameBuffer, Monitor};
+use crate::ram::{ColorRam, Ram};
 
 use std::cell::RefCell;
 use std::rc::Rc;
 
+// PAL (6569) timings
+pub const CYCLES_PER_LINE: u8 = 63;
+pub const LINES_PER_FRAME: u16 = 312;
+pub const CYCLES_PER_FRAME: u32 = CYCLES_PER_LINE as u32 * LINES_PER_FRAME as u32;
+pub const PAL_CLOCK: u32 = 985_248;
+
+// NTSC (6567R8) timings
+const NTSC_CYCLES_PER_LINE: u8 = 65;
+const NTSC_LINES_PER_FRAME: u16 = 263;
+pub const NTSC_CLOCK: u32 = 1_022_727;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum VideoStandard {
+    Pal,
+    // Lines are longer and there are less of them, the rest is the same here. Frame
+    // buffer keeps PAL size, so its bottom is never drawn.
+    Ntsc,
+}
+
+impl VideoStandard {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "pal" => Some(VideoStandard::Pal),
+            "ntsc" => Some(VideoStandard::Ntsc),
+            _ => None,
+        }
+    }
+
+    pub fn cycles_per_line(self) -> u8 {
+        match self {
+            VideoStandard::Pal => CYCLES_PER_LINE,
+            VideoStandard::Ntsc => NTSC_CYCLES_PER_LINE,
+        }
+    }
+
+    pub fn lines_per_frame(self) -> u16 {
+        match self {
+            VideoStandard::Pal => LINES_PER_FRAME,
+            VideoStandard::Ntsc => NTSC_LINES_PER_FRAME,
+        }
+    }
+
+    pub fn cycles_per_frame(self) -> u32 {
+        self.cycles_per_line() as u32 * self.lines_per_frame() as u32
+    }
+
+    // Cpu (and SID) clock in Hz
+    pub fn clock_frequency(self) -> u32 {
+        match self {
+            VideoStandard::Pal => PAL_CLOCK,
+            VideoStandard::Ntsc => NTSC_CLOCK,
+        }
+    }
+}
+
+// Visible part of the picture, including border
+pub const FRAME_WIDTH: usize = 384;
+pub const FRAME_HEIGHT: usize = 272;
+const FIRST_VISIBLE_LINE: u16 = 16;
+// Sprite X coordinate is shifted from frame one
+const SPRITE_X_OFFSET: i32 = 8;
+
+// Display window in frame coordinates (right and bottom are exclusive)
+const DISPLAY_LEFT: usize = 32;
+const DISPLAY_LEFT_38_COLUMNS: usize = 39;
+const DISPLAY_RIGHT: usize = 352;
+const DISPLAY_RIGHT_38_COLUMNS: usize = 343;
+const DISPLAY_TOP: u16 = 0x33;
+const DISPLAY_TOP_24_ROWS: u16 = 0x37;
+const DISPLAY_BOTTOM: u16 = 0xfb;
+const DISPLAY_BOTTOM_24_ROWS: u16 = 0xf7;
+
+pub const COLUMNS: u16 = 40;
+pub const ROWS: u16 = 25;
+const SPRITE_POINTERS_OFFSET: u16 = 0x3f8;
+
+const REGISTERS_COUNT: usize = 0x40;
+const REG_SPRITE_Y: usize = 0x01;
+const REG_SPRITE_X_MSB: usize = 0x10;
+const REG_CONTROL_1: usize = 0x11;
+const REG_RASTER: usize = 0x12;
+const REG_SPRITE_ENABLE: usize = 0x15;
+const REG_CONTROL_2: usize = 0x16;
+const REG_SPRITE_Y_EXPAND: usize = 0x17;
+const REG_MEMORY_POINTERS: usize = 0x18;
+const REG_INTERRUPT: usize = 0x19;
+const REG_INTERRUPT_ENABLE: usize = 0x1a;
+const REG_SPRITE_PRIORITY: usize = 0x1b;
+const REG_SPRITE_MULTICOLOR: usize = 0x1c;
+const REG_SPRITE_X_EXPAND: usize = 0x1d;
+const REG_BORDER_COLOR: usize = 0x20;
+const REG_BACKGROUND_COLOR: usize = 0x21;
+const REG_SPRITE_MULTICOLOR_0: usize = 0x25;
+const REG_SPRITE_MULTICOLOR_1: usize = 0x26;
+const REG_SPRITE_COLOR: usize = 0x27;
+
+const CONTROL_1_YSCROLL: u8 = 0b0000_0111;
+const CONTROL_1_RSEL: u8 = 0b0000_1000;
+const CONTROL_1_DEN: u8 = 0b0001_0000;
+const CONTROL_1_BMM: u8 = 0b0010_0000;
+const CONTROL_1_ECM: u8 = 0b0100_0000;
+const CONTROL_1_RST8: u8 = 0b1000_0000;
+
+// Interrupt sources in $d019/$d01a, only raster one is generated now
+const INTERRUPT_RASTER: u8 = 0b0000_0001;
+const INTERRUPT_SOURCES: u8 = 0b0000_1111;
+const INTERRUPT_IRQ: u8 = 0b1000_0000;
+
+const CONTROL_2_XSCROLL: u8 = 0b0000_0111;
+const CONTROL_2_CSEL: u8 = 0b0000_1000;
+const CONTROL_2_MCM: u8 = 0b0001_0000;
+
+const FIRST_DMA_LINE: u16 = 0x30;
+const LAST_DMA_LINE: u16 = 0xf7;
+
+// BA goes low 3 cycles before the first c-access (cycle 15) and stays low till the last one.
+const BADLINE_BA_FIRST_CYCLE: u8 = 12;
+const BADLINE_BA_LAST_CYCLE: u8 = 54;
+
+// Cycle when VIC checks if sprite DMA should be turned on or off
+const SPRITE_DMA_CHECK_CYCLE: u8 = 55;
+// First cycle of the p-access of each sprite. Sprites 3-7 are fetched at the beginning of
+// the next line.
+const SPRITE_FETCH_CYCLE: [u8; 8] = [58, 60, 62, 1, 3, 5, 7, 9];
+const SPRITE_HEIGHT: u8 = 21;
+const SPRITE_BYTES_PER_LINE: u16 = 3;
+const SPRITE_DATA_SIZE: u16 = 64;
+
+const CHAR_ROM_SIZE: usize = 0x1000;
+
+// One pixel of graphics layer. Foreground pixels are in front of sprites with low
+// priority and are checked for collisions.
+#[derive(Clone, Copy)]
+struct Pixel {
+    color: u8,
+    foreground: bool,
+}
+
 pub struct SimpleVic {
-    pub memory: Vec<u8>,
+    pub registers: [u8; REGISTERS_COUNT],
     pub monitor: Rc<RefCell<dyn Monitor>>,
-}
 
-#[derive(Debug)]
-pub struct SetMemoryError {}
+    ram: Rc<RefCell<Ram>>,
+    color_ram: Rc<RefCell<ColorRam>>,
+    // VIC sees character rom at $1000-$1fff in banks 0 and 2
+    char_rom: Option<Vec<u8>>,
+    // 16K bank VIC is looking at. Selected by CIA2.
+    bank: u8,
+    standard: VideoStandard,
+    // Frame being drawn now and the last completed one
+    back_frame: FrameBuffer,
+    frame: FrameBuffer,
+
+    raster_line: u16,
+    // 1-based, like in all VIC-II docs
+    raster_cycle: u8,
+    // DEN bit have to be set somewhere on line 0x30 to allow badlines in this frame
+    den_latched: bool,
+    sprite_dma: [bool; 8],
+    sprite_lines_left: [u8; 8],
+    ba_low: bool,
+}
 
 impl SimpleVic {
-    pub fn new(monitor: Rc<RefCell<dyn Monitor>>, size: usize) -> Self {
+    pub fn new(
+        monitor: Rc<RefCell<dyn Monitor>>,
+        ram: Rc<RefCell<Ram>>,
+        color_ram: Rc<RefCell<ColorRam>>,
+    ) -> Self {
+        // We don't have KERNAL yet, so start with values it would set
+        let mut registers = [0; REGISTERS_COUNT];
+        registers[REG_CONTROL_1] = 0x1b;
+        registers[REG_CONTROL_2] = 0xc8;
+        registers[REG_MEMORY_POINTERS] = 0x15;
+        registers[REG_BORDER_COLOR] = 0x0e;
+        registers[REG_BACKGROUND_COLOR] = 0x06;
         Self {
-            memory: vec![0; size],
+            registers,
             monitor,
+            ram,
+            color_ram,
+            char_rom: None,
+            bank: 0,
+            standard: VideoStandard::Pal,
+            back_frame: FrameBuffer::new(FRAME_WIDTH, FRAME_HEIGHT),
+            frame: FrameBuffer::new(FRAME_WIDTH, FRAME_HEIGHT),
+            raster_line: 0,
+            raster_cycle: 1,
+            den_latched: false,
+            sprite_dma: [false; 8],
+            sprite_lines_left: [0; 8],
+            ba_low: false,
+        }
+    }
+
+    pub fn set_char_rom(&mut self, data: Vec<u8>) {
+        assert_eq!(data.len(), CHAR_ROM_SIZE);
+        self.char_rom = Some(data);
+    }
+
+    pub fn set_standard(&mut self, standard: VideoStandard) {
+        self.standard = standard;
+    }
+
+    pub fn standard(&self) -> VideoStandard {
+        self.standard
+    }
+
+    pub fn set_bank(&mut self, bank: u8) {
+        self.bank = bank & 0b11;
+    }
+
+    // Address of video matrix as cpu sees it
+    pub fn screen_address(&self) -> u16 {
+        ((self.bank as u16) << 14) | self.screen_base()
+    }
+
+    // Last completed frame
+    pub fn frame(&self) -> &FrameBuffer {
+        &self.frame
+    }
+
+    // Copy of the last frame, either whole or only display window (320x200)
+    pub fn screenshot(&self, with_border: bool) -> FrameBuffer {
+        if with_border {
+            return self.frame.crop(0, 0, FRAME_WIDTH, FRAME_HEIGHT);
+        }
+        self.frame.crop(
+            DISPLAY_LEFT,
+            (DISPLAY_TOP - FIRST_VISIBLE_LINE) as usize,
+            DISPLAY_RIGHT - DISPLAY_LEFT,
+            (DISPLAY_BOTTOM - DISPLAY_TOP) as usize,
+        )
+    }
+
+    pub fn raster_line(&self) -> u16 {
+        self.raster_line
+    }
+
+    pub fn raster_cycle(&self) -> u8 {
+        self.raster_cycle
+    }
+
+    // BA line state for the upcoming cycle. When it's low cpu should be stopped
+    // via RDY input.
+    pub fn ba_low(&self) -> bool {
+        self.ba_low
+    }
+
+    // IRQ line, active when any enabled interrupt is latched
+    pub fn interrupt(&self) -> bool {
+        self.registers[REG_INTERRUPT] & self.registers[REG_INTERRUPT_ENABLE] & INTERRUPT_SOURCES
+            != 0
+    }
+
+    // Raster compare value, written to $d012 and bit 7 of $d011
+    fn raster_compare(&self) -> u16 {
+        let rst8 = (self.registers[REG_CONTROL_1] & CONTROL_1_RST8) as u16;
+        rst8 << 1 | self.registers[REG_RASTER] as u16
+    }
+
+    pub fn is_badline(&self) -> bool {
+        self.den_latched
+            && (FIRST_DMA_LINE..=LAST_DMA_LINE).contains(&self.raster_line)
+            && (self.raster_line as u8 & CONTROL_1_YSCROLL)
+                == (self.registers[REG_CONTROL_1] & CONTROL_1_YSCROLL)
+    }
+
+    fn sprite_ba_low(&self) -> bool {
+        (0..8).any(|i| {
+            if !self.sprite_dma[i] {
+                return false;
+            }
+            // BA is low 3 cycles before p-access, then during p- and s-accesses
+            let first = SPRITE_FETCH_CYCLE[i] as i16 - 3;
+            let distance = (self.raster_cycle as i16 - first)
+                .rem_euclid(self.standard.cycles_per_line() as i16);
+            distance < 5
+        })
+    }
+
+    fn update_sprite_dma(&mut self) {
+        let enabled = self.registers[REG_SPRITE_ENABLE];
+        let expanded = self.registers[REG_SPRITE_Y_EXPAND];
+        for i in 0..8 {
+            if self.sprite_dma[i] {
+                self.sprite_lines_left[i] -= 1;
+                if self.sprite_lines_left[i] == 0 {
+                    self.sprite_dma[i] = false;
+                }
+            } else if enabled & (1 << i) != 0
+                && self.registers[REG_SPRITE_Y + 2 * i] == self.raster_line as u8
+            {
+                self.sprite_dma[i] = true;
+                self.sprite_lines_left[i] = if expanded & (1 << i) != 0 {
+                    2 * SPRITE_HEIGHT
+                } else {
+                    SPRITE_HEIGHT
+                };
+            }
+        }
+    }
+
+    fn update_ba(&mut self) {
+        let badline_ba = self.is_badline()
+            && (BADLINE_BA_FIRST_CYCLE..=BADLINE_BA_LAST_CYCLE).contains(&self.raster_cycle);
+        self.ba_low = badline_ba || self.sprite_ba_low();
+    }
+
+    // Address is 14 bit, as VIC sees it inside the current bank
+    fn read_memory(&self, address: u16) -> u8 {
+        let address = address & 0x3fff;
+        if let Some(char_rom) = &self.char_rom {
+            if self.bank & 1 == 0 && (0x1000..0x2000).contains(&address) {
+                return char_rom[address as usize - 0x1000];
+            }
         }
+        self.ram
+            .borrow()
+            .get_byte(((self.bank as u16) << 14) | address)
     }
 
-    pub fn set_memory(&mut self, data: &[u8], offset: u16) -> Result<(), SetMemoryError> {
-        let offset = offset as usize;
-        if offset + data.len() > self.memory.len() {
-            return Err(SetMemoryError {});
+    fn screen_base(&self) -> u16 {
+        ((self.registers[REG_MEMORY_POINTERS] & 0xf0) as u16) << 6
+    }
+
+    fn char_base(&self) -> u16 {
+        ((self.registers[REG_MEMORY_POINTERS] & 0x0e) as u16) << 10
+    }
+
+    fn bitmap_base(&self) -> u16 {
+        ((self.registers[REG_MEMORY_POINTERS] & 0x08) as u16) << 10
+    }
+
+    // 8 pixels of one character cell
+    fn cell_pixels(&self, row: u16, column: u16, row_counter: u16) -> [Pixel; 8] {
+        let control_1 = self.registers[REG_CONTROL_1];
+        let ecm = control_1 & CONTROL_1_ECM != 0;
+        let bmm = control_1 & CONTROL_1_BMM != 0;
+        let mcm = self.registers[REG_CONTROL_2] & CONTROL_2_MCM != 0;
+
+        let cell = row * COLUMNS + column;
+        let code = self.read_memory(self.screen_base() + cell);
+        let color = self.color_ram.borrow().get_color(cell);
+        let background = self.registers[REG_BACKGROUND_COLOR] & 0x0f;
+
+        let char_data =
+            |code: u8| self.read_memory(self.char_base() + code as u16 * 8 + row_counter);
+        let bitmap_data = || self.read_memory(self.bitmap_base() + cell * 8 + row_counter);
+
+        match (ecm, bmm, mcm) {
+            (false, false, false) => hires_pixels(char_data(code), color, background),
+            (false, false, true) if color & 0x08 == 0 => {
+                hires_pixels(char_data(code), color & 0x07, background)
+            }
+            (false, false, true) => multicolor_pixels(
+                char_data(code),
+                [
+                    background,
+                    self.registers[REG_BACKGROUND_COLOR + 1] & 0x0f,
+                    self.registers[REG_BACKGROUND_COLOR + 2] & 0x0f,
+                    color & 0x07,
+                ],
+            ),
+            (true, false, false) => {
+                let background = self.registers[REG_BACKGROUND_COLOR + (code >> 6) as usize];
+                hires_pixels(char_data(code & 0x3f), color, background & 0x0f)
+            }
+            (false, true, false) => hires_pixels(bitmap_data(), code >> 4, code & 0x0f),
+            (false, true, true) => {
+                multicolor_pixels(bitmap_data(), [background, code >> 4, code & 0x0f, color])
+            }
+            // Invalid modes show only black, but still produce foreground for collisions
+            (_, true, _) => hires_pixels(bitmap_data(), 0, 0),
+            _ => hires_pixels(char_data(code & 0x3f), 0, 0),
+        }
+    }
+
+    fn graphics_line(&self, line: u16) -> Vec<Pixel> {
+        let background = Pixel {
+            color: self.registers[REG_BACKGROUND_COLOR] & 0x0f,
+            foreground: false,
+        };
+        let mut pixels = vec![background; (COLUMNS * 8) as usize];
+
+        let first_line =
+            FIRST_DMA_LINE + (self.registers[REG_CONTROL_1] & CONTROL_1_YSCROLL) as u16;
+        if !self.den_latched || line < first_line {
+            return pixels;
+        }
+        let row = (line - first_line) / 8;
+        let row_counter = (line - first_line) % 8;
+        if row >= ROWS {
+            return pixels;
+        }
+
+        let xscroll = (self.registers[REG_CONTROL_2] & CONTROL_2_XSCROLL) as usize;
+        for column in 0..COLUMNS {
+            let cell = self.cell_pixels(row, column, row_counter);
+            for (i, pixel) in cell.iter().enumerate() {
+                let x = column as usize * 8 + i + xscroll;
+                if x < pixels.len() {
+                    pixels[x] = *pixel;
+                }
+            }
         }
-        for i in 0..data.len() {
-            self.memory[i + offset] = data[i]
+        pixels
+    }
+
+    fn draw_sprites(&self, line: u16, graphics: &[Pixel], out: &mut [u8]) {
+        // Draw sprites from the lowest priority, so sprite 0 ends up on top
+        for i in (0..8).rev() {
+            self.draw_sprite(i, line, graphics, out);
         }
-        Ok(())
     }
+
+    fn draw_sprite(&self, i: usize, line: u16, graphics: &[Pixel], out: &mut [u8]) {
+        let enabled = self.registers[REG_SPRITE_ENABLE] & (1 << i) != 0;
+        if !enabled {
+            return;
+        }
+        let y_expand = self.registers[REG_SPRITE_Y_EXPAND] & (1 << i) != 0;
+        let height = if y_expand {
+            2 * SPRITE_HEIGHT
+        } else {
+            SPRITE_HEIGHT
+        } as i32;
+        // Sprite data fetched on line Y shows up starting from the next one
+        let sprite_line = line as i32 - self.registers[REG_SPRITE_Y + 2 * i] as i32 - 1;
+        if !(0..height).contains(&sprite_line) {
+            return;
+        }
+        let sprite_line = if y_expand {
+            sprite_line / 2
+        } else {
+            sprite_line
+        } as u16;
+
+        let pointer = self.read_memory(self.screen_base() + SPRITE_POINTERS_OFFSET + i as u16);
+        let address = pointer as u16 * SPRITE_DATA_SIZE + sprite_line * SPRITE_BYTES_PER_LINE;
+        let data = (self.read_memory(address) as u32) << 16
+            | (self.read_memory(address + 1) as u32) << 8
+            | self.read_memory(address + 2) as u32;
+
+        let multicolor = self.registers[REG_SPRITE_MULTICOLOR] & (1 << i) != 0;
+        let x_expand = self.registers[REG_SPRITE_X_EXPAND] & (1 << i) != 0;
+        let behind_graphics = self.registers[REG_SPRITE_PRIORITY] & (1 << i) != 0;
+        // Indexed by pixel bits, 0 is transparent
+        let colors = if multicolor {
+            [
+                None,
+                Some(self.registers[REG_SPRITE_MULTICOLOR_0] & 0x0f),
+                Some(self.registers[REG_SPRITE_COLOR + i] & 0x0f),
+                Some(self.registers[REG_SPRITE_MULTICOLOR_1] & 0x0f),
+            ]
+        } else {
+            [
+                None,
+                Some(self.registers[REG_SPRITE_COLOR + i] & 0x0f),
+                None,
+                None,
+            ]
+        };
+
+        let msb = (self.registers[REG_SPRITE_X_MSB] >> i) as i32 & 1;
+        let sprite_x = self.registers[2 * i] as i32 | msb << 8;
+        let pixel_width = if x_expand { 2 } else { 1 };
+
+        for bit in 0..24 {
+            let bits = if multicolor {
+                (data >> (22 - (bit & !1))) & 0b11
+            } else {
+                (data >> (23 - bit)) & 0b1
+            };
+            let color = match colors[bits as usize] {
+                Some(color) => color,
+                None => continue,
+            };
+            for dx in 0..pixel_width {
+                let x = sprite_x + SPRITE_X_OFFSET + bit * pixel_width + dx;
+                if !(0..FRAME_WIDTH as i32).contains(&x) {
+                    continue;
+                }
+                let x = x as usize;
+                let in_front = x
+                    .checked_sub(DISPLAY_LEFT)
+                    .and_then(|x| graphics.get(x))
+                    .is_some_and(|pixel| pixel.foreground);
+                if behind_graphics && in_front {
+                    continue;
+                }
+                out[x] = color;
+            }
+        }
+    }
+
+    fn border_open(&self, line: u16, x: usize) -> bool {
+        let control_1 = self.registers[REG_CONTROL_1];
+        if !self.den_latched {
+            return false;
+        }
+        let (top, bottom) = if control_1 & CONTROL_1_RSEL != 0 {
+            (DISPLAY_TOP, DISPLAY_BOTTOM)
+        } else {
+            (DISPLAY_TOP_24_ROWS, DISPLAY_BOTTOM_24_ROWS)
+        };
+        let (left, right) = if self.registers[REG_CONTROL_2] & CONTROL_2_CSEL != 0 {
+            (DISPLAY_LEFT, DISPLAY_RIGHT)
+        } else {
+            (DISPLAY_LEFT_38_COLUMNS, DISPLAY_RIGHT_38_COLUMNS)
+        };
+        (top..bottom).contains(&line) && (left..right).contains(&x)
+    }
+
+    fn draw_line(&mut self, line: u16) {
+        if line < FIRST_VISIBLE_LINE || line >= FIRST_VISIBLE_LINE + FRAME_HEIGHT as u16 {
+            return;
+        }
+        let y = (line - FIRST_VISIBLE_LINE) as usize;
+
+        let graphics = self.graphics_line(line);
+        let background = self.registers[REG_BACKGROUND_COLOR] & 0x0f;
+        let mut out = vec![background; FRAME_WIDTH];
+        for (i, pixel) in graphics.iter().enumerate() {
+            out[DISPLAY_LEFT + i] = pixel.color;
+        }
+        self.draw_sprites(line, &graphics, &mut out);
+
+        let border = self.registers[REG_BORDER_COLOR] & 0x0f;
+        for (x, color) in out.iter().enumerate() {
+            let color = if self.border_open(line, x) {
+                *color
+            } else {
+                border
+            };
+            self.back_frame.set(x, y, color);
+        }
+    }
+}
+
+fn hires_pixels(data: u8, foreground: u8, background: u8) -> [Pixel; 8] {
+    let mut pixels = [Pixel {
+        color: background,
+        foreground: false,
+    }; 8];
+    for (i, pixel) in pixels.iter_mut().enumerate() {
+        if data & (0x80 >> i) != 0 {
+            pixel.color = foreground;
+            pixel.foreground = true;
+        }
+    }
+    pixels
+}
+
+// Pixels are twice as wide, color selected by bit pairs. Pairs 00 and 01 are background.
+fn multicolor_pixels(data: u8, colors: [u8; 4]) -> [Pixel; 8] {
+    let mut pixels = [Pixel {
+        color: 0,
+        foreground: false,
+    }; 8];
+    for (i, pixel) in pixels.iter_mut().enumerate() {
+        let bits = (data >> (6 - (i & !1))) & 0b11;
+        pixel.color = colors[bits as usize];
+        pixel.foreground = bits & 0b10 != 0;
+    }
+    pixels
 }
 
 impl Device for SimpleVic {
     fn set_byte(&mut self, byte: u8, offset: u16) {
-        let offset = offset - 0xb000;
-        self.memory[offset as usize] = byte;
+        let reg = offset as usize % REGISTERS_COUNT;
+        if reg == REG_INTERRUPT {
+            // Latched interrupts are acknowledged by writing 1 to them
+            self.registers[reg] &= !byte;
+            return;
+        }
+        self.registers[reg] = byte;
+        if reg == REG_CONTROL_1 {
+            // Writing to $d011 may create or cancel badline in the middle of line
+            if self.raster_line == FIRST_DMA_LINE && byte & CONTROL_1_DEN != 0 {
+                self.den_latched = true;
+            }
+            self.update_ba();
+        }
     }
 
     fn get_byte(&self, offset: u16) -> u8 {
-        let offset = offset - 0xb000;
-        self.memory[offset as usize]
+        let reg = offset as usize % REGISTERS_COUNT;
+        match reg {
+            REG_CONTROL_1 => {
+                let rst8 = if self.raster_line & 0x100 != 0 {
+                    CONTROL_1_RST8
+                } else {
+                    0
+                };
+                (self.registers[reg] & !CONTROL_1_RST8) | rst8
+            }
+            REG_RASTER => self.raster_line as u8,
+            REG_INTERRUPT => {
+                let irq = if self.interrupt() { INTERRUPT_IRQ } else { 0 };
+                self.registers[reg] | irq | 0x70
+            }
+            REG_INTERRUPT_ENABLE => self.registers[reg] | 0xf0,
+            0x2f..=0x3f => 0xff,
+            _ => self.registers[reg],
+        }
     }
 
     fn get_bytes_slice(&self, from: u16, to: u16) -> Vec<u8> {
-        self.memory[from as usize..to as usize].to_vec()
+        (from..to).map(|offset| self.get_byte(offset)).collect()
     }
 
     fn tick(&mut self) {
-        let x = self.memory[0];
-        self.monitor
+        self.raster_cycle += 1;
+        if self.raster_cycle > self.standard.cycles_per_line() {
+            // Not cycle exact, but good enough for programs which don't change
+            // registers in the middle of the line
+            self.draw_line(self.raster_line);
+
+            self.raster_cycle = 1;
+            self.raster_line += 1;
+            if self.raster_line == self.standard.lines_per_frame() {
+                self.raster_line = 0;
+                self.den_latched = false;
+                std::mem::swap(&mut self.frame, &mut self.back_frame);
+                self.monitor.borrow_mut().draw_frame(&self.frame);
+            }
+            if self.raster_line == self.raster_compare() {
+                self.registers[REG_INTERRUPT] |= INTERRUPT_RASTER;
+            }
+        }
+
+        if self.raster_line == FIRST_DMA_LINE && self.registers[REG_CONTROL_1] & CONTROL_1_DEN != 0
+        {
+            self.den_latched = true;
+        }
+        if self.raster_cycle == SPRITE_DMA_CHECK_CYCLE {
+            self.update_sprite_dma();
+        }
+        self.update_ba();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::host_io::{Color, NullMonitor};
+
+    fn fixture() -> SimpleVic {
+        SimpleVic::new(
+            Rc::new(RefCell::new(NullMonitor {})),
+            Rc::new(RefCell::new(Ram::new(0xffff + 1))),
+            Rc::new(RefCell::new(ColorRam::new())),
+        )
+    }
+
+    fn run_frame(vic: &mut SimpleVic) {
+        run_to(vic, LINES_PER_FRAME - 1, 1);
+        run_to(vic, 0, 1);
+    }
+
+    // Pixel in the display window coordinates
+    fn display_pixel(vic: &SimpleVic, x: usize, y: usize) -> Color {
+        vic.frame().get(
+            DISPLAY_LEFT + x,
+            (DISPLAY_TOP - FIRST_VISIBLE_LINE) as usize + y,
+        )
+    }
+
+    fn run_to(vic: &mut SimpleVic, line: u16, cycle: u8) {
+        while vic.raster_line() != line || vic.raster_cycle() != cycle {
+            vic.tick();
+        }
+    }
+
+    // Collect cycles of the line where BA is low
+    fn ba_low_cycles(vic: &mut SimpleVic, line: u16) -> Vec<u8> {
+        run_to(vic, line, 1);
+        let mut cycles = vec![];
+        while vic.raster_line() == line {
+            if vic.ba_low() {
+                cycles.push(vic.raster_cycle());
+            }
+            vic.tick();
+        }
+        cycles
+    }
+
+    #[test]
+    fn raster_wraps_at_frame_end() {
+        let mut vic = fixture();
+        for _ in 0..(CYCLES_PER_LINE as u32 * LINES_PER_FRAME as u32) {
+            vic.tick();
+        }
+        assert_eq!(vic.raster_line(), 0);
+        assert_eq!(vic.raster_cycle(), 1);
+    }
+
+    #[test]
+    fn ntsc_frame() {
+        let mut vic = fixture();
+        vic.set_standard(VideoStandard::Ntsc);
+        for _ in 0..17095 {
+            vic.tick();
+        }
+        assert_eq!(vic.raster_line(), 0);
+        assert_eq!(vic.raster_cycle(), 1);
+        run_to(&mut vic, 0, 65);
+        vic.tick();
+        assert_eq!(vic.raster_line(), 1);
+    }
+
+    #[test]
+    fn raster_register() {
+        let mut vic = fixture();
+        run_to(&mut vic, 0x105, 1);
+        assert_eq!(vic.get_byte(0xd012), 0x05);
+        assert_eq!(vic.get_byte(0xd011) & CONTROL_1_RST8, CONTROL_1_RST8);
+    }
+
+    #[test]
+    fn badline_steals_cycles() {
+        let mut vic = fixture();
+        // default yscroll is 3
+        assert_eq!(ba_low_cycles(&mut vic, 0x32), Vec::<u8>::new());
+        assert_eq!(
+            ba_low_cycles(&mut vic, 0x33),
+            (BADLINE_BA_FIRST_CYCLE..=BADLINE_BA_LAST_CYCLE).collect::<Vec<u8>>()
+        );
+        assert_eq!(ba_low_cycles(&mut vic, 0x34), Vec::<u8>::new());
+        assert!(!ba_low_cycles(&mut vic, 0x3b).is_empty());
+    }
+
+    #[test]
+    fn no_badlines_if_display_disabled() {
+        let mut vic = fixture();
+        vic.set_byte(0x0b, 0xd011);
+        assert_eq!(ba_low_cycles(&mut vic, 0x33), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn sprite_dma_steals_cycles() {
+        let mut vic = fixture();
+        vic.set_byte(0x01, 0xd015);
+        vic.set_byte(0x60, 0xd001);
+
+        assert_eq!(ba_low_cycles(&mut vic, 0x60), vec![55, 56, 57, 58, 59]);
+        assert_eq!(ba_low_cycles(&mut vic, 0x61), vec![55, 56, 57, 58, 59]);
+        // 21 lines of sprite data
+        assert_eq!(ba_low_cycles(&mut vic, 0x74), vec![55, 56, 57, 58, 59]);
+        assert_eq!(ba_low_cycles(&mut vic, 0x75), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn sprite_dma_wraps_to_next_line() {
+        let mut vic = fixture();
+        vic.set_byte(0x08, 0xd015);
+        vic.set_byte(0x60, 0xd007);
+
+        assert_eq!(ba_low_cycles(&mut vic, 0x60), vec![61, 62, 63]);
+        assert_eq!(ba_low_cycles(&mut vic, 0x61), vec![1, 2, 61, 62, 63]);
+    }
+
+    #[test]
+    fn raster_interrupt() {
+        let mut vic = fixture();
+        vic.set_byte(0x9b, 0xd011);
+        vic.set_byte(0x05, 0xd012);
+        vic.set_byte(INTERRUPT_RASTER, 0xd01a);
+        assert_eq!(vic.get_byte(0xd01a), 0xf1);
+
+        run_to(&mut vic, 0x104, 1);
+        assert!(!vic.interrupt());
+        run_to(&mut vic, 0x105, 1);
+        assert!(vic.interrupt());
+        assert_eq!(vic.get_byte(0xd019), 0xf1);
+
+        vic.set_byte(INTERRUPT_RASTER, 0xd019);
+        assert!(!vic.interrupt());
+        assert_eq!(vic.get_byte(0xd019), 0x70);
+    }
+
+    #[test]
+    fn frame_is_passed_to_monitor() {
+        struct CountingMonitor {
+            frames: usize,
+        }
+        impl Monitor for CountingMonitor {
+            fn draw_frame(&mut self, frame: &FrameBuffer) {
+                assert_eq!(frame.width, FRAME_WIDTH);
+                assert_eq!(frame.height, FRAME_HEIGHT);
+                self.frames += 1;
+            }
+        }
+        let monitor = Rc::new(RefCell::new(CountingMonitor { frames: 0 }));
+        let mut vic = SimpleVic::new(
+            monitor.clone(),
+            Rc::new(RefCell::new(Ram::new(0xffff + 1))),
+            Rc::new(RefCell::new(ColorRam::new())),
+        );
+
+        run_frame(&mut vic);
+        run_frame(&mut vic);
+        assert_eq!(monitor.borrow().frames, 2);
+    }
+
+    #[test]
+    fn border_and_background() {
+        let mut vic = fixture();
+        vic.set_byte(0x02, 0xd020);
+        vic.set_byte(0x05, 0xd021);
+        run_frame(&mut vic);
+
+        assert_eq!(vic.frame().get(0, 0), Color::Red);
+        assert_eq!(vic.frame().get(DISPLAY_LEFT - 1, 100), Color::Red);
+        assert_eq!(vic.frame().get(DISPLAY_RIGHT, 100), Color::Red);
+        assert_eq!(display_pixel(&vic, 0, 0), Color::Green);
+        assert_eq!(display_pixel(&vic, 319, 199), Color::Green);
+    }
+
+    #[test]
+    fn blank_screen_when_display_disabled() {
+        let mut vic = fixture();
+        vic.set_byte(0x0b, 0xd011);
+        run_frame(&mut vic);
+
+        assert_eq!(display_pixel(&vic, 100, 100), Color::LightBlue);
+    }
+
+    #[test]
+    fn text_mode_char() {
+        let mut vic = fixture();
+        // Screen at $0400, chars at $2000
+        vic.set_byte(0x18, 0xd018);
+        vic.ram
             .borrow_mut()
-            .set_symbol(x as u16, 0, 'a', Color::Red);
+            .set_memory(&[0x01], 0x0400 + 41)
+            .unwrap();
+        vic.ram
+            .borrow_mut()
+            .set_memory(&[0x80, 0x40, 0, 0, 0, 0, 0, 0x01], 0x2008)
+            .unwrap();
+        vic.color_ram.borrow_mut().set_byte(0x07, 0xd800 + 41);
+        run_frame(&mut vic);
+
+        assert_eq!(display_pixel(&vic, 8, 8), Color::Yellow);
+        assert_eq!(display_pixel(&vic, 9, 8), Color::Blue);
+        assert_eq!(display_pixel(&vic, 9, 9), Color::Yellow);
+        assert_eq!(display_pixel(&vic, 15, 15), Color::Yellow);
+        assert_eq!(display_pixel(&vic, 7, 8), Color::Blue);
+    }
+
+    #[test]
+    fn char_rom_is_visible_in_bank_0() {
+        let mut vic = fixture();
+        let mut rom = vec![0; CHAR_ROM_SIZE];
+        rom[8] = 0xff;
+        vic.set_char_rom(rom);
+        vic.ram.borrow_mut().set_memory(&[0x01], 0x0400).unwrap();
+        vic.color_ram.borrow_mut().set_byte(0x01, 0xd800);
+        run_frame(&mut vic);
+
+        assert_eq!(display_pixel(&vic, 3, 0), Color::White);
+        assert_eq!(display_pixel(&vic, 3, 1), Color::Blue);
+    }
+
+    #[test]
+    fn multicolor_bitmap() {
+        let mut vic = fixture();
+        vic.set_byte(0x3b, 0xd011);
+        vic.set_byte(0xd8, 0xd016);
+        // Screen at $0400, bitmap at $2000
+        vic.set_byte(0x18, 0xd018);
+        vic.ram.borrow_mut().set_memory(&[0x27], 0x0400).unwrap();
+        vic.ram
+            .borrow_mut()
+            .set_memory(&[0b00_01_10_11], 0x2000)
+            .unwrap();
+        vic.color_ram.borrow_mut().set_byte(0x05, 0xd800);
+        run_frame(&mut vic);
+
+        assert_eq!(display_pixel(&vic, 0, 0), Color::Blue);
+        assert_eq!(display_pixel(&vic, 2, 0), Color::Red);
+        assert_eq!(display_pixel(&vic, 4, 0), Color::Yellow);
+        assert_eq!(display_pixel(&vic, 7, 0), Color::Green);
+    }
+
+    #[test]
+    fn screenshot_without_border() {
+        let mut vic = fixture();
+        vic.set_byte(0x02, 0xd020);
+        vic.set_byte(0x05, 0xd021);
+        run_frame(&mut vic);
+
+        let screen = vic.screenshot(false);
+        assert_eq!((screen.width, screen.height), (320, 200));
+        assert!(screen.pixels.iter().all(|&color| color == 0x05));
+
+        let screen = vic.screenshot(true);
+        assert_eq!((screen.width, screen.height), (FRAME_WIDTH, FRAME_HEIGHT));
+        assert_eq!(screen.get(0, 0), Color::Red);
+    }
+
+    #[test]
+    fn sprite() {
+        let mut vic = fixture();
+        vic.set_byte(0x01, 0xd015);
+        vic.set_byte(24, 0xd000);
+        vic.set_byte(50, 0xd001);
+        vic.set_byte(0x0a, 0xd027);
+        // Sprite pointer 0x80 -> data at $2000
+        vic.ram.borrow_mut().set_memory(&[0x80], 0x07f8).unwrap();
+        vic.ram
+            .borrow_mut()
+            .set_memory(&[0x80, 0, 0x01], 0x2000)
+            .unwrap();
+        run_frame(&mut vic);
+
+        assert_eq!(display_pixel(&vic, 0, 0), Color::LightRed);
+        assert_eq!(display_pixel(&vic, 1, 0), Color::Blue);
+        assert_eq!(display_pixel(&vic, 23, 0), Color::LightRed);
+        assert_eq!(display_pixel(&vic, 0, 1), Color::Blue);
     }
 }
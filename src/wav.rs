@@ -0,0 +1,140 @@
+// Recording of SID output to 16 bit mono PCM WAV file. Chunk sizes in the header are
+// not known until recording is finished, they are patched in then.
+
+use crate::host_io::Resampler;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+pub const WAV_RATE: u32 = 44100;
+
+const HEADER_SIZE: u32 = 44;
+const RIFF_SIZE_OFFSET: u64 = 4;
+const DATA_SIZE_OFFSET: u64 = 40;
+const FORMAT_PCM: u16 = 1;
+const BYTES_PER_SAMPLE: u32 = 2;
+
+pub struct WavRecorder<W: Write + Seek> {
+    writer: W,
+    resampler: Resampler,
+    samples: u32,
+}
+
+impl WavRecorder<BufWriter<File>> {
+    pub fn create(path: &Path, clock_frequency: u32) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), clock_frequency)
+    }
+}
+
+impl<W: Write + Seek> WavRecorder<W> {
+    // Samples come at cpu clock frequency
+    pub fn new(mut writer: W, clock_frequency: u32) -> io::Result<Self> {
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&(HEADER_SIZE - 8).to_le_bytes())?;
+        writer.write_all(b"WAVE")?;
+
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        writer.write_all(&FORMAT_PCM.to_le_bytes())?;
+        writer.write_all(&1u16.to_le_bytes())?;
+        writer.write_all(&WAV_RATE.to_le_bytes())?;
+        writer.write_all(&(WAV_RATE * BYTES_PER_SAMPLE).to_le_bytes())?;
+        writer.write_all(&(BYTES_PER_SAMPLE as u16).to_le_bytes())?;
+        writer.write_all(&(8 * BYTES_PER_SAMPLE as u16).to_le_bytes())?;
+
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+
+        Ok(Self {
+            writer,
+            resampler: Resampler::new(clock_frequency, WAV_RATE),
+            samples: 0,
+        })
+    }
+
+    pub fn push_sample(&mut self, sample: i16) -> io::Result<()> {
+        if let Some(sample) = self.resampler.push(sample) {
+            self.writer.write_all(&sample.to_le_bytes())?;
+            self.samples += 1;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        let data_size = self.samples * BYTES_PER_SAMPLE;
+        self.writer.seek(SeekFrom::Start(RIFF_SIZE_OFFSET))?;
+        self.writer
+            .write_all(&(HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(DATA_SIZE_OFFSET))?;
+        self.writer.write_all(&data_size.to_le_bytes())?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+// Samples of WAV file written by WavRecorder, for comparing with reference files
+#[cfg(test)]
+pub fn read_samples(data: &[u8]) -> io::Result<Vec<i16>> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    if data.len() < HEADER_SIZE as usize || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return Err(invalid("not a WAV file"));
+    }
+    if data[20..22] != FORMAT_PCM.to_le_bytes() || data[22..24] != 1u16.to_le_bytes() {
+        return Err(invalid("not a mono PCM file"));
+    }
+    let size = u32::from_le_bytes(data[40..44].try_into().unwrap()) as usize;
+    let samples = data
+        .get(HEADER_SIZE as usize..HEADER_SIZE as usize + size)
+        .ok_or_else(|| invalid("truncated data"))?;
+    Ok(samples
+        .chunks_exact(2)
+        .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vic::PAL_CLOCK;
+
+    use std::io::Cursor;
+
+    #[test]
+    fn header() {
+        let mut recorder = WavRecorder::new(Cursor::new(vec![]), PAL_CLOCK).unwrap();
+        for _ in 0..PAL_CLOCK / 10 {
+            recorder.push_sample(1000).unwrap();
+        }
+        let data = recorder.finish().unwrap().into_inner();
+
+        assert_eq!(data.len(), 44 + 4409 * 2);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(
+            u32::from_le_bytes(data[4..8].try_into().unwrap()),
+            36 + 8818
+        );
+        assert_eq!(&data[12..16], b"fmt ");
+        assert_eq!(u32::from_le_bytes(data[24..28].try_into().unwrap()), 44100);
+        assert_eq!(u16::from_le_bytes(data[34..36].try_into().unwrap()), 16);
+        assert_eq!(&data[36..40], b"data");
+    }
+
+    #[test]
+    fn read_back() {
+        let mut recorder = WavRecorder::new(Cursor::new(vec![]), PAL_CLOCK).unwrap();
+        for cycle in 0..PAL_CLOCK / 100 {
+            recorder
+                .push_sample(if cycle < 5000 { -300 } else { 300 })
+                .unwrap();
+        }
+        let data = recorder.finish().unwrap().into_inner();
+
+        let samples = read_samples(&data).unwrap();
+        assert_eq!(samples.len(), 440);
+        assert_eq!(samples[0], -300);
+        assert_eq!(samples[439], 300);
+        assert!(read_samples(&data[0..40]).is_err());
+    }
+}